@@ -0,0 +1,9 @@
+#![no_main]
+
+use increstore::gz::gz_to_plain;
+use libfuzzer_sys::fuzz_target;
+
+// errors are fine, panics and aborts are not
+fuzz_target!(|data: &[u8]| {
+    let _ = gz_to_plain(data, std::io::sink());
+});
@@ -0,0 +1,15 @@
+#![no_main]
+
+use increstore::zip::{zip_to_tar_bytes, StoreConfig};
+use libfuzzer_sys::fuzz_target;
+
+// errors are fine, panics and aborts are not
+fuzz_target!(|data: &[u8]| {
+    let _ = zip_to_tar_bytes(data, &StoreConfig::default());
+
+    let config = StoreConfig {
+        explode_nested: true,
+        ..Default::default()
+    };
+    let _ = zip_to_tar_bytes(data, &config);
+});
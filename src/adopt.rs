@@ -0,0 +1,278 @@
+use super::*;
+use std::collections::HashSet;
+use std::io::{BufRead, Write};
+
+/// writes all blobs as JSON lines, to be used by `adopt_objects` of a replica
+pub fn export_meta(conn: &mut db::Conn, filename: &str) -> Result<()> {
+    let mut w = io::BufWriter::new(std::fs::File::create(filename)?);
+    write_meta(conn, &mut w)?;
+    w.flush()?;
+
+    Ok(())
+}
+
+fn write_meta<W: Write>(conn: &mut db::Conn, mut w: W) -> Result<()> {
+    for entry in db::iter_all(conn).with_provenance() {
+        let (blob, provenance) = entry?;
+        writeln!(w, "{}", db::blob_json(&blob, &provenance))?;
+    }
+    Ok(())
+}
+
+fn read_meta(filename: &str) -> Result<HashMap<String, Blob>> {
+    let r = io::BufReader::new(std::fs::File::open(filename)?);
+
+    let mut blobs = HashMap::new();
+    for line in r.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let blob: Blob = serde_json::from_str(&line)?;
+        blobs.insert(blob.store_hash.clone(), blob);
+    }
+    Ok(blobs)
+}
+
+#[derive(Debug, Default)]
+pub struct AdoptReport {
+    /// store hashes of newly inserted blobs
+    pub adopted: Vec<String>,
+    /// objects without metadata
+    pub unknown: Vec<String>,
+    /// objects whose contents do not match their paths
+    pub mismatched: Vec<String>,
+    /// objects whose parent blobs do not exist
+    pub orphaned: Vec<String>,
+}
+
+/// inserts blob rows for object files which are not referenced by any blob, e.g. copied from
+/// other archive. metadata of the objects are read from `meta_filename`, written by
+/// `export_meta`.
+pub fn adopt_objects(conn: &mut db::Conn, meta_filename: Option<&str>) -> Result<AdoptReport> {
+    let mut report = AdoptReport::default();
+
+    let referenced = db::iter_all(conn)
+        .map(|blob| blob.map(|blob| blob.store_hash))
+        .collect::<rusqlite::Result<HashSet<_>>>()?;
+
+    let mut verified = Vec::new();
+    for (hash, meta) in object_files()? {
+        if referenced.contains(&hash) {
+            continue;
+        }
+
+        let path = filepath(&hash);
+        if file_hash(&path)? != hash {
+            error!("object content does not match its path: {}", path);
+            report.mismatched.push(hash);
+            continue;
+        }
+        verified.push((hash, meta.len()));
+    }
+    verified.sort();
+
+    let mut meta = match meta_filename {
+        Some(filename) => read_meta(filename)?,
+        None => HashMap::new(),
+    };
+
+    let mut pending = Vec::new();
+    for (hash, size) in verified {
+        match meta.remove(&hash) {
+            Some(blob) if blob.store_size == size => pending.push(blob),
+            Some(blob) => {
+                error!(
+                    "invalid file size: hash={}, expected={}, actual={}",
+                    hash, blob.store_size, size
+                );
+                report.mismatched.push(hash);
+            }
+            None => {
+                info!("unreferenced object without metadata: {}", hash);
+                report.unknown.push(hash);
+            }
+        }
+    }
+
+    // insert parents first, in the order of the original archive
+    pending.sort_by_key(|blob| blob.id);
+    let mut roots = Vec::new();
+    loop {
+        let mut inserted = false;
+        let mut remaining = Vec::new();
+        for blob in pending {
+            let parent_exists = match &blob.parent_hash {
+                None => true,
+                Some(parent_hash) => !db::by_content_hash(conn, parent_hash)?.is_empty(),
+            };
+            if !parent_exists {
+                remaining.push(blob);
+                continue;
+            }
+
+            info!("adopting object: {} {}", blob.store_hash, blob.filename);
+            insert_blob(conn, &blob)?;
+            let provenance = db::Provenance {
+                source_path: meta_filename.map(absolute_path),
+                source_host: hostname(),
+                source_url: None,
+            };
+            record_provenance(conn, &blob.content_hash, provenance)?;
+            if blob.is_root() {
+                roots.push(RootTransition::new(EVENT_ROOT_ADDED, &blob, "adopt"));
+            }
+            report.adopted.push(blob.store_hash);
+            inserted = true;
+        }
+
+        pending = remaining;
+        if !inserted || pending.is_empty() {
+            break;
+        }
+    }
+
+    for blob in pending {
+        error!("parent blob does not exist: {}", blob.store_hash);
+        report.orphaned.push(blob.store_hash);
+    }
+
+    if !report.adopted.is_empty() {
+        log_roots(conn, &roots)?;
+        let details = serde_json::json!({
+            "adopted": report.adopted,
+        });
+        log_op(conn, "adopt", details)?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+
+    fn copy_dir(src: &Path, dst: &Path) {
+        for entry in walkdir::WalkDir::new(src) {
+            let entry = entry.unwrap();
+            let target = dst.join(entry.path().strip_prefix(src).unwrap());
+            if entry.file_type().is_dir() {
+                std::fs::create_dir_all(&target).unwrap();
+            } else {
+                std::fs::copy(entry.path(), &target).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn adopt_rsynced_objects() {
+        with_store(|conn, dir| {
+            let v1 = write_file(dir, "app-1.tar", b"hello, world");
+            let v2 = write_file(dir, "app-2.tar", b"hello, world!!");
+            push(conn, &v1, FileType::Plain).unwrap();
+            push(conn, &v2, FileType::Plain).unwrap();
+
+            let meta_filename = dir.join("export.jsonl");
+            export_meta(conn, meta_filename.to_str().unwrap()).unwrap();
+
+            // replica: objects are copied, meta.db is empty
+            let replica = dir.join("replica");
+            copy_dir(&dir.join("objects"), &replica.join("objects"));
+            std::fs::create_dir_all(replica.join("objects/ab")).unwrap();
+            std::fs::write(replica.join("objects/ab/cdef"), b"broken").unwrap();
+
+            std::env::set_var("WORKDIR", &replica);
+            let mut replica_conn = db::open().unwrap();
+            db::prepare(&mut replica_conn).unwrap();
+
+            let report = adopt_objects(&mut replica_conn, None).unwrap();
+            assert!(report.adopted.is_empty());
+            assert_eq!(report.unknown.len(), 3);
+            assert_eq!(report.mismatched, vec!["abcdef".to_owned()]);
+            assert!(db::all(&mut replica_conn).unwrap().is_empty());
+
+            let report = adopt_objects(&mut replica_conn, meta_filename.to_str()).unwrap();
+            assert_eq!(report.adopted.len(), 3);
+            assert!(report.unknown.is_empty());
+            assert!(report.orphaned.is_empty());
+
+            let blobs = db::all(conn).unwrap();
+            let replica_blobs = db::all(&mut replica_conn).unwrap();
+            let hashes = |blobs: &[Blob]| {
+                blobs
+                    .iter()
+                    .map(|blob| (blob.id, blob.store_hash.clone()))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(hashes(&blobs), hashes(&replica_blobs));
+
+            // the export carries the provenance of pushes, adopted blobs come from the export
+            let export = std::fs::read_to_string(&meta_filename).unwrap();
+            assert!(export.contains("\"source_path\""), "{}", export);
+            let provenance = db::provenance(&mut replica_conn, &blobs[0].store_hash).unwrap();
+            let meta_path = std::fs::canonicalize(&meta_filename).unwrap();
+            assert_eq!(
+                provenance.source_path,
+                meta_path.to_str().map(str::to_owned)
+            );
+            assert_eq!(provenance.source_host, hostname());
+
+            validate(&mut replica_conn).unwrap();
+        });
+    }
+
+    #[test]
+    fn export_meta_streams() {
+        with_store(|conn, _dir| {
+            conn.execute_batch("begin").unwrap();
+            for id in 1..=20_000u32 {
+                let parent_hash = if id == 1 {
+                    None
+                } else {
+                    Some(format!("{:064x}", id - 1))
+                };
+                let blob = Blob {
+                    id,
+                    filename: format!("app-{}.tar", id),
+                    time_created: time::OffsetDateTime::UNIX_EPOCH,
+                    store_size: 10,
+                    content_size: 100,
+                    store_hash: format!("{:064x}", u64::MAX - id as u64),
+                    content_hash: format!("{:064x}", id),
+                    parent_hash,
+                };
+                db::insert(conn, &blob).unwrap();
+            }
+            conn.execute_batch("commit").unwrap();
+
+            // the previous implementation: collect every blob, then write
+            let (collected, collected_peak) = peak_alloc(|| {
+                let mut out = io::sink();
+                let blobs = db::all(conn).unwrap();
+                for blob in &blobs {
+                    writeln!(out, "{}", blob.to_json()).unwrap();
+                }
+                blobs.len()
+            });
+            let ((), streamed_peak) = peak_alloc(|| write_meta(conn, io::sink()).unwrap());
+            assert_eq!(collected, 20_000);
+            // at most a page of `db::ITER_PAGE_SIZE` blobs is held at once
+            assert!(collected > db::ITER_PAGE_SIZE * 10);
+            assert!(
+                streamed_peak * 10 < collected_peak,
+                "streamed={} collected={}",
+                streamed_peak,
+                collected_peak
+            );
+
+            let mut expected = Vec::new();
+            for blob in db::all(conn).unwrap() {
+                let json = db::blob_json(&blob, &db::Provenance::default());
+                writeln!(expected, "{}", json).unwrap();
+            }
+            let mut streamed = Vec::new();
+            write_meta(conn, &mut streamed).unwrap();
+            assert_eq!(streamed, expected);
+        });
+    }
+}
@@ -0,0 +1,358 @@
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+use super::*;
+
+const BLOCK_SIZE: u64 = 512;
+/// object files are streamed through a buffer of this size
+const ARCHIVE_BUF_SIZE: usize = 1024 * 1024;
+/// the output is flushed after this many bytes, so a slow reader backs up the archive early
+const ARCHIVE_FLUSH_INTERVAL: u64 = 16 * ARCHIVE_BUF_SIZE as u64;
+
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveOptions {
+    /// throttle the output to this many bytes per second
+    pub limit_rate: Option<u64>,
+}
+
+/// totals of a written archive
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ArchiveSummary {
+    pub files: usize,
+    /// bytes of file contents, excluding tar headers and padding
+    pub bytes: u64,
+}
+
+/// `name` is the path in the archive, relative to WORKDIR
+fn archive_header(path: &str, name: &str) -> Result<tar::Header> {
+    let meta = match std::fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::MissingObject(path.to_owned()));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let size = meta.len();
+
+    let mut header = tar::Header::new_gnu();
+    header.set_path(name)?;
+    header.set_size(size);
+    header.set_mode(0o644);
+
+    if let Ok(time) = meta.modified() {
+        if let Ok(duration) = time.duration_since(std::time::SystemTime::UNIX_EPOCH) {
+            header.set_mtime(duration.as_secs());
+        }
+    }
+
+    header.set_cksum();
+    Ok(header)
+}
+
+/// files in an archive with their headers: the database, genesis and delta blobs. objects are
+/// archived under `objects/`, also when the object directory is elsewhere. all files are checked
+/// here, so a missing object fails the archive before anything is written.
+fn archive_entries(conn: &mut db::Conn, dirs: &StoreDirs) -> Result<Vec<(String, tar::Header)>> {
+    let mut files = vec![(dirs.dbpath(), "meta.db".to_owned())];
+    for blob in db::iter_all(conn) {
+        let blob = blob?;
+        if blob.is_genesis() || !blob.is_root() {
+            let hash = &blob.store_hash;
+            let name = format!("objects/{}/{}", &hash[..2], &hash[2..]);
+            files.push((dirs.filepath(hash), name));
+        }
+    }
+
+    files
+        .into_iter()
+        .map(|(path, name)| {
+            let header = archive_header(&path, &name)?;
+            Ok((path, header))
+        })
+        .collect()
+}
+
+fn padding(size: u64) -> usize {
+    ((BLOCK_SIZE - size % BLOCK_SIZE) % BLOCK_SIZE) as usize
+}
+
+/// a writer which flushes periodically and sleeps to keep under `limit_rate`
+struct ArchiveWriter<W> {
+    w: W,
+    limit_rate: Option<u64>,
+    started: Instant,
+    written: u64,
+    unflushed: u64,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    fn new(w: W, limit_rate: Option<u64>) -> Self {
+        ArchiveWriter {
+            w,
+            limit_rate,
+            started: Instant::now(),
+            written: 0,
+            unflushed: 0,
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.w.write_all(buf)?;
+        self.written += buf.len() as u64;
+        self.unflushed += buf.len() as u64;
+
+        if self.unflushed >= ARCHIVE_FLUSH_INTERVAL {
+            self.w.flush()?;
+            self.unflushed = 0;
+        }
+
+        if let Some(limit_rate) = self.limit_rate {
+            let expected = Duration::from_secs_f64(self.written as f64 / limit_rate.max(1) as f64);
+            let elapsed = self.started.elapsed();
+            if expected > elapsed {
+                std::thread::sleep(expected - elapsed);
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.unflushed = 0;
+        self.w.flush()
+    }
+}
+
+pub fn archive0<W>(conn: &mut db::Conn, w: W, opts: &ArchiveOptions) -> Result<ArchiveSummary>
+where
+    W: Write,
+{
+    archive_in(conn, &StoreDirs::from_env(), w, opts)
+}
+
+/// `archive0` of the store in `dirs`
+pub(crate) fn archive_in<W>(
+    conn: &mut db::Conn,
+    dirs: &StoreDirs,
+    w: W,
+    opts: &ArchiveOptions,
+) -> Result<ArchiveSummary>
+where
+    W: Write,
+{
+    let entries = archive_entries(conn, dirs)?;
+    let total_bytes = entries
+        .iter()
+        .map(|(_path, header)| header.size())
+        .sum::<io::Result<u64>>()?;
+
+    let mut pb = Progress::bytes(total_bytes);
+
+    let mut w = ArchiveWriter::new(w, opts.limit_rate);
+    let mut buf = vec![0u8; ARCHIVE_BUF_SIZE];
+    let mut summary = ArchiveSummary::default();
+    for (path, header) in &entries {
+        let size = header.size()?;
+        debug!("add file name={:?}, size={}", header.path()?, size);
+        w.write_all(header.as_bytes())?;
+
+        let mut file = retry::open(path)?;
+        let mut remaining = size;
+        while remaining > 0 {
+            let len = (remaining as usize).min(buf.len());
+            if let Err(e) = file.read_exact(&mut buf[..len]) {
+                if e.kind() == io::ErrorKind::UnexpectedEof {
+                    return Err(crate::format_err!(
+                        "file size changed while archiving: {}",
+                        path
+                    ));
+                }
+                return Err(e.into());
+            }
+            w.write_all(&buf[..len])?;
+            remaining -= len as u64;
+            summary.bytes += len as u64;
+            pb.add(len as u64);
+        }
+        w.write_all(&[0u8; BLOCK_SIZE as usize][..padding(size)])?;
+
+        summary.files += 1;
+        pb.message(&format!("{}/{} files ", summary.files, entries.len()));
+    }
+
+    // end of archive: two zero blocks
+    w.write_all(&[0u8; 2 * BLOCK_SIZE as usize])?;
+    w.flush()?;
+    pb.finish();
+
+    info!(
+        "archive: files={} bytes={}",
+        summary.files,
+        bytesize::ByteSize(summary.bytes)
+    );
+    Ok(summary)
+}
+
+/// `archive0` for async writers. the tar stream is written block by block, so the output is the
+/// same as `archive0`.
+pub async fn archive0_async<W>(conn: &mut db::Conn, mut w: W) -> Result<()>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::AsyncWriteExt;
+
+    for (path, header) in archive_entries(conn, &StoreDirs::from_env())? {
+        w.write_all(header.as_bytes()).await?;
+
+        let mut file = tokio::fs::File::from_std(retry::open(&path)?);
+        let size = tokio::io::copy(&mut file, &mut w).await?;
+        if size != header.size()? {
+            return Err(crate::format_err!(
+                "file size changed while archiving: {}",
+                path
+            ));
+        }
+        w.write_all(&[0u8; BLOCK_SIZE as usize][..padding(size)])
+            .await?;
+    }
+
+    // end of archive: two zero blocks
+    w.write_all(&[0u8; 2 * BLOCK_SIZE as usize]).await?;
+    w.flush().await?;
+    Ok(())
+}
+
+pub fn archive(conn: &mut db::Conn, filename: &str, opts: &ArchiveOptions) -> Result<()> {
+    if filename != "-" {
+        let file = std::fs::File::create(filename)?;
+        archive0(conn, file, opts)?;
+    } else {
+        let stdout = std::io::stdout();
+        let out = stdout.lock();
+        archive0(conn, out, opts)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+
+    /// a writer which sleeps on every write, recording what it is given
+    #[derive(Default)]
+    struct SlowWriter {
+        out: Vec<u8>,
+        max_write_len: usize,
+        flushes: usize,
+    }
+
+    impl Write for SlowWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            std::thread::sleep(Duration::from_millis(1));
+            self.max_write_len = self.max_write_len.max(buf.len());
+            self.out.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    fn push_versions(conn: &mut db::Conn, dir: &Path) {
+        for i in 0..4 {
+            let body = format!("hello, world{}", "!".repeat(i)).repeat(1024 * (i + 1));
+            let path = write_file(dir, &format!("app-{}.tar", i), body.as_bytes());
+            push(conn, &path, FileType::Plain).unwrap();
+        }
+    }
+
+    fn entry_sizes(out: &[u8]) -> Vec<(String, u64)> {
+        let mut ar = tar::Archive::new(out);
+        ar.entries()
+            .unwrap()
+            .map(|e| {
+                let e = e.unwrap();
+                let name = e.path().unwrap().to_str().unwrap().to_owned();
+                (name, e.size())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn archive_slow_writer() {
+        with_store(|conn, dir| {
+            push_versions(conn, dir);
+
+            let mut w = SlowWriter::default();
+            let summary = archive0(conn, &mut w, &Default::default()).unwrap();
+
+            let entries = entry_sizes(&w.out);
+            assert_eq!(entries[0].0, "meta.db");
+            assert_eq!(summary.files, entries.len());
+            assert_eq!(
+                summary.bytes,
+                entries.iter().map(|(_name, size)| size).sum::<u64>()
+            );
+            assert_eq!(w.out.len() as u64 % BLOCK_SIZE, 0);
+            assert!(w.max_write_len <= ARCHIVE_BUF_SIZE);
+            assert!(w.flushes >= 1);
+        });
+    }
+
+    #[test]
+    fn archive_limit_rate() {
+        with_store(|conn, dir| {
+            push_versions(conn, dir);
+
+            let mut out = Vec::new();
+            archive0(conn, &mut out, &Default::default()).unwrap();
+
+            // 1/5 second worth of output
+            let opts = ArchiveOptions {
+                limit_rate: Some(out.len() as u64 * 5),
+                ..Default::default()
+            };
+            let sw = Stopwatch::start_new();
+            let mut throttled = Vec::new();
+            archive0(conn, &mut throttled, &opts).unwrap();
+            assert!(sw.elapsed_ms() >= 150, "took={}ms", sw.elapsed_ms());
+            assert_eq!(throttled.len(), out.len());
+        });
+    }
+
+    #[test]
+    fn archive_missing_object() {
+        with_store(|conn, dir| {
+            push_versions(conn, dir);
+
+            let blob = db::all(conn)
+                .unwrap()
+                .into_iter()
+                .find(|blob| !blob.is_root())
+                .unwrap();
+            std::fs::remove_file(filepath(&blob.store_hash)).unwrap();
+
+            let mut w = SlowWriter::default();
+            let err = archive0(conn, &mut w, &Default::default()).unwrap_err();
+            assert_eq!(exit_code(&err), EXIT_MISSING_OBJECT);
+            assert!(w.out.is_empty());
+        });
+    }
+
+    #[test]
+    fn archive_async() {
+        with_store(|conn, dir| {
+            push_versions(conn, dir);
+
+            let mut expected = Vec::new();
+            archive0(conn, &mut expected, &Default::default()).unwrap();
+
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            let mut out = Vec::new();
+            rt.block_on(archive0_async(conn, &mut out)).unwrap();
+            assert_eq!(out, expected);
+        });
+    }
+}
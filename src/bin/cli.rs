@@ -1,36 +1,87 @@
 use argh::FromArgs;
+use increstore::{
+    ChainFormat, FileType, GetFormat, ParentSelection, QuotaPolicy, StorageMode,
+    COMPACT_CHAIN_SLACK, PARALLEL_HASH_CHUNK_SIZE,
+};
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// Top-level command.
 struct TopLevel {
     #[argh(subcommand)]
     nested: MySubCommandEnum,
+
+    #[argh(switch)]
+    /// keep inputs and outputs of a decode which fails its checksum in WORKDIR/debug, same as
+    /// KEEP_TEMP=1
+    keep_temp: bool,
+
+    #[argh(option)]
+    /// directory of object files, instead of WORKDIR/objects. same as INCRESTORE_OBJECT_DIR
+    object_dir: Option<String>,
+
+    #[argh(switch)]
+    /// do not record input paths, hostnames and urls of pushed or adopted blobs
+    no_provenance: bool,
+
+    #[argh(option)]
+    /// draw progress bars on stderr: always, never or auto (default), which draws them only on a
+    /// terminal and not if CI or NO_COLOR is set. same as INCRESTORE_PROGRESS
+    progress: Option<increstore::ProgressMode>,
 }
 
+/// options of `TopLevel` taking a value, checked against its usage by the
+/// `top_level_value_options` test
+const VALUE_OPTIONS: &[&str] = &["--object-dir", "--progress"];
+
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand)]
 enum MySubCommandEnum {
     Push(SubCommandPush),
+    Watch(SubCommandWatch),
     Get(SubCommandGet),
     Exists(SubCommandExists),
+    Chain(SubCommandChain),
+    Describe(SubCommandDescribe),
+    Note(SubCommandNote),
 
     Rename(SubCommandRename),
+    CompactChain(SubCommandCompactChain),
+    CompactDeltaChain(SubCommandCompactDeltaChain),
 
-    Dedytrate(SubCommandDehydrate),
+    Dehydrate(SubCommandDehydrate),
     Hydrate(SubCommandHydrate),
 
     Archive(SubCommandArchive),
+    MergeConflicts(SubCommandMergeConflicts),
+    ExportMeta(SubCommandExportMeta),
+    MakePatch(SubCommandMakePatch),
+    Snapshot(SubCommandSnapshot),
+    AdoptObjects(SubCommandAdoptObjects),
 
     Validate(SubCommandValidate),
+    Fsck(SubCommandFsck),
+    Status(SubCommandStatus),
+    StorageMode(SubCommandStorageMode),
+    FsRetry(SubCommandFsRetry),
+    FilenameHint(SubCommandFilenameHint),
 
     BenchZip(SubCommandBenchZip),
 
     CleanUp(SubCommandCleanUp),
+    GcTmp(SubCommandGcTmp),
+    Gc(SubCommandGc),
+    BenchCleanUp(SubCommandBenchCleanUp),
+    Project(SubCommandProject),
     Stats(SubCommandStats),
+    RebuildStatsIndex(SubCommandRebuildStatsIndex),
     Graph(SubCommandGraph),
+    RootTransitions(SubCommandRootTransitions),
     ListFiles(SubCommandListFiles),
+    ListRoots(SubCommandListRoots),
+    Oplog(SubCommandOplog),
     Blobs(SubCommandBlobs),
     Hash(SubCommandHash),
+    ZipHashes(SubCommandZipHashes),
 }
 
 /// push a version to archive
@@ -44,20 +95,253 @@ struct SubCommandPush {
     is_zip: bool,
     #[argh(description = "gz", switch)]
     is_gz: bool,
+
+    #[argh(
+        description = "exit with non-zero code if store_size/content_size of the delta exceeds this",
+        option
+    )]
+    max_ratio: Option<f32>,
+    #[argh(description = "roll back the push if max-ratio is exceeded", switch)]
+    max_ratio_strict: bool,
+
+    #[argh(
+        description = "refuse the push if the store would grow past this many bytes",
+        option
+    )]
+    max_store_size: Option<u64>,
+    #[argh(
+        description = "reject or prune: what to do when max-store-size would be exceeded",
+        option,
+        default = "QuotaPolicy::Reject"
+    )]
+    quota_policy: QuotaPolicy,
+
+    #[argh(
+        description = "store nested zip members of a bundle (.aab, .apks) as their entries. get returns this exploded tar, not the original bundle",
+        switch
+    )]
+    explode_bundle: bool,
+
+    #[argh(
+        description = "append the version to this series, preferring the previous member as delta source",
+        option
+    )]
+    series: Option<String>,
+
+    #[argh(
+        description = "fail and roll back the push if the file changes while it is pushed",
+        switch
+    )]
+    verify_unchanged: bool,
+
+    #[argh(
+        description = "cap on temporary bytes of delta candidates encoded in parallel",
+        option
+    )]
+    tmp_budget: Option<u64>,
+
+    #[argh(
+        description = "url the file was downloaded from, recorded as its provenance",
+        option
+    )]
+    source_url: Option<String>,
+
+    #[argh(
+        description = "encode deltas against all candidates, without skipping the ones estimated dissimilar",
+        switch
+    )]
+    no_prefilter: bool,
+    #[argh(
+        description = "add estimated and actual delta sizes of every candidate to the report, encoding skipped ones too",
+        switch
+    )]
+    record_trials: bool,
+    #[argh(
+        description = "order delta candidates by content size only, instead of by shared chunks",
+        switch
+    )]
+    size_estimator: bool,
+    #[argh(
+        description = "encode the most promising candidate first, and keep its delta at or below this ratio without trying the others",
+        option
+    )]
+    good_enough_ratio: Option<f32>,
+    #[argh(
+        description = "with a filename hint, only try roots of the flavor of the input",
+        switch
+    )]
+    hint_strict: bool,
+    #[argh(
+        description = "greedy (default) keeps the smallest delta, optimal the one leaving the smallest store after cleanup. experimental",
+        option,
+        default = "ParentSelection::Greedy"
+    )]
+    parent_selection: ParentSelection,
+
+    #[argh(description = "write a json report of the push to this path", option)]
+    report_path: Option<String>,
+
+    #[argh(
+        description = "print the estimated delta size against each root, without pushing",
+        switch
+    )]
+    estimate: bool,
+
+    #[argh(
+        description = "update this node_exporter textfile collector file with metrics of the command",
+        option
+    )]
+    metrics_textfile: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// push files appearing in a directory, until interrupted
+#[argh(subcommand, name = "watch")]
+struct SubCommandWatch {
+    #[argh(positional)]
+    /// directory to watch
+    dir_arg: Option<String>,
+    #[argh(option)]
+    /// directory to watch, same as the positional argument
+    dir: Option<String>,
+    #[argh(option)]
+    /// only push files matching this glob, e.g. "*.apk"
+    pattern: Option<String>,
+    #[argh(switch)]
+    /// delete files after push
+    delete_after: bool,
+    #[argh(switch)]
+    /// move files to done/ under the watched directory after push
+    move_done: bool,
+    #[argh(option, default = "5")]
+    /// seconds a file size should stay unchanged before push
+    stable_secs: u64,
+    #[argh(option, long = "type")]
+    /// push every file as zip, gz or plain, instead of detecting the type from the extension
+    ty: Option<FileType>,
+    #[argh(switch)]
+    /// push files as soon as they are closed after writing, instead of waiting for --stable-secs
+    push_on_close: bool,
+    #[argh(switch)]
+    /// exit on the first failed push instead of retrying
+    fail_fast: bool,
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// get a version from archive
 #[argh(subcommand, name = "get")]
 struct SubCommandGet {
+    /// version to get and the output file, or several NAME=OUTPATH pairs. with --series, the
+    /// output file only. with --parents-only, a directory to fetch objects into as the output.
     #[argh(positional)]
-    filename: String,
-
-    #[argh(positional)]
-    out_filename: String,
+    args: Vec<String>,
+    #[argh(
+        description = "get versions listed in this file, a NAME<TAB>OUTPATH pair per line",
+        option
+    )]
+    batch: Option<String>,
 
     #[argh(description = "dry-run", switch)]
     dry_run: bool,
+    #[argh(description = "list objects to decode, without decoding", switch)]
+    parents_only: bool,
+    #[argh(
+        description = "replace out_filename if it already exists, instead of failing",
+        switch
+    )]
+    overwrite: bool,
+    #[argh(
+        description = "fail if out_filename already exists, the default without --overwrite",
+        switch
+    )]
+    no_clobber: bool,
+    #[argh(description = "backup existing output as .bak", switch)]
+    backup: bool,
+    #[argh(description = "create missing parent dirs", switch)]
+    parents: bool,
+    #[argh(
+        description = "decode into out_filename.partial next to the output, then rename it over out_filename",
+        switch
+    )]
+    in_place: bool,
+    #[argh(
+        description = "cache contents decoded in the middle of the chain, up to this many bytes, and resume from them",
+        option
+    )]
+    intermediate_cache: Option<u64>,
+    #[argh(
+        description = "hash the root object and each cached or shared intermediate before decoding from it",
+        switch
+    )]
+    verify_chain: bool,
+    #[argh(
+        description = "content (default) to decode the version, or delta to copy its stored object as is",
+        option,
+        default = "GetFormat::Content"
+    )]
+    format: GetFormat,
+
+    #[argh(description = "get a member of this series, with --latest", option)]
+    series: Option<String>,
+    #[argh(description = "get the newest member of --series", switch)]
+    latest: bool,
+
+    #[argh(
+        description = "update this node_exporter textfile collector file with metrics of the command",
+        option
+    )]
+    metrics_textfile: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// print decode chain of a version, from a root version
+#[argh(subcommand, name = "chain")]
+struct SubCommandChain {
+    #[argh(positional)]
+    /// filename or content hash
+    name: String,
+
+    #[argh(option, default = "ChainFormat::Text")]
+    /// output format: text, dot or mermaid
+    format: ChainFormat,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// print everything known about a version
+#[argh(subcommand, name = "describe")]
+struct SubCommandDescribe {
+    #[argh(positional)]
+    /// filename, or a content or store hash or its prefix
+    name: String,
+
+    #[argh(switch)]
+    /// print as json
+    json: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// free-text notes on versions, shown by describe
+#[argh(subcommand, name = "note")]
+struct SubCommandNote {
+    #[argh(subcommand)]
+    nested: NoteSubCommandEnum,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum NoteSubCommandEnum {
+    Add(SubCommandNoteAdd),
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// add a note to the content of a version
+#[argh(subcommand, name = "add")]
+struct SubCommandNoteAdd {
+    #[argh(positional)]
+    /// filename, or a content or store hash or its prefix
+    name: String,
+    #[argh(positional)]
+    note: String,
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -68,6 +352,43 @@ struct SubCommandRename {
     from_filename: String,
     #[argh(positional)]
     to_filename: String,
+    #[argh(switch)]
+    /// also decode the renamed versions and the deltas decoded from them
+    verify: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// re-encode the delta of a version against its grandparent, shortening its decode chain
+#[argh(subcommand, name = "compact-chain")]
+struct SubCommandCompactChain {
+    #[argh(option)]
+    /// version to compact
+    filename: String,
+
+    #[argh(option, default = "COMPACT_CHAIN_SLACK")]
+    /// re-parent if the new delta is at most this many times the current one
+    slack: f64,
+
+    #[argh(switch)]
+    /// also decode the re-parented version and the deltas decoded from it
+    verify: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// store versions of a decode chain in full, bounding its depth
+#[argh(subcommand, name = "compact-delta-chain")]
+struct SubCommandCompactDeltaChain {
+    #[argh(option)]
+    /// version whose chain is compacted
+    filename: String,
+
+    #[argh(option)]
+    /// deltas decoded at most to get a version of the chain
+    max_depth: usize,
+
+    #[argh(switch)]
+    /// also decode the new roots and the deltas decoded from them
+    verify: bool,
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -82,12 +403,23 @@ struct SubCommandExists {
 /// Remove all frontier versions from archive. The archive should be hydrated before adding a new
 /// version. You can still able to get a existing version from archive.
 #[argh(subcommand, name = "dehydrate")]
-struct SubCommandDehydrate {}
+struct SubCommandDehydrate {
+    #[argh(
+        description = "list the versions which would be removed, without removing",
+        switch
+    )]
+    dry_run: bool,
+}
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// Restore all frontier version from archive. It will allow dehydrated archive to add new version.
 #[argh(subcommand, name = "hydrate")]
-struct SubCommandHydrate {}
+struct SubCommandHydrate {
+    #[argh(option)]
+    /// write versions to the given path instead of the archive, e.g. 'out/{name}'. `{name}`,
+    /// `{hash}` and `{id}` are replaced with the version's filename, content hash and id.
+    output_template: Option<String>,
+}
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// Create a tar archive from archive. The tar archive contains dehydrated archive.
@@ -95,12 +427,239 @@ struct SubCommandHydrate {}
 struct SubCommandArchive {
     #[argh(positional)]
     filename: String,
+
+    #[argh(option)]
+    /// throttle the output to this many bytes per second
+    limit_rate: Option<u64>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Report conflicts of merging other archive into archive, without writing.
+#[argh(subcommand, name = "merge-conflicts")]
+struct SubCommandMergeConflicts {
+    #[argh(positional)]
+    /// WORKDIR of other archive
+    other: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Write metadata of all versions as JSON lines, to be used by adopt-objects.
+#[argh(subcommand, name = "export-meta")]
+struct SubCommandExportMeta {
+    #[argh(positional)]
+    filename: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Write a patch from one version to another, with a manifest to verify it as <out>.json.
+#[argh(subcommand, name = "make-patch")]
+struct SubCommandMakePatch {
+    #[argh(positional)]
+    /// version the patch applies to, a filename or a hash
+    from: String,
+    #[argh(positional)]
+    /// version the patch decodes to, a filename or a hash
+    to: String,
+    #[argh(positional)]
+    out_filename: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Add versions for object files copied into the object directory.
+#[argh(subcommand, name = "adopt-objects")]
+struct SubCommandAdoptObjects {
+    #[argh(option)]
+    /// metadata written by export-meta of the source archive
+    from_meta: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// snapshots of the metadata tables, to roll back experiments
+#[argh(subcommand, name = "snapshot")]
+struct SubCommandSnapshot {
+    #[argh(subcommand)]
+    nested: SnapshotSubCommandEnum,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum SnapshotSubCommandEnum {
+    Create(SubCommandSnapshotCreate),
+    Diff(SubCommandSnapshotDiff),
+    Rollback(SubCommandSnapshotRollback),
+    Restore(SubCommandSnapshotRestore),
+    List(SubCommandSnapshotList),
+    Delete(SubCommandSnapshotDelete),
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// copy tables and the object hash set into a snapshot
+#[argh(subcommand, name = "create")]
+struct SubCommandSnapshotCreate {
+    #[argh(positional)]
+    name: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// print blobs and objects added, removed or changed since a snapshot
+#[argh(subcommand, name = "diff")]
+struct SubCommandSnapshotDiff {
+    #[argh(positional)]
+    name: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// restore tables from a snapshot, and print missing and extra object files
+#[argh(subcommand, name = "rollback")]
+struct SubCommandSnapshotRollback {
+    #[argh(positional)]
+    name: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// roll back to a snapshot, and remove object files added after it
+#[argh(subcommand, name = "restore")]
+struct SubCommandSnapshotRestore {
+    #[argh(positional)]
+    name: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// list snapshots
+#[argh(subcommand, name = "list")]
+struct SubCommandSnapshotList {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// delete a snapshot
+#[argh(subcommand, name = "delete")]
+struct SubCommandSnapshotDelete {
+    #[argh(positional)]
+    name: String,
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// Get all versions from archive and validate checksum.
 #[argh(subcommand, name = "validate")]
-struct SubCommandValidate {}
+struct SubCommandValidate {
+    #[argh(
+        description = "write a json report of the validation to this path",
+        option
+    )]
+    report_path: Option<String>,
+
+    #[argh(option)]
+    /// validate only this fraction of leaf versions, e.g. 0.1
+    sample: Option<f64>,
+    #[argh(option)]
+    /// seed of --sample, for a reproducible selection
+    seed: Option<u64>,
+    #[argh(option)]
+    /// validate only the decode chain of this content hash or filename
+    chain: Option<String>,
+    #[argh(switch)]
+    /// only check that the parent of every delta exists, without decoding
+    parent_hashes: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Check consistency of the metadata, e.g. deltas whose parent does not exist.
+#[argh(subcommand, name = "fsck")]
+struct SubCommandFsck {
+    #[argh(switch)]
+    /// move deltas with a dangling parent into the blobs_quarantine table
+    repair: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Print health of the archive as json: results of the last checks and totals.
+#[argh(subcommand, name = "status")]
+struct SubCommandStatus {
+    #[argh(option)]
+    /// write the status to this path instead of printing it
+    write: Option<String>,
+    #[argh(option)]
+    /// report unhealthy if the last check is older than this many seconds
+    max_age_secs: Option<u64>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Remove stale files from WORKDIR/tmp, e.g. left by an interrupted push.
+#[argh(subcommand, name = "gc-tmp")]
+struct SubCommandGcTmp {
+    #[argh(option, default = "24 * 3600")]
+    /// remove files not modified for this many seconds, default 1 day
+    max_age_secs: u64,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Run every maintenance phase: tmp files, cleanup, intermediate cache, orphaned objects with
+/// --aggressive, and the stats index.
+#[argh(subcommand, name = "gc")]
+struct SubCommandGc {
+    #[argh(switch)]
+    /// report what would be reclaimed without removing anything
+    dry_run: bool,
+    #[argh(switch)]
+    /// also remove objects no blob refers to
+    aggressive: bool,
+    #[argh(option, default = "24 * 3600")]
+    /// keep temp files and orphaned objects modified in this many seconds, default 1 day
+    min_age_secs: u64,
+    #[argh(option, default = "increstore::GC_CACHE_BUDGET")]
+    /// bytes of the intermediate cache to keep, default 1GiB
+    cache_budget: u64,
+    #[argh(switch)]
+    /// skip removing stale temp files
+    skip_tmp: bool,
+    #[argh(switch)]
+    /// skip removing root blobs over the retention policy
+    skip_cleanup: bool,
+    #[argh(switch)]
+    /// skip evicting the intermediate cache
+    skip_cache: bool,
+    #[argh(switch)]
+    /// skip removing orphaned objects
+    skip_orphans: bool,
+    #[argh(switch)]
+    /// skip refreshing the stats index
+    skip_refresh: bool,
+    #[argh(switch)]
+    /// print the report as json
+    json: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// print storage mode of the store, or set it (delta or chunk) while the store is empty
+#[argh(subcommand, name = "storage-mode")]
+struct SubCommandStorageMode {
+    #[argh(positional)]
+    mode: Option<StorageMode>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// print how transient filesystem errors (ESTALE, EINTR, EAGAIN) are retried, or set it
+#[argh(subcommand, name = "fs-retry")]
+struct SubCommandFsRetry {
+    #[argh(option)]
+    /// calls made at most, including the first one
+    attempts: Option<u32>,
+    #[argh(option)]
+    /// wait before the n-th retry is n times this many milliseconds
+    backoff_ms: Option<u64>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// print the regex extracting the flavor and version of filenames, whose roots push tries first,
+/// or set it
+#[argh(subcommand, name = "filename-hint")]
+struct SubCommandFilenameHint {
+    #[argh(positional)]
+    /// regex with a `flavor` and optionally a `version` named group
+    regex: Option<String>,
+    #[argh(switch)]
+    /// remove the regex
+    clear: bool,
+}
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// bench-zip. for dev.
@@ -115,12 +674,51 @@ struct SubCommandBenchZip {
 #[derive(FromArgs, PartialEq, Debug)]
 /// cleanup
 #[argh(subcommand, name = "debug-cleanup")]
-struct SubCommandCleanUp {}
+struct SubCommandCleanUp {
+    #[argh(
+        description = "update this node_exporter textfile collector file with metrics of the command",
+        option
+    )]
+    metrics_textfile: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Simulate cleanup strategies without modifying archive.
+#[argh(subcommand, name = "debug-bench-cleanup")]
+struct SubCommandBenchCleanUp {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Project the store size, root count and decode depth after a number of pushes.
+#[argh(subcommand, name = "project")]
+struct SubCommandProject {
+    #[argh(option)]
+    /// number of pushes to project
+    pushes: usize,
+
+    #[argh(option)]
+    /// store_size/content_size of the next deltas, the mean of the stored deltas by default
+    ratio: Option<f32>,
+
+    #[argh(switch)]
+    /// print the projection as json
+    json: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Cache depths of all blobs, to speed up statistics of a large archive.
+#[argh(subcommand, name = "debug-rebuild-stats-index")]
+struct SubCommandRebuildStatsIndex {}
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// Print statistics of archive.
 #[argh(subcommand, name = "debug-stats")]
-struct SubCommandStats {}
+struct SubCommandStats {
+    #[argh(
+        description = "stat root files, to report the size of hydrated roots on disk",
+        switch
+    )]
+    probe_disk: bool,
+}
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// Write graphviz graph of archive.
@@ -130,6 +728,11 @@ struct SubCommandGraph {
     filename: String,
 }
 
+#[derive(FromArgs, PartialEq, Debug)]
+/// Print when blobs became root blobs or stopped being one, and why.
+#[argh(subcommand, name = "debug-root-transitions")]
+struct SubCommandRootTransitions {}
+
 #[derive(FromArgs, PartialEq, Debug)]
 /// debug-list-files
 #[argh(subcommand, name = "debug-ls-files")]
@@ -142,6 +745,24 @@ struct SubCommandListFiles {
     non_roots: bool,
     #[argh(description = "long", switch, short = 'l')]
     long: bool,
+    #[argh(description = "print blobs as json lines", switch)]
+    json: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// List root blobs with their child count, cleanup score and age.
+#[argh(subcommand, name = "list-roots")]
+struct SubCommandListRoots {
+    #[argh(description = "print roots as json lines", switch)]
+    json: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Print the log of pushes, renames and cleanups, oldest first.
+#[argh(subcommand, name = "oplog")]
+struct SubCommandOplog {
+    #[argh(description = "print entries as json lines", switch)]
+    json: bool,
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -155,21 +776,208 @@ struct SubCommandBlobs {}
 struct SubCommandHash {
     #[argh(positional)]
     filename: String,
+
+    #[argh(
+        description = "hash chunks in parallel. the digest differs from the content hash",
+        switch
+    )]
+    parallel: bool,
+    #[argh(
+        description = "chunk size of --parallel",
+        option,
+        default = "PARALLEL_HASH_CHUNK_SIZE"
+    )]
+    chunk_size: usize,
 }
 
-fn main() -> increstore::Result<()> {
-    use increstore::*;
+#[derive(FromArgs, PartialEq, Debug)]
+/// Print hashes of each zip entry.
+#[argh(subcommand, name = "zip-hashes")]
+struct SubCommandZipHashes {
+    #[argh(positional)]
+    filename: String,
+}
+
+/// versions of `get` given as NAME=OUTPATH pairs or by --batch, None for a single version.
+/// positional arguments are pairs only if all of them are, so `get NAME OUT` keeps working for
+/// names with `=`.
+fn get_pairs(
+    args: &[String],
+    batch: Option<&str>,
+) -> increstore::Result<Option<Vec<(String, String)>>> {
+    let mut pairs = Vec::new();
+    if let Some(batch) = batch {
+        for line in std::fs::read_to_string(batch)?.lines() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once('\t') {
+                Some((name, out)) => pairs.push((name.to_owned(), out.to_owned())),
+                None => return Err(increstore::format_err!("invalid batch line: {:?}", line)),
+            }
+        }
+    } else if args.is_empty() || !args.iter().all(|arg| arg.contains('=')) {
+        return Ok(None);
+    }
+
+    for arg in args {
+        match arg.split_once('=') {
+            Some((name, out)) => pairs.push((name.to_owned(), out.to_owned())),
+            None => return Err(increstore::format_err!("not a NAME=OUTPATH pair: {}", arg)),
+        }
+    }
+    Ok(Some(pairs))
+}
 
+/// `get_many`, printing the status of each version
+fn get_pairs_with_status(
+    conn: &mut increstore::db::Conn,
+    pairs: &[(String, String)],
+    opts: &increstore::GetOptions,
+) -> increstore::Result<()> {
+    let items = increstore::get_many(conn, pairs, opts)?;
+    let mut failed = 0;
+    for item in &items {
+        match &item.error {
+            None => println!("ok {} {}", item.filename, item.out_filename),
+            Some(e) => {
+                failed += 1;
+                println!("failed {} {}: {}", item.filename, item.out_filename, e);
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(increstore::format_err!(
+            "{} of {} versions failed",
+            failed,
+            items.len()
+        ));
+    }
+    Ok(())
+}
+
+/// other names of subcommands, as (alias, subcommand)
+const ALIASES: &[(&str, &str)] = &[
+    ("ls", "debug-ls-files"),
+    ("stat", "status"),
+    ("st", "status"),
+];
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = prev[j] + if ca == *cb { 0 } else { 1 };
+            cur.push(substitute.min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+/// the closest subcommand or alias to a mistyped name, if it is close enough to be a typo
+fn suggest<'a>(name: &str, names: &[&'a str]) -> Option<&'a str> {
+    names
+        .iter()
+        .map(|candidate| (edit_distance(name, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= 2 && *distance < name.len())
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/// parses the command line as `increstore <cmd>`. aliases are replaced with their subcommand,
+/// and an unknown subcommand exits with a suggestion instead of the usage of argh.
+fn parse_args() -> TopLevel {
+    use argh::SubCommands;
+
+    let mut args = std::env::args().collect::<Vec<_>>();
+    let names = MySubCommandEnum::COMMANDS
+        .iter()
+        .map(|info| info.name)
+        .chain(ALIASES.iter().map(|(alias, _)| *alias))
+        .collect::<Vec<_>>();
+
+    // the first positional argument, after top-level options. argh only takes `--opt value`,
+    // so `--opt=value` is split in two.
+    let mut idx = 1;
+    while idx < args.len() && args[idx].starts_with('-') {
+        if let Some((name, value)) = args[idx].split_once('=') {
+            if VALUE_OPTIONS.contains(&name) {
+                let value = value.to_owned();
+                args[idx] = name.to_owned();
+                args.insert(idx + 1, value);
+            }
+        }
+        idx += if VALUE_OPTIONS.contains(&args[idx].as_str()) {
+            2
+        } else {
+            1
+        };
+    }
+    if let Some(arg) = args.get_mut(idx) {
+        if let Some((_, name)) = ALIASES.iter().find(|(alias, _)| alias == arg) {
+            *arg = name.to_string();
+        } else if arg != "help" && !names.contains(&arg.as_str()) {
+            eprintln!("Error: unknown command: {}", arg);
+            if let Some(name) = suggest(arg, &names) {
+                eprintln!("Did you mean '{}'?", name);
+            }
+            eprintln!("Run increstore --help for the list of commands.");
+            std::process::exit(increstore::EXIT_USAGE);
+        }
+    }
+
+    let strs = args.iter().skip(1).map(|s| s.as_str()).collect::<Vec<_>>();
+    TopLevel::from_args(&["increstore"], &strs).unwrap_or_else(|early_exit| {
+        match early_exit.status {
+            Ok(()) => {
+                println!("{}", early_exit.output);
+                std::process::exit(0)
+            }
+            Err(()) => {
+                eprintln!(
+                    "{}\nRun increstore --help for more information.",
+                    early_exit.output
+                );
+                std::process::exit(increstore::EXIT_USAGE)
+            }
+        }
+    })
+}
+
+fn main() {
     env_logger::init();
 
-    std::fs::create_dir_all(prefix()).expect("failed to create dir");
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        std::process::exit(increstore::exit_code(&e));
+    }
+}
+
+fn run() -> increstore::Result<()> {
+    use increstore::*;
 
-    let mut conn = db::open()?;
+    let up = parse_args();
+
+    std::fs::create_dir_all(prefix()).expect("failed to create dir");
 
+    let mut conn = open_store()?;
     let conn = &mut conn;
-    db::prepare(conn).expect("failed to prepare");
 
-    let up: TopLevel = argh::from_env();
+    if up.keep_temp {
+        set_keep_temp(true);
+    }
+    if up.no_provenance {
+        set_record_provenance(false);
+    }
+    if let Some(mode) = up.progress {
+        set_progress_mode(mode);
+    }
+    if let Some(object_dir) = &up.object_dir {
+        std::env::set_var(OBJECT_DIR_ENV, object_dir);
+    }
 
     match up.nested {
         MySubCommandEnum::Push(cmd) => {
@@ -179,46 +987,522 @@ fn main() -> increstore::Result<()> {
                 }
                 (true, false) => FileType::Zip,
                 (false, true) => FileType::Gz,
-                (false, false) => {
-                    let path = std::path::Path::new(&cmd.filename);
-                    if let Some(ext) = path.extension() {
-                        if ext == "zip" || ext == "apk" || ext == "aab" {
-                            FileType::Zip
-                        } else if ext == "gz" {
-                            FileType::Gz
-                        } else if ext == "tar" {
-                            FileType::Plain
-                        } else {
-                            panic!("unknown extension: {:?}", ext);
-                        }
-                    } else {
-                        panic!("unknown extension: {}", cmd.filename);
-                    }
+                (false, false) => match FileType::from_path(&cmd.filename) {
+                    Some(ty) => ty,
+                    None => panic!("unknown extension: {}", cmd.filename),
+                },
+            };
+            if cmd.estimate {
+                for (store_hash, size) in
+                    estimate_push_delta_sizes(conn, &cmd.filename, ty, cmd.explode_bundle)?
+                {
+                    println!("{} {}", store_hash, size);
+                }
+                return Ok(());
+            }
+            let opts = PushOptions {
+                max_ratio: cmd.max_ratio,
+                max_ratio_strict: cmd.max_ratio_strict,
+                max_store_size: cmd.max_store_size,
+                quota_policy: cmd.quota_policy,
+                explode_bundle: cmd.explode_bundle,
+                series: cmd.series,
+                verify_unchanged: cmd.verify_unchanged,
+                tmp_budget: cmd.tmp_budget,
+                source_url: cmd.source_url,
+                no_prefilter: cmd.no_prefilter,
+                record_trials: cmd.record_trials,
+                estimator: if cmd.size_estimator {
+                    Some(std::sync::Arc::new(increstore::SizeRatioEstimator))
+                } else {
+                    None
+                },
+                good_enough_ratio: cmd.good_enough_ratio,
+                hint_strict: cmd.hint_strict,
+                parent_selection: cmd.parent_selection,
+                cancel: Some(increstore::cancel_on_signal()?),
+            };
+            let sw = std::time::Instant::now();
+            let lock = lock_store()?;
+            let res = push_with_options(conn, &cmd.filename, ty, &opts);
+            drop(lock);
+            if let Some(path) = &cmd.metrics_textfile {
+                metrics::record_command(conn, path, "push", &res, |reg, report| {
+                    reg.record_push(report, sw.elapsed())
+                });
+            }
+            let report = res?;
+            if let Some(report_path) = &cmd.report_path {
+                write_report(report_path, &report)?;
+            }
+            match (report.delta, report.ratio, report.max_ratio) {
+                (Some(blob), Some(ratio), Some(max_ratio)) if ratio > max_ratio => {
+                    Err(Error::RatioExceeded {
+                        filename: blob.filename,
+                        ratio,
+                        max_ratio,
+                    })
                 }
+                _ => Ok(()),
+            }
+        }
+        MySubCommandEnum::Watch(cmd) => {
+            let dir = match cmd.dir_arg.or(cmd.dir) {
+                Some(dir) => dir,
+                None => return Err(increstore::format_err!("directory to watch is not given")),
+            };
+            let opts = WatchOptions {
+                pattern: cmd.pattern,
+                delete_after: cmd.delete_after,
+                move_done: cmd.move_done,
+                stable_duration: std::time::Duration::from_secs(cmd.stable_secs),
+                ty: cmd.ty,
+                push_on_close: cmd.push_on_close,
+                fail_fast: cmd.fail_fast,
             };
-            push(conn, &cmd.filename, ty)
+            watch(conn, &dir, &opts)
+        }
+        MySubCommandEnum::Get(cmd) => {
+            let metrics_textfile = cmd.metrics_textfile.clone();
+            let res = (|| {
+                if cmd.overwrite && cmd.no_clobber {
+                    return Err(increstore::format_err!(
+                        "--overwrite and --no-clobber are exclusive"
+                    ));
+                }
+                if let Some(pairs) = get_pairs(&cmd.args, cmd.batch.as_deref())? {
+                    if cmd.series.is_some() || cmd.parents_only {
+                        return Err(increstore::format_err!(
+                            "NAME=OUTPATH pairs can not be used with --series or --parents-only"
+                        ));
+                    }
+                    let opts = GetOptions {
+                        dry_run: cmd.dry_run,
+                        overwrite: cmd.overwrite,
+                        backup: cmd.backup,
+                        parents: cmd.parents,
+                        in_place: cmd.in_place,
+                        intermediate_cache: cmd.intermediate_cache,
+                        verify_chain: cmd.verify_chain,
+                        format: cmd.format,
+                    };
+                    return get_pairs_with_status(conn, &pairs, &opts);
+                }
+
+                let mut args = cmd.args.into_iter();
+                let (filename, out_filename) = match (args.next(), args.next(), args.next()) {
+                    (Some(filename), out_filename, None) => (filename, out_filename),
+                    (None, _, _) => return Err(increstore::format_err!("filename is required")),
+                    (Some(_), _, Some(_)) => {
+                        return Err(increstore::format_err!(
+                            "give a filename and out_filename, or NAME=OUTPATH pairs"
+                        ));
+                    }
+                };
+                let (filename, out_filename) = match (&cmd.series, out_filename) {
+                    (Some(_), _) if !cmd.latest => {
+                        return Err(increstore::format_err!("--series requires --latest"));
+                    }
+                    (Some(series), None) => (series_latest_filename(conn, series)?, Some(filename)),
+                    (Some(_), Some(_)) => {
+                        return Err(increstore::format_err!(
+                            "with --series, give out_filename only"
+                        ));
+                    }
+                    (None, _) if cmd.latest => {
+                        return Err(increstore::format_err!("--latest requires --series"));
+                    }
+                    (None, out_filename) => (filename, out_filename),
+                };
+                if cmd.parents_only {
+                    return get_parents_only(conn, &filename, out_filename.as_deref());
+                }
+                let out_filename = match out_filename {
+                    Some(out_filename) => out_filename,
+                    None => return Err(increstore::format_err!("out_filename is required")),
+                };
+                let opts = GetOptions {
+                    dry_run: cmd.dry_run,
+                    overwrite: cmd.overwrite,
+                    backup: cmd.backup,
+                    parents: cmd.parents,
+                    in_place: cmd.in_place,
+                    intermediate_cache: cmd.intermediate_cache,
+                    verify_chain: cmd.verify_chain,
+                    format: cmd.format,
+                };
+                // drawn as the top-level --progress decides
+                let pb = std::cell::RefCell::new(None);
+                get_with_options_progress(
+                    conn,
+                    &filename,
+                    &out_filename,
+                    &opts,
+                    |decoded, total| {
+                        let mut pb = pb.borrow_mut();
+                        pb.get_or_insert_with(|| Progress::bytes(total))
+                            .set(decoded);
+                    },
+                )?;
+                if let Some(mut pb) = pb.into_inner() {
+                    pb.finish();
+                }
+                Ok(())
+            })();
+            if let Some(path) = &metrics_textfile {
+                metrics::record_command(conn, path, "get", &res, |reg, _| reg.record_get());
+            }
+            res
         }
-        MySubCommandEnum::Get(cmd) => get(conn, &cmd.filename, &cmd.out_filename, cmd.dry_run),
         MySubCommandEnum::Exists(cmd) => exists(conn, &cmd.filename),
+        MySubCommandEnum::Chain(cmd) => chain(conn, &cmd.name, cmd.format),
+        MySubCommandEnum::Describe(cmd) => print_description(conn, &cmd.name, cmd.json),
+        MySubCommandEnum::Note(cmd) => match cmd.nested {
+            NoteSubCommandEnum::Add(cmd) => note_add(conn, &cmd.name, &cmd.note),
+        },
 
-        MySubCommandEnum::Rename(cmd) => rename(conn, &cmd.from_filename, &cmd.to_filename),
+        MySubCommandEnum::Rename(cmd) => {
+            rename(conn, &cmd.from_filename, &cmd.to_filename, cmd.verify)
+        }
+        MySubCommandEnum::CompactDeltaChain(cmd) => {
+            compact_delta_chain(conn, &cmd.filename, cmd.max_depth, cmd.verify)
+        }
+        MySubCommandEnum::CompactChain(cmd) => {
+            let report = compact_chain(conn, &cmd.filename, cmd.slack, cmd.verify)?;
+            match (&report.new_parent_hash, report.new_store_size) {
+                (Some(parent_hash), Some(new_store_size)) => println!(
+                    "re-parented {} to {}, store_size={} -> {}",
+                    report.filename, parent_hash, report.old_store_size, new_store_size
+                ),
+                (None, Some(new_store_size)) => println!(
+                    "kept {}, store_size={} grandparent_store_size={}",
+                    report.filename, report.old_store_size, new_store_size
+                ),
+                _ => println!("kept {}, parent is a root", report.filename),
+            }
+            Ok(())
+        }
 
-        MySubCommandEnum::Dedytrate(_cmd) => dehydrate(conn),
-        MySubCommandEnum::Hydrate(_cmd) => hydrate(conn),
+        MySubCommandEnum::Dehydrate(cmd) => {
+            let report = dehydrate(conn, cmd.dry_run)?;
+            if cmd.dry_run {
+                for root in &report.roots {
+                    println!(
+                        "{} {} {}",
+                        root.filename,
+                        root.path,
+                        bytesize::ByteSize(root.size)
+                    );
+                }
+                println!(
+                    "{} versions, {} reclaimable",
+                    report.roots.len(),
+                    bytesize::ByteSize(report.total_size)
+                );
+            }
+            Ok(())
+        }
+        MySubCommandEnum::Hydrate(cmd) => hydrate(conn, cmd.output_template.as_deref()),
 
-        MySubCommandEnum::Archive(cmd) => archive(conn, &cmd.filename),
+        MySubCommandEnum::Archive(cmd) => {
+            let opts = ArchiveOptions {
+                limit_rate: cmd.limit_rate,
+            };
+            archive(conn, &cmd.filename, &opts)
+        }
+        MySubCommandEnum::MergeConflicts(cmd) => print_merge_conflicts(conn, &cmd.other),
+        MySubCommandEnum::ExportMeta(cmd) => export_meta(conn, &cmd.filename),
+        MySubCommandEnum::MakePatch(cmd) => {
+            let manifest = make_patch(conn, &cmd.from, &cmd.to, &cmd.out_filename)?;
+            println!("{}", serde_json::to_string(&manifest)?);
+            Ok(())
+        }
+        MySubCommandEnum::Snapshot(cmd) => match cmd.nested {
+            SnapshotSubCommandEnum::Create(cmd) => snapshot_create(conn, &cmd.name),
+            SnapshotSubCommandEnum::Diff(cmd) => print_snapshot_diff(conn, &cmd.name),
+            SnapshotSubCommandEnum::Rollback(cmd) => {
+                print_snapshot_rollback(conn, &cmd.name, false)
+            }
+            SnapshotSubCommandEnum::Restore(cmd) => print_snapshot_rollback(conn, &cmd.name, true),
+            SnapshotSubCommandEnum::List(_cmd) => snapshot_list(conn),
+            SnapshotSubCommandEnum::Delete(cmd) => snapshot_delete(conn, &cmd.name),
+        },
+        MySubCommandEnum::AdoptObjects(cmd) => {
+            let report = adopt_objects(conn, cmd.from_meta.as_deref())?;
+            for hash in &report.adopted {
+                println!("adopted {}", hash);
+            }
+            for hash in &report.unknown {
+                println!("unknown {}", hash);
+            }
+            for hash in &report.mismatched {
+                println!("mismatched {}", hash);
+            }
+            for hash in &report.orphaned {
+                println!("orphaned {}", hash);
+            }
+            Ok(())
+        }
+
+        MySubCommandEnum::Fsck(cmd) => {
+            let report = fsck(conn, cmd.repair)?;
+            for blob in &report.dangling {
+                println!(
+                    "dangling parent {} of {}",
+                    blob.parent_hash.as_deref().unwrap_or_default(),
+                    blob.filename
+                );
+            }
+            if cmd.repair {
+                println!("quarantined {} blobs", report.quarantined);
+            } else if !report.dangling.is_empty() {
+                return Err(increstore::format_err!(
+                    "{} deltas have a dangling parent, run with --repair to quarantine them",
+                    report.dangling.len()
+                ));
+            }
+            Ok(())
+        }
+
+        MySubCommandEnum::Validate(cmd) => {
+            if cmd.parent_hashes {
+                let report = verify_parent_hashes(conn)?;
+                for hash in &report.broken_refs {
+                    println!("missing parent {}", hash);
+                }
+                if !report.broken_refs.is_empty() {
+                    return Err(increstore::format_err!(
+                        "{} parent hashes of {} deltas are missing",
+                        report.broken_refs.len(),
+                        report.total_deltas
+                    ));
+                }
+                return Ok(());
+            }
+            if let Some(name) = &cmd.chain {
+                let (report, res) = validate_chain_with_error(conn, name)?;
+                if let Some(report_path) = &cmd.report_path {
+                    write_report(report_path, &report)?;
+                }
+                res?;
+                for id in report.checked.unwrap_or_default() {
+                    println!("checked V{}", id);
+                }
+                return Ok(());
+            }
+
+            let opts = ValidateOptions {
+                sample: cmd.sample,
+                seed: cmd.seed,
+            };
+            let res = validate_with_options(conn, &opts);
+            let checked = match (&res, opts.sample) {
+                (Ok(checked), Some(_)) => {
+                    for blob in checked {
+                        println!("checked V{} {}", blob.id, blob.filename);
+                    }
+                    Some(checked.iter().map(|blob| blob.id).collect())
+                }
+                _ => None,
+            };
+            let res = res.map(|_checked| ());
+            if let Some(report_path) = &cmd.report_path {
+                let mut report = ValidateReport::new(conn, &res)?;
+                report.checked = checked;
+                write_report(report_path, &report)?;
+            }
+            res
+        }
 
-        MySubCommandEnum::Validate(_cmd) => validate(conn),
+        MySubCommandEnum::Status(cmd) => print_status(
+            conn,
+            cmd.write.as_deref(),
+            cmd.max_age_secs.map(std::time::Duration::from_secs),
+        ),
 
+        MySubCommandEnum::StorageMode(cmd) => match cmd.mode {
+            Some(mode) => set_storage_mode(conn, mode),
+            None => {
+                println!("{}", storage_mode(conn)?);
+                Ok(())
+            }
+        },
+        MySubCommandEnum::FsRetry(cmd) => {
+            let mut policy = retry_policy(conn)?;
+            if cmd.attempts.is_none() && cmd.backoff_ms.is_none() {
+                println!(
+                    "attempts={} backoff_ms={}",
+                    policy.attempts,
+                    policy.backoff.as_millis()
+                );
+                return Ok(());
+            }
+            if let Some(attempts) = cmd.attempts {
+                policy.attempts = attempts;
+            }
+            if let Some(backoff_ms) = cmd.backoff_ms {
+                policy.backoff = std::time::Duration::from_millis(backoff_ms);
+            }
+            set_retry_policy(conn, policy)
+        }
+        MySubCommandEnum::FilenameHint(cmd) => {
+            if cmd.clear {
+                return set_filename_hint(conn, None);
+            }
+            match &cmd.regex {
+                Some(regex) => set_filename_hint(conn, Some(regex)),
+                None => {
+                    if let Some(regex) = filename_hint(conn)? {
+                        println!("{}", regex);
+                    }
+                    Ok(())
+                }
+            }
+        }
         MySubCommandEnum::BenchZip(cmd) => bench_zip(&cmd.filename, cmd.parallel),
 
-        MySubCommandEnum::CleanUp(_cmd) => cleanup(conn),
-        MySubCommandEnum::Stats(_cmd) => debug_stats(conn),
+        MySubCommandEnum::CleanUp(cmd) => {
+            let res = cleanup(conn);
+            if let Some(path) = &cmd.metrics_textfile {
+                metrics::record_command(conn, path, "cleanup", &res, |reg, _| reg.record_cleanup());
+            }
+            res
+        }
+        MySubCommandEnum::GcTmp(cmd) => {
+            let max_age = std::time::Duration::from_secs(cmd.max_age_secs);
+            let removed = gc_unreferenced_tmpfiles(&tmpdir(), max_age)?;
+            println!("removed {} files", removed);
+            Ok(())
+        }
+        MySubCommandEnum::Gc(cmd) => {
+            let opts = GcOptions {
+                dry_run: cmd.dry_run,
+                aggressive: cmd.aggressive,
+                min_age: std::time::Duration::from_secs(cmd.min_age_secs),
+                cache_budget: cmd.cache_budget,
+                skip_tmp: cmd.skip_tmp,
+                skip_cleanup: cmd.skip_cleanup,
+                skip_cache: cmd.skip_cache,
+                skip_orphans: cmd.skip_orphans,
+                skip_refresh: cmd.skip_refresh,
+            };
+            let lock = lock_store()?;
+            let report = gc(conn, &opts)?;
+            drop(lock);
+            if cmd.json {
+                println!("{}", serde_json::to_string(&report)?);
+                return Ok(());
+            }
+            for phase in &report.phases {
+                if phase.skipped {
+                    println!("{:<8} skipped", phase.name);
+                    continue;
+                }
+                println!(
+                    "{:<8} files={} bytes={} took={}ms",
+                    phase.name,
+                    phase.reclaimed.files,
+                    bytesize::ByteSize(phase.reclaimed.bytes),
+                    phase.elapsed_ms
+                );
+            }
+            println!(
+                "{}reclaimed={} store_size={} -> {} took={}ms",
+                if report.dry_run { "dry run: " } else { "" },
+                bytesize::ByteSize(report.bytes_reclaimed),
+                bytesize::ByteSize(report.store_size_before),
+                bytesize::ByteSize(report.store_size_after),
+                report.elapsed_ms
+            );
+            Ok(())
+        }
+        MySubCommandEnum::BenchCleanUp(_cmd) => debug_bench_cleanup(conn),
+        MySubCommandEnum::Project(cmd) => {
+            let ratio = match cmd.ratio {
+                Some(ratio) => ratio,
+                // without history, every version is assumed not to compress
+                None => average_delta_ratio(conn)?.unwrap_or(1.0),
+            };
+            let p = estimate_storage_after_n_pushes(conn, cmd.pushes, ratio)?;
+            if cmd.json {
+                println!("{}", serde_json::to_string(&p)?);
+                return Ok(());
+            }
+            println!(
+                "pushes={} ratio={:.02}% avg_content_size={}",
+                p.pushes,
+                p.avg_delta_ratio * 100.0,
+                bytesize::ByteSize(p.avg_content_size)
+            );
+            println!(
+                "store_size={} -> {}",
+                bytesize::ByteSize(p.current_store_size),
+                bytesize::ByteSize(p.estimated_store_size)
+            );
+            println!(
+                "root_count={} max_depth={}",
+                p.estimated_root_count, p.estimated_max_depth
+            );
+            if p.compact_advised {
+                println!(
+                    "max_depth reaches {}, consider compact-delta-chain",
+                    PROJECTION_COMPACT_DEPTH
+                );
+            }
+            Ok(())
+        }
+        MySubCommandEnum::Stats(cmd) => debug_stats(conn, cmd.probe_disk),
+        MySubCommandEnum::RebuildStatsIndex(_cmd) => rebuild_stats_index(conn),
         MySubCommandEnum::Graph(cmd) => debug_graph(conn, &cmd.filename),
-        MySubCommandEnum::ListFiles(cmd) => {
-            debug_list_files(conn, cmd.genesis, cmd.roots, cmd.non_roots, cmd.long)
+        MySubCommandEnum::RootTransitions(_cmd) => debug_root_transitions(conn),
+        MySubCommandEnum::ListFiles(cmd) => debug_list_files(
+            conn,
+            cmd.genesis,
+            cmd.roots,
+            cmd.non_roots,
+            cmd.long,
+            cmd.json,
+        ),
+        MySubCommandEnum::ListRoots(cmd) => {
+            for (blob, info) in list_root_blobs(conn)? {
+                if cmd.json {
+                    let line = serde_json::json!({ "blob": blob, "info": info });
+                    println!("{}", line);
+                    continue;
+                }
+                let mut flags = Vec::new();
+                if !info.hydrated {
+                    flags.push(" dehydrated");
+                }
+                if info.pinned {
+                    flags.push(" pinned");
+                }
+                println!(
+                    "V{} {} child_count={} score={} age={}s{}",
+                    blob.id,
+                    blob.filename,
+                    info.child_count,
+                    info.score,
+                    info.age_secs,
+                    flags.concat()
+                );
+            }
+            Ok(())
+        }
+        MySubCommandEnum::Oplog(cmd) => {
+            for entry in db::oplog(conn)? {
+                if cmd.json {
+                    println!("{}", serde_json::to_string(&entry)?);
+                } else {
+                    println!("{} {} {}", entry.ts, entry.op, entry.details_json);
+                }
+            }
+            Ok(())
         }
         MySubCommandEnum::Blobs(_cmd) => debug_blobs(conn),
-        MySubCommandEnum::Hash(cmd) => debug_hash(&cmd.filename),
+        MySubCommandEnum::Hash(cmd) => {
+            debug_hash(&cmd.filename, Some(cmd.chunk_size).filter(|_| cmd.parallel))
+        }
+        MySubCommandEnum::ZipHashes(cmd) => zip_hashes(&cmd.filename),
     }
 }
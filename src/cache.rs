@@ -0,0 +1,170 @@
+use std::time::SystemTime;
+
+use super::*;
+
+/// contents decoded in the middle of a `get` chain, kept under `cache/intermediate` of the store
+/// by content hash, so an interrupted or a later `get` resumes from the deepest cached ancestor.
+/// entries are verified before use, and evicted least recently used first.
+pub struct IntermediateCache {
+    dir: PathBuf,
+    /// bytes of entries kept after an insert
+    budget: u64,
+}
+
+impl IntermediateCache {
+    pub fn new(dirs: &StoreDirs, budget: u64) -> Result<Self> {
+        let dir = PathBuf::from(format!("{}/cache/intermediate", dirs.workdir));
+        std::fs::create_dir_all(&dir)?;
+        Ok(IntermediateCache { dir, budget })
+    }
+
+    fn entry_path(&self, content_hash: &str) -> PathBuf {
+        self.dir.join(content_hash)
+    }
+
+    /// path of a cached content, if it exists and matches its hash. a broken entry is removed.
+    pub fn lookup(&self, content_hash: &str) -> Result<Option<PathBuf>> {
+        let path = self.entry_path(content_hash);
+        let path_str = path.to_str().expect("invalid cache dir");
+        let digest = match file_hash(path_str) {
+            Ok(digest) => digest,
+            Err(_) if !path.exists() => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if digest != content_hash {
+            warn!("cache: removing broken entry {:?}", path);
+            std::fs::remove_file(&path)?;
+            return Ok(None);
+        }
+        touch(&path)?;
+        Ok(Some(path))
+    }
+
+    /// copies a cached content to `dst`, if it exists. the bytes copied are hashed, not the entry
+    /// which may change after a `lookup`. a broken entry is removed and fails the copy.
+    pub fn copy_verified(&self, content_hash: &str, dst: &Path) -> Result<bool> {
+        let path = self.entry_path(content_hash);
+        let mut file = match std::fs::File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        let mut w = HashRW::new(std::fs::File::create(dst)?);
+        io::copy(&mut file, &mut w)?;
+        let digest = w.meta().digest();
+        if digest != content_hash {
+            warn!("cache: removing broken entry {:?}", path);
+            std::fs::remove_file(&path)?;
+            return Err(Error::ChecksumMismatch {
+                filename: path.to_string_lossy().into_owned(),
+                expected: content_hash.to_owned(),
+                actual: digest,
+            });
+        }
+        touch(&path)?;
+        Ok(true)
+    }
+
+    /// copies a verified content into the cache, then evicts entries over the budget. the source
+    /// is reused by the next decode steps, so it is not hard-linked.
+    pub fn insert(&self, content_hash: &str, src: &Path) -> Result<()> {
+        let path = self.entry_path(content_hash);
+        if path.exists() {
+            return Ok(());
+        }
+        let tmpfile = NamedTempFile::new_in(&self.dir)?;
+        std::fs::copy(src, tmpfile.path())?;
+        tmpfile.persist(&path)?;
+        self.evict(Some(content_hash), false)?;
+        Ok(())
+    }
+
+    /// evicts entries over the budget, as `gc` does after a budget is lowered
+    pub fn gc(&self, dry_run: bool) -> Result<Reclaimed> {
+        self.evict(None, dry_run)
+    }
+
+    /// removes least recently used entries until the cache fits the budget, keeping `keep`
+    fn evict(&self, keep: Option<&str>, dry_run: bool) -> Result<Reclaimed> {
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            if meta.is_file() {
+                entries.push((meta.modified()?, meta.len(), entry.path()));
+            }
+        }
+        entries.sort();
+
+        let mut size = entries.iter().map(|(_, len, _)| len).sum::<u64>();
+        let mut reclaimed = Reclaimed::default();
+        for (_, len, path) in entries {
+            if size <= self.budget {
+                break;
+            }
+            if keep.is_some() && path.file_name().and_then(|name| name.to_str()) == keep {
+                continue;
+            }
+            debug!("cache: evicting {:?}", path);
+            if !dry_run {
+                std::fs::remove_file(&path)?;
+            }
+            size -= len;
+            reclaimed.add(len);
+        }
+        Ok(reclaimed)
+    }
+}
+
+/// marks an entry recently used
+fn touch(path: &Path) -> Result<()> {
+    std::fs::File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(SystemTime::now())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+
+    #[test]
+    fn lru() {
+        with_store(|_conn, dir| {
+            // under the given store, not WORKDIR
+            let store_dir = dir.join("store");
+            let cache = IntermediateCache::new(&StoreDirs::at(store_dir.to_str().unwrap()), 20);
+            let cache = cache.unwrap();
+            assert!(store_dir.join("cache/intermediate").is_dir());
+            assert!(!dir.join("cache").exists());
+            let insert = |name: &str, body: &[u8]| {
+                let path = write_file(dir, name, body);
+                let hash = file_hash(&path).unwrap();
+                cache.insert(&hash, Path::new(&path)).unwrap();
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                hash
+            };
+            let first = insert("v1", b"first content");
+            // 13 + 14 bytes is over the budget
+            let second = insert("v2", b"second content");
+            assert!(cache.lookup(&first).unwrap().is_none());
+            let third = insert("v3", b"third");
+
+            // `second` is used after `third`, so `third` is evicted
+            assert!(cache.lookup(&second).unwrap().is_some());
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            let fourth = insert("v4", b"fourth");
+            assert!(cache.lookup(&third).unwrap().is_none());
+            assert!(cache.lookup(&second).unwrap().is_some());
+            assert!(cache.lookup(&fourth).unwrap().is_some());
+
+            // an entry not matching its hash is dropped
+            let path = cache.entry_path(&fourth);
+            std::fs::write(&path, b"broken").unwrap();
+            assert!(cache.lookup(&fourth).unwrap().is_none());
+            assert!(!path.exists());
+        });
+    }
+}
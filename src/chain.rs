@@ -0,0 +1,225 @@
+use super::*;
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChainFormat {
+    Text,
+    Dot,
+    Mermaid,
+}
+
+impl std::str::FromStr for ChainFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ChainFormat::Text),
+            "dot" => Ok(ChainFormat::Dot),
+            "mermaid" => Ok(ChainFormat::Mermaid),
+            _ => Err(format!("unknown format: {}, expected text|dot|mermaid", s)),
+        }
+    }
+}
+
+/// renders a decode chain, ordered from a root blob. each hop shows its store size and bytes read
+/// to decode the chain up to the hop, in bytes.
+pub fn format_chain(blobs: &[Blob], format: ChainFormat) -> String {
+    let mut s = String::new();
+    let mut cumulative = 0;
+
+    match format {
+        ChainFormat::Text => {
+            for (hop, blob) in blobs.iter().enumerate() {
+                cumulative += blob.store_size;
+                writeln!(
+                    s,
+                    "{} V{} {} store_size={} decode_size={} {}",
+                    hop,
+                    blob.id,
+                    blob.filename,
+                    blob.store_size,
+                    cumulative,
+                    filepath(&blob.store_hash),
+                )
+                .ok();
+            }
+        }
+        ChainFormat::Dot => {
+            writeln!(s, "digraph chain {{").ok();
+            writeln!(s, "  rankdir=\"LR\"").ok();
+            for blob in blobs {
+                cumulative += blob.store_size;
+                writeln!(
+                    s,
+                    "  V{} [label=\"V{}\\n{}\\n{}B / {}B\"];",
+                    blob.id, blob.id, blob.filename, blob.store_size, cumulative,
+                )
+                .ok();
+            }
+            for pair in blobs.windows(2) {
+                writeln!(s, "  V{} -> V{};", pair[0].id, pair[1].id).ok();
+            }
+            writeln!(s, "}}").ok();
+        }
+        ChainFormat::Mermaid => {
+            writeln!(s, "flowchart LR").ok();
+            for blob in blobs {
+                cumulative += blob.store_size;
+                writeln!(
+                    s,
+                    "  V{}[\"V{} {}<br/>{}B / {}B\"]",
+                    blob.id, blob.id, blob.filename, blob.store_size, cumulative,
+                )
+                .ok();
+            }
+            for pair in blobs.windows(2) {
+                writeln!(s, "  V{} --> V{}", pair[0].id, pair[1].id).ok();
+            }
+        }
+    }
+
+    s
+}
+
+pub fn chain(conn: &mut db::Conn, name: &str, format: ChainFormat) -> Result<()> {
+    let blobs = db::chain(conn, name)?;
+    if blobs.is_empty() {
+        return Err(crate::format_err!("unknown filename or hash: {}", name));
+    }
+
+    print!("{}", format_chain(&blobs, format));
+    Ok(())
+}
+
+/// rough throughput of applying a delta, including reading inputs and writing the output
+const ESTIMATED_DECODE_THROUGHPUT: f64 = 200.0 * 1024.0 * 1024.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeltaChainCost {
+    pub decode_steps: usize,
+    /// sum of `store_size` of all blobs in the chain, including the root blob
+    pub total_read_bytes: u64,
+    /// sum of `content_size` of all decoded blobs, including intermediates
+    pub total_write_bytes: u64,
+    pub estimated_seconds: f64,
+}
+
+/// estimates the cost of `get` for a filename
+pub fn delta_chain_cost(conn: &mut db::Conn, filename: &str) -> Result<DeltaChainCost> {
+    let blobs = db::chain(conn, filename)?;
+    if blobs.is_empty() {
+        return Err(Error::UnknownFilename(filename.to_owned()));
+    }
+
+    let total_read_bytes = blobs.iter().map(|blob| blob.store_size).sum::<u64>();
+    let total_write_bytes = blobs
+        .iter()
+        .skip(1)
+        .map(|blob| blob.content_size)
+        .sum::<u64>();
+
+    Ok(DeltaChainCost {
+        decode_steps: blobs.len() - 1,
+        total_read_bytes,
+        total_write_bytes,
+        estimated_seconds: (total_read_bytes + total_write_bytes) as f64
+            / ESTIMATED_DECODE_THROUGHPUT,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+
+    fn synthetic_chain(conn: &mut db::Conn) -> Vec<Blob> {
+        for blob in &[
+            blob(1, "aa", None, 1000),
+            blob(2, "bb", None, 1000),
+            blob(3, "bb", Some("aa"), 100),
+            blob(4, "cc", Some("bb"), 10),
+        ] {
+            db::insert(conn, blob).unwrap();
+        }
+        db::chain(conn, "app-4.tar").unwrap()
+    }
+
+    #[test]
+    fn chain_query() {
+        with_store(|conn, _dir| {
+            let blobs = synthetic_chain(conn);
+            let ids = blobs.iter().map(|blob| blob.id).collect::<Vec<_>>();
+            assert_eq!(ids, vec![1, 3, 4]);
+
+            assert_eq!(db::chain(conn, "cc").unwrap().len(), 3);
+            assert!(db::chain(conn, "unknown").unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn chain_cost() {
+        with_store(|conn, _dir| {
+            synthetic_chain(conn);
+            let cost = delta_chain_cost(conn, "app-4.tar").unwrap();
+            assert_eq!(cost.decode_steps, 2);
+            assert_eq!(cost.total_read_bytes, 1110);
+            assert_eq!(cost.total_write_bytes, 2000);
+            assert!(cost.estimated_seconds > 0.0);
+
+            assert!(delta_chain_cost(conn, "unknown").is_err());
+        });
+    }
+
+    #[test]
+    fn chain_format_dot() {
+        with_store(|conn, _dir| {
+            let blobs = synthetic_chain(conn);
+            assert_eq!(
+                format_chain(&blobs, ChainFormat::Dot),
+                r#"digraph chain {
+  rankdir="LR"
+  V1 [label="V1\napp-1.tar\n1000B / 1000B"];
+  V3 [label="V3\napp-3.tar\n100B / 1100B"];
+  V4 [label="V4\napp-4.tar\n10B / 1110B"];
+  V1 -> V3;
+  V3 -> V4;
+}
+"#
+            );
+        });
+    }
+
+    #[test]
+    fn chain_format_mermaid() {
+        with_store(|conn, _dir| {
+            let blobs = synthetic_chain(conn);
+            assert_eq!(
+                format_chain(&blobs, ChainFormat::Mermaid),
+                r#"flowchart LR
+  V1["V1 app-1.tar<br/>1000B / 1000B"]
+  V3["V3 app-3.tar<br/>100B / 1100B"]
+  V4["V4 app-4.tar<br/>10B / 1110B"]
+  V1 --> V3
+  V3 --> V4
+"#
+            );
+        });
+    }
+
+    #[test]
+    fn chain_format_text() {
+        with_store(|conn, dir| {
+            let blobs = synthetic_chain(conn);
+            let objects = format!("{}/objects", dir.display());
+            assert_eq!(
+                format_chain(&blobs, ChainFormat::Text),
+                format!(
+                    "0 V1 app-1.tar store_size=1000 decode_size=1000 {0}/aa/1\n\
+                     1 V3 app-3.tar store_size=100 decode_size=1100 {0}/bb/3\n\
+                     2 V4 app-4.tar store_size=10 decode_size=1110 {0}/cc/4\n",
+                    objects
+                )
+            );
+        });
+    }
+}
@@ -0,0 +1,386 @@
+use std::io::Write;
+
+use super::*;
+
+/// how a store keeps versions, selected per store with `set_storage_mode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageMode {
+    /// full root blobs and xdelta3 deltas against them
+    Delta,
+    /// content-defined chunks, stored content-addressed under `chunks/`
+    Chunk,
+}
+
+impl std::str::FromStr for StorageMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "delta" => Ok(StorageMode::Delta),
+            "chunk" => Ok(StorageMode::Chunk),
+            _ => Err(crate::format_err!("unknown storage mode: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for StorageMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StorageMode::Delta => write!(f, "delta"),
+            StorageMode::Chunk => write!(f, "chunk"),
+        }
+    }
+}
+
+const STORAGE_MODE_KEY: &str = "storage_mode";
+
+pub fn storage_mode(conn: &mut db::Conn) -> Result<StorageMode> {
+    match db::get_setting(conn, STORAGE_MODE_KEY)? {
+        Some(mode) => mode.parse(),
+        None => Ok(StorageMode::Delta),
+    }
+}
+
+/// storage mode can be changed only while the store is empty
+pub fn set_storage_mode(conn: &mut db::Conn, mode: StorageMode) -> Result<()> {
+    if storage_mode(conn)? == mode {
+        return Ok(());
+    }
+    if !db::all(conn)?.is_empty() || db::chunked_count(conn)? > 0 {
+        return Err(crate::format_err!(
+            "cannot change storage mode of a non-empty store"
+        ));
+    }
+    db::set_setting(conn, STORAGE_MODE_KEY, &mode.to_string())?;
+    Ok(())
+}
+
+const MIN_CHUNK_SIZE: usize = 2 * 1024;
+const MAX_CHUNK_SIZE: usize = 64 * 1024;
+/// cut when the low 13 bits of the rolling hash are zero, 8KiB chunks on average
+const CHUNK_MASK: u64 = (1 << 13) - 1;
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64, fixed seed so chunk boundaries are stable across builds
+    let mut table = [0u64; 256];
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+const GEAR: [u64; 256] = gear_table();
+
+/// length of the next chunk at the start of `data`, using a gear rolling hash
+fn next_chunk_len(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+
+    let end = data.len().min(MAX_CHUNK_SIZE);
+    let mut hash = 0u64;
+    for (i, b) in data.iter().enumerate().take(end).skip(MIN_CHUNK_SIZE) {
+        hash = (hash << 1).wrapping_add(GEAR[*b as usize]);
+        if hash & CHUNK_MASK == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+pub fn chunk_lens(data: &[u8]) -> Vec<usize> {
+    let mut lens = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let len = next_chunk_len(&data[offset..]);
+        lens.push(len);
+        offset += len;
+    }
+    lens
+}
+
+fn chunkpath(dirs: &StoreDirs, s: &str) -> String {
+    format!("{}/chunks/{}/{}", dirs.workdir, &s[..2], &s[2..])
+}
+
+fn chunk_hash(data: &[u8]) -> String {
+    let mut w = HashRW::new(io::sink());
+    w.write_all(data).expect("failed to hash");
+    w.meta().digest()
+}
+
+/// stores a chunk unless it already exists. returns true if the chunk is new.
+fn store_chunk(hash: &str, data: &[u8]) -> Result<bool> {
+    let path = chunkpath(&StoreDirs::from_env(), hash);
+    if Path::new(&path).exists() {
+        return Ok(false);
+    }
+
+    let mut tmpfile = NamedTempFile::new_in(tmpdir())?;
+    tmpfile.write_all(data)?;
+    store_object(tmpfile, &path)?;
+    Ok(true)
+}
+
+/// `push` in chunk storage mode. the canonical form of the input is split into content-defined
+/// chunks, and only chunks which are not in the store yet are written.
+pub fn push_chunked(
+    conn: &mut db::Conn,
+    input_filepath: &str,
+    ty: FileType,
+    opts: &PushOptions,
+) -> Result<PushReport> {
+    // chunks are shared between versions and never removed, so there is no store size or
+    // delta ratio to check them against
+    let unsupported = [
+        ("--max-store-size", opts.max_store_size.is_some()),
+        (
+            "--quota-policy",
+            opts.quota_policy != QuotaPolicy::default(),
+        ),
+        ("--max-ratio", opts.max_ratio.is_some()),
+        ("--max-ratio-strict", opts.max_ratio_strict),
+    ];
+    if let Some((name, _)) = unsupported.iter().find(|(_, set)| *set) {
+        return Err(crate::format_err!(
+            "{} is not supported in chunk storage mode",
+            name
+        ));
+    }
+
+    let explode_bundle = opts.explode_bundle;
+    let input_filename = Path::new(input_filepath)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut report = PushReport {
+        filename: input_filename.clone(),
+        ..Default::default()
+    };
+
+    let input_hash = input_hash(input_filepath, opts)?;
+    let sw = Stopwatch::start_new();
+    let tmpfile = NamedTempFile::new_in(tmpdir())?;
+    let meta = canonicalize(
+        Path::new(input_filepath),
+        ty,
+        explode_bundle,
+        tmpfile.path(),
+    )?;
+
+    let mmap;
+    let data: &[u8] = if meta.is_empty() {
+        &[]
+    } else {
+        mmap = unsafe { memmap::Mmap::map(tmpfile.as_file())? };
+        &mmap
+    };
+
+    let mut chunks = Vec::new();
+    let mut new_chunk_bytes = 0;
+    let mut offset = 0;
+    for len in chunk_lens(data) {
+        let chunk = &data[offset..offset + len];
+        let hash = chunk_hash(chunk);
+        if store_chunk(&hash, chunk)? {
+            new_chunk_bytes += len as u64;
+        }
+        chunks.push((hash, len as u64));
+        offset += len;
+    }
+
+    // chunks written so far are shared, and left to be reused
+    check_unchanged(input_filepath, input_hash.as_deref())?;
+
+    let blob = meta.blob(&input_filename);
+    report.content_hash = blob.content_hash.clone();
+    let file = db::ChunkedFile {
+        id: 0,
+        filename: blob.filename,
+        time_created: blob.time_created,
+        content_size: blob.content_size,
+        content_hash: blob.content_hash,
+    };
+    if !db::insert_chunked(conn, &file, &chunks)? {
+        info!("push: content already exists, skipping");
+        report.skipped = true;
+        return Ok(report);
+    }
+    db::set_exploded(conn, &file.content_hash, explode_bundle)?;
+
+    info!(
+        "push: chunks={} new_chunk_bytes={} content_size={} took={}ms",
+        chunks.len(),
+        new_chunk_bytes,
+        file.content_size,
+        sw.elapsed_ms()
+    );
+    report.new_chunk_bytes = Some(new_chunk_bytes);
+    Ok(report)
+}
+
+/// `get` of a chunked file, concatenating its chunks
+pub fn get_chunked(
+    conn: &mut db::Conn,
+    dirs: &StoreDirs,
+    file: &db::ChunkedFile,
+    out_filename: &str,
+    opts: &GetOptions,
+) -> Result<()> {
+    let chunks = db::chunks(conn, &file.content_hash)?;
+
+    if opts.dry_run {
+        for (hash, _size) in chunks {
+            println!("{} {}", chunkpath(dirs, &hash), file.filename);
+        }
+        return Ok(());
+    }
+
+    let tmpfile = NamedTempFile::new_in(dirs.tmpdir())?;
+    let mut w = HashRW::new(io::BufWriter::with_capacity(BUF_SIZE, tmpfile.reopen()?));
+    for (hash, _size) in chunks {
+        let path = chunkpath(dirs, &hash);
+        let mut chunk_file = match std::fs::File::open(&path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(Error::MissingObject(path));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        io::copy(&mut chunk_file, &mut w)?;
+    }
+    w.flush()?;
+
+    let meta = w.meta();
+    if meta.digest() != file.content_hash {
+        return Err(Error::ChecksumMismatch {
+            filename: file.filename.clone(),
+            expected: file.content_hash.clone(),
+            actual: meta.digest(),
+        });
+    }
+
+    persist_output(tmpfile, out_filename, opts)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+
+    fn random_bytes(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                // xorshift64
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chunk_boundaries() {
+        let data = random_bytes(1, 256 * 1024);
+        let lens = chunk_lens(&data);
+        assert_eq!(lens.iter().sum::<usize>(), data.len());
+        assert!(lens.iter().all(|len| *len <= MAX_CHUNK_SIZE));
+
+        // boundaries re-synchronize after an insertion
+        let mut shifted = random_bytes(2, 100);
+        shifted.extend_from_slice(&data);
+        let shifted_lens = chunk_lens(&shifted);
+        assert_eq!(lens.last(), shifted_lens.last());
+    }
+
+    #[test]
+    fn push_get_chunked() {
+        with_store(|conn, dir| {
+            set_storage_mode(conn, StorageMode::Chunk).unwrap();
+
+            let middle = random_bytes(1, 1024 * 1024);
+            let mut v1 = random_bytes(2, 64 * 1024);
+            v1.extend_from_slice(&middle);
+            v1.extend_from_slice(&random_bytes(3, 64 * 1024));
+            let mut v2 = random_bytes(4, 100 * 1024);
+            v2.extend_from_slice(&middle);
+            v2.extend_from_slice(&random_bytes(5, 30 * 1024));
+
+            let v1 = write_file(dir, "app-1.tar", &v1);
+            let v2_path = write_file(dir, "app-2.tar", &v2);
+
+            let report = push_with_options(conn, &v1, FileType::Plain, &Default::default());
+            assert!(report.unwrap().new_chunk_bytes.unwrap() > 1024 * 1024);
+
+            let report = push_with_options(conn, &v2_path, FileType::Plain, &Default::default());
+            let new_chunk_bytes = report.unwrap().new_chunk_bytes.unwrap();
+            assert!(
+                new_chunk_bytes < v2.len() as u64 / 4,
+                "new_chunk_bytes={}",
+                new_chunk_bytes
+            );
+
+            // the shared region is not stored again, as with a delta of v2 against v1. only the
+            // chunks cut by the edges of the changed regions are stored on top of it.
+            let (delta, _) =
+                compact::encode_delta("app-2.tar", Path::new(&v1), Path::new(&v2_path)).unwrap();
+            let delta_size = delta.as_file().metadata().unwrap().len();
+            assert!(
+                new_chunk_bytes <= delta_size + 2 * MAX_CHUNK_SIZE as u64,
+                "new_chunk_bytes={} delta_size={}",
+                new_chunk_bytes,
+                delta_size
+            );
+            assert!(db::all(conn).unwrap().is_empty());
+
+            let out = dir.join("out.tar");
+            get(conn, "app-2.tar", out.to_str().unwrap(), false).unwrap();
+            assert_eq!(std::fs::read(&out).unwrap(), v2);
+
+            assert!(set_storage_mode(conn, StorageMode::Delta).is_err());
+        });
+    }
+
+    #[test]
+    fn push_chunked_rejects_quota() {
+        with_store(|conn, dir| {
+            set_storage_mode(conn, StorageMode::Chunk).unwrap();
+            let path = write_file(dir, "app-1.tar", &random_bytes(1, 64 * 1024));
+
+            let options = [
+                PushOptions {
+                    max_store_size: Some(1),
+                    ..Default::default()
+                },
+                PushOptions {
+                    quota_policy: QuotaPolicy::Prune,
+                    ..Default::default()
+                },
+                PushOptions {
+                    max_ratio: Some(0.5),
+                    ..Default::default()
+                },
+                PushOptions {
+                    max_ratio_strict: true,
+                    ..Default::default()
+                },
+            ];
+            for opts in &options {
+                let e = push_with_options(conn, &path, FileType::Plain, opts).unwrap_err();
+                assert!(e.to_string().contains("chunk storage mode"), "{}", e);
+            }
+            // nothing is written before the options are rejected
+            assert_eq!(db::chunked_count(conn).unwrap(), 0);
+            assert!(!dir.join("chunks").exists());
+        });
+    }
+}
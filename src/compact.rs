@@ -0,0 +1,417 @@
+use super::*;
+
+/// default slack of `compact_chain`: the delta against the grandparent may be this much larger
+/// than the current delta
+pub const COMPACT_CHAIN_SLACK: f64 = 1.1;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CompactReport {
+    pub filename: String,
+    pub old_parent_hash: String,
+    pub old_store_size: u64,
+    /// the grandparent, None if the blob is not re-parented
+    pub new_parent_hash: Option<String>,
+    /// size of the delta against the grandparent, None if it was not encoded
+    pub new_store_size: Option<u64>,
+}
+
+/// decodes the content of a blob into a temporary file
+pub fn decode_content(conn: &mut db::Conn, blob: &Blob) -> Result<NamedTempFile> {
+    let tmpfile = NamedTempFile::new_in(tmpdir())?;
+    let tmp_path = tmpfile.path().to_str().expect("invalid tmpdir");
+    // replaces the empty tmpfile
+    let opts = GetOptions {
+        overwrite: true,
+        ..Default::default()
+    };
+    get_with_options(conn, &blob.filename, tmp_path, &opts)?;
+
+    // the filename may refer to a newer blob of another content
+    let digest = file_hash(tmp_path)?;
+    if digest != blob.content_hash {
+        return Err(Error::ChecksumMismatch {
+            filename: blob.filename.clone(),
+            expected: blob.content_hash.clone(),
+            actual: digest,
+        });
+    }
+    Ok(tmpfile)
+}
+
+pub fn encode_delta(
+    filename: &str,
+    src_path: &Path,
+    input_path: &Path,
+) -> Result<(NamedTempFile, Blob)> {
+    let rt = tokio::runtime::Runtime::new()?;
+    let tmpfile = NamedTempFile::new_in(tmpdir())?;
+
+    let meta = rt.block_on(async {
+        use tokio::{fs::File, io::*};
+
+        let src_file = File::open(src_path).await?;
+        let input_file = File::open(input_path).await?;
+        let dst_file = File::create(tmpfile.path()).await?;
+
+        delta::delta(
+            delta::ProcessMode::Encode,
+            BufReader::with_capacity(BUF_SIZE, src_file),
+            BufReader::with_capacity(BUF_SIZE, input_file),
+            BufWriter::with_capacity(BUF_SIZE, dst_file),
+        )
+        .await
+        .map_err(|error| crate::Error::Delta {
+            filename: filename.to_owned(),
+            error,
+        })
+    })?;
+
+    let blob = meta.blob(filename);
+    Ok((tmpfile, blob))
+}
+
+/// re-encodes the delta of `filename` against its grandparent, and re-parents it if the new delta
+/// is at most `slack` times the current one. this shortens the decode chain by one. the
+/// re-parented blob is checked with `verify_scope`, and kept as before if the check fails.
+pub fn compact_chain(
+    conn: &mut db::Conn,
+    filename: &str,
+    slack: f64,
+    verify: bool,
+) -> Result<CompactReport> {
+    let blob = match db::by_filename(conn, filename)?.pop() {
+        Some(blob) => blob,
+        None => return Err(Error::UnknownFilename(filename.to_owned())),
+    };
+    let parent_hash = match &blob.parent_hash {
+        Some(parent_hash) => parent_hash.clone(),
+        None => return Err(crate::format_err!("{} is not a delta", filename)),
+    };
+    let parent = match db::by_content_hash(conn, &parent_hash)?.pop() {
+        Some(parent) => parent,
+        None => return Err(Error::MissingObject(parent_hash)),
+    };
+
+    let mut report = CompactReport {
+        filename: filename.to_owned(),
+        old_parent_hash: parent_hash,
+        old_store_size: blob.store_size,
+        ..Default::default()
+    };
+
+    let grandparent_hash = match &parent.parent_hash {
+        Some(hash) => hash.clone(),
+        None => {
+            info!("compact-chain: parent of {} is a root", filename);
+            return Ok(report);
+        }
+    };
+
+    // a delta against a content decoded through the blob itself could never be decoded
+    let grandparent_chain = db::chain(conn, &grandparent_hash)?;
+    if grandparent_chain.iter().any(|b| b.is_same_content(&blob)) {
+        return Err(crate::format_err!(
+            "compacting {} would make a cycle",
+            filename
+        ));
+    }
+    if db::protected_store_hashes(conn)?.contains(&blob.store_hash) {
+        return Err(crate::format_err!(
+            "{} is needed by a dehydrated root",
+            filename
+        ));
+    }
+    let grandparent = match grandparent_chain.last() {
+        Some(grandparent) => grandparent.clone(),
+        None => return Err(Error::MissingObject(grandparent_hash)),
+    };
+
+    let input = decode_content(conn, &blob)?;
+    let src = decode_content(conn, &grandparent)?;
+    let (tmpfile, mut new_blob) = encode_delta(filename, src.path(), input.path())?;
+    new_blob.time_created = blob.time_created;
+    new_blob.content_size = blob.content_size;
+    new_blob.content_hash = blob.content_hash.clone();
+    new_blob.parent_hash = Some(grandparent_hash.clone());
+    report.new_store_size = Some(new_blob.store_size);
+
+    if new_blob.store_size as f64 > blob.store_size as f64 * slack {
+        info!(
+            "compact-chain: keeping {}, store_size={} grandparent_store_size={}",
+            filename, blob.store_size, new_blob.store_size
+        );
+        return Ok(report);
+    }
+
+    let same_object = new_blob.store_hash == blob.store_hash;
+    store_object(tmpfile, filepath(&new_blob.store_hash))?;
+    db::savepoint(conn, "compact_chain")?;
+    let res = (|| -> Result<()> {
+        if !db::reparent(conn, &blob.store_hash, &new_blob)? {
+            return Err(crate::format_err!(
+                "{} was removed while compacting",
+                filename
+            ));
+        }
+        verify_scope(conn, &[blob.id], verify_depth(verify))?;
+        Ok(())
+    })();
+    if let Err(e) = res {
+        db::rollback_to(conn, "compact_chain")?;
+        if !same_object {
+            remove_object_if_exists(&new_blob.store_hash)?;
+        }
+        return Err(e);
+    }
+    db::release(conn, "compact_chain")?;
+    // a delta which does not refer to the source has the same object
+    if !same_object {
+        remove_object_if_exists(&blob.store_hash)?;
+    }
+
+    info!(
+        "compact-chain: {} re-parented, store_size={} -> {}",
+        filename, blob.store_size, new_blob.store_size
+    );
+    report.new_parent_hash = Some(grandparent_hash);
+    Ok(report)
+}
+
+fn remove_object_if_exists(store_hash: &str) -> Result<()> {
+    match std::fs::remove_file(filepath(store_hash)) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// bounds the decode depth of `filename` by `max_depth`. every `max_depth + 1`th version of its
+/// chain is stored in full as a new root, so the chain becomes a tree of short chains below roots.
+/// deltas below a new root are kept, as they already refer to its content. the new roots are
+/// checked with `verify_scope`, and removed again if the check fails.
+pub fn compact_delta_chain(
+    conn: &mut db::Conn,
+    filename: &str,
+    max_depth: usize,
+    verify: bool,
+) -> Result<()> {
+    if max_depth == 0 {
+        return Err(crate::format_err!("max_depth should be positive"));
+    }
+    let chain = db::chain(conn, filename)?;
+    if chain.is_empty() {
+        return Err(Error::UnknownFilename(filename.to_owned()));
+    }
+    if chain.len() - 1 <= max_depth {
+        info!(
+            "compact-delta-chain: depth of {} is {}",
+            filename,
+            chain.len() - 1
+        );
+        return Ok(());
+    }
+
+    // objects stored here, removed again on failure
+    let mut stored = Vec::new();
+    db::savepoint(conn, "compact_delta_chain")?;
+    let res = (|| -> Result<()> {
+        let mut ids = Vec::new();
+        let mut roots = Vec::new();
+        // a version decoded later starts from the roots added before it
+        for blob in chain.iter().skip(max_depth + 1).step_by(max_depth + 1) {
+            let content = decode_content(conn, blob)?;
+            let root = Blob {
+                store_size: blob.content_size,
+                store_hash: blob.content_hash.clone(),
+                parent_hash: None,
+                ..blob.clone()
+            };
+            let path = filepath(&root.store_hash);
+            if !Path::new(&path).exists() {
+                stored.push(root.store_hash.clone());
+            }
+            store_object(content, path)?;
+            if insert_blob(conn, &root)? {
+                ids.push(conn.last_insert_rowid() as u32);
+            }
+            // cleanup would remove the copy again, as its alias is a delta
+            db::pin_root(conn, &root.store_hash)?;
+            roots.push(RootTransition::new(
+                EVENT_ROOT_ADDED,
+                &root,
+                "compact_delta_chain",
+            ));
+            info!("compact-delta-chain: {} is a root", blob.filename);
+        }
+        verify_scope(conn, &ids, verify_depth(verify))?;
+        log_roots(conn, &roots)?;
+        let details = serde_json::json!({
+            "filename": filename,
+            "max_depth": max_depth,
+        });
+        log_op(conn, "compact_delta_chain", details)?;
+        Ok(())
+    })();
+    if let Err(e) = res {
+        db::rollback_to(conn, "compact_delta_chain")?;
+        for store_hash in &stored {
+            remove_object_if_exists(store_hash)?;
+        }
+        return Err(e);
+    }
+    db::release(conn, "compact_delta_chain")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+    use std::sync::{atomic::AtomicUsize, Arc};
+
+    fn link(conn: &mut db::Conn, input: &Blob, src: &Blob) {
+        let race = Arc::new(AtomicUsize::new(0));
+        let (tmp, blob) = append_delta(input, src, race, None).unwrap().unwrap();
+        update_blob(conn, tmp, &blob).unwrap();
+    }
+
+    #[test]
+    fn compact_middle() {
+        with_store(|conn, dir| {
+            let v1 = "hello, world\n".repeat(4096).into_bytes();
+            let mut v2 = v1.clone();
+            v2.extend_from_slice(b"v2");
+            let mut v3 = v2.clone();
+            v3.extend_from_slice(b"v3");
+
+            let mut blobs = Vec::new();
+            for (i, body) in [&v1, &v2, &v3].iter().enumerate() {
+                let path = write_file(dir, &format!("app-{}.tar", i + 1), body);
+                let (blob, _) = append_full(conn, &path, FileType::Plain, false).unwrap();
+                blobs.push(blob);
+            }
+            // app-3 -> app-2 -> app-1
+            link(conn, &blobs[1], &blobs[0]);
+            link(conn, &blobs[2], &blobs[1]);
+            remove_root(conn, &blobs[1]).unwrap();
+            remove_root(conn, &blobs[2]).unwrap();
+            assert_eq!(db::chain(conn, "app-3.tar").unwrap().len(), 3);
+
+            let report = compact_chain(conn, "app-3.tar", 0.5, false).unwrap();
+            assert!(report.new_parent_hash.is_none());
+            assert!(report.new_store_size.is_some());
+            assert_eq!(db::chain(conn, "app-3.tar").unwrap().len(), 3);
+
+            let old = db::by_filename(conn, "app-3.tar").unwrap().pop().unwrap();
+            let report = compact_chain(conn, "app-3.tar", COMPACT_CHAIN_SLACK, false).unwrap();
+            assert_eq!(report.new_parent_hash, Some(blobs[0].content_hash.clone()));
+            assert_eq!(db::chain(conn, "app-3.tar").unwrap().len(), 2);
+            let new = db::by_filename(conn, "app-3.tar").unwrap().pop().unwrap();
+            assert_eq!(
+                Path::new(&filepath(&old.store_hash)).exists(),
+                old.store_hash == new.store_hash
+            );
+
+            for (filename, body) in &[("app-2.tar", &v2), ("app-3.tar", &v3)] {
+                let out = dir.join(format!("out-{}", filename));
+                get(conn, filename, out.to_str().unwrap(), false).unwrap();
+                assert_eq!(&std::fs::read(&out).unwrap(), *body);
+            }
+
+            // the parent is a root now
+            let report = compact_chain(conn, "app-3.tar", COMPACT_CHAIN_SLACK, false).unwrap();
+            assert!(report.new_parent_hash.is_none());
+            assert!(compact_chain(conn, "app-1.tar", COMPACT_CHAIN_SLACK, false).is_err());
+        });
+    }
+
+    #[test]
+    fn compact_rollback() {
+        with_store(|conn, dir| {
+            let v1 = "hello, world\n".repeat(4096).into_bytes();
+            let mut v2 = v1.clone();
+            v2.extend_from_slice(b"v2");
+            let mut v3 = v2.clone();
+            v3.extend_from_slice(b"v3");
+
+            let mut blobs = Vec::new();
+            for (i, body) in [&v1, &v2, &v3].iter().enumerate() {
+                let path = write_file(dir, &format!("app-{}.tar", i + 1), body);
+                let (blob, _) = append_full(conn, &path, FileType::Plain, false).unwrap();
+                blobs.push(blob);
+            }
+            link(conn, &blobs[1], &blobs[0]);
+            link(conn, &blobs[2], &blobs[1]);
+            remove_root(conn, &blobs[1]).unwrap();
+            remove_root(conn, &blobs[2]).unwrap();
+            let old = db::by_filename(conn, "app-3.tar").unwrap().pop().unwrap();
+
+            // the object of app-3 is missing once it is reparented. deltas may be stored as
+            // they are, so the object may be the old one.
+            let objects = object_files().unwrap();
+            let kept = objects
+                .keys()
+                .filter(|hash| **hash != old.store_hash)
+                .map(|hash| PathBuf::from(filepath(hash)))
+                .collect::<Vec<_>>();
+            let fs = MissingFs(move |path: &Path| !kept.iter().any(|p| p == path));
+            let res = retry::with_test_fs(Box::new(fs), || {
+                compact_chain(conn, "app-3.tar", COMPACT_CHAIN_SLACK, true)
+            });
+            assert!(res.is_err());
+
+            // new objects are removed, shared ones are kept
+            assert_eq!(object_files().unwrap().len(), objects.len());
+            assert_eq!(db::chain(conn, "app-3.tar").unwrap().len(), 3);
+            let blob = db::by_filename(conn, "app-3.tar").unwrap().pop().unwrap();
+            assert_eq!(blob.store_hash, old.store_hash);
+            assert!(Path::new(&filepath(&old.store_hash)).exists());
+            let out = dir.join("out-app-3.tar");
+            get(conn, "app-3.tar", out.to_str().unwrap(), false).unwrap();
+            assert_eq!(std::fs::read(&out).unwrap(), v3);
+        });
+    }
+
+    #[test]
+    fn compact_delta_chain_depth() {
+        with_store(|conn, dir| {
+            let mut bodies = Vec::new();
+            let mut blobs = Vec::<Blob>::new();
+            let mut body = "hello, world\n".repeat(4096).into_bytes();
+            for i in 1..=8 {
+                body.extend_from_slice(format!("v{}", i).as_bytes());
+                let path = write_file(dir, &format!("app-{}.tar", i), &body);
+                let (blob, _) = append_full(conn, &path, FileType::Plain, false).unwrap();
+                if let Some(parent) = blobs.last() {
+                    link(conn, &blob, parent);
+                }
+                bodies.push(body.clone());
+                blobs.push(blob);
+            }
+            for blob in &blobs[1..] {
+                remove_root(conn, blob).unwrap();
+            }
+            assert_eq!(db::chain(conn, "app-8.tar").unwrap().len(), 8);
+
+            compact_delta_chain(conn, "app-8.tar", 3, false).unwrap();
+            // app-5 and app-1 are roots, app-5 is kept from cleanup
+            assert_eq!(db::roots(conn).unwrap().len(), 2);
+            let protected = db::protected_store_hashes(conn).unwrap();
+            assert!(protected.contains(&blobs[4].content_hash));
+            assert!(db::dehydrated_roots(conn).unwrap().is_empty());
+            for (i, body) in bodies.iter().enumerate() {
+                let filename = format!("app-{}.tar", i + 1);
+                assert!(db::chain(conn, &filename).unwrap().len() <= 4);
+
+                let out = dir.join(format!("out-{}", filename));
+                get(conn, &filename, out.to_str().unwrap(), false).unwrap();
+                assert_eq!(&std::fs::read(&out).unwrap(), body);
+            }
+
+            // already within the bound
+            compact_delta_chain(conn, "app-8.tar", 3, false).unwrap();
+            assert_eq!(db::roots(conn).unwrap().len(), 2);
+            assert!(compact_delta_chain(conn, "unknown.tar", 3, false).is_err());
+        });
+    }
+}
@@ -1,11 +1,13 @@
 use crate::prefix;
 use log::info;
 use rusqlite::{params, Connection, Result};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Blob {
     pub id: u32,
     pub filename: String,
+    #[serde(with = "time::serde::rfc3339")]
     pub time_created: time::OffsetDateTime,
 
     pub store_size: u64,
@@ -27,8 +29,81 @@ impl Blob {
         // sqlite ROWID starts from 1
         self.id == 1
     }
+    /// both blobs decode to the same content, e.g. a root blob and its alias
+    pub fn is_same_content(&self, other: &Blob) -> bool {
+        self.content_hash == other.content_hash
+    }
+    /// `self` is a delta against the content of `parent`
+    pub fn is_direct_child_of(&self, parent: &Blob) -> bool {
+        self.parent_hash.as_deref() == Some(&parent.content_hash)
+    }
+    /// single-line json, with time_created in rfc3339
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("failed to serialize blob")
+    }
+}
+
+/// where a blob came from. None if it is not recorded, e.g. for blobs added before provenance
+/// was recorded or with `set_record_provenance(false)`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// absolute path of the pushed input, or the metadata file of adopted objects
+    pub source_path: Option<String>,
+    pub source_host: Option<String>,
+    /// url the input was downloaded from
+    pub source_url: Option<String>,
+}
+
+impl Provenance {
+    pub fn is_unknown(&self) -> bool {
+        self == &Provenance::default()
+    }
+}
+
+impl std::fmt::Display for Provenance {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.is_unknown() {
+            return write!(f, "unknown");
+        }
+        let field = |v: &Option<String>| v.clone().unwrap_or_else(|| "unknown".to_owned());
+        write!(
+            f,
+            "path={} host={} url={}",
+            field(&self.source_path),
+            field(&self.source_host),
+            field(&self.source_url)
+        )
+    }
+}
+
+/// a blob with its provenance as a single-line json, fields of both at the top level
+pub fn blob_json(blob: &Blob, provenance: &Provenance) -> String {
+    #[derive(Serialize)]
+    struct BlobWithProvenance<'a> {
+        #[serde(flatten)]
+        blob: &'a Blob,
+        #[serde(flatten)]
+        provenance: &'a Provenance,
+    }
+    serde_json::to_string(&BlobWithProvenance { blob, provenance })
+        .expect("failed to serialize blob")
 }
 
+/// a version stored as a list of content-defined chunks, in chunk storage mode
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkedFile {
+    pub id: u32,
+    pub filename: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub time_created: time::OffsetDateTime,
+
+    pub content_size: u64,
+    pub content_hash: String,
+}
+
+/// version of the tables created by `prepare`, reported by `status`
+pub const SCHEMA_VERSION: u32 = 1;
+
 pub fn dbpath() -> String {
     format!("{}/meta.db", prefix())
 }
@@ -36,9 +111,13 @@ pub fn dbpath() -> String {
 pub type Conn = rusqlite::Connection;
 
 pub fn open() -> Result<rusqlite::Connection> {
+    open_path(dbpath())
+}
+
+pub fn open_path<P: AsRef<std::path::Path>>(path: P) -> Result<rusqlite::Connection> {
     let mut wait_count = 0;
     loop {
-        match Connection::open(dbpath()) {
+        match Connection::open(path.as_ref()) {
             Ok(conn) => return Ok(conn),
             Err(e) => match e {
                 rusqlite::Error::SqliteFailure(ref e2, ref _msg) => {
@@ -59,6 +138,12 @@ pub fn open() -> Result<rusqlite::Connection> {
     }
 }
 
+/// opens a database of other archive, which should not be modified
+pub fn open_readonly<P: AsRef<std::path::Path>>(path: P) -> Result<rusqlite::Connection> {
+    use rusqlite::OpenFlags;
+    Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+}
+
 pub fn prepare(conn: &mut Conn) -> Result<()> {
     conn.execute(
         r#"
@@ -82,153 +167,1623 @@ create table if not exists blobs (
         params![],
     )?;
 
-    Ok(())
-}
+    conn.execute(
+        r#"
+create table if not exists settings (
+    key             text primary key,
+    value           text not null
+)
+    "#,
+        params![],
+    )?;
 
-pub fn all(conn: &mut Conn) -> Result<Vec<Blob>> {
-    let mut stmt = conn.prepare(
+    add_depth_column(conn)?;
+    add_provenance_columns(conn)?;
+
+    conn.execute(
         r#"
-select
-    id, filename, time_created,
-    store_size, content_size, store_hash, content_hash, parent_hash
-from blobs
-"#,
+create table if not exists chunked_files (
+    id              integer primary key,
+
+    filename        text not null,
+    time_created    text not null,
+
+    content_size    integer not null,
+    content_hash    text not null unique
+)
+    "#,
+        params![],
     )?;
 
-    let mut rows = Vec::new();
-    for row_res in stmt.query_map(params![], decode_row)? {
-        rows.push(row_res?);
-    }
-    Ok(rows)
-}
+    conn.execute(
+        r#"
+create table if not exists chunks (
+    content_hash    text not null,
+    idx             integer not null,
 
-pub fn by_filename(conn: &mut Conn, filename: &str) -> Result<Vec<Blob>> {
-    let mut stmt = conn.prepare(
+    chunk_hash      text not null,
+    chunk_size      integer not null,
+
+    primary key (content_hash, idx)
+)
+    "#,
+        params![],
+    )?;
+
+    conn.execute(
         r#"
-select
-    id, filename, time_created,
-    store_size, content_size, store_hash, content_hash, parent_hash
-from blobs
-where filename = ?
-"#,
+create table if not exists protected_blobs (
+    store_hash      text not null,
+    root_hash       text not null,
+
+    primary key (store_hash, root_hash)
+)
+    "#,
+        params![],
     )?;
 
-    let mut rows = Vec::new();
-    for row_res in stmt.query_map(params![filename], decode_row)? {
-        rows.push(row_res?);
-    }
-    Ok(rows)
-}
+    conn.execute(
+        r#"
+create table if not exists exploded_contents (
+    content_hash    text primary key
+)
+    "#,
+        params![],
+    )?;
 
-pub fn by_content_hash(conn: &mut Conn, content_hash: &str) -> Result<Vec<Blob>> {
-    let mut stmt = conn.prepare(
+    conn.execute(
         r#"
-select
-    id, filename, time_created,
-    store_size, content_size, store_hash, content_hash, parent_hash
-from blobs
-where content_hash = ?
-"#,
+create table if not exists series (
+    name            text not null,
+    seq             integer not null,
+
+    filename        text not null,
+    content_hash    text not null,
+    time_created    text not null,
+
+    primary key (name, seq)
+)
+    "#,
+        params![],
     )?;
 
-    let mut rows = Vec::new();
-    for row_res in stmt.query_map(params![content_hash], decode_row)? {
-        rows.push(row_res?);
+    conn.execute(
+        r#"
+create table if not exists blobs_quarantine (
+    id              integer primary key,
+
+    filename        text not null,
+    time_created    text not null,
+
+    store_size      integer not null,
+    content_size    integer not null,
+
+    store_hash      text not null,
+    content_hash    text not null,
+    parent_hash     text,
+
+    reason          text not null,
+    time_quarantined text not null
+)
+    "#,
+        params![],
+    )?;
+
+    conn.execute(
+        r#"
+create table if not exists original_hashes (
+    original_hash   text not null,
+    kind            text not null,
+    content_hash    text not null,
+    original_size   integer,
+
+    primary key (original_hash, kind)
+)
+    "#,
+        params![],
+    )?;
+    // migration: inputs are only hashed for a lookup if one of the same size was converted
+    if !has_column(conn, "original_hashes", "original_size")? {
+        conn.execute(
+            "alter table original_hashes add column original_size integer",
+            params![],
+        )?;
     }
-    Ok(rows)
-}
 
-fn decode_row(row: &rusqlite::Row) -> Result<Blob> {
-    let store_size: i64 = row.get(3)?;
-    let content_size: i64 = row.get(4)?;
-    Ok(Blob {
-        id: row.get(0)?,
-        filename: row.get(1)?,
-        time_created: row.get(2)?,
-        store_size: store_size as u64,
-        content_size: content_size as u64,
-        store_hash: row.get(5)?,
-        content_hash: row.get(6)?,
+    conn.execute(
+        r#"
+create table if not exists aliases (
+    filename        text primary key,
+    content_hash    text not null,
+    time_created    text not null
+)
+    "#,
+        params![],
+    )?;
 
-        parent_hash: row.get(7)?,
-    })
-}
+    conn.execute(
+        r#"
+create table if not exists notes (
+    id              integer primary key,
+    content_hash    text not null,
+    note            text not null,
+    time_created    text not null
+)
+    "#,
+        params![],
+    )?;
 
-pub fn latest(conn: &mut Conn) -> Result<Blob> {
-    conn.query_row(
+    conn.execute(
         r#"
-select
-    id, filename, time_created,
-    store_size, content_size, store_hash, content_hash, parent_hash
-from blobs
-order by id desc
-limit 1"#,
+create table if not exists checks (
+    kind            text primary key,
+    ok              integer not null,
+    time_checked    text not null,
+    error           text
+)
+    "#,
         params![],
-        decode_row,
-    )
-}
+    )?;
 
-pub fn insert(conn: &mut Conn, blob: &Blob) -> Result<bool> {
-    let inserted = conn.execute(
+    conn.execute(
         r#"
-insert or ignore into blobs (
-    filename,
-    time_created,
-    store_size,
-    content_size,
-    store_hash,
-    content_hash,
-    parent_hash
+create table if not exists snapshots (
+    name            text primary key,
+    time_created    text not null
 )
-    values (?1, ?2, ?3, ?4, ?5, ?6, ?7)"#,
-        params![
-            blob.filename,
-            blob.time_created,
-            blob.store_size as i64,
-            blob.content_size as i64,
-            blob.store_hash,
-            blob.content_hash,
-            blob.parent_hash
-        ],
+    "#,
+        params![],
     )?;
 
-    Ok(inserted > 0)
-}
+    conn.execute(
+        r#"
+create table if not exists oplog (
+    id              integer primary key,
+    ts              text not null,
+    op              text not null,
+    details_json    text not null
+)
+    "#,
+        params![],
+    )?;
 
-pub fn rename(conn: &mut Conn, from_filename: &str, to_filename: &str) -> Result<bool> {
-    let updated = conn.execute(
+    conn.execute(
         r#"
-    update blobs set filename = ?2 where filename = ?1
+create table if not exists root_sketches (
+    content_hash    text primary key,
+    sketch          blob not null
+)
     "#,
-        params![from_filename, to_filename],
+        params![],
     )?;
-    Ok(updated > 0)
-}
 
-pub fn remove(conn: &mut Conn, blob: &Blob) -> Result<()> {
     conn.execute(
         r#"
-delete from blobs where store_hash = ?1
-"#,
-        params![blob.store_hash],
+create table if not exists events (
+    id              integer primary key,
+    timestamp       text not null,
+    event_type      text not null,
+    store_hash      text not null,
+    reason          text
+)
+    "#,
+        params![],
     )?;
+
     Ok(())
 }
 
-pub fn roots(conn: &mut Conn) -> Result<Vec<Blob>> {
-    let mut stmt = conn.prepare(
-        r#"
-select
-    id, filename, time_created,
-    store_size, content_size, store_hash, content_hash, parent_hash
-from blobs
-where parent_hash is null
-"#,
+pub fn has_table(conn: &Conn, table: &str) -> Result<bool> {
+    let count: u32 = conn.query_row(
+        "select count(*) from sqlite_master where type = 'table' and name = ?",
+        params![table],
+        |row| row.get(0),
     )?;
+    Ok(count > 0)
+}
 
-    let mut rows = Vec::new();
-    for row_res in stmt.query_map(params![], decode_row)? {
-        rows.push(row_res?);
+fn has_column(conn: &Conn, table: &str, column: &str) -> Result<bool> {
+    let columns = conn
+        .prepare(&format!("pragma table_info({})", table))?
+        .query_map(params![], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>>>()?;
+    Ok(columns.iter().any(|name| name == column))
+}
+
+/// migration: nullable provenance columns, see `Provenance`. rows added before are unknown.
+fn add_provenance_columns(conn: &mut Conn) -> Result<()> {
+    for column in &["source_path", "source_host", "source_url"] {
+        if !has_column(conn, "blobs", column)? {
+            conn.execute(
+                &format!("alter table blobs add column {} text", column),
+                params![],
+            )?;
+        }
     }
-    Ok(rows)
+    Ok(())
+}
+
+/// setting which marks `blobs.depth` as up to date, removed by the triggers below
+const DEPTH_INDEX_KEY: &str = "depth_index";
+
+/// migration: `blobs.depth` caches the depth of `Stats`, see `set_depths`. changes of the graph
+/// invalidate the cache by triggers, so every writer keeps it consistent.
+fn add_depth_column(conn: &mut Conn) -> Result<()> {
+    if !has_column(conn, "blobs", "depth")? {
+        conn.execute("alter table blobs add column depth integer", params![])?;
+    }
+
+    conn.execute_batch(
+        r#"
+create trigger if not exists blobs_depth_insert after insert on blobs
+begin
+    delete from settings where key = 'depth_index';
+end;
+
+create trigger if not exists blobs_depth_delete after delete on blobs
+begin
+    delete from settings where key = 'depth_index';
+end;
+
+create trigger if not exists blobs_depth_update
+    after update of store_hash, content_hash, parent_hash on blobs
+begin
+    delete from settings where key = 'depth_index';
+end;
+"#,
+    )
+}
+
+/// stores depths of blobs by id in a single transaction, and marks them as up to date
+pub fn set_depths(conn: &mut Conn, depths: &[(u32, usize)]) -> Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare("update blobs set depth = ?2 where id = ?1")?;
+        for (id, depth) in depths {
+            stmt.execute(params![id, *depth as i64])?;
+        }
+    }
+    tx.execute(
+        "insert or replace into settings (key, value) values (?1, ?2)",
+        params![DEPTH_INDEX_KEY, time::OffsetDateTime::now_utc()],
+    )?;
+    tx.commit()
+}
+
+/// depths of blobs by id, if they are up to date
+pub fn cached_depths(conn: &mut Conn) -> Result<Option<std::collections::HashMap<u32, usize>>> {
+    if get_setting(conn, DEPTH_INDEX_KEY)?.is_none() {
+        return Ok(None);
+    }
+
+    let mut stmt = conn.prepare("select id, depth from blobs where depth is not null")?;
+    let rows = stmt.query_map(params![], |row| {
+        Ok((row.get::<_, u32>(0)?, row.get::<_, i64>(1)? as usize))
+    })?;
+    let mut depths = std::collections::HashMap::new();
+    for row in rows {
+        let (id, depth) = row?;
+        depths.insert(id, depth);
+    }
+    Ok(Some(depths))
+}
+
+pub fn get_setting(conn: &mut Conn, key: &str) -> Result<Option<String>> {
+    use rusqlite::OptionalExtension;
+    conn.query_row(
+        "select value from settings where key = ?",
+        params![key],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+pub fn set_setting(conn: &mut Conn, key: &str, value: &str) -> Result<()> {
+    conn.execute(
+        "insert or replace into settings (key, value) values (?1, ?2)",
+        params![key, value],
+    )?;
+    Ok(())
+}
+
+pub fn all(conn: &mut Conn) -> Result<Vec<Blob>> {
+    let mut stmt = conn.prepare(
+        r#"
+select
+    id, filename, time_created,
+    store_size, content_size, store_hash, content_hash, parent_hash
+from blobs
+"#,
+    )?;
+
+    let mut rows = Vec::new();
+    for row_res in stmt.query_map(params![], decode_row)? {
+        rows.push(row_res?);
+    }
+    Ok(rows)
+}
+
+/// rows read per query by `iter_all`
+pub const ITER_PAGE_SIZE: usize = 1024;
+
+/// blobs ordered by id, read `ITER_PAGE_SIZE` rows at a time, so only a page of the store is in
+/// memory. use `all` when every blob is needed at once.
+pub fn iter_all(conn: &Conn) -> BlobIter<'_> {
+    iter_all_paged(conn, ITER_PAGE_SIZE)
+}
+
+/// `iter_all`, reading `page_size` rows at a time
+pub fn iter_all_paged(conn: &Conn, page_size: usize) -> BlobIter<'_> {
+    BlobIter {
+        conn,
+        page_size: page_size.max(1),
+        last_id: None,
+        page: Vec::new().into_iter(),
+        done: false,
+        provenance: false,
+    }
+}
+
+/// `page`-th page of `page_size` blobs ordered by id, and whether a page follows it
+pub fn list_blobs_paginated(
+    conn: &mut Conn,
+    page: usize,
+    page_size: usize,
+) -> Result<(Vec<Blob>, bool)> {
+    // one more blob than the page tells whether there is a next page
+    let mut blobs = iter_all_paged(conn, page_size + 1)
+        .skip(page * page_size)
+        .take(page_size + 1)
+        .collect::<Result<Vec<_>>>()?;
+    let has_next = blobs.len() > page_size;
+    blobs.truncate(page_size);
+    Ok((blobs, has_next))
+}
+
+/// calls `f` with every blob in the order of `iter_all`, stopping at the first error
+pub fn for_each<F, E>(conn: &mut Conn, mut f: F) -> std::result::Result<(), E>
+where
+    F: FnMut(Blob) -> std::result::Result<(), E>,
+    E: From<rusqlite::Error>,
+{
+    iter_all(conn).try_for_each(|blob| f(blob?))
+}
+
+pub struct BlobIter<'a> {
+    conn: &'a Conn,
+    page_size: usize,
+    /// id of the last blob read, the next page starts after it
+    last_id: Option<u32>,
+    page: std::vec::IntoIter<(Blob, Provenance)>,
+    done: bool,
+    /// whether provenance columns are read, see `with_provenance`
+    provenance: bool,
+}
+
+impl<'a> BlobIter<'a> {
+    /// blobs read but not yet returned
+    pub fn buffered(&self) -> usize {
+        self.page.len()
+    }
+
+    /// yields the provenance of each blob along with it. provenance is unknown on a store which
+    /// is not migrated yet.
+    pub fn with_provenance(mut self) -> WithProvenance<'a> {
+        self.provenance = true;
+        WithProvenance(self)
+    }
+
+    fn next_page(&mut self) -> Result<Vec<(Blob, Provenance)>> {
+        let last_id = self.last_id.map_or(-1, i64::from);
+        let limit = self.page_size as i64;
+        if self.provenance && has_column(self.conn, "blobs", "source_url")? {
+            let mut stmt = self.conn.prepare_cached(
+                r#"
+select
+    id, filename, time_created,
+    store_size, content_size, store_hash, content_hash, parent_hash,
+    source_path, source_host, source_url
+from blobs
+where id > ?1
+order by id
+limit ?2
+"#,
+            )?;
+            let rows = stmt.query_map(params![last_id, limit], |row| {
+                let provenance = Provenance {
+                    source_path: row.get(8)?,
+                    source_host: row.get(9)?,
+                    source_url: row.get(10)?,
+                };
+                Ok((decode_row(row)?, provenance))
+            })?;
+            return rows.collect();
+        }
+
+        let mut stmt = self.conn.prepare_cached(
+            r#"
+select
+    id, filename, time_created,
+    store_size, content_size, store_hash, content_hash, parent_hash
+from blobs
+where id > ?1
+order by id
+limit ?2
+"#,
+        )?;
+        let rows = stmt.query_map(params![last_id, limit], |row| {
+            Ok((decode_row(row)?, Provenance::default()))
+        })?;
+        rows.collect()
+    }
+
+    fn next_entry(&mut self) -> Option<Result<(Blob, Provenance)>> {
+        if let Some(entry) = self.page.next() {
+            return Some(Ok(entry));
+        }
+        if self.done {
+            return None;
+        }
+        match self.next_page() {
+            Ok(page) => {
+                self.done = page.len() < self.page_size;
+                self.last_id = page.last().map(|(blob, _)| blob.id).or(self.last_id);
+                self.page = page.into_iter();
+                self.page.next().map(Ok)
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl Iterator for BlobIter<'_> {
+    type Item = Result<Blob>;
+
+    fn next(&mut self) -> Option<Result<Blob>> {
+        self.next_entry().map(|res| res.map(|(blob, _)| blob))
+    }
+}
+
+/// `BlobIter` yielding blobs with their provenance
+pub struct WithProvenance<'a>(BlobIter<'a>);
+
+impl Iterator for WithProvenance<'_> {
+    type Item = Result<(Blob, Provenance)>;
+
+    fn next(&mut self) -> Option<Result<(Blob, Provenance)>> {
+        self.0.next_entry()
+    }
+}
+
+pub fn count(conn: &mut Conn) -> Result<usize> {
+    let count: i64 = conn.query_row("select count(*) from blobs", params![], |row| row.get(0))?;
+    Ok(count as usize)
+}
+
+/// condition on `content_hash` matching the alias `?1`. a store opened read-only before
+/// migration has no aliases, and nothing matches.
+fn alias_condition(conn: &Conn) -> Result<&'static str> {
+    if has_table(conn, "aliases")? {
+        Ok("content_hash = (select content_hash from aliases where filename = ?1)")
+    } else {
+        Ok("0")
+    }
+}
+
+pub fn by_filename(conn: &mut Conn, filename: &str) -> Result<Vec<Blob>> {
+    let mut stmt = conn.prepare(&format!(
+        r#"
+select
+    id, filename, time_created,
+    store_size, content_size, store_hash, content_hash, parent_hash
+from blobs
+where filename = ?1
+    or {}
+order by id
+"#,
+        alias_condition(conn)?
+    ))?;
+
+    let mut rows = Vec::new();
+    for row_res in stmt.query_map(params![filename], decode_row)? {
+        rows.push(row_res?);
+    }
+    Ok(rows)
+}
+
+pub fn by_content_hash(conn: &mut Conn, content_hash: &str) -> Result<Vec<Blob>> {
+    let mut stmt = conn.prepare(
+        r#"
+select
+    id, filename, time_created,
+    store_size, content_size, store_hash, content_hash, parent_hash
+from blobs
+where content_hash = ?
+"#,
+    )?;
+
+    let mut rows = Vec::new();
+    for row_res in stmt.query_map(params![content_hash], decode_row)? {
+        rows.push(row_res?);
+    }
+    Ok(rows)
+}
+
+/// blobs whose content or store hash starts with `prefix`
+pub fn by_hash_prefix(conn: &mut Conn, prefix: &str) -> Result<Vec<Blob>> {
+    let mut stmt = conn.prepare(
+        r#"
+select
+    id, filename, time_created,
+    store_size, content_size, store_hash, content_hash, parent_hash
+from blobs
+where substr(content_hash, 1, length(?1)) = ?1 or substr(store_hash, 1, length(?1)) = ?1
+order by id
+"#,
+    )?;
+
+    let mut rows = Vec::new();
+    for row_res in stmt.query_map(params![prefix], decode_row)? {
+        rows.push(row_res?);
+    }
+    Ok(rows)
+}
+
+pub fn by_id(conn: &mut Conn, id: u32) -> Result<Option<Blob>> {
+    use rusqlite::OptionalExtension;
+    conn.query_row(
+        r#"
+select
+    id, filename, time_created,
+    store_size, content_size, store_hash, content_hash, parent_hash
+from blobs
+where id = ?1
+"#,
+        params![id],
+        decode_row,
+    )
+    .optional()
+}
+
+/// delta blobs encoded against `content_hash`
+pub fn children(conn: &mut Conn, content_hash: &str) -> Result<Vec<Blob>> {
+    let mut stmt = conn.prepare(
+        r#"
+select
+    id, filename, time_created,
+    store_size, content_size, store_hash, content_hash, parent_hash
+from blobs
+where parent_hash = ?
+order by id
+"#,
+    )?;
+
+    let mut rows = Vec::new();
+    for row_res in stmt.query_map(params![content_hash], decode_row)? {
+        rows.push(row_res?);
+    }
+    Ok(rows)
+}
+
+pub fn delta_count(conn: &mut Conn) -> Result<usize> {
+    let count: i64 = conn.query_row(
+        "select count(*) from blobs where parent_hash is not null",
+        params![],
+        |row| row.get(0),
+    )?;
+    Ok(count as usize)
+}
+
+/// number of delta blobs below the content `content_hash`, at any depth
+pub fn descendant_count(conn: &mut Conn, content_hash: &str) -> Result<usize> {
+    let count: i64 = conn.query_row(
+        r#"
+with recursive descendants(content_hash) as (
+    select content_hash from blobs where parent_hash = ?1
+    union
+    select b.content_hash from blobs b join descendants d on b.parent_hash = d.content_hash
+)
+select count(*) from blobs
+where content_hash in (select content_hash from descendants) and parent_hash is not null
+"#,
+        params![content_hash],
+        |row| row.get(0),
+    )?;
+    Ok(count as usize)
+}
+
+/// ids of all blobs, ascending
+pub fn ids(conn: &mut Conn) -> Result<Vec<u32>> {
+    let mut stmt = conn.prepare("select id from blobs order by id")?;
+    let mut ids = Vec::new();
+    for row_res in stmt.query_map(params![], |row| row.get(0))? {
+        ids.push(row_res?);
+    }
+    Ok(ids)
+}
+
+/// parent hashes of deltas not matching the content hash of any blob. the foreign key of
+/// parent_hash is not enforced by sqlite.
+pub fn broken_parent_hashes(conn: &mut Conn) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        r#"
+select distinct parent_hash
+from blobs as child
+where parent_hash is not null
+    and not exists (select 1 from blobs as parent where parent.content_hash = child.parent_hash)
+order by parent_hash
+"#,
+    )?;
+
+    let mut hashes = Vec::new();
+    for row_res in stmt.query_map(params![], |row| row.get(0))? {
+        hashes.push(row_res?);
+    }
+    Ok(hashes)
+}
+
+/// deltas whose parent content does not exist
+pub fn dangling_blobs(conn: &mut Conn) -> Result<Vec<Blob>> {
+    let mut stmt = conn.prepare(
+        r#"
+select
+    id, filename, time_created,
+    store_size, content_size, store_hash, content_hash, parent_hash
+from blobs as child
+where parent_hash is not null
+    and not exists (select 1 from blobs as parent where parent.content_hash = child.parent_hash)
+order by id
+"#,
+    )?;
+
+    let mut rows = Vec::new();
+    for row_res in stmt.query_map(params![], decode_row)? {
+        rows.push(row_res?);
+    }
+    Ok(rows)
+}
+
+/// moves a blob into `blobs_quarantine`. its object is kept.
+pub fn quarantine(conn: &mut Conn, blob: &Blob, reason: &str) -> Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute(
+        r#"
+insert or replace into blobs_quarantine
+    select
+        id, filename, time_created,
+        store_size, content_size, store_hash, content_hash, parent_hash,
+        ?2, ?3
+    from blobs where id = ?1
+"#,
+        params![blob.id, reason, time::OffsetDateTime::now_utc()],
+    )?;
+    tx.execute("delete from blobs where id = ?1", params![blob.id])?;
+    tx.commit()
+}
+
+pub fn quarantined(conn: &mut Conn) -> Result<Vec<Blob>> {
+    let mut stmt = conn.prepare(
+        r#"
+select
+    id, filename, time_created,
+    store_size, content_size, store_hash, content_hash, parent_hash
+from blobs_quarantine
+order by id
+"#,
+    )?;
+
+    let mut rows = Vec::new();
+    for row_res in stmt.query_map(params![], decode_row)? {
+        rows.push(row_res?);
+    }
+    Ok(rows)
+}
+
+/// decode chain of a blob, from a root blob to the blob itself. `name` is either a filename or a
+/// content hash. like `get`, the latest blob is used when multiple blobs share a name.
+pub fn chain(conn: &mut Conn, name: &str) -> Result<Vec<Blob>> {
+    let mut stmt = conn.prepare(&format!(
+        r#"
+with recursive chain(
+    id, filename, time_created,
+    store_size, content_size, store_hash, content_hash, parent_hash, hop
+) as (
+    select
+        id, filename, time_created,
+        store_size, content_size, store_hash, content_hash, parent_hash, 0
+    from blobs
+    where id = (
+        select max(id) from blobs
+        where filename = ?1
+            or content_hash = ?1
+            or {}
+    )
+
+    union all
+
+    select
+        b.id, b.filename, b.time_created,
+        b.store_size, b.content_size, b.store_hash, b.content_hash, b.parent_hash, chain.hop + 1
+    from blobs b join chain
+    on b.id = (select max(id) from blobs where content_hash = chain.parent_hash)
+)
+select
+    id, filename, time_created,
+    store_size, content_size, store_hash, content_hash, parent_hash
+from chain
+order by hop desc
+"#,
+        alias_condition(conn)?
+    ))?;
+
+    let mut rows = Vec::new();
+    for row_res in stmt.query_map(params![name], decode_row)? {
+        rows.push(row_res?);
+    }
+    Ok(rows)
+}
+
+fn decode_row(row: &rusqlite::Row) -> Result<Blob> {
+    let store_size: i64 = row.get(3)?;
+    let content_size: i64 = row.get(4)?;
+    Ok(Blob {
+        id: row.get(0)?,
+        filename: row.get(1)?,
+        time_created: row.get(2)?,
+        store_size: store_size as u64,
+        content_size: content_size as u64,
+        store_hash: row.get(5)?,
+        content_hash: row.get(6)?,
+
+        parent_hash: row.get(7)?,
+    })
+}
+
+pub fn latest(conn: &mut Conn) -> Result<Blob> {
+    conn.query_row(
+        r#"
+select
+    id, filename, time_created,
+    store_size, content_size, store_hash, content_hash, parent_hash
+from blobs
+order by id desc
+limit 1"#,
+        params![],
+        decode_row,
+    )
+}
+
+pub fn insert(conn: &mut Conn, blob: &Blob) -> Result<bool> {
+    let inserted = conn.execute(
+        r#"
+insert or ignore into blobs (
+    filename,
+    time_created,
+    store_size,
+    content_size,
+    store_hash,
+    content_hash,
+    parent_hash
+)
+    values (?1, ?2, ?3, ?4, ?5, ?6, ?7)"#,
+        params![
+            blob.filename,
+            blob.time_created,
+            blob.store_size as i64,
+            blob.content_size as i64,
+            blob.store_hash,
+            blob.content_hash,
+            blob.parent_hash
+        ],
+    )?;
+
+    Ok(inserted > 0)
+}
+
+/// `insert` of a blob whose parent content exists, checked in the same statement. returns None
+/// if the parent does not exist.
+pub fn insert_with_parent(conn: &mut Conn, blob: &Blob) -> Result<Option<bool>> {
+    let inserted = conn.execute(
+        r#"
+insert or ignore into blobs (
+    filename,
+    time_created,
+    store_size,
+    content_size,
+    store_hash,
+    content_hash,
+    parent_hash
+)
+    select ?1, ?2, ?3, ?4, ?5, ?6, ?7
+    where ?7 is null or exists (select 1 from blobs where content_hash = ?7)"#,
+        params![
+            blob.filename,
+            blob.time_created,
+            blob.store_size as i64,
+            blob.content_size as i64,
+            blob.store_hash,
+            blob.content_hash,
+            blob.parent_hash
+        ],
+    )?;
+    if inserted > 0 {
+        return Ok(Some(true));
+    }
+
+    match &blob.parent_hash {
+        Some(parent_hash) if by_content_hash(conn, parent_hash)?.is_empty() => Ok(None),
+        _ => Ok(Some(false)),
+    }
+}
+
+/// records the provenance of blobs of a content whose provenance is unknown, so the first source
+/// of a content is kept
+pub fn set_provenance(conn: &mut Conn, content_hash: &str, provenance: &Provenance) -> Result<()> {
+    conn.execute(
+        r#"
+update blobs set source_path = ?2, source_host = ?3, source_url = ?4
+where content_hash = ?1
+    and source_path is null and source_host is null and source_url is null
+"#,
+        params![
+            content_hash,
+            provenance.source_path,
+            provenance.source_host,
+            provenance.source_url
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn provenance(conn: &mut Conn, store_hash: &str) -> Result<Provenance> {
+    use rusqlite::OptionalExtension;
+    let provenance = conn
+        .query_row(
+            "select source_path, source_host, source_url from blobs where store_hash = ?1",
+            params![store_hash],
+            |row| {
+                Ok(Provenance {
+                    source_path: row.get(0)?,
+                    source_host: row.get(1)?,
+                    source_url: row.get(2)?,
+                })
+            },
+        )
+        .optional()?;
+    Ok(provenance.unwrap_or_default())
+}
+
+pub fn rename(conn: &mut Conn, from_filename: &str, to_filename: &str) -> Result<bool> {
+    let updated = conn.execute(
+        r#"
+    update blobs set filename = ?2 where filename = ?1
+    "#,
+        params![from_filename, to_filename],
+    )?;
+    let aliases = conn.execute(
+        "update aliases set filename = ?2 where filename = ?1",
+        params![from_filename, to_filename],
+    )?;
+    Ok(updated + aliases > 0)
+}
+
+/// corrects `time_created` of the blob stored as `store_hash`, e.g. of blobs imported with the
+/// time of the import. fails with `QueryReturnedNoRows` if there is no such blob.
+#[cfg(feature = "admin-ops")]
+pub fn update_time_created(
+    conn: &mut Conn,
+    store_hash: &str,
+    new_time: time::OffsetDateTime,
+) -> Result<()> {
+    let updated = conn.execute(
+        "update blobs set time_created = ?2 where store_hash = ?1",
+        params![store_hash, new_time],
+    )?;
+    if updated == 0 {
+        return Err(rusqlite::Error::QueryReturnedNoRows);
+    }
+    Ok(())
+}
+
+/// content converted from an input file with hash `original_hash`, as `kind`
+pub fn original_content_hash(
+    conn: &mut Conn,
+    original_hash: &str,
+    kind: &str,
+) -> Result<Option<String>> {
+    use rusqlite::OptionalExtension;
+    conn.query_row(
+        "select content_hash from original_hashes where original_hash = ?1 and kind = ?2",
+        params![original_hash, kind],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+/// whether an input file of `original_size` bytes was converted as `kind` before
+pub fn has_original_size(conn: &mut Conn, kind: &str, original_size: u64) -> Result<bool> {
+    conn.query_row(
+        "select exists(select 1 from original_hashes where kind = ?1 and original_size = ?2)",
+        params![kind, original_size as i64],
+        |row| row.get(0),
+    )
+}
+
+pub fn set_original_hash(
+    conn: &mut Conn,
+    original_hash: &str,
+    original_size: u64,
+    kind: &str,
+    content_hash: &str,
+) -> Result<()> {
+    conn.execute(
+        r#"
+insert or replace into original_hashes (original_hash, kind, content_hash, original_size)
+values (?1, ?2, ?3, ?4)
+"#,
+        params![original_hash, kind, content_hash, original_size as i64],
+    )?;
+    Ok(())
+}
+
+/// adds `filename` as another name of a stored content, resolved by `by_filename`
+pub fn alias_add(conn: &mut Conn, filename: &str, content_hash: &str) -> Result<bool> {
+    let inserted = conn.execute(
+        "insert or ignore into aliases (filename, content_hash, time_created) values (?1, ?2, ?3)",
+        params![filename, content_hash, time::OffsetDateTime::now_utc()],
+    )?;
+    Ok(inserted > 0)
+}
+
+pub fn remove(conn: &mut Conn, blob: &Blob) -> Result<()> {
+    conn.execute(
+        r#"
+delete from blobs where store_hash = ?1
+"#,
+        params![blob.store_hash],
+    )?;
+    Ok(())
+}
+
+/// replaces the delta object of a blob, e.g. with a delta against another parent
+pub fn reparent(conn: &mut Conn, old_store_hash: &str, blob: &Blob) -> Result<bool> {
+    let updated = conn.execute(
+        r#"
+update blobs set store_hash = ?2, store_size = ?3, parent_hash = ?4 where store_hash = ?1
+"#,
+        params![
+            old_store_hash,
+            blob.store_hash,
+            blob.store_size as i64,
+            blob.parent_hash
+        ],
+    )?;
+    Ok(updated > 0)
+}
+
+pub fn roots(conn: &mut Conn) -> Result<Vec<Blob>> {
+    let mut stmt = conn.prepare(
+        r#"
+select
+    id, filename, time_created,
+    store_size, content_size, store_hash, content_hash, parent_hash
+from blobs
+where parent_hash is null
+"#,
+    )?;
+
+    let mut rows = Vec::new();
+    for row_res in stmt.query_map(params![], decode_row)? {
+        rows.push(row_res?);
+    }
+    Ok(rows)
+}
+
+/// (sum of store_size, sum of content_size) of all blobs
+pub fn total_sizes(conn: &mut Conn) -> Result<(u64, u64)> {
+    let (store_size, content_size): (i64, i64) = conn.query_row(
+        "select coalesce(sum(store_size), 0), coalesce(sum(content_size), 0) from blobs",
+        params![],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    Ok((store_size as u64, content_size as u64))
+}
+
+pub fn savepoint(conn: &mut Conn, name: &str) -> Result<()> {
+    conn.execute_batch(&format!("savepoint {}", name))
+}
+
+/// keeps changes since the savepoint `name` and releases it
+pub fn release(conn: &mut Conn, name: &str) -> Result<()> {
+    conn.execute_batch(&format!("release {}", name))
+}
+
+/// discards all changes since the savepoint `name` and releases it
+pub fn rollback_to(conn: &mut Conn, name: &str) -> Result<()> {
+    conn.execute_batch(&format!("rollback to {}; release {}", name, name))
+}
+
+/// inserts a chunked file with its chunk list of (chunk_hash, chunk_size)
+pub fn insert_chunked(
+    conn: &mut Conn,
+    file: &ChunkedFile,
+    chunks: &[(String, u64)],
+) -> Result<bool> {
+    let tx = conn.savepoint()?;
+    let inserted = tx.execute(
+        r#"
+insert or ignore into chunked_files (filename, time_created, content_size, content_hash)
+    values (?1, ?2, ?3, ?4)"#,
+        params![
+            file.filename,
+            file.time_created,
+            file.content_size as i64,
+            file.content_hash
+        ],
+    )?;
+    if inserted == 0 {
+        return Ok(false);
+    }
+
+    for (idx, (chunk_hash, chunk_size)) in chunks.iter().enumerate() {
+        tx.execute(
+            r#"
+insert into chunks (content_hash, idx, chunk_hash, chunk_size)
+    values (?1, ?2, ?3, ?4)"#,
+            params![
+                file.content_hash,
+                idx as i64,
+                chunk_hash,
+                *chunk_size as i64
+            ],
+        )?;
+    }
+    tx.commit()?;
+    Ok(true)
+}
+
+pub fn chunked_by_filename(conn: &mut Conn, filename: &str) -> Result<Option<ChunkedFile>> {
+    use rusqlite::OptionalExtension;
+    // a store opened read-only before migration has no chunked files
+    if !has_table(conn, "chunked_files")? {
+        return Ok(None);
+    }
+    conn.query_row(
+        r#"
+select id, filename, time_created, content_size, content_hash
+from chunked_files
+where filename = ?
+order by id desc
+limit 1
+"#,
+        params![filename],
+        |row| {
+            let content_size: i64 = row.get(3)?;
+            Ok(ChunkedFile {
+                id: row.get(0)?,
+                filename: row.get(1)?,
+                time_created: row.get(2)?,
+                content_size: content_size as u64,
+                content_hash: row.get(4)?,
+            })
+        },
+    )
+    .optional()
+}
+
+pub fn chunked_count(conn: &mut Conn) -> Result<usize> {
+    let count: i64 = conn.query_row("select count(*) from chunked_files", params![], |row| {
+        row.get(0)
+    })?;
+    Ok(count as usize)
+}
+
+/// chunk list of a chunked file, in order
+pub fn chunks(conn: &mut Conn, content_hash: &str) -> Result<Vec<(String, u64)>> {
+    let mut stmt = conn.prepare(
+        r#"
+select chunk_hash, chunk_size
+from chunks
+where content_hash = ?
+order by idx
+"#,
+    )?;
+
+    let mut rows = Vec::new();
+    for row_res in stmt.query_map(params![content_hash], |row| {
+        let chunk_size: i64 = row.get(1)?;
+        Ok((row.get(0)?, chunk_size as u64))
+    })? {
+        rows.push(row_res?);
+    }
+    Ok(rows)
+}
+
+/// marks blobs needed to regenerate the dehydrated root `root_hash`
+pub fn protect(conn: &mut Conn, root_hash: &str, store_hashes: &[String]) -> Result<()> {
+    let tx = conn.savepoint()?;
+    for store_hash in store_hashes {
+        tx.execute(
+            "insert or ignore into protected_blobs (store_hash, root_hash) values (?1, ?2)",
+            params![store_hash, root_hash],
+        )?;
+    }
+    tx.commit()
+}
+
+/// keeps the root blob `store_hash` from cleanup, as a row protecting the root itself. it is not
+/// a dehydrated root, and `unprotect` leaves the row.
+pub fn pin_root(conn: &mut Conn, store_hash: &str) -> Result<()> {
+    protect(conn, store_hash, &[store_hash.to_owned()])
+}
+
+pub fn unprotect(conn: &mut Conn, root_hash: &str) -> Result<()> {
+    conn.execute(
+        "delete from protected_blobs where root_hash = ?1 and store_hash != ?1",
+        params![root_hash],
+    )?;
+    Ok(())
+}
+
+pub fn protected_store_hashes(conn: &mut Conn) -> Result<std::collections::HashSet<String>> {
+    let mut stmt = conn.prepare("select distinct store_hash from protected_blobs")?;
+    let rows = stmt.query_map(params![], |row| row.get(0))?;
+    rows.collect()
+}
+
+/// content hashes of dehydrated roots
+pub fn dehydrated_roots(conn: &mut Conn) -> Result<Vec<String>> {
+    // a pinned root protects only itself
+    let mut stmt = conn.prepare(
+        r#"
+select distinct root_hash from protected_blobs
+where store_hash != root_hash
+order by root_hash
+"#,
+    )?;
+    let rows = stmt.query_map(params![], |row| row.get(0))?;
+    rows.collect()
+}
+
+/// marks a content as stored in the exploded form of a bundle, see `StoreConfig::explode_nested`
+pub fn set_exploded(conn: &mut Conn, content_hash: &str, exploded: bool) -> Result<()> {
+    if exploded {
+        conn.execute(
+            "insert or ignore into exploded_contents (content_hash) values (?1)",
+            params![content_hash],
+        )?;
+    } else {
+        conn.execute(
+            "delete from exploded_contents where content_hash = ?1",
+            params![content_hash],
+        )?;
+    }
+    Ok(())
+}
+
+pub fn is_exploded(conn: &mut Conn, content_hash: &str) -> Result<bool> {
+    if !has_table(conn, "exploded_contents")? {
+        return Ok(false);
+    }
+    let count: i64 = conn.query_row(
+        "select count(*) from exploded_contents where content_hash = ?1",
+        params![content_hash],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+/// a version pushed as the `seq`-th member of a named series, see `push --series`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SeriesMember {
+    pub name: String,
+    pub seq: u32,
+    pub filename: String,
+    pub content_hash: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub time_created: time::OffsetDateTime,
+}
+
+fn decode_series_row(row: &rusqlite::Row) -> Result<SeriesMember> {
+    Ok(SeriesMember {
+        name: row.get(0)?,
+        seq: row.get(1)?,
+        filename: row.get(2)?,
+        content_hash: row.get(3)?,
+        time_created: row.get(4)?,
+    })
+}
+
+/// appends a member to a series, unless it is the latest member already. returns its seq.
+pub fn series_append(
+    conn: &mut Conn,
+    name: &str,
+    filename: &str,
+    content_hash: &str,
+) -> Result<u32> {
+    let tx = conn.savepoint()?;
+    let latest = series_latest(&tx, name)?;
+    if let Some(latest) = &latest {
+        if latest.content_hash == content_hash {
+            return Ok(latest.seq);
+        }
+    }
+    let seq = latest.map_or(1, |member| member.seq + 1);
+    tx.execute(
+        r#"
+insert into series (name, seq, filename, content_hash, time_created)
+values (?1, ?2, ?3, ?4, ?5)
+"#,
+        params![
+            name,
+            seq,
+            filename,
+            content_hash,
+            time::OffsetDateTime::now_utc()
+        ],
+    )?;
+    tx.commit()?;
+    Ok(seq)
+}
+
+pub fn series_latest(conn: &Conn, name: &str) -> Result<Option<SeriesMember>> {
+    use rusqlite::OptionalExtension;
+    conn.query_row(
+        r#"
+select name, seq, filename, content_hash, time_created
+from series
+where name = ?1
+order by seq desc
+limit 1
+"#,
+        params![name],
+        decode_series_row,
+    )
+    .optional()
+}
+
+/// series memberships of a content
+pub fn series_by_content_hash(conn: &mut Conn, content_hash: &str) -> Result<Vec<SeriesMember>> {
+    let mut stmt = conn.prepare(
+        r#"
+select name, seq, filename, content_hash, time_created
+from series
+where content_hash = ?1
+order by name, seq
+"#,
+    )?;
+    let rows = stmt.query_map(params![content_hash], decode_series_row)?;
+    rows.collect()
+}
+
+pub fn series_members(conn: &mut Conn, name: &str) -> Result<Vec<SeriesMember>> {
+    let mut stmt = conn.prepare(
+        r#"
+select name, seq, filename, content_hash, time_created
+from series
+where name = ?1
+order by seq
+"#,
+    )?;
+    let rows = stmt.query_map(params![name], decode_series_row)?;
+    rows.collect()
+}
+
+/// a free-text note on a content, see `note add`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Note {
+    pub id: u32,
+    pub content_hash: String,
+    pub note: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub time_created: time::OffsetDateTime,
+}
+
+pub fn note_add(
+    conn: &mut Conn,
+    content_hash: &str,
+    note: &str,
+    time_created: time::OffsetDateTime,
+) -> Result<()> {
+    conn.execute(
+        "insert into notes (content_hash, note, time_created) values (?1, ?2, ?3)",
+        params![content_hash, note, time_created],
+    )?;
+    Ok(())
+}
+
+pub fn notes(conn: &mut Conn, content_hash: &str) -> Result<Vec<Note>> {
+    let mut stmt = conn.prepare(
+        "select id, content_hash, note, time_created from notes where content_hash = ?1 order by id",
+    )?;
+    let rows = stmt.query_map(params![content_hash], |row| {
+        Ok(Note {
+            id: row.get(0)?,
+            content_hash: row.get(1)?,
+            note: row.get(2)?,
+            time_created: row.get(3)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// a mutating operation on the store, see `oplog_append`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OplogEntry {
+    pub id: u32,
+    #[serde(with = "time::serde::rfc3339")]
+    pub ts: time::OffsetDateTime,
+    pub op: String,
+    pub details_json: String,
+}
+
+/// appends an entry to the oplog. entries are never updated or removed, not even by a snapshot
+/// rollback.
+pub fn oplog_append(conn: &mut Conn, op: &str, details_json: &str) -> Result<()> {
+    conn.execute(
+        "insert into oplog (ts, op, details_json) values (?1, ?2, ?3)",
+        params![time::OffsetDateTime::now_utc(), op, details_json],
+    )?;
+    Ok(())
+}
+
+pub fn oplog(conn: &mut Conn) -> Result<Vec<OplogEntry>> {
+    let mut stmt = conn.prepare("select id, ts, op, details_json from oplog order by id")?;
+    let rows = stmt.query_map(params![], |row| {
+        Ok(OplogEntry {
+            id: row.get(0)?,
+            ts: row.get(1)?,
+            op: row.get(2)?,
+            details_json: row.get(3)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// cached `Sketch` of a root content, see `sketch::root_sketch`
+pub fn sketch(conn: &mut Conn, content_hash: &str) -> Result<Option<Vec<u8>>> {
+    use rusqlite::OptionalExtension;
+    conn.query_row(
+        "select sketch from root_sketches where content_hash = ?",
+        params![content_hash],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+pub fn set_sketch(conn: &mut Conn, content_hash: &str, sketch: &[u8]) -> Result<()> {
+    conn.execute(
+        "insert or replace into root_sketches (content_hash, sketch) values (?1, ?2)",
+        params![content_hash, sketch],
+    )?;
+    Ok(())
+}
+
+pub fn remove_sketch(conn: &mut Conn, content_hash: &str) -> Result<()> {
+    conn.execute(
+        "delete from root_sketches where content_hash = ?",
+        params![content_hash],
+    )?;
+    Ok(())
+}
+
+/// outcome of the last run of a check, e.g. validate
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckResult {
+    pub kind: String,
+    pub ok: bool,
+    #[serde(with = "time::serde::rfc3339")]
+    pub time_checked: time::OffsetDateTime,
+    pub error: Option<String>,
+}
+
+/// replaces the last result of `kind` in a single statement, so a crash leaves either the
+/// previous result or the new one
+pub fn record_check(conn: &mut Conn, kind: &str, error: Option<&str>) -> Result<()> {
+    conn.execute(
+        "insert or replace into checks (kind, ok, time_checked, error) values (?1, ?2, ?3, ?4)",
+        params![
+            kind,
+            error.is_none(),
+            time::OffsetDateTime::now_utc(),
+            error
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn checks(conn: &mut Conn) -> Result<Vec<CheckResult>> {
+    let mut stmt =
+        conn.prepare("select kind, ok, time_checked, error from checks order by time_checked")?;
+    let rows = stmt.query_map(params![], |row| {
+        Ok(CheckResult {
+            kind: row.get(0)?,
+            ok: row.get(1)?,
+            time_checked: row.get(2)?,
+            error: row.get(3)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// a change of a blob, e.g. a blob becoming a root or no longer being one, see `event_append`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub id: u32,
+    #[serde(with = "time::serde::rfc3339")]
+    pub timestamp: time::OffsetDateTime,
+    pub event_type: String,
+    pub store_hash: String,
+    pub reason: Option<String>,
+}
+
+/// appends an event. like the oplog, events are kept across snapshot rollbacks.
+pub fn event_append(
+    conn: &mut Conn,
+    event_type: &str,
+    store_hash: &str,
+    reason: Option<&str>,
+) -> Result<()> {
+    conn.execute(
+        "insert into events (timestamp, event_type, store_hash, reason) values (?1, ?2, ?3, ?4)",
+        params![
+            time::OffsetDateTime::now_utc(),
+            event_type,
+            store_hash,
+            reason
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn events(conn: &mut Conn) -> Result<Vec<Event>> {
+    // a store opened read-only before migration has no events
+    if !has_table(conn, "events")? {
+        return Ok(Vec::new());
+    }
+    let mut stmt = conn
+        .prepare("select id, timestamp, event_type, store_hash, reason from events order by id")?;
+    let rows = stmt.query_map(params![], |row| {
+        Ok(Event {
+            id: row.get(0)?,
+            timestamp: row.get(1)?,
+            event_type: row.get(2)?,
+            store_hash: row.get(3)?,
+            reason: row.get(4)?,
+        })
+    })?;
+    rows.collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub name: String,
+    pub time_created: time::OffsetDateTime,
+}
+
+/// tables copied into `snapshot_{name}_{table}` by `snapshot_create`
+const SNAPSHOT_TABLES: &[&str] = &[
+    "blobs",
+    "settings",
+    "chunked_files",
+    "chunks",
+    "protected_blobs",
+    "exploded_contents",
+    "series",
+    "original_hashes",
+    "aliases",
+    "blobs_quarantine",
+    "notes",
+];
+
+/// copies tables and the given object hashes into snapshot tables. `name` is used in table
+/// names, so callers should only pass alphanumeric names.
+pub fn snapshot_create(conn: &mut Conn, name: &str, object_hashes: &[String]) -> Result<bool> {
+    let tx = conn.transaction()?;
+    let inserted = tx.execute(
+        "insert or ignore into snapshots (name, time_created) values (?1, ?2)",
+        params![name, time::OffsetDateTime::now_utc()],
+    )?;
+    if inserted == 0 {
+        return Ok(false);
+    }
+
+    for table in SNAPSHOT_TABLES {
+        tx.execute_batch(&format!(
+            "create table snapshot_{}_{} as select * from {}",
+            name, table, table
+        ))?;
+    }
+    tx.execute_batch(&format!(
+        "create table snapshot_{}_objects (hash text primary key)",
+        name
+    ))?;
+    for hash in object_hashes {
+        tx.execute(
+            &format!("insert into snapshot_{}_objects (hash) values (?)", name),
+            params![hash],
+        )?;
+    }
+    tx.commit()?;
+    Ok(true)
+}
+
+pub fn snapshots(conn: &mut Conn) -> Result<Vec<Snapshot>> {
+    let mut stmt = conn.prepare("select name, time_created from snapshots order by name")?;
+    let rows = stmt.query_map(params![], |row| {
+        Ok(Snapshot {
+            name: row.get(0)?,
+            time_created: row.get(1)?,
+        })
+    })?;
+    rows.collect()
+}
+
+pub fn snapshot_delete(conn: &mut Conn, name: &str) -> Result<bool> {
+    let tx = conn.transaction()?;
+    let deleted = tx.execute("delete from snapshots where name = ?", params![name])?;
+    for table in SNAPSHOT_TABLES.iter().chain(&["objects"]) {
+        tx.execute_batch(&format!("drop table if exists snapshot_{}_{}", name, table))?;
+    }
+    tx.commit()?;
+    Ok(deleted > 0)
+}
+
+pub fn snapshot_blobs(conn: &mut Conn, name: &str) -> Result<Vec<Blob>> {
+    let mut stmt = conn.prepare(&format!(
+        r#"
+select
+    id, filename, time_created,
+    store_size, content_size, store_hash, content_hash, parent_hash
+from snapshot_{}_blobs
+order by id
+"#,
+        name
+    ))?;
+
+    let mut rows = Vec::new();
+    for row_res in stmt.query_map(params![], decode_row)? {
+        rows.push(row_res?);
+    }
+    Ok(rows)
+}
+
+pub fn snapshot_objects(conn: &mut Conn, name: &str) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(&format!(
+        "select hash from snapshot_{}_objects order by hash",
+        name
+    ))?;
+    let rows = stmt.query_map(params![], |row| row.get(0))?;
+    rows.collect()
+}
+
+/// replaces contents of tables with the snapshot
+pub fn snapshot_restore(conn: &mut Conn, name: &str) -> Result<()> {
+    let tx = conn.transaction()?;
+    for table in SNAPSHOT_TABLES {
+        let snapshot_table = format!("snapshot_{}_{}", name, table);
+        let exists: i64 = tx.query_row(
+            "select count(*) from sqlite_master where type = 'table' and name = ?1",
+            params![snapshot_table],
+            |row| row.get(0),
+        )?;
+        tx.execute_batch(&format!("delete from {}", table))?;
+        // snapshots taken before a table was added have no copy of it, it was empty then
+        if exists > 0 {
+            // nor of columns added later, e.g. `blobs.depth`
+            let columns = tx
+                .prepare(&format!("pragma table_info({})", snapshot_table))?
+                .query_map(params![], |row| row.get::<_, String>(1))?
+                .collect::<Result<Vec<_>>>()?
+                .join(", ");
+            tx.execute_batch(&format!(
+                "insert into {} ({}) select {} from {}",
+                table, columns, columns, snapshot_table
+            ))?;
+        }
+    }
+    tx.commit()
 }
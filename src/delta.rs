@@ -6,18 +6,20 @@ use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
 pub use xdelta3::stream::ProcessMode;
 
 /// uses std::io::Result to trigger TimedOut
+///
+/// only the output is hashed, while it is written. callers already know the digest of the input
+/// (the unpacked content on push, the stored delta on decode), so input is not hashed again.
 pub async fn delta<R1, R2, W>(
     op: xdelta3::stream::ProcessMode,
     src_reader: R1,
-    input_reader: R2,
+    mut input_reader: R2,
     dst: W,
-) -> std::io::Result<(WriteMetadata, WriteMetadata)>
+) -> std::io::Result<WriteMetadata>
 where
     R1: AsyncRead + Unpin,
     R2: AsyncRead + Unpin,
     W: tokio::io::AsyncWrite + Unpin,
 {
-    let mut input_reader = HashRW::new(input_reader);
     let mut dst = HashRW::new(dst);
 
     let cfg = xdelta3::stream::Xd3Config::new()
@@ -34,8 +36,5 @@ where
     )
     .await?;
 
-    let input_meta = input_reader.meta();
-    let dst_meta = dst.meta();
-
-    Ok((input_meta, dst_meta))
+    Ok(dst.meta())
 }
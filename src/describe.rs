@@ -0,0 +1,290 @@
+use std::fmt::Write;
+
+use super::*;
+
+/// minimum length of a hash prefix accepted by `resolve_blob`
+const MIN_HASH_PREFIX: usize = 6;
+
+/// the blob named by a filename, a content or store hash, or a unique prefix of a hash. like
+/// `get`, the latest blob is used when blobs share a filename or a content.
+pub fn resolve_blob(conn: &mut db::Conn, name: &str) -> Result<Blob> {
+    if let Some(blob) = db::by_filename(conn, name)?.pop() {
+        return Ok(blob);
+    }
+
+    let is_hash = name.len() >= MIN_HASH_PREFIX && name.chars().all(|c| c.is_ascii_hexdigit());
+    if !is_hash {
+        return Err(Error::UnknownFilename(name.to_owned()));
+    }
+
+    let mut blobs = db::by_hash_prefix(conn, name)?;
+    if let Some(blob) = blobs.iter().find(|blob| blob.store_hash == name) {
+        return Ok(blob.clone());
+    }
+    let mut contents = blobs
+        .iter()
+        .map(|blob| blob.content_hash.as_str())
+        .collect::<Vec<_>>();
+    contents.sort_unstable();
+    contents.dedup();
+    if contents.len() > 1 {
+        return Err(crate::format_err!(
+            "ambiguous hash prefix {}, matches {} contents",
+            name,
+            contents.len()
+        ));
+    }
+
+    match blobs.pop() {
+        Some(blob) => Ok(blob),
+        None => Err(Error::UnknownFilename(name.to_owned())),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlobRef {
+    pub id: u32,
+    pub filename: String,
+}
+
+impl From<&Blob> for BlobRef {
+    fn from(blob: &Blob) -> Self {
+        BlobRef {
+            id: blob.id,
+            filename: blob.filename.clone(),
+        }
+    }
+}
+
+/// everything known about a blob, see `describe`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Description {
+    pub blob: Blob,
+    pub object_path: String,
+    /// size of the object file, None if it does not exist
+    pub object_size: Option<u64>,
+    pub parent: Option<BlobRef>,
+    pub children: Vec<BlobRef>,
+    /// other blobs of the same content, e.g. the root blob of an alias
+    pub same_content: Vec<BlobRef>,
+    /// deltas applied by `get` of the content
+    pub chain_depth: usize,
+    /// bytes read by `get` of the content
+    pub decode_read_bytes: u64,
+    pub exploded: bool,
+    pub dehydrated: bool,
+    #[serde(default)]
+    pub provenance: db::Provenance,
+    pub series: Vec<db::SeriesMember>,
+    pub notes: Vec<db::Note>,
+}
+
+pub fn describe(conn: &mut db::Conn, name: &str) -> Result<Description> {
+    let blob = resolve_blob(conn, name)?;
+
+    let object_path = filepath(&blob.store_hash);
+    let object_size = match std::fs::metadata(&object_path) {
+        Ok(meta) => Some(meta.len()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    let parent = match &blob.parent_hash {
+        Some(parent_hash) => db::by_content_hash(conn, parent_hash)?
+            .last()
+            .map(BlobRef::from),
+        None => None,
+    };
+    let children = db::children(conn, &blob.content_hash)?
+        .iter()
+        .map(BlobRef::from)
+        .collect();
+    let same_content = db::by_content_hash(conn, &blob.content_hash)?
+        .iter()
+        .filter(|other| other.id != blob.id)
+        .map(BlobRef::from)
+        .collect();
+
+    let chain = db::chain(conn, &blob.content_hash)?;
+    let dehydrated = blob.is_root() && db::dehydrated_roots(conn)?.contains(&blob.content_hash);
+
+    Ok(Description {
+        object_path,
+        object_size,
+        parent,
+        children,
+        same_content,
+        chain_depth: chain.len().saturating_sub(1),
+        decode_read_bytes: chain.iter().map(|blob| blob.store_size).sum(),
+        exploded: db::is_exploded(conn, &blob.content_hash)?,
+        dehydrated,
+        provenance: db::provenance(conn, &blob.store_hash)?,
+        series: db::series_by_content_hash(conn, &blob.content_hash)?,
+        notes: db::notes(conn, &blob.content_hash)?,
+        blob,
+    })
+}
+
+fn format_refs(refs: &[BlobRef]) -> String {
+    if refs.is_empty() {
+        return "-".to_owned();
+    }
+    refs.iter()
+        .map(|r| format!("V{} {}", r.id, r.filename))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub fn format_description(desc: &Description) -> String {
+    let blob = &desc.blob;
+    let mut s = String::new();
+
+    writeln!(s, "V{} {}", blob.id, blob.filename).ok();
+    writeln!(s, "  time_created   {}", blob.time_created).ok();
+    writeln!(s, "  content_hash   {}", blob.content_hash).ok();
+    writeln!(s, "  store_hash     {}", blob.store_hash).ok();
+    writeln!(
+        s,
+        "  parent_hash    {}",
+        blob.parent_hash.as_deref().unwrap_or("-")
+    )
+    .ok();
+    writeln!(
+        s,
+        "  size           store={} content={}",
+        blob.store_size, blob.content_size
+    )
+    .ok();
+    match desc.object_size {
+        Some(size) => writeln!(s, "  object         {} size={}", desc.object_path, size),
+        None if desc.dehydrated => writeln!(s, "  object         {} dehydrated", desc.object_path),
+        None => writeln!(s, "  object         {} missing", desc.object_path),
+    }
+    .ok();
+
+    let parent = desc.parent.iter().cloned().collect::<Vec<_>>();
+    writeln!(s, "  parent         {}", format_refs(&parent)).ok();
+    writeln!(s, "  children       {}", format_refs(&desc.children)).ok();
+    writeln!(s, "  same content   {}", format_refs(&desc.same_content)).ok();
+    writeln!(
+        s,
+        "  chain          depth={} decode_read_bytes={}",
+        desc.chain_depth, desc.decode_read_bytes
+    )
+    .ok();
+    writeln!(s, "  provenance     {}", desc.provenance).ok();
+    if desc.exploded {
+        writeln!(s, "  exploded       yes").ok();
+    }
+    for member in &desc.series {
+        writeln!(s, "  series         {} seq={}", member.name, member.seq).ok();
+    }
+    for note in &desc.notes {
+        writeln!(s, "  note           {} {}", note.time_created, note.note).ok();
+    }
+    s
+}
+
+pub fn print_description(conn: &mut db::Conn, name: &str, json: bool) -> Result<()> {
+    let desc = describe(conn, name)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&desc)?);
+    } else {
+        print!("{}", format_description(&desc));
+    }
+    Ok(())
+}
+
+/// adds a note to the content of a blob, shown by `describe`
+pub fn note_add(conn: &mut db::Conn, name: &str, note: &str) -> Result<()> {
+    let blob = resolve_blob(conn, name)?;
+    db::note_add(
+        conn,
+        &blob.content_hash,
+        note,
+        time::OffsetDateTime::now_utc(),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+
+    fn synthetic_store(conn: &mut db::Conn) {
+        for blob in &[
+            blob(1, "aaaaaa", None, 1000),
+            blob(2, "bbbbbb", None, 1000),
+            blob(3, "bbbbbb", Some("aaaaaa"), 100),
+            blob(4, "bbcccc", Some("bbbbbb"), 10),
+        ] {
+            db::insert(conn, blob).unwrap();
+        }
+    }
+
+    #[test]
+    fn resolve() {
+        with_store(|conn, _dir| {
+            synthetic_store(conn);
+            assert_eq!(resolve_blob(conn, "app-2.tar").unwrap().id, 2);
+            // latest blob of the content
+            assert_eq!(resolve_blob(conn, "bbbbbb").unwrap().id, 3);
+            assert_eq!(resolve_blob(conn, "bbbbbb2").unwrap().id, 2);
+            assert_eq!(resolve_blob(conn, "bbcccc4").unwrap().id, 4);
+            // matches bbbbbb and bbcccc
+            assert!(resolve_blob(conn, "bbbbbb".get(..2).unwrap()).is_err());
+            assert!(resolve_blob(conn, "bbb").is_err());
+            let err = resolve_blob(conn, "dddddd").unwrap_err();
+            assert_eq!(error_kind(&err), ErrorKind::UnknownFilename);
+        });
+    }
+
+    #[test]
+    fn describe_text() {
+        with_store(|conn, dir| {
+            synthetic_store(conn);
+            let delta = db::by_filename(conn, "app-3.tar").unwrap().pop().unwrap();
+            let path = filepath(&delta.store_hash);
+            std::fs::create_dir_all(Path::new(&path).parent().unwrap()).unwrap();
+            std::fs::write(&path, vec![0u8; 100]).unwrap();
+            db::note_add(
+                conn,
+                "bbbbbb",
+                "built with -O2",
+                time::OffsetDateTime::UNIX_EPOCH,
+            )
+            .unwrap();
+
+            let desc = describe(conn, "app-3.tar").unwrap();
+            assert_eq!(
+                format_description(&desc),
+                format!(
+                    "V3 app-3.tar\n\
+                     \x20 time_created   1970-01-01 0:00:00.0 +00:00:00\n\
+                     \x20 content_hash   bbbbbb\n\
+                     \x20 store_hash     bbbbbb3\n\
+                     \x20 parent_hash    aaaaaa\n\
+                     \x20 size           store=100 content=1000\n\
+                     \x20 object         {0}/bb/bbbb3 size=100\n\
+                     \x20 parent         V1 app-1.tar\n\
+                     \x20 children       V4 app-4.tar\n\
+                     \x20 same content   V2 app-2.tar\n\
+                     \x20 chain          depth=1 decode_read_bytes=1100\n\
+                     \x20 provenance     unknown\n\
+                     \x20 note           1970-01-01 0:00:00.0 +00:00:00 built with -O2\n",
+                    dir.join("objects").display()
+                )
+            );
+
+            let desc = describe(conn, "app-4.tar").unwrap();
+            assert!(desc.object_size.is_none());
+            assert_eq!(desc.chain_depth, 2);
+            assert!(format_description(&desc).contains("/bb/cccc4 missing\n"));
+
+            let json = serde_json::to_string(&desc).unwrap();
+            let parsed: Description = serde_json::from_str(&json).unwrap();
+            assert_eq!(parsed.blob, desc.blob);
+        });
+    }
+}
@@ -0,0 +1,286 @@
+use std::io;
+
+/// errors of the crate. callers match on the variant, or on its `ErrorKind` to choose e.g. a
+/// process exit code.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("unknown filename: {0}")]
+    UnknownFilename(String),
+    /// an object file referenced by a blob does not exist
+    #[error("missing object: {0}")]
+    MissingObject(String),
+    #[error("checksum mismatch: filename={filename}, expected={expected}, actual={actual}")]
+    ChecksumMismatch {
+        filename: String,
+        expected: String,
+        actual: String,
+    },
+    /// the best delta of a pushed file compresses worse than the given threshold
+    #[error(
+        "compression ratio exceeded: filename={filename}, ratio={:.02}%, max_ratio={:.02}%",
+        .ratio * 100.0,
+        .max_ratio * 100.0
+    )]
+    RatioExceeded {
+        filename: String,
+        ratio: f32,
+        max_ratio: f32,
+    },
+    /// a push would grow the store past `max_store_size`
+    #[error(
+        "store size quota exceeded: filename={filename}, projected_size={projected_size}, \
+         max_store_size={max_store_size}"
+    )]
+    QuotaExceeded {
+        filename: String,
+        projected_size: u64,
+        max_store_size: u64,
+    },
+    /// xdelta3 failed to encode or decode a version
+    #[error("delta failed: filename={filename}, {error}")]
+    Delta {
+        filename: String,
+        #[source]
+        error: io::Error,
+    },
+    /// the input file was written to while it was pushed
+    #[error("file changed during push: {0}")]
+    FileMutated(String),
+    /// a delta refers to a parent content which is not stored
+    #[error("missing parent: filename={filename}, parent_hash={parent_hash}")]
+    MissingParent {
+        filename: String,
+        parent_hash: String,
+    },
+    /// the store is tagged with a hash scheme this build does not know
+    #[error(
+        "unsupported hash algorithm: store={0}, supported={}",
+        crate::rw::HASH_ALGO
+    )]
+    UnsupportedHashAlgo(String),
+    /// the operation was cancelled, e.g. by SIGINT, and the store was left as before
+    #[error("cancelled")]
+    Cancelled,
+    /// the database is busy or locked by another process
+    #[error("database is locked: {0}")]
+    Locked(#[source] rusqlite::Error),
+    #[error(transparent)]
+    Db(rusqlite::Error),
+    /// a malformed zip input
+    #[error(transparent)]
+    Zip(zip::result::ZipError),
+    #[error(transparent)]
+    Io(io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    /// any other failure, see `format_err!`
+    #[error("{0}")]
+    Other(String),
+}
+
+/// an `Error::Other` with a formatted message
+#[macro_export]
+macro_rules! format_err {
+    ($($arg:tt)*) => {
+        $crate::Error::Other(format!($($arg)*))
+    };
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        if let rusqlite::Error::SqliteFailure(failure, _msg) = &e {
+            if failure.code == rusqlite::ErrorCode::DatabaseBusy
+                || failure.code == rusqlite::ErrorCode::DatabaseLocked
+            {
+                return Error::Locked(e);
+            }
+        }
+        Error::Db(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        // zip errors are converted to io errors by the zip to tar conversion
+        let zip_error = e
+            .get_ref()
+            .is_some_and(|inner| inner.is::<zip::result::ZipError>());
+        if zip_error {
+            let inner = e.into_inner().expect("checked above");
+            return Error::Zip(*inner.downcast().expect("checked above"));
+        }
+        Error::Io(e)
+    }
+}
+
+impl From<zip::result::ZipError> for Error {
+    fn from(e: zip::result::ZipError) -> Self {
+        Error::Zip(e)
+    }
+}
+
+impl From<walkdir::Error> for Error {
+    fn from(e: walkdir::Error) -> Self {
+        Error::from(io::Error::from(e))
+    }
+}
+
+impl From<tokio::task::JoinError> for Error {
+    fn from(e: tokio::task::JoinError) -> Self {
+        Error::Io(e.into())
+    }
+}
+
+impl From<notify::Error> for Error {
+    fn from(e: notify::Error) -> Self {
+        Error::Other(format!("watch: {}", e))
+    }
+}
+
+impl From<regex::Error> for Error {
+    fn from(e: regex::Error) -> Self {
+        Error::Other(e.to_string())
+    }
+}
+
+impl From<std::num::ParseIntError> for Error {
+    fn from(e: std::num::ParseIntError) -> Self {
+        Error::Other(e.to_string())
+    }
+}
+
+impl From<tempfile::PersistError> for Error {
+    fn from(e: tempfile::PersistError) -> Self {
+        Error::from(e.error)
+    }
+}
+
+/// category of an error, so callers can match on it instead of its message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    UnknownFilename,
+    MissingObject,
+    ChecksumMismatch,
+    RatioExceeded,
+    QuotaExceeded,
+    Delta,
+    FileMutated,
+    MissingParent,
+    UnsupportedHashAlgo,
+    Cancelled,
+    /// the database is locked by another process
+    Locked,
+    Db,
+    /// a malformed zip input
+    Zip,
+    Io,
+    Other,
+}
+
+/// category of an error
+pub fn error_kind(err: &Error) -> ErrorKind {
+    match err {
+        Error::UnknownFilename(_) => ErrorKind::UnknownFilename,
+        Error::MissingObject(_) => ErrorKind::MissingObject,
+        Error::ChecksumMismatch { .. } => ErrorKind::ChecksumMismatch,
+        Error::RatioExceeded { .. } => ErrorKind::RatioExceeded,
+        Error::QuotaExceeded { .. } => ErrorKind::QuotaExceeded,
+        Error::Delta { .. } => ErrorKind::Delta,
+        Error::FileMutated(_) => ErrorKind::FileMutated,
+        Error::MissingParent { .. } => ErrorKind::MissingParent,
+        Error::UnsupportedHashAlgo(_) => ErrorKind::UnsupportedHashAlgo,
+        Error::Cancelled => ErrorKind::Cancelled,
+        Error::Locked(_) => ErrorKind::Locked,
+        Error::Db(_) => ErrorKind::Db,
+        Error::Zip(_) => ErrorKind::Zip,
+        Error::Io(_) => ErrorKind::Io,
+        Error::Json(_) | Error::Other(_) => ErrorKind::Other,
+    }
+}
+
+pub const EXIT_UNKNOWN_FILENAME: i32 = 2;
+pub const EXIT_MISSING_OBJECT: i32 = 3;
+pub const EXIT_CHECKSUM_MISMATCH: i32 = 4;
+pub const EXIT_LOCKED: i32 = 5;
+pub const EXIT_RATIO_EXCEEDED: i32 = 6;
+pub const EXIT_QUOTA_EXCEEDED: i32 = 7;
+pub const EXIT_FILE_MUTATED: i32 = 8;
+pub const EXIT_UNSUPPORTED_HASH_ALGO: i32 = 9;
+/// EX_USAGE of sysexits.h, a malformed command line
+pub const EXIT_USAGE: i32 = 64;
+/// 128 + SIGINT, as shells report a process killed by it
+pub const EXIT_CANCELLED: i32 = 130;
+
+/// process exit code for an error. uncategorized errors exit with 1.
+pub fn exit_code(err: &Error) -> i32 {
+    match error_kind(err) {
+        ErrorKind::UnknownFilename => EXIT_UNKNOWN_FILENAME,
+        ErrorKind::MissingObject => EXIT_MISSING_OBJECT,
+        ErrorKind::ChecksumMismatch => EXIT_CHECKSUM_MISMATCH,
+        ErrorKind::Locked => EXIT_LOCKED,
+        ErrorKind::RatioExceeded => EXIT_RATIO_EXCEEDED,
+        ErrorKind::QuotaExceeded => EXIT_QUOTA_EXCEEDED,
+        ErrorKind::FileMutated => EXIT_FILE_MUTATED,
+        ErrorKind::UnsupportedHashAlgo => EXIT_UNSUPPORTED_HASH_ALGO,
+        ErrorKind::Cancelled => EXIT_CANCELLED,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+    use crate::*;
+
+    #[test]
+    fn error_variants() {
+        with_store(|conn, dir| {
+            let out = dir.join("out");
+            let out = out.to_str().unwrap();
+
+            let err = get(conn, "unknown.tar", out, false).unwrap_err();
+            assert!(matches!(&err, Error::UnknownFilename(name) if name == "unknown.tar"));
+            assert_eq!(exit_code(&err), EXIT_UNKNOWN_FILENAME);
+
+            let missing = dir.join("missing.tar");
+            let err = push(conn, missing.to_str().unwrap(), FileType::Plain).unwrap_err();
+            assert!(matches!(&err, Error::Io(e) if e.kind() == io::ErrorKind::NotFound));
+
+            // the zip error is unwrapped from the io error of the conversion
+            let broken = write_file(dir, "broken.zip", b"PK\x03\x04 not a zip");
+            let err = push(conn, &broken, FileType::Zip).unwrap_err();
+            assert!(matches!(err, Error::Zip(_)), "{:?}", err);
+
+            let v1 = write_file(dir, "app-1.tar", b"hello, world");
+            let v2 = write_file(dir, "app-2.tar", b"hello, world!!");
+            push(conn, &v1, FileType::Plain).unwrap();
+            push(conn, &v2, FileType::Plain).unwrap();
+            let delta = db::by_filename(conn, "app-2.tar")
+                .unwrap()
+                .into_iter()
+                .find(|blob| !blob.is_root())
+                .unwrap();
+            std::fs::write(filepath(&delta.store_hash), b"").unwrap();
+            let err = get(conn, "app-2.tar", out, false).unwrap_err();
+            assert!(matches!(&err, Error::Delta { filename, .. } if filename == "app-2.tar"));
+            assert!(err.to_string().contains("app-2.tar"));
+            assert!(std::error::Error::source(&err).is_some());
+
+            let busy = rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+                None,
+            );
+            let err = Error::from(busy);
+            assert!(matches!(err, Error::Locked(_)));
+            assert_eq!(exit_code(&err), EXIT_LOCKED);
+            let err = Error::from(rusqlite::Error::QueryReturnedNoRows);
+            assert!(matches!(err, Error::Db(_)));
+            assert_eq!(error_kind(&err), ErrorKind::Db);
+
+            let err = crate::format_err!("bad input: {}", 1);
+            assert!(matches!(&err, Error::Other(msg) if msg == "bad input: 1"));
+            assert_eq!(exit_code(&err), 1);
+        });
+    }
+}
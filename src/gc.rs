@@ -0,0 +1,257 @@
+//! `gc`, a single maintenance entry point for cron. runs the cleanup phases of the store in
+//! order and reports what each reclaimed.
+
+use std::collections::HashSet;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::*;
+
+/// default `GcOptions::min_age`, the same as `gc-tmp`
+pub const GC_MIN_AGE: Duration = Duration::from_secs(24 * 3600);
+/// default `GcOptions::cache_budget`
+pub const GC_CACHE_BUDGET: u64 = 1 << 30;
+
+#[derive(Debug, Clone)]
+pub struct GcOptions {
+    /// report what each phase would reclaim without removing anything
+    pub dry_run: bool,
+    /// also remove objects no blob refers to
+    pub aggressive: bool,
+    /// temp files and orphaned objects younger than this may belong to a running push, and are
+    /// kept
+    pub min_age: Duration,
+    /// bytes of the intermediate cache of `get` to keep
+    pub cache_budget: u64,
+    pub skip_tmp: bool,
+    pub skip_cleanup: bool,
+    pub skip_cache: bool,
+    pub skip_orphans: bool,
+    pub skip_refresh: bool,
+}
+
+impl Default for GcOptions {
+    fn default() -> Self {
+        GcOptions {
+            dry_run: false,
+            aggressive: false,
+            min_age: GC_MIN_AGE,
+            cache_budget: GC_CACHE_BUDGET,
+            skip_tmp: false,
+            skip_cleanup: false,
+            skip_cache: false,
+            skip_orphans: false,
+            skip_refresh: false,
+        }
+    }
+}
+
+/// files removed by a phase, or which would be on dry run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Reclaimed {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+impl Reclaimed {
+    pub fn add(&mut self, len: u64) {
+        self.files += 1;
+        self.bytes += len;
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GcPhase {
+    pub name: String,
+    /// skipped by an option, or as it is not safe to run
+    pub skipped: bool,
+    pub reclaimed: Reclaimed,
+    pub elapsed_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GcReport {
+    pub dry_run: bool,
+    /// in the order they ran: tmp, cleanup, cache, orphans and refresh
+    pub phases: Vec<GcPhase>,
+    pub bytes_reclaimed: u64,
+    /// sum of store_size of all blobs
+    pub store_size_before: u64,
+    pub store_size_after: u64,
+    pub blob_count: usize,
+    pub elapsed_ms: u64,
+}
+
+/// runs `f` as phase `name` unless `skip`
+fn run_phase<F>(phases: &mut Vec<GcPhase>, name: &str, skip: bool, f: F) -> Result<()>
+where
+    F: FnOnce() -> Result<Reclaimed>,
+{
+    let sw = Instant::now();
+    let reclaimed = if skip {
+        info!("gc: skipping {}", name);
+        Reclaimed::default()
+    } else {
+        f()?
+    };
+    phases.push(GcPhase {
+        name: name.to_owned(),
+        skipped: skip,
+        reclaimed,
+        elapsed_ms: sw.elapsed().as_millis() as u64,
+    });
+    Ok(())
+}
+
+/// removes stale temp files, root blobs over the retention policy, intermediate cache entries
+/// over the budget, orphaned objects if `aggressive`, then refreshes the stats index. callers
+/// should hold `lock_store`, so a push waits for it.
+pub fn gc(conn: &mut db::Conn, opts: &GcOptions) -> Result<GcReport> {
+    let sw = Instant::now();
+    let dry_run = opts.dry_run;
+    let (store_size_before, _) = db::total_sizes(conn)?;
+
+    let mut phases = Vec::new();
+    run_phase(&mut phases, "tmp", opts.skip_tmp, || {
+        gc_tmpfiles(&tmpdir(), opts.min_age, dry_run)
+    })?;
+    run_phase(&mut phases, "cleanup", opts.skip_cleanup, || {
+        cleanup_roots(conn, dry_run)
+    })?;
+    run_phase(&mut phases, "cache", opts.skip_cache, || {
+        cache::IntermediateCache::new(&StoreDirs::from_env(), opts.cache_budget)?.gc(dry_run)
+    })?;
+    // other stores may keep their objects in a shared object directory
+    let shared = objectdir() != format!("{}/objects", prefix());
+    if opts.aggressive && shared {
+        warn!("gc: object directory is not under WORKDIR, not pruning orphaned objects");
+    }
+    let skip_orphans = opts.skip_orphans || !opts.aggressive || shared;
+    run_phase(&mut phases, "orphans", skip_orphans, || {
+        prune_orphaned_objects(conn, opts.min_age, dry_run)
+    })?;
+    run_phase(&mut phases, "refresh", opts.skip_refresh, || {
+        if !dry_run {
+            rebuild_stats_index(conn)?;
+        }
+        Ok(Reclaimed::default())
+    })?;
+
+    let (store_size_after, _) = db::total_sizes(conn)?;
+    let report = GcReport {
+        dry_run,
+        bytes_reclaimed: phases.iter().map(|phase| phase.reclaimed.bytes).sum(),
+        phases,
+        store_size_before,
+        store_size_after,
+        blob_count: db::count(conn)?,
+        elapsed_ms: sw.elapsed().as_millis() as u64,
+    };
+    if !dry_run {
+        log_op(conn, "gc", serde_json::to_value(&report)?)?;
+    }
+    Ok(report)
+}
+
+/// removes objects no blob refers to, e.g. persisted by a push killed before its blob was
+/// inserted. objects of quarantined blobs and of snapshots, and objects younger than `min_age`
+/// are kept.
+fn prune_orphaned_objects(
+    conn: &mut db::Conn,
+    min_age: Duration,
+    dry_run: bool,
+) -> Result<Reclaimed> {
+    let mut referenced = db::iter_all(conn)
+        .map(|blob| blob.map(|blob| blob.store_hash))
+        .collect::<rusqlite::Result<HashSet<_>>>()?;
+    // quarantined blobs keep their objects, and snapshots keep theirs for a restore
+    referenced.extend(
+        db::quarantined(conn)?
+            .into_iter()
+            .map(|blob| blob.store_hash),
+    );
+    for snapshot in db::snapshots(conn)? {
+        referenced.extend(db::snapshot_objects(conn, &snapshot.name)?);
+    }
+    let now = SystemTime::now();
+
+    let mut reclaimed = Reclaimed::default();
+    for (hash, meta) in object_files()? {
+        if referenced.contains(&hash) || hash.len() < 3 {
+            continue;
+        }
+        let young = now
+            .duration_since(meta.modified()?)
+            .map_or(true, |age| age < min_age);
+        if young {
+            debug!("gc: keeping recent orphaned object {}", hash);
+            continue;
+        }
+        info!("gc: orphaned object {}, size={}", hash, meta.len());
+        if !dry_run {
+            match std::fs::remove_file(filepath(&hash)) {
+                Ok(()) => (),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        reclaimed.add(meta.len());
+    }
+    Ok(reclaimed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+
+    fn write_object(hash: &str) -> String {
+        let path = filepath(hash);
+        std::fs::create_dir_all(Path::new(&path).parent().unwrap()).unwrap();
+        std::fs::write(&path, hash).unwrap();
+        path
+    }
+
+    #[test]
+    fn gc_keeps_quarantined_objects() {
+        with_store(|conn, dir| {
+            for i in 0..2 {
+                let body = format!("hello, world{}", "!".repeat(i));
+                let input = write_file(dir, &format!("app-{}.tar", i), body.as_bytes());
+                push(conn, &input, FileType::Plain).unwrap();
+            }
+
+            // a delta of a missing parent, quarantined by fsck
+            let dangling = Blob {
+                id: 0,
+                filename: "dangling.tar".to_owned(),
+                time_created: time::OffsetDateTime::now_utc(),
+                store_size: 8,
+                content_size: 8,
+                store_hash: "dd0000000000".to_owned(),
+                content_hash: "cc0000000000".to_owned(),
+                parent_hash: Some("ee0000000000".to_owned()),
+            };
+            let quarantined = write_object(&dangling.store_hash);
+            db::insert(conn, &dangling).unwrap();
+            assert_eq!(fsck(conn, true).unwrap().quarantined, 1);
+
+            // an object only a snapshot refers to, and one nothing refers to
+            let snapshotted = write_object("aa0000000000");
+            snapshot_create(conn, "before").unwrap();
+            let orphaned = write_object("bb0000000000");
+
+            let opts = GcOptions {
+                aggressive: true,
+                min_age: Duration::ZERO,
+                ..Default::default()
+            };
+            gc(conn, &opts).unwrap();
+            assert!(Path::new(&quarantined).exists());
+            assert!(Path::new(&snapshotted).exists());
+            assert!(!Path::new(&orphaned).exists());
+            for blob in db::all(conn).unwrap() {
+                assert!(Path::new(&filepath(&blob.store_hash)).exists());
+            }
+        });
+    }
+}
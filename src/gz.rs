@@ -8,9 +8,17 @@ where
 {
     let input_file = std::fs::File::open(input_path)?;
     let mut dst_file = std::fs::File::create(dst_path)?;
-    let mut decoder = flate2::read::GzDecoder::new(input_file);
+    gz_to_plain(input_file, &mut dst_file)
+}
 
-    let mut out_file = HashRW::new(&mut dst_file);
+/// decompresses a gzip stream to `dst`. all members of a multi-member stream are decompressed.
+pub fn gz_to_plain<R, W>(input: R, dst: W) -> std::io::Result<WriteMetadata>
+where
+    R: std::io::Read,
+    W: std::io::Write,
+{
+    let mut decoder = flate2::read::MultiGzDecoder::new(input);
+    let mut out_file = HashRW::new(dst);
 
     std::io::copy(&mut decoder, &mut out_file)?;
     Ok(out_file.meta())
@@ -28,3 +36,36 @@ where
     std::io::copy(&mut input_file, &mut out_file)?;
     Ok(out_file.meta())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+
+    fn gz_bytes(data: &[u8]) -> Vec<u8> {
+        use std::io::Write;
+
+        let mut w = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        w.write_all(data).unwrap();
+        w.finish().unwrap()
+    }
+
+    #[test]
+    fn multi_member() {
+        let mut data = gz_bytes(b"hello, ");
+        data.extend_from_slice(&gz_bytes(b"world"));
+
+        let mut out = Vec::new();
+        gz_to_plain(data.as_slice(), &mut out).unwrap();
+        assert_eq!(out, b"hello, world");
+    }
+
+    #[test]
+    fn fuzz_regressions() {
+        // all of the checked in inputs are broken, and should be rejected without panicking
+        for (path, data) in fuzz_corpus("store_gz") {
+            let res = gz_to_plain(data.as_slice(), std::io::sink());
+            assert!(res.is_err(), "{:?}", path);
+        }
+    }
+}
@@ -0,0 +1,186 @@
+//! delta candidates hinted by filenames. a regex stored in the settings of the store extracts the
+//! flavor and version of a filename, e.g. `^app-(?P<flavor>[a-z]+)-(?P<version>[0-9.]+)\.apk$`,
+//! and push tries roots of the flavor of the input before the others.
+
+use regex::Regex;
+
+use super::*;
+
+const FILENAME_HINT_KEY: &str = "filename_hint";
+
+/// the regex of the store, None if not set
+pub fn filename_hint(conn: &mut db::Conn) -> Result<Option<String>> {
+    let regex = db::get_setting(conn, FILENAME_HINT_KEY)?;
+    Ok(regex.filter(|regex| !regex.is_empty()))
+}
+
+/// sets the regex of the store, or clears it. it should have a `flavor` group, and may have a
+/// `version` group.
+pub fn set_filename_hint(conn: &mut db::Conn, regex: Option<&str>) -> Result<()> {
+    if let Some(regex) = regex {
+        compile(regex)?;
+    }
+    db::set_setting(conn, FILENAME_HINT_KEY, regex.unwrap_or_default())?;
+    Ok(())
+}
+
+fn compile(regex: &str) -> Result<Regex> {
+    let compiled = Regex::new(regex)?;
+    if !compiled.capture_names().any(|name| name == Some("flavor")) {
+        return Err(crate::format_err!(
+            "filename hint has no flavor group: {}",
+            regex
+        ));
+    }
+    Ok(compiled)
+}
+
+/// flavor and version of a filename, None if it does not match
+fn parse(regex: &Regex, filename: &str) -> Option<(String, Option<String>)> {
+    let captures = regex.captures(filename)?;
+    let flavor = captures.name("flavor")?.as_str().to_owned();
+    let version = captures.name("version").map(|m| m.as_str().to_owned());
+    Some((flavor, version))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HintPath {
+    /// the filename does not match, candidates are tried in their order
+    NoMatch,
+    /// roots of the same flavor are tried first
+    SameFlavorFirst,
+    /// only roots of the same flavor are tried, with `PushOptions::hint_strict`
+    Strict,
+    /// with `PushOptions::hint_strict`, but no root has the flavor of the input. candidates are
+    /// tried in their order rather than not at all.
+    StrictFallback,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HintReport {
+    pub regex: String,
+    pub flavor: Option<String>,
+    pub version: Option<String>,
+    pub path: HintPath,
+    /// candidates of the flavor of the input
+    pub same_flavor: usize,
+}
+
+/// splits `candidates` into roots of the flavor of `filename` and the others, keeping their
+/// order. with `strict`, the others are dropped, unless no root has the flavor. if `filename`
+/// does not match, all candidates are others.
+pub fn split_candidates(
+    regex: &str,
+    filename: &str,
+    candidates: Vec<Blob>,
+    strict: bool,
+) -> Result<(Vec<Blob>, Vec<Blob>, HintReport)> {
+    let compiled = compile(regex)?;
+    let mut report = HintReport {
+        regex: regex.to_owned(),
+        flavor: None,
+        version: None,
+        path: HintPath::NoMatch,
+        same_flavor: 0,
+    };
+    let (flavor, version) = match parse(&compiled, filename) {
+        Some(parsed) => parsed,
+        None => return Ok((Vec::new(), candidates, report)),
+    };
+
+    let (same, mut others): (Vec<_>, Vec<_>) = candidates.into_iter().partition(|root| {
+        parse(&compiled, &root.filename).is_some_and(|(root_flavor, _)| root_flavor == flavor)
+    });
+    report.path = if !strict {
+        HintPath::SameFlavorFirst
+    } else if same.is_empty() {
+        HintPath::StrictFallback
+    } else {
+        others.clear();
+        HintPath::Strict
+    };
+    report.flavor = Some(flavor);
+    report.version = version;
+    report.same_flavor = same.len();
+    Ok((same, others, report))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+
+    const REGEX: &str = r"^app-(?P<flavor>[a-z]+)-(?P<version>[0-9.]+)\.tar$";
+
+    #[test]
+    fn push_filename_hint() {
+        with_store(|conn, dir| {
+            assert!(set_filename_hint(conn, Some("^app-(.*)$")).is_err());
+            set_filename_hint(conn, Some(REGEX)).unwrap();
+            assert_eq!(filename_hint(conn).unwrap().as_deref(), Some(REGEX));
+
+            let mut rng = Rng::new(17);
+            let mut random =
+                |len: usize| (0..len).map(|_| rng.next_u64() as u8).collect::<Vec<_>>();
+            let flavors = ["us", "eu", "kr"];
+            let bodies = flavors.map(|_| random(256 * 1024));
+            for (flavor, body) in flavors.iter().zip(&bodies) {
+                let input = write_file(dir, &format!("app-{}-1.0.tar", flavor), body);
+                push(conn, &input, FileType::Plain).unwrap();
+            }
+            // the estimator prefers app-eu, every candidate is encoded. returns filenames of the
+            // roots tried.
+            let push_edited = |conn: &mut db::Conn, filename: &str, strict: bool| {
+                let mut edited = bodies[0].clone();
+                edited.splice(1000..1000, filename.bytes());
+                let input = write_file(dir, filename, &edited);
+                let opts = PushOptions {
+                    no_prefilter: true,
+                    good_enough_ratio: Some(0.0),
+                    estimator: Some(Arc::new(FilenameEstimator("app-eu-1.0.tar"))),
+                    hint_strict: strict,
+                    ..Default::default()
+                };
+                let roots = db::roots(conn).unwrap();
+                let report = push_with_options(conn, &input, FileType::Plain, &opts).unwrap();
+                let tried = report
+                    .tried_roots
+                    .iter()
+                    .map(|hash| {
+                        let root = roots.iter().find(|r| &r.store_hash == hash).unwrap();
+                        root.filename.clone()
+                    })
+                    .collect::<Vec<_>>();
+                (report.hint.unwrap(), tried)
+            };
+
+            let (hint, tried) = push_edited(conn, "app-us-1.1.tar", false);
+            assert_eq!(hint.path, HintPath::SameFlavorFirst);
+            assert_eq!(hint.flavor.as_deref(), Some("us"));
+            assert_eq!(hint.version.as_deref(), Some("1.1"));
+            assert_eq!(hint.same_flavor, 1);
+            assert_eq!(tried.len(), 3);
+            assert_eq!(tried[..2], ["app-us-1.0.tar", "app-eu-1.0.tar"]);
+
+            let (hint, tried) = push_edited(conn, "unversioned.tar", false);
+            assert_eq!(hint.path, HintPath::NoMatch);
+            assert_eq!(hint.flavor, None);
+            assert_eq!(tried[0], "app-eu-1.0.tar");
+
+            let (hint, tried) = push_edited(conn, "app-us-1.2.tar", true);
+            assert_eq!(hint.path, HintPath::Strict);
+            assert!(tried.contains(&"app-us-1.0.tar".to_owned()));
+            assert!(tried.iter().all(|filename| filename.starts_with("app-us-")));
+
+            // no root of the flavor: the others are still tried
+            let (hint, tried) = push_edited(conn, "app-jp-1.0.tar", true);
+            assert_eq!(hint.path, HintPath::StrictFallback);
+            assert_eq!(hint.same_flavor, 0);
+            assert_eq!(tried[0], "app-eu-1.0.tar");
+
+            set_filename_hint(conn, None).unwrap();
+            assert_eq!(filename_hint(conn).unwrap(), None);
+        });
+    }
+}
@@ -0,0 +1,548 @@
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::time::Duration;
+
+use log::*;
+use tempfile::NamedTempFile;
+
+use crate::rw::WriteMetadata;
+use crate::zip::{store_zip_reader, StoreConfig};
+
+/// bytes fetched by a range request. zip entries are read in small pieces, so reads are served
+/// from a block of this size.
+const RANGE_BLOCK_SIZE: u64 = 256 * 1024;
+
+/// timeout of connecting, and of each read and write on a connection
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// redirects followed by `open_url` before giving up
+const MAX_REDIRECTS: usize = 5;
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// host:port and path of a plain http url. https is not supported.
+fn parse_url(url: &str) -> io::Result<(String, String)> {
+    let rest = match url.strip_prefix("http://") {
+        Some(rest) => rest,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("only http:// urls are supported: {}", url),
+            ))
+        }
+    };
+    let (host, path) = match rest.find('/') {
+        Some(idx) => (&rest[..idx], &rest[idx..]),
+        None => (rest, "/"),
+    };
+    let host = if host.contains(':') {
+        host.to_owned()
+    } else {
+        format!("{}:80", host)
+    };
+    Ok((host, path.to_owned()))
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Body,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _value)| key.eq_ignore_ascii_case(name))
+            .map(|(_key, value)| value.as_str())
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        self.header("content-length")?.parse().ok()
+    }
+
+    fn is_redirect(&self) -> bool {
+        matches!(self.status, 301 | 302 | 303 | 307 | 308)
+    }
+}
+
+/// a response body, without the framing of chunked transfer encoding
+enum Body {
+    Plain(BufReader<TcpStream>),
+    /// the stream and the bytes left in the current chunk, None before the first chunk
+    Chunked(BufReader<TcpStream>, Option<u64>),
+}
+
+impl Body {
+    fn read_chunk_size(stream: &mut BufReader<TcpStream>) -> io::Result<u64> {
+        let mut line = String::new();
+        stream.read_line(&mut line)?;
+        // chunk extensions follow a `;`
+        let size = line.trim_end().split(';').next().unwrap_or_default();
+        u64::from_str_radix(size.trim(), 16)
+            .map_err(|_| invalid_data(format!("invalid chunk size: {:?}", line)))
+    }
+}
+
+impl Read for Body {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (stream, left) = match self {
+            Body::Plain(stream) => return stream.read(buf),
+            Body::Chunked(stream, left) => (stream, left),
+        };
+        let remaining = match *left {
+            Some(0) => return Ok(0),
+            Some(remaining) => remaining,
+            None => {
+                let size = Body::read_chunk_size(stream)?;
+                *left = Some(size);
+                if size == 0 {
+                    return Ok(0);
+                }
+                size
+            }
+        };
+
+        let max = buf.len().min(remaining as usize);
+        let n = stream.read(&mut buf[..max])?;
+        if n == 0 && max > 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let remaining = remaining - n as u64;
+        if remaining > 0 {
+            *left = Some(remaining);
+        } else {
+            // the chunk ends with CRLF, then the size of the next one
+            let mut crlf = [0u8; 2];
+            stream.read_exact(&mut crlf)?;
+            *left = Some(Body::read_chunk_size(stream)?);
+        }
+        Ok(n)
+    }
+}
+
+fn connect(host: &str) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in host.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, TIMEOUT) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(TIMEOUT))?;
+                stream.set_write_timeout(Some(TIMEOUT))?;
+                return Ok(stream);
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| invalid_data(format!("no address of {}", host))))
+}
+
+/// GET with an optional range. a connection is used for a single request.
+fn get(host: &str, path: &str, range: Option<(u64, u64)>) -> io::Result<Response> {
+    let mut stream = connect(host)?;
+    let mut req = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+        path, host
+    );
+    if let Some((start, end)) = range {
+        req += &format!("Range: bytes={}-{}\r\n", start, end - 1);
+    }
+    req += "\r\n";
+    stream.write_all(req.as_bytes())?;
+
+    let mut body = BufReader::new(stream);
+    let mut line = String::new();
+    body.read_line(&mut line)?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| invalid_data(format!("invalid status line: {:?}", line)))?;
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if body.read_line(&mut line)? == 0 {
+            return Err(invalid_data("unexpected end of headers".to_owned()));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((key, value)) = line.split_once(':') {
+            headers.push((key.trim().to_owned(), value.trim().to_owned()));
+        }
+    }
+
+    let chunked = headers.iter().any(|(key, value)| {
+        key.eq_ignore_ascii_case("transfer-encoding") && value.eq_ignore_ascii_case("chunked")
+    });
+    let body = if chunked {
+        Body::Chunked(body, None)
+    } else {
+        Body::Plain(body)
+    };
+    Ok(Response {
+        status,
+        headers,
+        body,
+    })
+}
+
+/// `get` of a url, following redirects. returns the url which answered, as later requests of a
+/// `RangeReader` go there directly.
+fn get_url(url: &str, range: Option<(u64, u64)>) -> io::Result<(String, Response)> {
+    let mut url = url.to_owned();
+    for _ in 0..=MAX_REDIRECTS {
+        let (host, path) = parse_url(&url)?;
+        let res = get(&host, &path, range)?;
+        if !res.is_redirect() {
+            return Ok((url, res));
+        }
+        let location = res
+            .header("location")
+            .ok_or_else(|| invalid_data(format!("redirect without location from {}", url)))?;
+        debug!("http: {} redirects to {}", url, location);
+        url = if location.starts_with('/') {
+            format!("http://{}{}", host, location)
+        } else {
+            location.to_owned()
+        };
+    }
+    Err(invalid_data(format!("too many redirects from {}", url)))
+}
+
+/// `Read + Seek` over a file on an http server supporting range requests
+pub struct RangeReader {
+    host: String,
+    path: String,
+    len: u64,
+    pos: u64,
+    block_start: u64,
+    block: Vec<u8>,
+    /// bytes received in response bodies so far
+    pub fetched: u64,
+}
+
+impl RangeReader {
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn fetch(&mut self, start: u64) -> io::Result<()> {
+        let end = (start + RANGE_BLOCK_SIZE).min(self.len);
+        let res = get(&self.host, &self.path, Some((start, end)))?;
+        if res.status != 206 {
+            return Err(invalid_data(format!(
+                "range request failed with status {}",
+                res.status
+            )));
+        }
+
+        let mut block = Vec::with_capacity((end - start) as usize);
+        res.body.take(end - start).read_to_end(&mut block)?;
+        if block.len() as u64 != end - start {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        trace!("range: {}-{} of {}", start, end, self.path);
+        self.fetched += block.len() as u64;
+        self.block_start = start;
+        self.block = block;
+        Ok(())
+    }
+}
+
+impl Read for RangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let block_end = self.block_start + self.block.len() as u64;
+        if self.pos < self.block_start || self.pos >= block_end {
+            self.fetch(self.pos)?;
+        }
+
+        let offset = (self.pos - self.block_start) as usize;
+        let n = buf.len().min(self.block.len() - offset);
+        buf[..n].copy_from_slice(&self.block[offset..offset + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for RangeReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative position",
+            )),
+        }
+    }
+}
+
+/// a remote file, read with range requests, or downloaded when the server does not support them
+pub enum UrlSource {
+    Ranged(RangeReader),
+    Downloaded(NamedTempFile),
+}
+
+impl UrlSource {
+    /// bytes received from the server so far
+    pub fn fetched(&self) -> io::Result<u64> {
+        match self {
+            UrlSource::Ranged(r) => Ok(r.fetched),
+            UrlSource::Downloaded(file) => Ok(file.as_file().metadata()?.len()),
+        }
+    }
+}
+
+/// opens a remote file. the first byte is requested to probe range support, and a server
+/// answering with the whole file is read to a temporary file in `tmp_dir` instead. redirects are
+/// followed, only plain http is supported.
+pub fn open_url<P: AsRef<Path>>(url: &str, tmp_dir: P) -> io::Result<UrlSource> {
+    let (final_url, mut res) = get_url(url, Some((0, 1)))?;
+    let (host, path) = parse_url(&final_url)?;
+
+    match res.status {
+        206 => {
+            // Content-Range: bytes 0-0/len
+            let len = res
+                .header("content-range")
+                .and_then(|range| range.rsplit('/').next())
+                .and_then(|len| len.parse().ok())
+                .ok_or_else(|| invalid_data(format!("invalid content-range of {}", url)))?;
+            Ok(UrlSource::Ranged(RangeReader {
+                host,
+                path,
+                len,
+                pos: 0,
+                block_start: 0,
+                block: Vec::new(),
+                fetched: 0,
+            }))
+        }
+        200 => {
+            info!("open_url: no range support, downloading {}", url);
+            let mut file = NamedTempFile::new_in(tmp_dir)?;
+            let len = match res.content_length() {
+                Some(len) => io::copy(&mut (&mut res.body).take(len), &mut file)?,
+                None => io::copy(&mut res.body, &mut file)?,
+            };
+            if res.content_length().is_some_and(|expected| expected != len) {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            Ok(UrlSource::Downloaded(file))
+        }
+        status => Err(io::Error::other(format!(
+            "GET {} failed with status {}",
+            url, status
+        ))),
+    }
+}
+
+/// `store_zip_reproducible` of a zip archive on an http server. with range support, only the
+/// central directory and stored entries are fetched, so entries skipped by `exclude_entries` are
+/// never downloaded.
+pub fn store_zip_url<P1, P2>(
+    url: &str,
+    tmp_dir: P1,
+    dst_path: P2,
+    config: &StoreConfig,
+) -> io::Result<(WriteMetadata, UrlSource)>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let mut source = open_url(url, tmp_dir)?;
+    let meta = match &mut source {
+        UrlSource::Ranged(r) => store_zip_reader(r, dst_path, config)?,
+        UrlSource::Downloaded(file) => {
+            file.as_file_mut().seek(SeekFrom::Start(0))?;
+            store_zip_reader(file.as_file_mut(), dst_path, config)?
+        }
+    };
+    Ok((meta, source))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+    use std::io::Cursor;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum Server {
+        Ranges,
+        /// the whole file, with Content-Length
+        Whole,
+        /// the whole file in chunks of 1000 bytes
+        Chunked,
+    }
+
+    /// serves `body` to every request, honoring `Range` with `Server::Ranges`. returns the url
+    /// and the number of body bytes sent.
+    fn serve(body: Vec<u8>, server: Server) -> (String, Arc<Mutex<u64>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/app.apk", listener.local_addr().unwrap());
+        let sent = Arc::new(Mutex::new(0));
+
+        let sent0 = sent.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut range = None;
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                    if let Some(value) = line.strip_prefix("Range: bytes=") {
+                        let (start, end) = value.trim().split_once('-').unwrap();
+                        range = Some((
+                            start.parse::<usize>().unwrap(),
+                            end.parse::<usize>().unwrap(),
+                        ));
+                    }
+                    line.clear();
+                }
+
+                let head = match range {
+                    Some((start, end)) if server == Server::Ranges => {
+                        let end = end.min(body.len() - 1);
+                        format!(
+                            "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                            end + 1 - start, start, end, body.len()
+                        )
+                        .into_bytes()
+                        .into_iter()
+                        .chain(body[start..=end].iter().copied())
+                        .collect::<Vec<_>>()
+                    }
+                    _ if server == Server::Chunked => {
+                        let mut head =
+                            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+                        for chunk in body.chunks(1000) {
+                            head.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+                            head.extend_from_slice(chunk);
+                            head.extend_from_slice(b"\r\n");
+                        }
+                        head.extend_from_slice(b"0\r\n\r\n");
+                        *sent0.lock().unwrap() += body.len() as u64;
+                        stream.write_all(&head).ok();
+                        continue;
+                    }
+                    _ => format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len())
+                        .into_bytes()
+                        .into_iter()
+                        .chain(body.iter().copied())
+                        .collect(),
+                };
+                let body_len =
+                    head.len() - head.windows(4).position(|w| w == b"\r\n\r\n").unwrap() - 4;
+                *sent0.lock().unwrap() += body_len as u64;
+                // the client may close early, e.g. after the probe
+                stream.write_all(&head).ok();
+            }
+        });
+        (url, sent)
+    }
+
+    /// redirects every request to `location`, returns the url
+    fn redirect(location: String) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/old.apk", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                    line.clear();
+                }
+                let head = format!(
+                    "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\n\r\n",
+                    location
+                );
+                stream.write_all(head.as_bytes()).ok();
+            }
+        });
+        url
+    }
+
+    fn test_zip() -> Vec<u8> {
+        let mut rng = Rng::new(1);
+        let mut w = ::zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let opts = ::zip::write::SimpleFileOptions::default()
+            .compression_method(::zip::CompressionMethod::Stored);
+        w.start_file("assets/big.bin", opts).unwrap();
+        let big = (0..4 * 1024 * 1024)
+            .map(|_| rng.next_u64() as u8)
+            .collect::<Vec<_>>();
+        w.write_all(&big).unwrap();
+        w.start_file("classes.dex", opts).unwrap();
+        w.write_all(b"dex\n").unwrap();
+        w.start_file("AndroidManifest.xml", opts).unwrap();
+        w.write_all(b"<manifest/>").unwrap();
+        w.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn ranged_zip() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = test_zip();
+        let config = StoreConfig {
+            exclude_entries: vec!["assets/".to_owned()],
+            ..Default::default()
+        };
+
+        let local = dir.path().join("local.tar");
+        let zip_path = write_file(dir.path(), "app.apk", &data);
+        let expected = std::fs::File::open(&zip_path).unwrap();
+        let expected = store_zip_reader(expected, &local, &config).unwrap();
+
+        let (url, sent) = serve(data.clone(), Server::Ranges);
+        let out = dir.path().join("ranged.tar");
+        let (meta, source) = store_zip_url(&url, dir.path(), &out, &config).unwrap();
+        assert_eq!(meta.digest(), expected.digest());
+        assert_eq!(std::fs::read(&out).unwrap(), std::fs::read(&local).unwrap());
+        assert!(matches!(source, UrlSource::Ranged(_)));
+        let fetched = source.fetched().unwrap();
+        assert!(fetched < data.len() as u64 / 4, "fetched={}", fetched);
+        assert!(*sent.lock().unwrap() < data.len() as u64 / 4);
+
+        // later range requests go to the redirected url
+        let out = dir.path().join("redirected.tar");
+        let (meta, source) = store_zip_url(&redirect(url), dir.path(), &out, &config).unwrap();
+        assert_eq!(meta.digest(), expected.digest());
+        assert!(matches!(source, UrlSource::Ranged(_)));
+
+        // no range support: the whole file is downloaded, without the chunk framing
+        for server in [Server::Whole, Server::Chunked] {
+            let (url, _sent) = serve(data.clone(), server);
+            let out = dir.path().join("downloaded.tar");
+            let (meta, source) = store_zip_url(&url, dir.path(), &out, &config).unwrap();
+            assert_eq!(meta.digest(), expected.digest());
+            assert!(matches!(source, UrlSource::Downloaded(_)));
+            assert_eq!(source.fetched().unwrap(), data.len() as u64);
+            std::fs::remove_file(&out).unwrap();
+        }
+
+        assert!(open_url("https://example.com/app.apk", dir.path()).is_err());
+    }
+}
@@ -1,37 +1,124 @@
+use std::collections::HashMap;
 use std::io;
 use std::path::*;
 
-pub use failure::Error;
 use futures::prelude::*;
 use log::*;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use stopwatch::Stopwatch;
 use tempfile::*;
 
+mod adopt;
+mod archive;
+mod cache;
+mod chain;
+mod chunk;
+mod compact;
 pub mod db;
 mod delta;
-mod gz;
+mod describe;
+mod error;
+mod gc;
+pub mod gz;
+mod hint;
+pub mod http;
+mod merge;
+pub mod metrics;
+mod patch;
+mod plan;
+mod progress;
+mod retry;
 mod rw;
+mod sketch;
+mod snapshot;
 mod stats;
+mod status;
+mod store;
+#[cfg(test)]
+mod testutil;
 mod validate;
+mod watch;
 pub mod zip;
 
 use crate::zip::store_zip;
+pub use crate::zip::{store_zip_reproducible, store_zip_with_source_map, SourceMap, StoreConfig};
+pub use adopt::{adopt_objects, export_meta, AdoptReport};
+pub use archive::{archive, archive0_async, ArchiveOptions, ArchiveSummary};
+pub use chain::{chain, delta_chain_cost, format_chain, ChainFormat, DeltaChainCost};
+pub use chunk::{set_storage_mode, storage_mode, StorageMode};
+pub use compact::{compact_chain, compact_delta_chain, CompactReport, COMPACT_CHAIN_SLACK};
 use db::Blob;
+pub use describe::{
+    describe, format_description, note_add, print_description, resolve_blob, BlobRef, Description,
+};
+pub use error::*;
+pub use gc::{gc, GcOptions, GcPhase, GcReport, Reclaimed, GC_CACHE_BUDGET, GC_MIN_AGE};
+pub use hint::{filename_hint, set_filename_hint, HintPath, HintReport};
+pub use merge::{merge_conflicts, print_merge_conflicts, MergeConflict, MergeReport};
+pub use patch::{make_patch, manifest_path, PatchManifest, PATCH_FORMAT};
+pub use plan::{get_many, GetItem};
+pub use progress::{
+    progress_enabled, progress_mode, set_progress_mode, Progress, ProgressMode, PROGRESS_ENV,
+};
+pub use retry::{retry_policy, set_retry_policy, RetryPolicy};
+pub use rw::WriteMetadata;
 use rw::*;
+pub use sketch::{MinhashEstimator, SimilarityEstimator, SizeRatioEstimator, Trial};
+pub use snapshot::{
+    print_snapshot_diff, print_snapshot_rollback, snapshot_create, snapshot_delete, snapshot_diff,
+    snapshot_list, snapshot_restore, snapshot_rollback, RollbackReport, SnapshotDiff,
+};
 use stats::Stats;
+pub use status::{print_status, status, StoreStatus};
 use std::env;
-pub use validate::validate;
+pub use store::Store;
+pub use validate::{
+    fsck, validate, validate_chain, validate_chain_with_error, validate_gz_stored,
+    validate_with_options, verify_depth, verify_parent_hashes, verify_scope, FsckReport,
+    ParentHashReport, ValidateOptions, ValidateReport, CHECK_VALIDATE, CHECK_VALIDATE_SAMPLE,
+    VERIFY_DECODE_DEPTH,
+};
+pub use watch::{watch, watch_and_push, WatchOptions};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum FileType {
     Zip,
     Gz,
     Plain,
 }
 
+impl FileType {
+    /// detects file type from the file extension
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<FileType> {
+        let ext = path.as_ref().extension()?;
+        if ext == "zip" || ext == "apk" || ext == "aab" || ext == "apks" {
+            Some(FileType::Zip)
+        } else if ext == "gz" {
+            Some(FileType::Gz)
+        } else if ext == "tar" {
+            Some(FileType::Plain)
+        } else {
+            None
+        }
+    }
+}
+
+impl std::str::FromStr for FileType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "zip" => Ok(FileType::Zip),
+            "gz" => Ok(FileType::Gz),
+            "plain" => Ok(FileType::Plain),
+            _ => Err(crate::format_err!("unknown file type: {}", s)),
+        }
+    }
+}
+
 pub fn max_root_blobs() -> usize {
     5
 }
@@ -40,435 +127,3000 @@ pub fn prefix() -> String {
     env::var("WORKDIR").unwrap_or("data".to_owned())
 }
 
-pub fn tmpdir() -> String {
-    let tmp_dir = format!("{}/tmp", prefix());
-    //TODO
-    std::fs::create_dir_all(&tmp_dir).ok();
-    tmp_dir
+/// exclusive lock of a store across processes, released on drop
+pub struct StoreLock {
+    _file: std::fs::File,
 }
 
-fn filepath(s: &str) -> String {
-    format!("{}/objects/{}/{}", prefix(), &s[..2], &s[2..]).into()
+/// waits for the lock file `{WORKDIR}/lock`. pushes of the cli and of `watch` are serialized
+/// under it.
+pub fn lock_store() -> Result<StoreLock> {
+    let file = lock_file(&format!("{}/lock", prefix()))?;
+    Ok(StoreLock { _file: file })
 }
 
-fn store_object<P>(src_path: NamedTempFile, dst_path: P) -> Result<()>
-where
-    P: AsRef<Path>,
-{
-    trace!(
-        "store_object: src={:?}, dst={:?}",
-        src_path.as_ref(),
-        dst_path.as_ref()
-    );
+/// opens `path`, creating it if needed, and waits for an exclusive lock on it. the lock is
+/// released when the file is closed.
+fn lock_file(path: &str) -> Result<std::fs::File> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    flock_exclusive(&file)?;
+    Ok(file)
+}
 
-    if let Some(dir) = Path::new(dst_path.as_ref()).parent() {
-        std::fs::create_dir_all(dir)?;
-    } else {
-        error!("failed to get a parent directory: {:?}", dst_path.as_ref());
+/// waits for an exclusive lock on the directory of `path`. writers which replace `path` by a
+/// rename are serialized under it, without leaving a lock file behind.
+pub(crate) fn lock_parent_dir(path: &str) -> Result<std::fs::File> {
+    let file = std::fs::File::open(parent_dir(path))?;
+    flock_exclusive(&file)?;
+    Ok(file)
+}
+
+/// the directory of `path`, `.` for a bare filename
+pub(crate) fn parent_dir(path: &str) -> PathBuf {
+    match Path::new(path).parent() {
+        Some(dir) if dir != Path::new("") => dir.to_path_buf(),
+        _ => PathBuf::from("."),
     }
-    src_path.persist(dst_path)?;
-    Ok(())
 }
 
-fn update_blob(conn: &mut db::Conn, tmp_path: NamedTempFile, blob: &Blob) -> Result<bool> {
-    let path = filepath(&blob.store_hash);
+/// makes a file created by `NamedTempFile`, which is only readable by its owner, readable by
+/// others before it replaces an output file
+pub(crate) fn set_readable(tmpfile: &NamedTempFile) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let permissions = std::fs::Permissions::from_mode(0o644);
+        tmpfile.as_file().set_permissions(permissions)?;
+    }
+    Ok(())
+}
 
-    trace!("path={:?}", path);
-    store_object(tmp_path, &path)?;
+fn flock_exclusive(file: &std::fs::File) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::AsRawFd;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
 
-    // TODO: update id
-    db::insert(conn, blob).map_err(Error::from)
+/// the flag of `cancel_on_signal`, once its handler is installed
+static CANCEL: std::sync::Mutex<Option<Arc<AtomicBool>>> = std::sync::Mutex::new(None);
+
+/// sets the returned flag on SIGINT or SIGTERM, for long operations such as push to stop at
+/// their next check, see `PushOptions::cancel`. a second signal exits at once with
+/// `EXIT_CANCELLED`. the handler is installed once per process, and every call returns the same
+/// flag, which stays set once a signal arrives.
+pub fn cancel_on_signal() -> Result<Arc<AtomicBool>> {
+    let mut cancel = CANCEL.lock().expect("cancel flag poisoned");
+    if let Some(flag) = &*cancel {
+        return Ok(flag.clone());
+    }
+    let flag = Arc::new(AtomicBool::new(false));
+    install_signal_handler(flag.clone())?;
+    *cancel = Some(flag.clone());
+    Ok(flag)
 }
 
-const BUF_SIZE: usize = 16 * 1024 * 1024;
+fn on_signal(flag: &AtomicBool) {
+    if flag.swap(true, Ordering::SeqCst) {
+        warn!("interrupted again, exiting");
+        std::process::exit(EXIT_CANCELLED);
+    }
+    info!("interrupted, cancelling");
+}
 
-pub fn get(conn: &mut db::Conn, filename: &str, out_filename: &str, dry_run: bool) -> Result<()> {
-    let mut blob = match db::by_filename(conn, filename)?.pop() {
-        Some(blob) => blob,
-        None => {
-            eprintln!("unknown filename: {}", filename);
-            //TODO
-            return Ok(());
-        }
+#[cfg(unix)]
+fn install_signal_handler(flag: Arc<AtomicBool>) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    // handlers are installed before returning, so no signal is missed
+    let (mut sigint, mut sigterm) = {
+        let _rt = rt.enter();
+        (
+            signal(SignalKind::interrupt())?,
+            signal(SignalKind::terminate())?,
+        )
     };
 
-    let mut decode_path = Vec::new();
-
-    //TODO: use graph?
-    while let Some(parent_hash) = &blob.parent_hash {
-        let parent_blob = db::by_content_hash(conn, parent_hash)?
-            .pop()
-            .expect(&format!("no blob with content_hash {}", parent_hash));
+    std::thread::spawn(move || {
+        rt.block_on(async {
+            loop {
+                tokio::select! {
+                    _ = sigint.recv() => (),
+                    _ = sigterm.recv() => (),
+                }
+                on_signal(&flag);
+            }
+        })
+    });
+    Ok(())
+}
 
-        let old_blob = std::mem::replace(&mut blob, parent_blob);
-        decode_path.push(old_blob);
-    }
+/// ctrl-c only, there is no SIGTERM
+#[cfg(not(unix))]
+fn install_signal_handler(flag: Arc<AtomicBool>) -> Result<()> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    std::thread::spawn(move || {
+        rt.block_on(async {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                on_signal(&flag);
+            }
+        })
+    });
+    Ok(())
+}
 
-    decode_path.reverse();
+const HASH_ALGO_KEY: &str = "hash_algo";
 
-    if dry_run {
-        for blob in decode_path {
-            println!("{} {}", filepath(&blob.store_hash), blob.filename);
+/// refuses a store tagged with a hash scheme other than `rw::HASH_ALGO`, as its content hashes
+/// would never match. an untagged store, new or created before the tag, is tagged with it.
+pub fn check_hash_algo(conn: &mut db::Conn) -> Result<()> {
+    match db::get_setting(conn, HASH_ALGO_KEY)? {
+        Some(algo) if algo == rw::HASH_ALGO => Ok(()),
+        Some(algo) => Err(Error::UnsupportedHashAlgo(algo)),
+        None => {
+            db::set_setting(conn, HASH_ALGO_KEY, rw::HASH_ALGO)?;
+            Ok(())
         }
+    }
+}
+
+/// refuses a store tagged with a hash scheme other than `rw::HASH_ALGO`, without tagging it. the
+/// tag is only read, so this runs on a store which is not migrated, or opened read-only.
+fn check_stored_hash_algo(conn: &mut db::Conn) -> Result<()> {
+    // a store created before settings has no tag
+    if !db::has_table(conn, "settings")? {
         return Ok(());
     }
+    match db::get_setting(conn, HASH_ALGO_KEY)? {
+        Some(algo) if algo != rw::HASH_ALGO => Err(Error::UnsupportedHashAlgo(algo)),
+        _ => Ok(()),
+    }
+}
 
-    assert!(blob.parent_hash.is_none());
+/// opens and prepares the database of the store, checking its hash scheme
+pub fn open_store() -> Result<db::Conn> {
+    open_store_path(db::dbpath())
+}
 
-    let tmp_dir = tmpdir();
-    let mut old_tmpfile = NamedTempFile::new_in(&tmp_dir)?;
-    let mut tmpfile = NamedTempFile::new_in(&tmp_dir)?;
+fn open_store_path(path: String) -> Result<db::Conn> {
+    let mut conn = db::open_path(path)?;
+    // a store of another scheme is refused before it is migrated
+    check_stored_hash_algo(&mut conn)?;
+    db::prepare(&mut conn)?;
+    check_hash_algo(&mut conn)?;
+    retry::load_retry_policy(&mut conn)?;
+    Ok(conn)
+}
 
-    let rt = tokio::runtime::Runtime::new()?;
-    let mut src_filepath = PathBuf::from(filepath(&blob.content_hash));
-    for delta_blob in decode_path {
-        use tokio::fs::File;
-        use tokio::io::*;
+/// env var overriding the object directory, to keep objects apart from meta.db
+pub const OBJECT_DIR_ENV: &str = "INCRESTORE_OBJECT_DIR";
 
-        let delta_filepath = filepath(&delta_blob.store_hash);
-        debug!("decode filename={}", delta_blob.filename);
-        debug!("trace={:?}, input={:?}", src_filepath, delta_filepath);
-        let (_input_meta, dst_meta) = rt.block_on(async {
-            let src_file = File::open(&src_filepath).await?;
-            let input_file = File::open(&delta_filepath).await?;
-            let dst_file = File::create(tmpfile.path()).await?;
+/// directory of object files, `{WORKDIR}/objects` unless overridden with INCRESTORE_OBJECT_DIR
+pub fn objectdir() -> String {
+    StoreDirs::from_env().objectdir
+}
 
-            delta::delta(
-                delta::ProcessMode::Decode,
-                BufReader::with_capacity(BUF_SIZE, src_file),
-                BufReader::with_capacity(BUF_SIZE, input_file),
-                BufWriter::with_capacity(BUF_SIZE, dst_file),
-            )
-            .await
-        })?;
+pub fn tmpdir() -> String {
+    StoreDirs::from_env().tmpdir()
+}
 
-        trace!("delta.content_hash={}", delta_blob.content_hash);
-        trace!("dst.content_hash  ={}", dst_meta.digest());
-        assert_eq!(delta_blob.content_hash, dst_meta.digest());
-        std::mem::swap(&mut tmpfile, &mut old_tmpfile);
-        src_filepath = old_tmpfile.path().to_path_buf();
+/// directories of a store: the workdir holding meta.db and temp files, and the object directory
+#[derive(Debug, Clone)]
+struct StoreDirs {
+    workdir: String,
+    objectdir: String,
+}
+
+impl StoreDirs {
+    /// the store of WORKDIR and INCRESTORE_OBJECT_DIR
+    fn from_env() -> Self {
+        let workdir = prefix();
+        let objectdir = match env::var(OBJECT_DIR_ENV) {
+            Ok(dir) if !dir.is_empty() => dir,
+            _ => format!("{}/objects", workdir),
+        };
+        StoreDirs { workdir, objectdir }
     }
 
-    // result: old_tmpfile
-    old_tmpfile.persist(out_filename)?;
+    /// a store at `workdir`, with objects in `{workdir}/objects`
+    fn at(workdir: &str) -> Self {
+        StoreDirs {
+            workdir: workdir.to_owned(),
+            objectdir: format!("{}/objects", workdir),
+        }
+    }
 
-    Ok(())
-}
+    fn dbpath(&self) -> String {
+        format!("{}/meta.db", self.workdir)
+    }
 
-pub fn exists(conn: &mut db::Conn, filename: &str) -> Result<()> {
-    let input_filename = Path::new(&filename).file_name().unwrap().to_str().unwrap();
+    fn filepath(&self, hash: &str) -> String {
+        format!("{}/{}/{}", self.objectdir, &hash[..2], &hash[2..])
+    }
 
-    let blobs = db::by_filename(conn, &input_filename)?;
-    if blobs.is_empty() {
-        std::process::exit(1);
-    } else {
-        println!("{}", blobs[0].store_hash);
+    fn tmpdir(&self) -> String {
+        let tmp_dir = format!("{}/tmp", self.workdir);
+        //TODO
+        std::fs::create_dir_all(&tmp_dir).ok();
+        tmp_dir
     }
-    Ok(())
 }
 
-pub fn rename(conn: &mut db::Conn, from_filename: &str, to_filename: &str) -> Result<()> {
-    let renamed = db::rename(conn, from_filename, to_filename)?;
-    if !renamed {
-        error!("file not exists: {}", from_filename);
-    }
-    Ok(())
+/// removes files under `tmpdir` not modified for `max_age`, e.g. temp files left by a killed
+/// push. files of running operations are younger than any sensible `max_age`. returns the number
+/// of removed files.
+pub fn gc_unreferenced_tmpfiles(tmpdir: &str, max_age: std::time::Duration) -> Result<usize> {
+    Ok(gc_tmpfiles(tmpdir, max_age, false)?.files)
 }
 
-pub fn dehydrate(conn: &mut db::Conn) -> Result<()> {
-    let blobs = db::all(conn)?;
-    let stats = Stats::from_blobs(blobs);
+/// `gc_unreferenced_tmpfiles` with the bytes removed. nothing is removed on `dry_run`.
+fn gc_tmpfiles(tmpdir: &str, max_age: std::time::Duration, dry_run: bool) -> Result<Reclaimed> {
+    let now = std::time::SystemTime::now();
+    let mut removed = Reclaimed::default();
+    for entry in walkdir::WalkDir::new(tmpdir) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let meta = entry.metadata()?;
+        let modified = meta.modified()?;
+        let age = match now.duration_since(modified) {
+            Ok(age) => age,
+            // modified in the future
+            Err(_e) => continue,
+        };
+        if age < max_age {
+            continue;
+        }
 
-    let root_candidates = stats.root_candidates();
-    for root_blob in root_candidates {
-        let path = filepath(&root_blob.blob.content_hash);
-        match std::fs::remove_file(&path) {
+        if dry_run {
+            removed.add(meta.len());
+            continue;
+        }
+        match std::fs::remove_file(entry.path()) {
             Ok(()) => {
-                info!("dehydrating blob={}", path);
-            }
-            Err(_e) => {
-                info!(
-                    "dehydrating blob={} failed, already dehydrated? err={:?}",
-                    path, _e
-                );
+                debug!("gc: removed {:?}, age={}s", entry.path(), age.as_secs());
+                removed.add(meta.len());
             }
+            // removed by its owner meanwhile
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
         }
     }
+    info!("gc: removed {} files from {}", removed.files, tmpdir);
+    Ok(removed)
+}
 
-    Ok(())
+static KEEP_TEMP: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// keeps inputs and outputs of decode hops whose hash mismatches, see `keep_decode_files`
+pub fn set_keep_temp(keep: bool) {
+    KEEP_TEMP.store(keep, std::sync::atomic::Ordering::SeqCst);
 }
 
-pub fn hydrate(conn: &mut db::Conn) -> Result<()> {
-    let blobs = db::all(conn)?;
-    let stats = Stats::from_blobs(blobs);
+/// set with `set_keep_temp` or the KEEP_TEMP env var
+pub fn keep_temp() -> bool {
+    KEEP_TEMP.load(std::sync::atomic::Ordering::SeqCst)
+        || env::var("KEEP_TEMP").is_ok_and(|v| !v.is_empty() && v != "0")
+}
 
-    let root_candidates = stats.root_candidates();
-    for root_blob in root_candidates {
-        let path = filepath(&root_blob.blob.content_hash);
-        info!("hydrating blob={}", path);
-        get(conn, &root_blob.blob.filename, &path, false)?;
-    }
+static RECORD_PROVENANCE: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(true);
 
-    Ok(())
+/// records where pushed and adopted blobs came from, see `db::Provenance`. disabled for stores
+/// which should not keep paths and hostnames.
+pub fn set_record_provenance(record: bool) {
+    RECORD_PROVENANCE.store(record, std::sync::atomic::Ordering::SeqCst);
 }
 
-fn archive_add_file<W>(ar: &mut tar::Builder<W>, path: &str) -> Result<()>
-where
-    W: std::io::Write,
-{
-    let meta = std::fs::metadata(path)?;
-    let size = meta.len();
+/// records `provenance` of a content unless disabled with `set_record_provenance`
+fn record_provenance(
+    conn: &mut db::Conn,
+    content_hash: &str,
+    provenance: db::Provenance,
+) -> Result<()> {
+    if RECORD_PROVENANCE.load(std::sync::atomic::Ordering::SeqCst) {
+        db::set_provenance(conn, content_hash, &provenance)?;
+    }
+    Ok(())
+}
 
-    let mut header = tar::Header::new_gnu();
-    let strip_path = Path::new(path)
-        .strip_prefix(&prefix())
-        .expect("invalid file");
-    header.set_path(strip_path)?;
-    header.set_size(size);
-    header.set_mode(0o644);
+/// the absolute form of `path`, or `path` itself if it can not be resolved
+fn absolute_path(path: &str) -> String {
+    match std::fs::canonicalize(path) {
+        Ok(path) => path.to_string_lossy().into_owned(),
+        Err(_) => path.to_owned(),
+    }
+}
 
-    if let Ok(time) = meta.modified() {
-        if let Ok(duration) = time.duration_since(std::time::SystemTime::UNIX_EPOCH) {
-            header.set_mtime(duration.as_secs());
+/// name of this host, None if it is unknown
+fn hostname() -> Option<String> {
+    #[cfg(unix)]
+    {
+        let mut buf = [0u8; 256];
+        if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+            return None;
         }
+        let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+        String::from_utf8(buf[..len].to_vec()).ok()
     }
+    #[cfg(not(unix))]
+    {
+        env::var("COMPUTERNAME").ok()
+    }
+}
 
-    header.set_cksum();
+/// copies the source, the delta and the decoded output of a mismatched decode hop to
+/// `{WORKDIR}/debug`, returning the directory. does nothing without `keep_temp`.
+fn keep_decode_files(
+    dirs: &StoreDirs,
+    blob: &Blob,
+    src_path: &Path,
+    delta_path: &Path,
+    dst_path: &Path,
+) -> Result<Option<PathBuf>> {
+    if !keep_temp() {
+        return Ok(None);
+    }
 
-    debug!("add file name={:?}, size={}", strip_path, size);
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let dir = PathBuf::from(format!(
+        "{}/debug/{}-{}",
+        dirs.workdir,
+        blob.store_hash,
+        now.as_nanos()
+    ));
+    std::fs::create_dir_all(&dir)?;
+
+    for (name, path) in &[("src", src_path), ("delta", delta_path), ("dst", dst_path)] {
+        let kept = dir.join(name);
+        std::fs::copy(path, &kept)?;
+        warn!(
+            "keep-temp: filename={} {}={:?}",
+            blob.filename,
+            name,
+            kept.canonicalize()?
+        );
+    }
+    Ok(Some(dir))
+}
 
-    let file = std::fs::File::open(path)?;
-    ar.append(&header, file)?;
-    Ok(())
+fn filepath(s: &str) -> String {
+    StoreDirs::from_env().filepath(s)
 }
 
-fn archive0<W>(conn: &mut db::Conn, w: W) -> Result<()>
+fn store_object<P>(src_path: NamedTempFile, dst_path: P) -> Result<()>
 where
-    W: std::io::Write,
+    P: AsRef<Path>,
 {
-    let mut ar = tar::Builder::new(w);
-    archive_add_file(&mut ar, &db::dbpath())?;
+    trace!(
+        "store_object: src={:?}, dst={:?}",
+        src_path.as_ref(),
+        dst_path.as_ref()
+    );
 
-    let blobs = db::all(conn)?;
-    for blob in blobs {
-        if blob.is_genesis() || !blob.is_root() {
-            archive_add_file(&mut ar, &filepath(&blob.store_hash))?;
+    let dir = match Path::new(dst_path.as_ref()).parent() {
+        Some(dir) => dir,
+        None => {
+            error!("failed to get a parent directory: {:?}", dst_path.as_ref());
+            retry::persist(src_path, dst_path, false)?;
+            return Ok(());
         }
-    }
+    };
+    std::fs::create_dir_all(dir)?;
+
+    let src_path = match retry::persist(src_path, &dst_path, false) {
+        Ok(_file) => return Ok(()),
+        Err(e) => {
+            // probably EXDEV: the object directory is on another filesystem than tmpdir
+            debug!(
+                "failed to persist object, retry from {:?}: {:?}",
+                dir, e.error
+            );
+            e.file
+        }
+    };
+    let mut dst_tmpfile = NamedTempFile::new_in(dir)?;
+    std::io::copy(&mut std::fs::File::open(src_path.path())?, &mut dst_tmpfile)?;
+    dst_tmpfile.as_file().sync_all()?;
+    retry::persist(dst_tmpfile, dst_path, false)?;
     Ok(())
 }
 
-pub fn archive(conn: &mut db::Conn, filename: &str) -> Result<()> {
-    if filename != "-" {
-        let file = std::fs::File::create(filename)?;
-        archive0(conn, file)
-    } else {
-        let stdout = std::io::stdout();
-        let out = stdout.lock();
-        archive0(conn, out)
+fn update_blob(conn: &mut db::Conn, tmp_path: NamedTempFile, blob: &Blob) -> Result<bool> {
+    let path = filepath(&blob.store_hash);
+
+    trace!("path={:?}", path);
+    store_object(tmp_path, &path)?;
+
+    // TODO: update id
+    insert_blob(conn, blob)
+}
+
+/// `db::insert` refusing a delta whose parent content does not exist, e.g. removed by a cleanup
+/// running at the same time
+fn insert_blob(conn: &mut db::Conn, blob: &Blob) -> Result<bool> {
+    match db::insert_with_parent(conn, blob)? {
+        Some(inserted) => Ok(inserted),
+        None => Err(Error::MissingParent {
+            filename: blob.filename.clone(),
+            parent_hash: blob.parent_hash.clone().unwrap_or_default(),
+        }),
     }
 }
 
-pub fn cleanup(conn: &mut db::Conn) -> Result<()> {
-    let blobs = db::all(conn)?;
-    let stats = Stats::from_blobs(blobs);
+const BUF_SIZE: usize = 16 * 1024 * 1024;
 
-    let mut root_candidates = stats.root_candidates();
-    root_candidates.sort_by_key(|blob| {
-        // sort by score desc
-        u64::max_value() - blob.score
-    });
+#[derive(Debug, Clone, Default)]
+pub struct GetOptions {
+    pub dry_run: bool,
+    /// replace out_filename if it already exists. without this or `backup`, get fails on an
+    /// existing out_filename.
+    pub overwrite: bool,
+    /// rename existing out_filename to `{out_filename}.bak` before replacing it
+    pub backup: bool,
+    /// create missing parent directories of out_filename
+    pub parents: bool,
+    /// decode the last step into `{out_filename}.partial` next to the output instead of tmpdir,
+    /// then rename it over out_filename. replaces an existing out_filename.
+    pub in_place: bool,
+    /// keep contents decoded in the middle of the chain in an intermediate cache of this many
+    /// bytes, and resume decoding from the deepest cached one
+    pub intermediate_cache: Option<u64>,
+    /// hash the root object and each cached or shared intermediate right before a decode step
+    /// reads it. a broken cache entry fails the get instead of being decoded again.
+    pub verify_chain: bool,
+    pub format: GetFormat,
+}
 
-    {
-        let mut s = String::new();
-        for root_blob in &root_candidates {
-            let alias = root_blob.alias;
-            s += &format!(
-                "{}={:.02}%,{} ",
-                alias.id,
-                alias.compression_ratio() * 100.0,
-                bytesize::ByteSize(root_blob.score),
-            );
+/// what `get` writes to the output
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum GetFormat {
+    /// the decoded content of the version
+    #[default]
+    Content,
+    /// the stored object as is: delta bytes, or the full content of a root blob
+    Delta,
+}
+
+impl std::str::FromStr for GetFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "content" => Ok(GetFormat::Content),
+            "delta" => Ok(GetFormat::Delta),
+            _ => Err(crate::format_err!("unknown get format: {}", s)),
         }
-        debug!("root compression ratio: {}", s);
     }
+}
 
-    // TODO: store distances
+/// moves a reconstructed file to out_filename. the final step is always a rename within the
+/// destination filesystem, so readers never observe a partially written output.
+fn persist_output(tmpfile: NamedTempFile, out_filename: &str, opts: &GetOptions) -> Result<()> {
+    let out_path = Path::new(out_filename);
+    let out_dir = match out_path.parent() {
+        Some(dir) if dir != Path::new("") => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
 
-    for root_blob in root_candidates.into_iter().skip(max_root_blobs()) {
-        let root = root_blob.blob;
-        db::remove(conn, &root)?;
-        std::fs::remove_file(&filepath(&root.content_hash))?;
+    if opts.parents {
+        std::fs::create_dir_all(&out_dir)?;
     }
 
-    Ok(())
-}
+    if out_path.exists() {
+        if opts.backup {
+            let backup_filename = format!("{}.bak", out_filename);
+            info!("backup existing output to {}", backup_filename);
+            retry::rename(out_path, &backup_filename)?;
+        } else if !opts.overwrite {
+            return Err(crate::format_err!(
+                "output already exists, use --overwrite to replace it: {}",
+                out_filename
+            ));
+        }
+    }
 
-fn store_blob<F>(input_filepath: &str, f: F) -> Result<Blob>
-where
-    F: FnOnce(&Path, &Path) -> std::io::Result<WriteMetadata>,
-{
-    let input_filename = Path::new(&input_filepath)
-        .file_name()
-        .unwrap()
-        .to_str()
-        .unwrap();
+    // without overwrite, an output created while decoding is not replaced either
+    let persist = |tmpfile: NamedTempFile| retry::persist(tmpfile, out_path, !opts.overwrite);
+
+    let tmpfile = match persist(tmpfile) {
+        Ok(_file) => return Ok(()),
+        Err(e) if e.error.kind() == io::ErrorKind::AlreadyExists => return Err(e.error.into()),
+        Err(e) => {
+            // probably EXDEV: tmpdir and out_filename are on different filesystems. copy to a
+            // temporary file next to out_filename and rename it instead.
+            debug!(
+                "failed to persist output, retry from {:?}: {:?}",
+                out_dir, e.error
+            );
+            e.file
+        }
+    };
 
-    let tmp_dir = tmpdir();
-    let tmp_unzip_path = NamedTempFile::new_in(&tmp_dir)?;
+    let mut dst_tmpfile = NamedTempFile::new_in(&out_dir)?;
+    std::io::copy(&mut std::fs::File::open(tmpfile.path())?, &mut dst_tmpfile)?;
+    dst_tmpfile.as_file().sync_all()?;
+    persist(dst_tmpfile)?;
 
-    let meta = f(Path::new(input_filepath), tmp_unzip_path.path())?;
+    Ok(())
+}
 
-    let input_blob = meta.blob(input_filename);
-    let store_filepath = filepath(&input_blob.store_hash);
-    store_object(tmp_unzip_path, &store_filepath)?;
-    Ok(input_blob)
+#[cfg(test)]
+thread_local! {
+    /// free space seen by `preallocate`, to simulate a full filesystem in tests
+    static FAKE_FREE_SPACE: std::cell::Cell<Option<u64>> = const { std::cell::Cell::new(None) };
 }
 
-fn append_full(conn: &mut db::Conn, input_filepath: &str, ty: FileType) -> Result<Option<Blob>> {
-    trace!("append_full: input_filepath={} ty={:?}", input_filepath, ty);
+/// reserves `len` bytes of a file, so a full filesystem fails here instead of in the middle of a
+/// decode
+fn preallocate(file: &std::fs::File, len: u64) -> io::Result<()> {
+    #[cfg(test)]
+    if let Some(free) = FAKE_FREE_SPACE.with(|free| free.get()) {
+        if len > free {
+            return Err(io::Error::from_raw_os_error(libc::ENOSPC));
+        }
+    }
 
-    let blob = match ty {
-        FileType::Zip => store_blob(input_filepath, |p1, p2| store_zip(p1, p2, true))?,
-        FileType::Gz => store_blob(input_filepath, |p1, p2| gz::store_gz(p1, p2))?,
-        FileType::Plain => store_blob(input_filepath, |p1, p2| gz::store_plain(p1, p2))?,
-    };
-    if db::insert(conn, &blob)? {
-        Ok(Some(blob))
-    } else {
-        Ok(None)
+    #[cfg(target_os = "linux")]
+    if len > 0 {
+        use std::os::unix::io::AsRawFd;
+        match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, len as libc::off_t) } {
+            0 => return Ok(()),
+            libc::EOPNOTSUPP => (),
+            errno => return Err(io::Error::from_raw_os_error(errno)),
+        }
     }
+    file.set_len(len)
 }
 
-use std::sync::{atomic::AtomicUsize, Arc};
+/// output of `get --in-place`, removed unless it is renamed over the output
+struct PartialOutput {
+    path: PathBuf,
+    file: std::fs::File,
+    persisted: bool,
+}
 
-fn append_delta(
-    input_blob: &Blob,
-    src_blob: &Blob,
-    race: Arc<AtomicUsize>,
-) -> Result<Option<(NamedTempFile, Blob)>> {
-    let rt = tokio::runtime::Runtime::new()?;
-    let sw = Stopwatch::start_new();
-    let input_filepath = filepath(&input_blob.content_hash);
+impl PartialOutput {
+    fn create(out_filename: &str, len: u64, opts: &GetOptions) -> Result<Self> {
+        let path = PathBuf::from(format!("{}.partial", out_filename));
+        if opts.parents {
+            if let Some(dir) = path.parent().filter(|dir| *dir != Path::new("")) {
+                std::fs::create_dir_all(dir)?;
+            }
+        }
 
-    let (tmp, blob) = {
-        let tmp_dir = tmpdir();
-        let tmp_path = NamedTempFile::new_in(&tmp_dir)?;
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        let partial = PartialOutput {
+            path,
+            file,
+            persisted: false,
+        };
+        preallocate(&partial.file, len)?;
+        Ok(partial)
+    }
 
-        let src_hash = &src_blob.content_hash;
-        let src_filepath = filepath(src_hash);
+    fn persist(mut self, out_filename: &str, opts: &GetOptions) -> Result<()> {
+        self.file.sync_all()?;
+        if opts.backup && Path::new(out_filename).exists() {
+            let backup_filename = format!("{}.bak", out_filename);
+            info!("backup existing output to {}", backup_filename);
+            retry::rename(out_filename, &backup_filename)?;
+        }
+        retry::rename(&self.path, out_filename)?;
+        self.persisted = true;
+        Ok(())
+    }
+}
 
-        let res = rt.block_on(async {
-            use tokio::{fs::File, io::*};
+impl Drop for PartialOutput {
+    fn drop(&mut self) {
+        if !self.persisted {
+            if let Err(e) = std::fs::remove_file(&self.path) {
+                warn!("failed to remove {:?}: {}", self.path, e);
+            }
+        }
+    }
+}
 
-            let src_file = File::open(&src_filepath).await?;
-            let input_file = File::open(&input_filepath).await?;
-            let dst_file = File::create(tmp_path.path()).await?;
+/// root blob of a version, and delta blobs to apply on it in decode order
+fn decode_path(conn: &mut db::Conn, filename: &str) -> Result<(Blob, Vec<Blob>)> {
+    let mut blob = match db::by_filename(conn, filename)?.pop() {
+        Some(blob) => blob,
+        None => {
+            return Err(Error::UnknownFilename(filename.to_owned()));
+        }
+    };
+
+    let mut decode_path = Vec::new();
+
+    //TODO: use graph?
+    while let Some(parent_hash) = &blob.parent_hash {
+        let parent_blob = match db::by_content_hash(conn, parent_hash)?.pop() {
+            Some(blob) => blob,
+            None => return Err(Error::MissingObject(parent_hash.to_owned())),
+        };
+
+        let old_blob = std::mem::replace(&mut blob, parent_blob);
+        decode_path.push(old_blob);
+    }
+
+    decode_path.reverse();
+    Ok((blob, decode_path))
+}
+
+/// hashes of objects `get` reads to decode a version, root object first
+pub fn decode_objects(conn: &mut db::Conn, filename: &str) -> Result<Vec<String>> {
+    let (root, decode_path) = decode_path(conn, filename)?;
+    let hashes = std::iter::once(root.content_hash)
+        .chain(decode_path.into_iter().map(|blob| blob.store_hash))
+        .collect();
+    Ok(hashes)
+}
+
+/// blobs `get` decodes the latest version of `filename` through, root first, with their object
+/// files. what `get --dry-run` prints, for tools.
+pub fn debug_decode_path(conn: &mut db::Conn, filename: &str) -> Result<Vec<(Blob, PathBuf)>> {
+    let (root, decode_path) = decode_path(conn, filename)?;
+    let path = std::iter::once(root)
+        .chain(decode_path)
+        .map(|blob| {
+            let object = PathBuf::from(filepath(&blob.store_hash));
+            (blob, object)
+        })
+        .collect();
+    Ok(path)
+}
+
+/// lists objects needed to decode a version, without decoding it. with `fetch_dir`, objects are
+/// also copied to `{fetch_dir}/objects`, so the directory can be used as a WORKDIR of a client.
+pub fn get_parents_only(
+    conn: &mut db::Conn,
+    filename: &str,
+    fetch_dir: Option<&str>,
+) -> Result<()> {
+    for hash in decode_objects(conn, filename)? {
+        let path = filepath(&hash);
+        if !Path::new(&path).exists() {
+            return Err(Error::MissingObject(path));
+        }
+        println!("{} {}", hash, path);
+
+        if let Some(fetch_dir) = fetch_dir {
+            let dst = Path::new(fetch_dir)
+                .join("objects")
+                .join(&hash[..2])
+                .join(&hash[2..]);
+            std::fs::create_dir_all(dst.parent().expect("no parent"))?;
+            std::fs::copy(&path, &dst)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn get(conn: &mut db::Conn, filename: &str, out_filename: &str, dry_run: bool) -> Result<()> {
+    let opts = GetOptions {
+        dry_run,
+        ..Default::default()
+    };
+    get_with_options(conn, filename, out_filename, &opts)
+}
+
+pub fn get_with_options(
+    conn: &mut db::Conn,
+    filename: &str,
+    out_filename: &str,
+    opts: &GetOptions,
+) -> Result<()> {
+    get_with_options_progress(conn, filename, out_filename, opts, |_decoded, _total| {})
+}
+
+/// `get` calling `progress(bytes_decoded, total_estimated_bytes)` after each delta step. the
+/// total is the sum of content sizes of the decode chain, which bytes_decoded reaches at the end.
+pub fn get_with_progress<F>(
+    conn: &mut db::Conn,
+    filename: &str,
+    out_filename: &str,
+    progress: F,
+) -> Result<()>
+where
+    F: Fn(u64, u64),
+{
+    get_with_options_progress(conn, filename, out_filename, &Default::default(), progress)
+}
+
+/// `get` with `GetFormat::Delta`: copies the stored object of the blob `get` would decode
+fn get_object(
+    conn: &mut db::Conn,
+    dirs: &StoreDirs,
+    filename: &str,
+    out_filename: &str,
+    opts: &GetOptions,
+) -> Result<()> {
+    let blob = match db::by_filename(conn, filename)?.pop() {
+        Some(blob) => blob,
+        None if db::chunked_by_filename(conn, filename)?.is_some() => {
+            return Err(crate::format_err!(
+                "{} is stored as chunks, it has no single object",
+                filename
+            ));
+        }
+        None => return Err(Error::UnknownFilename(filename.to_owned())),
+    };
+
+    let path = dirs.filepath(&blob.store_hash);
+    if opts.dry_run {
+        println!("{} {}", path, blob.filename);
+        return Ok(());
+    }
+    if !Path::new(&path).exists() {
+        return Err(Error::MissingObject(path));
+    }
+
+    let tmpfile = NamedTempFile::new_in(dirs.tmpdir())?;
+    std::fs::copy(&path, tmpfile.path())?;
+    persist_output(tmpfile, out_filename, opts)
+}
+
+/// `get_with_options` with the progress callback of `get_with_progress`
+pub fn get_with_options_progress<F>(
+    conn: &mut db::Conn,
+    filename: &str,
+    out_filename: &str,
+    opts: &GetOptions,
+    progress: F,
+) -> Result<()>
+where
+    F: Fn(u64, u64),
+{
+    let dirs = StoreDirs::from_env();
+    get_in(conn, &dirs, filename, out_filename, opts, progress)
+}
+
+/// `get_with_options_progress` of the store in `dirs`
+fn get_in<F>(
+    conn: &mut db::Conn,
+    dirs: &StoreDirs,
+    filename: &str,
+    out_filename: &str,
+    opts: &GetOptions,
+    progress: F,
+) -> Result<()>
+where
+    F: Fn(u64, u64),
+{
+    if opts.format == GetFormat::Delta {
+        return get_object(conn, dirs, filename, out_filename, opts);
+    }
+    if db::by_filename(conn, filename)?.is_empty() {
+        if let Some(file) = db::chunked_by_filename(conn, filename)? {
+            chunk::get_chunked(conn, dirs, &file, out_filename, opts)?;
+            progress(file.content_size, file.content_size);
+            return Ok(());
+        }
+    }
+    let (blob, decode_path) = decode_path(conn, filename)?;
+    let content_hash = &decode_path.last().unwrap_or(&blob).content_hash;
+    if db::is_exploded(conn, content_hash)? {
+        info!("get: {} is stored as an exploded bundle", filename);
+    }
+
+    if opts.dry_run {
+        for blob in decode_path {
+            println!("{} {}", dirs.filepath(&blob.store_hash), blob.filename);
+        }
+        return Ok(());
+    }
+
+    assert!(blob.parent_hash.is_none());
+
+    let tmp_dir = dirs.tmpdir();
+    let mut old_tmpfile = NamedTempFile::new_in(&tmp_dir)?;
+    let mut tmpfile = NamedTempFile::new_in(&tmp_dir)?;
+
+    let cache = match opts.intermediate_cache {
+        Some(budget) => Some(cache::IntermediateCache::new(dirs, budget)?),
+        None => None,
+    };
+    // decoding resumes after the deepest cached content of the chain
+    let mut start = 0;
+    let mut src_filepath = PathBuf::from(dirs.filepath(&blob.content_hash));
+    if let Some(cache) = &cache {
+        for (idx, delta_blob) in decode_path.iter().enumerate().rev() {
+            let cached = if opts.verify_chain {
+                // decoding reads the verified copy, not the entry
+                let copied = cache.copy_verified(&delta_blob.content_hash, old_tmpfile.path())?;
+                Some(old_tmpfile.path().to_path_buf()).filter(|_| copied)
+            } else {
+                cache.lookup(&delta_blob.content_hash)?
+            };
+            if let Some(path) = cached {
+                info!("get: resuming after cached {}", delta_blob.filename);
+                start = idx + 1;
+                src_filepath = path;
+                break;
+            }
+        }
+    }
+
+    let root_path = Some(dirs.filepath(&blob.content_hash)).filter(|_| start == 0);
+    let delta_paths = decode_path[start..]
+        .iter()
+        .map(|blob| dirs.filepath(&blob.store_hash));
+    for path in root_path.into_iter().chain(delta_paths) {
+        if !Path::new(&path).exists() {
+            return Err(Error::MissingObject(path));
+        }
+    }
+    if opts.verify_chain && start == 0 {
+        check_content(&src_filepath, &blob)?;
+    }
+
+    let total_bytes = blob.content_size
+        + decode_path
+            .iter()
+            .map(|blob| blob.content_size)
+            .sum::<u64>();
+    let mut decoded_bytes = blob.content_size
+        + decode_path[..start]
+            .iter()
+            .map(|blob| blob.content_size)
+            .sum::<u64>();
+
+    // the output is preallocated before decoding, so a full destination fails early
+    let mut partial = if opts.in_place {
+        let content_size = decode_path.last().unwrap_or(&blob).content_size;
+        Some(PartialOutput::create(out_filename, content_size, opts)?)
+    } else {
+        None
+    };
+
+    let rt = tokio::runtime::Runtime::new()?;
+    if start == decode_path.len() {
+        // a root without an alias, e.g. genesis, or a cached content: nothing to decode
+        let src_blob = decode_path.last().unwrap_or(&blob);
+        match &mut partial {
+            Some(partial) => {
+                let mut w = HashRW::new(&partial.file);
+                io::copy(&mut std::fs::File::open(&src_filepath)?, &mut w)?;
+                if w.meta().digest() != src_blob.content_hash {
+                    return Err(Error::ChecksumMismatch {
+                        filename: src_blob.filename.clone(),
+                        expected: src_blob.content_hash.clone(),
+                        actual: w.meta().digest(),
+                    });
+                }
+            }
+            None if src_filepath != old_tmpfile.path() => {
+                std::fs::copy(&src_filepath, old_tmpfile.path())?;
+            }
+            None => (),
+        }
+        progress(decoded_bytes, total_bytes);
+    }
+    let steps = decode_path.len();
+    for (step, delta_blob) in decode_path.into_iter().enumerate().skip(start) {
+        use tokio::fs::File;
+        use tokio::io::*;
+
+        // the last step of --in-place writes to the preallocated output
+        let dst = match &partial {
+            Some(partial) if step + 1 == steps => Some(partial.file.try_clone()?),
+            _ => None,
+        };
+        let dst_path = match &partial {
+            Some(partial) if dst.is_some() => partial.path.clone(),
+            _ => tmpfile.path().to_path_buf(),
+        };
 
-            let race = RaceWrite::new(BufWriter::with_capacity(BUF_SIZE, dst_file), race);
+        let delta_filepath = dirs.filepath(&delta_blob.store_hash);
+        debug!("decode filename={}", delta_blob.filename);
+        debug!("trace={:?}, input={:?}", src_filepath, delta_filepath);
+        let dst_meta = rt.block_on(async {
+            let src_file = File::open(&src_filepath).await?;
+            let input_file = File::open(&delta_filepath).await?;
+            let dst_file = match dst {
+                Some(file) => File::from_std(file),
+                None => File::create(&dst_path).await?,
+            };
 
             delta::delta(
-                delta::ProcessMode::Encode,
+                delta::ProcessMode::Decode,
                 BufReader::with_capacity(BUF_SIZE, src_file),
                 BufReader::with_capacity(BUF_SIZE, input_file),
-                race,
+                BufWriter::with_capacity(BUF_SIZE, dst_file),
             )
             .await
-        });
+            .map_err(|error| crate::Error::Delta {
+                filename: delta_blob.filename.clone(),
+                error,
+            })
+        })?;
 
-        let (_input_meta, dst_meta) = match res {
-            Ok(s) => s,
-            Err(e) => {
-                if e.kind() == io::ErrorKind::Other {
-                    // timeout from race
-                    return Ok(None);
-                } else {
-                    return Err(e.into());
-                }
+        trace!("delta.content_hash={}", delta_blob.content_hash);
+        trace!("dst.content_hash  ={}", dst_meta.digest());
+        if delta_blob.content_hash != dst_meta.digest() {
+            keep_decode_files(
+                dirs,
+                &delta_blob,
+                &src_filepath,
+                Path::new(&delta_filepath),
+                &dst_path,
+            )?;
+            return Err(crate::Error::ChecksumMismatch {
+                filename: delta_blob.filename,
+                expected: delta_blob.content_hash,
+                actual: dst_meta.digest(),
+            });
+        }
+        if let Some(cache) = cache.as_ref().filter(|_| step + 1 < steps) {
+            // the cache only saves work, a get does not fail on it
+            if let Err(e) = cache.insert(&delta_blob.content_hash, &dst_path) {
+                warn!("get: failed to cache {}: {}", delta_blob.filename, e);
             }
+        }
+        std::mem::swap(&mut tmpfile, &mut old_tmpfile);
+        src_filepath = old_tmpfile.path().to_path_buf();
+
+        decoded_bytes += delta_blob.content_size;
+        progress(decoded_bytes, total_bytes);
+    }
+
+    if let Some(partial) = partial {
+        return partial.persist(out_filename, opts);
+    }
+
+    // result: old_tmpfile
+    persist_output(old_tmpfile, out_filename, opts)?;
+
+    Ok(())
+}
+
+/// `get` for async callers. decode runs on a blocking thread with its own database connection,
+/// and the result is streamed into `w`.
+pub async fn get_async<W>(filename: String, w: W) -> Result<WriteMetadata>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    let tmpfile = NamedTempFile::new_in(tmpdir())?;
+    let tmp_path = tmpfile.path().to_str().expect("invalid tmpdir").to_owned();
+
+    tokio::task::spawn_blocking(move || -> Result<()> {
+        let mut conn = open_store()?;
+        // replaces the empty tmpfile
+        let opts = GetOptions {
+            overwrite: true,
+            ..Default::default()
         };
+        get_with_options(&mut conn, &filename, &tmp_path, &opts)
+    })
+    .await??;
 
-        let mut blob = dst_meta.blob(&input_blob.filename);
-        blob.content_size = input_blob.content_size;
-        blob.content_hash = input_blob.content_hash.clone();
-        blob.parent_hash = Some(src_hash.to_owned());
+    let mut src = tokio::fs::File::open(tmpfile.path()).await?;
+    let mut dst = HashRW::new(w);
+    tokio::io::copy(&mut src, &mut dst).await?;
+    tokio::io::AsyncWriteExt::flush(&mut dst).await?;
 
-        trace!(
-            "content_hash={}, store_hash={}",
-            blob.content_hash,
-            blob.store_hash
-        );
-        (tmp_path, blob)
-    };
-    let dt_store_delta = sw.elapsed_ms();
+    Ok(dst.meta())
+}
 
-    info!(
-        "append_delta: ratio={:.02}%, dt_store_delta={}ms",
-        blob.compression_ratio() * 100.0,
-        dt_store_delta,
-    );
-    Ok(Some((tmp, blob)))
+/// `push` for async callers, runs on a blocking thread with its own database connection
+pub async fn push_async(input_filepath: String, ty: FileType) -> Result<()> {
+    tokio::task::spawn_blocking(move || {
+        let mut conn = open_store()?;
+        push(&mut conn, &input_filepath, ty)
+    })
+    .await?
 }
 
-fn ratio_summary(blobs: &[(NamedTempFile, Blob)]) -> String {
-    let mut s = String::new();
-    for blob in blobs {
-        let blob = &blob.1;
-        s += &format!("{}={:.02}% ", blob.id, blob.compression_ratio() * 100.0);
+pub fn exists(conn: &mut db::Conn, filename: &str) -> Result<()> {
+    let input_filename = Path::new(&filename).file_name().unwrap().to_str().unwrap();
+
+    let blobs = db::by_filename(conn, &input_filename)?;
+    if blobs.is_empty() {
+        std::process::exit(1);
+    } else {
+        println!("{}", blobs[0].store_hash);
     }
-    s
+    Ok(())
 }
 
-pub fn push(conn: &mut db::Conn, input_filepath: &str, ty: FileType) -> Result<()> {
-    debug!("push: input_filepath={}", input_filepath);
+pub const EVENT_ROOT_ADDED: &str = "ROOT_ADDED";
+pub const EVENT_ROOT_REMOVED: &str = "ROOT_REMOVED";
+
+/// a blob becoming a root blob, or no longer being one. recorded in the events table by the
+/// operation, see `root_transitions`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RootTransition {
+    /// `EVENT_ROOT_ADDED` or `EVENT_ROOT_REMOVED`
+    pub event: String,
+    pub store_hash: String,
+    pub reason: String,
+}
 
-    let root_blobs = db::roots(conn)?;
+impl RootTransition {
+    fn new(event: &str, root: &Blob, reason: &str) -> Self {
+        RootTransition {
+            event: event.to_owned(),
+            store_hash: root.store_hash.clone(),
+            reason: reason.to_owned(),
+        }
+    }
+}
 
-    let sw = Stopwatch::start_new();
-    let input_blob = match append_full(conn, input_filepath, ty)? {
-        Some(blob) => blob,
-        None => {
-            info!("push: content already exists, skipping");
-            return Ok(());
+/// root transitions in the events table, oldest first, with the time of each
+pub fn root_transitions(
+    conn: &mut db::Conn,
+) -> Result<Vec<(time::OffsetDateTime, RootTransition)>> {
+    let transitions = db::events(conn)?
+        .into_iter()
+        .filter(|event| {
+            event.event_type == EVENT_ROOT_ADDED || event.event_type == EVENT_ROOT_REMOVED
+        })
+        .map(|event| {
+            let root = RootTransition {
+                event: event.event_type,
+                store_hash: event.store_hash,
+                reason: event.reason.unwrap_or_default(),
+            };
+            (event.timestamp, root)
+        })
+        .collect();
+    Ok(transitions)
+}
+
+/// appends `roots` to the events table
+fn log_roots(conn: &mut db::Conn, roots: &[RootTransition]) -> Result<()> {
+    for root in roots {
+        db::event_append(conn, &root.event, &root.store_hash, Some(&root.reason))?;
+    }
+    Ok(())
+}
+
+/// appends an entry of `op` with `details` to the oplog
+fn log_op(conn: &mut db::Conn, op: &str, details: serde_json::Value) -> Result<()> {
+    db::oplog_append(conn, op, &details.to_string())?;
+    Ok(())
+}
+
+/// renames the blobs of `from_filename`, then checks them with `verify_scope`. objects are not
+/// touched, so the check is cheap unless `verify` decodes them.
+pub fn rename(
+    conn: &mut db::Conn,
+    from_filename: &str,
+    to_filename: &str,
+    verify: bool,
+) -> Result<()> {
+    let ids = db::by_filename(conn, from_filename)?
+        .iter()
+        .map(|blob| blob.id)
+        .collect::<Vec<_>>();
+
+    // the entry is kept only along with the rename
+    db::savepoint(conn, "rename")?;
+    let res = (|| -> Result<bool> {
+        let renamed = db::rename(conn, from_filename, to_filename)?;
+        if renamed {
+            let details = serde_json::json!({
+                "from": from_filename,
+                "to": to_filename,
+                "ids": ids,
+            });
+            db::oplog_append(conn, "rename", &details.to_string())?;
+            verify_scope(conn, &ids, verify_depth(verify))?;
+        }
+        Ok(renamed)
+    })();
+    let renamed = match res {
+        Ok(renamed) => renamed,
+        Err(e) => {
+            db::rollback_to(conn, "rename")?;
+            return Err(e);
         }
     };
-    info!("push: append_full={}ms", sw.elapsed_ms(),);
+    db::release(conn, "rename")?;
 
-    if root_blobs.is_empty() {
-        info!("push: no root blobs: genesis");
-        return Ok(());
+    if !renamed {
+        error!("file not exists: {}", from_filename);
     }
+    Ok(())
+}
 
-    let race = Arc::new(AtomicUsize::new(0));
+/// blobs needed to regenerate a dehydrated root: its decode chain, from a full root blob to the
+/// alias delta of the root
+fn dehydrate_deps(conn: &mut db::Conn, root: &Blob) -> Result<Vec<Blob>> {
+    Ok(db::chain(conn, &root.content_hash)?)
+}
 
-    let link_blobs = root_blobs
-        .into_par_iter()
-        .map(|root_blob| append_delta(&input_blob, &root_blob, race.clone()))
-        .collect::<Result<Vec<_>>>()?;
+/// object files of the dependency chain which are missing
+fn missing_deps(root: &Blob, deps: &[Blob]) -> Vec<String> {
+    let (first, last) = match (deps.first(), deps.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return vec![root.content_hash.clone()],
+    };
 
-    let mut link_blobs = link_blobs.into_iter().filter_map(|v| v).collect::<Vec<_>>();
+    let mut missing = Vec::new();
+    if first.is_same_content(root) || last.is_root() {
+        // no alias delta, the root would be the only copy of its content
+        missing.push(root.content_hash.clone());
+    }
+    let root_path = filepath(&first.content_hash);
+    let delta_paths = deps[1..].iter().map(|blob| filepath(&blob.store_hash));
+    for path in std::iter::once(root_path).chain(delta_paths) {
+        if !Path::new(&path).exists() {
+            missing.push(path);
+        }
+    }
+    missing
+}
 
-    link_blobs.sort_by_key(|blob| blob.1.store_size);
+/// removes content files of root candidates, which can be regenerated from their alias deltas.
+/// the dependency chain of each root is verified first and protected from cleanup until the root
+/// is hydrated again. with `dry_run`, only reports the root objects which would be removed.
+pub fn dehydrate(conn: &mut db::Conn, dry_run: bool) -> Result<DehydrateReport> {
+    let stats = load_stats(conn)?;
 
-    debug!("compression ratio: {}", ratio_summary(&link_blobs));
+    let mut plan = Vec::new();
+    let mut report = DehydrateReport::default();
+    for root_blob in stats.root_candidates() {
+        let root = root_blob.blob;
+        let path = filepath(&root.content_hash);
+        let size = match std::fs::metadata(&path) {
+            Ok(meta) => meta.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                info!("blob={} already dehydrated", root.content_hash);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
 
-    let (tmp_path, blob) = link_blobs.into_iter().next().expect("no blobs");
-    // optimal block
-    if !update_blob(conn, tmp_path, &blob)? {
-        info!(
-            "append_delta: failed to insert, store_hash={}",
-            blob.store_hash
-        );
+        let deps = dehydrate_deps(conn, root)?;
+        let missing = missing_deps(root, &deps);
+        if !missing.is_empty() {
+            return Err(crate::format_err!(
+                "refusing to dehydrate {}: missing dependencies {:?}",
+                root.filename,
+                missing
+            ));
+        }
+        report.total_size += size;
+        report.roots.push(DehydrateEntry {
+            filename: root.filename.clone(),
+            path,
+            size,
+        });
+        plan.push((root, deps));
     }
 
-    cleanup(conn)?;
+    if dry_run {
+        return Ok(report);
+    }
 
-    Ok(())
-}
+    for (root, deps) in plan {
+        let store_hashes = deps
+            .iter()
+            .map(|blob| blob.store_hash.clone())
+            .collect::<Vec<_>>();
+        db::protect(conn, &root.content_hash, &store_hashes)?;
 
-pub fn bench_zip(input_filepath: &str, parallel: bool) -> Result<()> {
-    let tmp_dir = tmpdir();
-    let tempfile = NamedTempFile::new_in(&tmp_dir)?;
+        let path = filepath(&root.content_hash);
+        std::fs::remove_file(&path)?;
+        info!("dehydrating blob={}", path);
+    }
+
+    Ok(report)
+}
+
+/// a root object removed by `dehydrate`
+#[derive(Debug, Clone, Serialize)]
+pub struct DehydrateEntry {
+    pub filename: String,
+    pub path: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DehydrateReport {
+    pub roots: Vec<DehydrateEntry>,
+    /// bytes reclaimed by removing the root objects
+    pub total_size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct DehydratedRoot {
+    pub content_hash: String,
+    pub filename: Option<String>,
+    pub deps: Vec<Blob>,
+    /// missing objects of the dependency chain. empty if the root can be regenerated.
+    pub missing: Vec<String>,
+}
+
+pub fn dehydrated_roots(conn: &mut db::Conn) -> Result<Vec<DehydratedRoot>> {
+    let mut roots = Vec::new();
+    for content_hash in db::dehydrated_roots(conn)? {
+        let root = db::by_content_hash(conn, &content_hash)?
+            .into_iter()
+            .find(|blob| blob.is_root());
+        let (filename, deps, missing) = match root {
+            Some(root) => {
+                let deps = dehydrate_deps(conn, &root)?;
+                let missing = missing_deps(&root, &deps);
+                (Some(root.filename), deps, missing)
+            }
+            // root blob was removed by cleanup
+            None => (None, Vec::new(), Vec::new()),
+        };
+        roots.push(DehydratedRoot {
+            content_hash,
+            filename,
+            deps,
+            missing,
+        });
+    }
+    Ok(roots)
+}
+
+/// expands `{name}`, `{hash}` and `{id}` placeholders of the hydrate output template
+fn hydrate_path(template: &str, blob: &Blob) -> String {
+    template
+        .replace("{name}", &blob.filename)
+        .replace("{hash}", &blob.content_hash)
+        .replace("{id}", &blob.id.to_string())
+}
+
+/// restore frontier versions. without template, versions are restored into the object directory,
+/// so dehydrated archive can accept new versions again.
+pub fn hydrate(conn: &mut db::Conn, output_template: Option<&str>) -> Result<()> {
+    let stats = load_stats(conn)?;
+
+    let mut targets = Vec::new();
+    let mut content_hashes = Vec::new();
+    for root_blob in stats.root_candidates() {
+        let path = match output_template {
+            Some(template) => {
+                let path = hydrate_path(template, root_blob.blob);
+                if let Some(dir) = Path::new(&path).parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                path
+            }
+            None => filepath(&root_blob.blob.content_hash),
+        };
+        info!("hydrating blob={}", path);
+        targets.push((root_blob.blob.filename.clone(), path));
+        content_hashes.push(root_blob.blob.content_hash.clone());
+    }
+
+    // frontier versions often share most of their chains
+    let opts = GetOptions {
+        overwrite: true,
+        ..Default::default()
+    };
+    let items = get_many(conn, &targets, &opts)?;
+    let mut first_error = None;
+    for (item, content_hash) in items.into_iter().zip(content_hashes) {
+        match item.error {
+            None if output_template.is_none() => db::unprotect(conn, &content_hash)?,
+            None => (),
+            Some(e) => {
+                error!("hydrate: failed to restore {}: {}", item.filename, e);
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    match first_error {
+        Some(e) => Err(crate::format_err!("hydrate: {}", e)),
+        None => Ok(()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CleanupStrategy {
+    /// `Stats::root_score`: size of the delta alias, weighted by recent use
+    Current,
+    /// number of blobs decoded from the root
+    SubtreeSize,
+    /// keep roots which are used recently
+    AgeOnly,
+}
+
+/// root blobs which can be removed, the ones to keep first
+fn ranked_root_candidates(stats: &Stats, strategy: CleanupStrategy) -> Vec<Blob> {
+    let mut root_candidates = stats.root_candidates();
+    root_candidates.sort_by_key(|root_blob| {
+        let score = match strategy {
+            CleanupStrategy::Current => root_blob.score,
+            CleanupStrategy::SubtreeSize => stats.child_count(root_blob.idx) as u64,
+            CleanupStrategy::AgeOnly => u64::MAX - stats.root_age(root_blob.idx) as u64,
+        };
+        // sort by score desc
+        u64::MAX - score
+    });
+
+    if strategy == CleanupStrategy::Current {
+        let mut s = String::new();
+        for root_blob in &root_candidates {
+            let alias = root_blob.alias;
+            s += &format!(
+                "{}={:.02}%,{} ",
+                alias.id,
+                alias.compression_ratio() * 100.0,
+                bytesize::ByteSize(root_blob.score),
+            );
+        }
+        debug!("root compression ratio: {}", s);
+    }
+
+    // TODO: store distances
+
+    root_candidates
+        .into_iter()
+        .map(|root_blob| root_blob.blob.clone())
+        .collect()
+}
+
+/// root blobs which should be removed to keep `max_root_blobs` roots
+fn cleanup_victims(stats: &Stats, strategy: CleanupStrategy) -> Vec<Blob> {
+    ranked_root_candidates(stats, strategy)
+        .into_iter()
+        .skip(max_root_blobs())
+        .collect()
+}
+
+/// removes the full blob of a root, which stays decodable through its alias
+#[cfg(test)]
+fn remove_root(conn: &mut db::Conn, root: &Blob) -> Result<()> {
+    let mut unlink = Vec::new();
+    remove_root_deferred(conn, root, &mut unlink)?;
+    unlink_objects(&unlink)
+}
+
+/// `remove_root` without deleting the object file, whose path is added to `unlink`. the file is
+/// deleted once the removal is committed, as a rollback can not bring it back.
+fn remove_root_deferred(conn: &mut db::Conn, root: &Blob, unlink: &mut Vec<String>) -> Result<()> {
+    db::remove(conn, root)?;
+    db::remove_sketch(conn, &root.content_hash)?;
+    let path = filepath(&root.content_hash);
+    if Path::new(&path).exists() {
+        unlink.push(path);
+    } else {
+        // dehydrated root, its dependencies are no longer needed
+        db::unprotect(conn, &root.content_hash)?;
+    }
+    Ok(())
+}
+
+/// deletes object files of removed blobs, see `remove_root_deferred`
+fn unlink_objects(paths: &[String]) -> Result<()> {
+    for path in paths {
+        match std::fs::remove_file(path) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// `Stats` of all blobs, starting from depths cached by `rebuild_stats_index` if they are up to
+/// date
+fn load_stats(conn: &mut db::Conn) -> Result<Stats> {
+    let blobs = db::all(conn)?;
+    match db::cached_depths(conn)? {
+        Some(depths) => Ok(Stats::from_blobs_with_depths(blobs, &depths)),
+        None => Ok(Stats::from_blobs(blobs)),
+    }
+}
+
+/// computes depths of all blobs and caches them in `blobs.depth`, so following `Stats` of a large
+/// store skip the computation until the blobs change
+pub fn rebuild_stats_index(conn: &mut db::Conn) -> Result<()> {
+    let stats = Stats::from_blobs(db::all(conn)?);
+    let depths = stats
+        .blobs
+        .iter()
+        .zip(&stats.depths)
+        .map(|(blob, node)| (blob.id, node.depth))
+        .collect::<Vec<_>>();
+    db::set_depths(conn, &depths)?;
+    info!(
+        "stats index: {} blobs, max depth {}",
+        depths.len(),
+        stats.max_depth()
+    );
+    Ok(())
+}
+
+/// what `list_root_blobs` tells about a root blob
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RootBlobInfo {
+    /// delta blobs below the root, at any depth
+    pub child_count: usize,
+    /// false if the root object was removed by `dehydrate`
+    pub hydrated: bool,
+    /// `Stats::root_score`, roots with a lower score are removed first by `cleanup`
+    pub score: u64,
+    pub age_secs: i64,
+    /// the blob is needed by a dehydrated root, so `cleanup` keeps it
+    pub pinned: bool,
+}
+
+/// root blobs with their `RootBlobInfo`, oldest first. unlike `Stats`, only the blobs around
+/// each root are read.
+pub fn list_root_blobs(conn: &mut db::Conn) -> Result<Vec<(Blob, RootBlobInfo)>> {
+    let dehydrated = db::dehydrated_roots(conn)?;
+    let protected = db::protected_store_hashes(conn)?;
+    let now = time::OffsetDateTime::now_utc();
+
+    let ids = db::ids(conn)?;
+
+    let mut roots = db::roots(conn)?;
+    roots.sort_by_key(|root| root.id);
+    let mut list = Vec::with_capacity(roots.len());
+    for root in roots {
+        // `Stats::root_score` by id instead of by index
+        let alias = db::by_content_hash(conn, &root.content_hash)?
+            .into_iter()
+            .filter(|blob| !blob.is_root())
+            .max_by_key(|blob| blob.id);
+        let score = match alias {
+            None => u64::MAX,
+            Some(alias) => {
+                let last_id = db::children(conn, &root.content_hash)?
+                    .last()
+                    .map_or(root.id, |child| child.id);
+                let age = ids.len() - ids.partition_point(|id| *id < last_id);
+                stats::score_by_age(alias.store_size, age)
+            }
+        };
+
+        let info = RootBlobInfo {
+            child_count: db::descendant_count(conn, &root.content_hash)?,
+            hydrated: !dehydrated.contains(&root.content_hash),
+            score,
+            age_secs: (now - root.time_created).whole_seconds(),
+            pinned: protected.contains(&root.store_hash),
+        };
+        list.push((root, info));
+    }
+    Ok(list)
+}
+
+pub fn cleanup(conn: &mut db::Conn) -> Result<()> {
+    cleanup_roots(conn, false)?;
+    Ok(())
+}
+
+/// `cleanup` with the root objects it removes. nothing is removed on `dry_run`.
+fn cleanup_roots(conn: &mut db::Conn, dry_run: bool) -> Result<Reclaimed> {
+    let (store_size_before, _) = db::total_sizes(conn)?;
+    let mut unlink = Vec::new();
+    let (reclaimed, removed) = remove_cleanup_victims(conn, dry_run, &mut unlink)?;
+    if dry_run {
+        return Ok(reclaimed);
+    }
+    unlink_objects(&unlink)?;
+
+    log_roots(conn, &removed_transitions(&removed, CLEANUP_REASON))?;
+    let (store_size_after, _) = db::total_sizes(conn)?;
+    let details = serde_json::json!({
+        "removed_ids": removed.iter().map(|root| root.id).collect::<Vec<_>>(),
+        "store_size_before": store_size_before,
+        "store_size_after": store_size_after,
+    });
+    log_op(conn, "cleanup", details)?;
+    Ok(reclaimed)
+}
+
+/// reason of root transitions by `cleanup`
+const CLEANUP_REASON: &str = "score_below_threshold";
+
+fn removed_transitions(roots: &[Blob], reason: &str) -> Vec<RootTransition> {
+    roots
+        .iter()
+        .map(|root| RootTransition::new(EVENT_ROOT_REMOVED, root, reason))
+        .collect()
+}
+
+/// removes unprotected `cleanup_victims`, without an oplog entry, and returns them. their object
+/// files are added to `unlink`, see `remove_root_deferred`. nothing is removed on `dry_run`.
+fn remove_cleanup_victims(
+    conn: &mut db::Conn,
+    dry_run: bool,
+    unlink: &mut Vec<String>,
+) -> Result<(Reclaimed, Vec<Blob>)> {
+    let stats = load_stats(conn)?;
+    let protected = db::protected_store_hashes(conn)?;
+    let mut removed = Vec::new();
+    let mut reclaimed = Reclaimed::default();
+    for root in cleanup_victims(&stats, CleanupStrategy::Current) {
+        if protected.contains(&root.store_hash) {
+            info!("cleanup: skipping protected blob={}", root.store_hash);
+            continue;
+        }
+        reclaimed.add(root.store_size);
+        if dry_run {
+            info!("cleanup: would remove root blob={}", root.store_hash);
+            continue;
+        }
+        remove_root_deferred(conn, &root, unlink)?;
+        removed.push(root);
+    }
+    Ok((reclaimed, removed))
+}
+
+/// what to do when a push would grow the store past `max_store_size`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuotaPolicy {
+    /// refuse the push
+    #[default]
+    Reject,
+    /// remove more root blobs than cleanup would, refusing the push only if that is not enough
+    Prune,
+}
+
+impl std::str::FromStr for QuotaPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "reject" => Ok(QuotaPolicy::Reject),
+            "prune" => Ok(QuotaPolicy::Prune),
+            _ => Err(crate::format_err!("unknown quota policy: {}", s)),
+        }
+    }
+}
+
+/// the store size after a push: the current blobs plus `new_blob`, minus the root blobs cleanup
+/// would remove. also returns the root blobs cleanup would keep, the ones to keep first.
+fn project_cleanup(conn: &mut db::Conn, new_blob: Option<&Blob>) -> Result<(u64, Vec<Blob>)> {
+    db::savepoint(conn, "project_cleanup")?;
+    let res = (|| -> Result<(u64, Vec<Blob>)> {
+        if let Some(blob) = new_blob {
+            insert_blob(conn, blob)?;
+        }
+        let (mut projected_size, _) = db::total_sizes(conn)?;
+
+        let protected = db::protected_store_hashes(conn)?;
+        let stats = load_stats(conn)?;
+        let mut candidates = ranked_root_candidates(&stats, CleanupStrategy::Current)
+            .into_iter()
+            .filter(|root| !protected.contains(&root.store_hash))
+            .collect::<Vec<_>>();
+
+        let kept = candidates.len().min(max_root_blobs());
+        for root in candidates.drain(kept..) {
+            projected_size -= root.store_size;
+        }
+        Ok((projected_size, candidates))
+    })();
+    db::rollback_to(conn, "project_cleanup")?;
+    res
+}
+
+/// checks the projected store size after a push, see `project_cleanup`. returns root blobs to
+/// remove on top of cleanup.
+fn check_quota(
+    conn: &mut db::Conn,
+    filename: &str,
+    new_blob: Option<&Blob>,
+    max_store_size: u64,
+    policy: QuotaPolicy,
+) -> Result<Vec<Blob>> {
+    let (mut projected_size, mut candidates) = project_cleanup(conn, new_blob)?;
+
+    let mut pruned = Vec::new();
+    if policy == QuotaPolicy::Prune {
+        while projected_size > max_store_size {
+            match candidates.pop() {
+                Some(root) => {
+                    projected_size -= root.store_size;
+                    pruned.push(root);
+                }
+                None => break,
+            }
+        }
+    }
+
+    info!(
+        "push: projected_size={} max_store_size={} pruned={}",
+        bytesize::ByteSize(projected_size),
+        bytesize::ByteSize(max_store_size),
+        pruned.len()
+    );
+    if projected_size > max_store_size {
+        return Err(Error::QuotaExceeded {
+            filename: filename.to_owned(),
+            projected_size,
+            max_store_size,
+        });
+    }
+    Ok(pruned)
+}
+
+#[derive(Debug, Clone)]
+pub struct CleanupOutcome {
+    pub removed: Vec<Blob>,
+    /// disk space reclaimed by removing full root blobs
+    pub removed_size: u64,
+    pub root_count: usize,
+    pub max_depth: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchCleanupResult {
+    pub current_strategy: CleanupOutcome,
+    pub subtree_size_strategy: CleanupOutcome,
+    pub age_only_strategy: CleanupOutcome,
+}
+
+fn simulate_cleanup(conn: &mut db::Conn, strategy: CleanupStrategy) -> Result<CleanupOutcome> {
+    let stats = load_stats(conn)?;
+    let removed = cleanup_victims(&stats, strategy);
+
+    db::savepoint(conn, "bench_cleanup")?;
+    let res = (|| -> Result<CleanupOutcome> {
+        for root in &removed {
+            db::remove(conn, root)?;
+        }
+        let stats = load_stats(conn)?;
+        Ok(CleanupOutcome {
+            removed_size: removed.iter().map(|blob| blob.content_size).sum(),
+            root_count: stats.blobs.iter().filter(|blob| blob.is_root()).count(),
+            max_depth: stats.max_depth(),
+            removed,
+        })
+    })();
+    db::rollback_to(conn, "bench_cleanup")?;
+
+    res
+}
+
+/// simulates cleanup strategies without modifying the archive
+pub fn bench_cleanup(conn: &mut db::Conn) -> Result<BenchCleanupResult> {
+    Ok(BenchCleanupResult {
+        current_strategy: simulate_cleanup(conn, CleanupStrategy::Current)?,
+        subtree_size_strategy: simulate_cleanup(conn, CleanupStrategy::SubtreeSize)?,
+        age_only_strategy: simulate_cleanup(conn, CleanupStrategy::AgeOnly)?,
+    })
+}
+
+pub fn debug_bench_cleanup(conn: &mut db::Conn) -> Result<()> {
+    let res = bench_cleanup(conn)?;
+
+    for (name, outcome) in &[
+        ("current", &res.current_strategy),
+        ("subtree_size", &res.subtree_size_strategy),
+        ("age_only", &res.age_only_strategy),
+    ] {
+        let removed = outcome
+            .removed
+            .iter()
+            .map(|blob| blob.id.to_string())
+            .collect::<Vec<_>>();
+        println!(
+            "{}: removed=[{}] removed_size={} root_count={} max_depth={}",
+            name,
+            removed.join(","),
+            bytesize::ByteSize(outcome.removed_size),
+            outcome.root_count,
+            outcome.max_depth,
+        );
+    }
+
+    Ok(())
+}
+
+/// projected max depth from which `estimate_storage_after_n_pushes` advises `compact_delta_chain`
+pub const PROJECTION_COMPACT_DEPTH: usize = 32;
+
+/// growth of the store after a number of pushes, see `estimate_storage_after_n_pushes`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StorageProjection {
+    pub pushes: usize,
+    pub avg_delta_ratio: f32,
+    /// mean content_size of the stored contents, taken as the size of the next versions
+    pub avg_content_size: u64,
+    pub current_store_size: u64,
+    pub estimated_store_size: u64,
+    /// roots kept by the cleanup of the last push
+    pub estimated_root_count: usize,
+    /// upper bound of the max depth, see `estimate_storage_after_n_pushes`
+    pub estimated_max_depth: usize,
+    /// `estimated_max_depth` reaches `PROJECTION_COMPACT_DEPTH`
+    pub compact_advised: bool,
+}
+
+/// mean `compression_ratio` of the delta blobs, None if there is no delta
+pub fn average_delta_ratio(conn: &mut db::Conn) -> Result<Option<f32>> {
+    let mut ratios = Vec::new();
+    for blob in db::iter_all(conn) {
+        let blob = blob?;
+        if !blob.is_root() && blob.content_size > 0 {
+            ratios.push(blob.compression_ratio());
+        }
+    }
+    if ratios.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(ratios.iter().sum::<f32>() / ratios.len() as f32))
+}
+
+/// projects the store after `n` pushes of versions the size of the average content. each push
+/// stores a root and a delta of `avg_delta_ratio`, then cleanup keeps `max_root_blobs` roots
+/// besides the genesis. versions below a removed root decode through its alias, one step deeper
+/// than before, so `estimated_max_depth` is a bound: it is reached only when every removed root
+/// is the delta parent of the next one.
+pub fn estimate_storage_after_n_pushes(
+    conn: &mut db::Conn,
+    n: usize,
+    avg_delta_ratio: f32,
+) -> Result<StorageProjection> {
+    if !avg_delta_ratio.is_finite() || avg_delta_ratio < 0.0 {
+        return Err(crate::format_err!(
+            "avg_delta_ratio should be a non-negative number: {}",
+            avg_delta_ratio
+        ));
+    }
+    let stats = load_stats(conn)?;
+    let (current_store_size, _) = db::total_sizes(conn)?;
+
+    let contents = stats
+        .blobs
+        .iter()
+        .map(|blob| (&blob.content_hash, blob.content_size))
+        .collect::<HashMap<_, _>>();
+    let avg_content_size = match contents.len() {
+        0 => 0,
+        len => contents.values().sum::<u64>() / len as u64,
+    };
+
+    // roots without an alias, e.g. the genesis, are never removed by cleanup
+    let base_count = stats.base_roots().len();
+    let candidate_count = stats.blobs.iter().filter(|blob| blob.is_root()).count() - base_count;
+    let kept_count = match n {
+        0 => candidate_count,
+        n => (candidate_count + n).min(max_root_blobs()),
+    };
+    let removed_roots = candidate_count + n - kept_count;
+    let estimated_root_count = base_count + kept_count;
+
+    let delta_size = (avg_content_size as f64 * avg_delta_ratio as f64) as u64;
+    let added = n as u64 * (avg_content_size + delta_size);
+    let removed = removed_roots as u64 * avg_content_size;
+    let estimated_store_size = (current_store_size + added).saturating_sub(removed);
+
+    let estimated_max_depth = stats.max_depth() + removed_roots;
+    Ok(StorageProjection {
+        pushes: n,
+        avg_delta_ratio,
+        avg_content_size,
+        current_store_size,
+        estimated_store_size,
+        estimated_root_count,
+        estimated_max_depth,
+        compact_advised: estimated_max_depth >= PROJECTION_COMPACT_DEPTH,
+    })
+}
+
+fn store_blob<F>(input_filepath: &str, f: F) -> Result<Blob>
+where
+    F: FnOnce(&Path, &Path) -> std::io::Result<WriteMetadata>,
+{
+    let input_filename = Path::new(&input_filepath)
+        .file_name()
+        .unwrap()
+        .to_str()
+        .unwrap();
+
+    let tmp_dir = tmpdir();
+    let tmp_unzip_path = NamedTempFile::new_in(&tmp_dir)?;
+
+    let meta = f(Path::new(input_filepath), tmp_unzip_path.path())?;
+
+    let input_blob = meta.blob(input_filename);
+    let store_filepath = filepath(&input_blob.store_hash);
+    store_object(tmp_unzip_path, &store_filepath)?;
+    Ok(input_blob)
+}
+
+#[cfg(test)]
+thread_local! {
+    /// calls of `canonicalize`, to check pushes skipping the conversion in tests
+    static CONVERSIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// converts an input file to the canonical form kept in the store. `explode_bundle` applies to
+/// zip inputs only, see `StoreConfig::explode_nested`.
+fn canonicalize(
+    input_path: &Path,
+    ty: FileType,
+    explode_bundle: bool,
+    dst_path: &Path,
+) -> io::Result<WriteMetadata> {
+    #[cfg(test)]
+    CONVERSIONS.with(|count| count.set(count.get() + 1));
+
+    match ty {
+        FileType::Zip => {
+            let config = StoreConfig {
+                explode_nested: explode_bundle,
+                ..Default::default()
+            };
+            store_zip_reproducible(input_path, dst_path, &config)
+        }
+        FileType::Gz => gz::store_gz(input_path, dst_path),
+        FileType::Plain => gz::store_plain(input_path, dst_path),
+    }
+}
+
+/// stores the full blob of an input. returns the blob and whether it was inserted, false if the
+/// content already exists.
+fn append_full(
+    conn: &mut db::Conn,
+    input_filepath: &str,
+    ty: FileType,
+    explode_bundle: bool,
+) -> Result<(Blob, bool)> {
+    trace!("append_full: input_filepath={} ty={:?}", input_filepath, ty);
+
+    let blob = store_blob(input_filepath, |p1, p2| {
+        canonicalize(p1, ty, explode_bundle, p2)
+    })?;
+    if db::insert(conn, &blob)? {
+        db::set_exploded(conn, &blob.content_hash, explode_bundle)?;
+        Ok((blob, true))
+    } else {
+        Ok((blob, false))
+    }
+}
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
+
+/// encodes `input_blob` against `src_blob`, giving up with `None` once the delta grows past
+/// `race` or `cancel` is set
+fn append_delta(
+    input_blob: &Blob,
+    src_blob: &Blob,
+    race: Arc<AtomicUsize>,
+    cancel: Option<Arc<AtomicBool>>,
+) -> Result<Option<(NamedTempFile, Blob)>> {
+    let rt = tokio::runtime::Runtime::new()?;
+    let sw = Stopwatch::start_new();
+    let input_filepath = filepath(&input_blob.content_hash);
+
+    let (tmp, blob) = {
+        let tmp_dir = tmpdir();
+        let tmp_path = NamedTempFile::new_in(&tmp_dir)?;
+
+        let src_hash = &src_blob.content_hash;
+        let src_filepath = filepath(src_hash);
+
+        let res = rt.block_on(async {
+            use tokio::{fs::File, io::*};
+
+            let src_file = File::open(&src_filepath).await?;
+            let input_file = File::open(&input_filepath).await?;
+            let dst_file = File::create(tmp_path.path()).await?;
+
+            let race = RaceWrite::new(BufWriter::with_capacity(BUF_SIZE, dst_file), race)
+                .cancel_on(cancel);
+
+            delta::delta(
+                delta::ProcessMode::Encode,
+                BufReader::with_capacity(BUF_SIZE, src_file),
+                BufReader::with_capacity(BUF_SIZE, input_file),
+                race,
+            )
+            .await
+        });
+
+        let dst_meta = match res {
+            Ok(s) => s,
+            Err(e) => {
+                if e.kind() == io::ErrorKind::Other {
+                    // timeout from race, or cancelled
+                    return Ok(None);
+                } else {
+                    return Err(Error::Delta {
+                        filename: input_blob.filename.clone(),
+                        error: e,
+                    });
+                }
+            }
+        };
+
+        let mut blob = dst_meta.blob(&input_blob.filename);
+        blob.content_size = input_blob.content_size;
+        blob.content_hash = input_blob.content_hash.clone();
+        blob.parent_hash = Some(src_hash.to_owned());
+
+        trace!(
+            "content_hash={}, store_hash={}",
+            blob.content_hash,
+            blob.store_hash
+        );
+        (tmp_path, blob)
+    };
+    let dt_store_delta = sw.elapsed_ms();
+
+    info!(
+        "append_delta: ratio={:.02}%, dt_store_delta={}ms",
+        blob.compression_ratio() * 100.0,
+        dt_store_delta,
+    );
+    Ok(Some((tmp, blob)))
+}
+
+fn ratio_summary(blobs: &[Blob]) -> String {
+    let mut s = String::new();
+    for blob in blobs {
+        s += &format!("{}={:.02}% ", blob.id, blob.compression_ratio() * 100.0);
+    }
+    s
+}
+
+#[derive(Debug, Default)]
+struct TmpUsage {
+    /// reserved by running candidates, plus the size of kept deltas
+    used: u64,
+    peak: u64,
+    running: usize,
+}
+
+/// bounds temporary bytes of delta candidates encoded in parallel. a candidate reserves an
+/// estimate of its delta size before it starts, and waits while the reservation does not fit.
+/// one candidate always runs, so a budget smaller than a single delta serializes candidates.
+struct TmpBudget {
+    limit: Option<u64>,
+    usage: std::sync::Mutex<TmpUsage>,
+    freed: std::sync::Condvar,
+}
+
+impl TmpBudget {
+    fn new(limit: Option<u64>) -> Self {
+        TmpBudget {
+            limit,
+            usage: Default::default(),
+            freed: Default::default(),
+        }
+    }
+
+    fn acquire(&self, reserve: u64) {
+        let mut usage = self.usage.lock().expect("poisoned");
+        if let Some(limit) = self.limit {
+            while usage.running > 0 && usage.used + reserve > limit {
+                debug!(
+                    "tmp budget: waiting, used={} reserve={} limit={}",
+                    usage.used, reserve, limit
+                );
+                usage = self.freed.wait(usage).expect("poisoned");
+            }
+        }
+        usage.used += reserve;
+        usage.peak = usage.peak.max(usage.used);
+        usage.running += 1;
+    }
+
+    /// replaces the reservation of a finished candidate with the size of its kept delta
+    fn release(&self, reserve: u64, kept: u64) {
+        let mut usage = self.usage.lock().expect("poisoned");
+        usage.used = usage.used - reserve + kept;
+        usage.peak = usage.peak.max(usage.used);
+        usage.running -= 1;
+        self.freed.notify_all();
+    }
+
+    /// a kept delta is dropped
+    fn free(&self, bytes: u64) {
+        let mut usage = self.usage.lock().expect("poisoned");
+        usage.used -= bytes;
+        self.freed.notify_all();
+    }
+
+    fn peak(&self) -> u64 {
+        self.usage.lock().expect("poisoned").peak
+    }
+}
+
+/// candidates of `push_delta`. only the smallest delta is kept on disk, so finished candidates
+/// free their space for the ones still waiting.
+#[derive(Default)]
+struct DeltaCandidates {
+    best: Option<(NamedTempFile, Blob)>,
+    blobs: Vec<Blob>,
+    cut_short: usize,
+    errors: Vec<Error>,
+    /// keep every delta in `kept` instead of the smallest one in `best`
+    keep_all: bool,
+    kept: Vec<(NamedTempFile, Blob)>,
+}
+
+impl DeltaCandidates {
+    fn add(&mut self, budget: &TmpBudget, res: Result<Option<(NamedTempFile, Blob)>>) {
+        let (tmp, blob) = match res {
+            Ok(Some(link)) => link,
+            Ok(None) => {
+                self.cut_short += 1;
+                return;
+            }
+            Err(e) => {
+                warn!("push: delta candidate failed: {}", e);
+                self.errors.push(e);
+                return;
+            }
+        };
+
+        self.blobs.push(blob.clone());
+        if self.keep_all {
+            self.kept.push((tmp, blob));
+            return;
+        }
+        match &self.best {
+            Some((_tmp, best)) if best.store_size <= blob.store_size => {
+                budget.free(blob.store_size);
+            }
+            _ => {
+                if let Some((_tmp, old)) = self.best.replace((tmp, blob)) {
+                    budget.free(old.store_size);
+                }
+            }
+        }
+    }
+}
+
+/// how push picks the parent of a version among the deltas of its candidates
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParentSelection {
+    /// the smallest delta
+    #[default]
+    Greedy,
+    /// the delta leaving the smallest store after the following cleanup. a larger delta may
+    /// keep the version or its parent as a root, so cleanup removes a larger root instead. every
+    /// candidate is encoded in full. experimental.
+    Optimal,
+}
+
+impl std::str::FromStr for ParentSelection {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "greedy" => Ok(ParentSelection::Greedy),
+            "optimal" => Ok(ParentSelection::Optimal),
+            _ => Err(crate::format_err!("unknown parent selection: {}", s)),
+        }
+    }
+}
+
+/// index of the delta `selection` picks among deltas of the same version, None if there is none
+fn select_delta(
+    conn: &mut db::Conn,
+    deltas: &[Blob],
+    selection: ParentSelection,
+) -> Result<Option<usize>> {
+    let mut best = None;
+    for (idx, delta) in deltas.iter().enumerate() {
+        let projected_size = match selection {
+            ParentSelection::Greedy => 0,
+            ParentSelection::Optimal => project_cleanup(conn, Some(delta))?.0,
+        };
+        debug!(
+            "push: parent={:?} store_size={} projected_size={}",
+            delta.parent_hash, delta.store_size, projected_size
+        );
+        let key = (projected_size, delta.store_size);
+        if best.as_ref().is_none_or(|(best_key, _)| key < *best_key) {
+            best = Some((key, idx));
+        }
+    }
+    Ok(best.map(|(_key, idx)| idx))
+}
+
+/// number of root blobs to run delta encoding against on push
+const DELTA_CANDIDATES: usize = 3;
+/// the previous member of a series is kept as delta source unless its delta is larger than the
+/// best candidate by this factor
+const SERIES_PARENT_SLACK: f64 = 1.25;
+
+/// cheap similarity between two blobs, from their content sizes. 1.0 for the same size.
+fn size_similarity(a: &Blob, b: &Blob) -> f64 {
+    let (min, max) = if a.content_size < b.content_size {
+        (a.content_size, b.content_size)
+    } else {
+        (b.content_size, a.content_size)
+    };
+    if max == 0 {
+        return 1.0;
+    }
+    min as f64 / max as f64
+}
+
+/// sorts root blobs by predicted delta quality against `blob`, best first
+fn rank_roots(mut roots: Vec<Blob>, blob: &Blob) -> Vec<Blob> {
+    roots.retain(|root| !root.is_same_content(blob));
+    roots.sort_by(|a, b| {
+        size_similarity(b, blob)
+            .partial_cmp(&size_similarity(a, blob))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    roots
+}
+
+/// predicts the best delta source for a content without running delta encoding
+pub fn find_optimal_root_for(conn: &mut db::Conn, new_content_hash: &str) -> Result<Option<Blob>> {
+    let blob = match db::by_content_hash(conn, new_content_hash)?.pop() {
+        Some(blob) => blob,
+        None => return Ok(None),
+    };
+
+    let roots = db::roots(conn)?;
+    Ok(rank_roots(roots, &blob).into_iter().next())
+}
+
+/// size of the delta of `input_path` against a root, encoded into a sink
+fn estimate_delta_size(root: &Blob, input_path: &str) -> Result<u64> {
+    let rt = tokio::runtime::Runtime::new()?;
+    let meta = rt.block_on(async {
+        use tokio::{fs::File, io::*};
+
+        let src_file = File::open(filepath(&root.content_hash)).await?;
+        let input_file = File::open(input_path).await?;
+
+        delta::delta(
+            delta::ProcessMode::Encode,
+            BufReader::with_capacity(BUF_SIZE, src_file),
+            BufReader::with_capacity(BUF_SIZE, input_file),
+            sink(),
+        )
+        .await
+    })?;
+    Ok(meta.len())
+}
+
+/// previews `push` of a file: the delta size against each root blob, smallest first. zip and gz
+/// inputs are converted to a temp file first, as `push` stores them. deltas are discarded, so
+/// nothing is written to the store.
+pub fn estimate_push_delta_sizes(
+    conn: &mut db::Conn,
+    input_filepath: &str,
+    ty: FileType,
+    explode_bundle: bool,
+) -> Result<Vec<(String, u64)>> {
+    let roots = db::roots(conn)?
+        .into_iter()
+        .filter(|root| {
+            let exists = Path::new(&filepath(&root.content_hash)).exists();
+            if !exists {
+                info!("estimate: skipping dehydrated root {}", root.filename);
+            }
+            exists
+        })
+        .collect::<Vec<_>>();
+
+    // plain inputs are stored as they are
+    let converted = match ty {
+        FileType::Plain => None,
+        _ => {
+            let tmp = NamedTempFile::new_in(tmpdir())?;
+            canonicalize(Path::new(input_filepath), ty, explode_bundle, tmp.path())?;
+            Some(tmp)
+        }
+    };
+    let canonical_path = match &converted {
+        Some(tmp) => tmp.path().to_string_lossy().into_owned(),
+        None => input_filepath.to_owned(),
+    };
+
+    let mut sizes = roots
+        .into_par_iter()
+        .map(|root| {
+            let size = estimate_delta_size(&root, &canonical_path)?;
+            Ok((root.store_hash, size))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    sizes.sort_by_key(|(_hash, size)| *size);
+    Ok(sizes)
+}
+
+/// writes a machine-readable report as json. the file is replaced atomically, so readers see
+/// either the previous report or the new one.
+pub fn write_report<T: Serialize>(report_path: &str, report: &T) -> Result<()> {
+    let dir = match Path::new(report_path).parent() {
+        Some(dir) if dir != Path::new("") => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let mut tmpfile = NamedTempFile::new_in(dir)?;
+    serde_json::to_writer_pretty(&mut tmpfile, report)?;
+    tmpfile.persist(report_path)?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PushOptions {
+    /// flag the push when the chosen delta's store_size/content_size exceeds this
+    pub max_ratio: Option<f32>,
+    /// roll the push back instead of keeping the blob when max_ratio is exceeded
+    pub max_ratio_strict: bool,
+    /// cap on the sum of store_size of all blobs
+    pub max_store_size: Option<u64>,
+    pub quota_policy: QuotaPolicy,
+    /// store a zip bundle (.aab, .apks) with its nested zip members exploded. get returns the
+    /// exploded tar, the original bundle can not be reassembled.
+    pub explode_bundle: bool,
+    /// append the version to a named series, preferring the previous member as delta source
+    pub series: Option<String>,
+    /// hash the input file before and after it is stored, and roll the push back if it changed.
+    /// the input is read twice more.
+    pub verify_unchanged: bool,
+    /// cap on temporary bytes of delta candidates encoded in parallel. candidates wait for space
+    /// instead of starting, but one always runs.
+    pub tmp_budget: Option<u64>,
+    /// url the input was downloaded from, recorded as its provenance
+    pub source_url: Option<String>,
+    /// encode deltas against all candidates, without skipping the ones `sketch::prefilter`
+    /// estimates hopeless
+    pub no_prefilter: bool,
+    /// report the estimated and the actual delta size of every candidate in `PushReport::trials`.
+    /// skipped candidates are encoded after the push to measure them.
+    pub record_trials: bool,
+    /// set from another thread, e.g. by `cancel_on_signal`, to stop the push at its next check.
+    /// it then fails with `Error::Cancelled`, leaving the store as before.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// orders delta candidates, `MinhashEstimator` if not set
+    pub estimator: Option<Arc<dyn SimilarityEstimator>>,
+    /// encode the most promising candidate alone first, and keep its delta without trying the
+    /// others if it is at or below this ratio. candidates are all encoded at once if not set, as
+    /// the probe delays the others.
+    pub good_enough_ratio: Option<f32>,
+    /// with a `filename_hint`, try only roots of the flavor of the input
+    pub hint_strict: bool,
+    pub parent_selection: ParentSelection,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PushReport {
+    pub filename: String,
+    #[serde(default)]
+    pub content_hash: String,
+    /// content already exists in the archive
+    pub skipped: bool,
+    /// the delta blob stored on push, None for genesis or skipped pushes
+    pub delta: Option<Blob>,
+    pub ratio: Option<f32>,
+    pub max_ratio: Option<f32>,
+    /// bytes of chunks written on push, in chunk storage mode
+    pub new_chunk_bytes: Option<u64>,
+    /// seq of the version in `PushOptions::series`
+    #[serde(default)]
+    pub series_seq: Option<u32>,
+    /// peak of temporary bytes reserved by delta candidates and taken by kept deltas
+    #[serde(default)]
+    pub peak_tmp_bytes: Option<u64>,
+    /// the filename is added as another name of the existing content
+    #[serde(default)]
+    pub aliased: bool,
+    /// delta candidates, with `PushOptions::record_trials`
+    #[serde(default)]
+    pub trials: Option<Vec<Trial>>,
+    /// store hashes of delta candidates in the order they are encoded. candidates skipped by a
+    /// good enough delta are left out.
+    #[serde(default)]
+    pub tried_roots: Vec<String>,
+    /// how candidates were ordered by the `filename_hint` of the store, if it is set
+    #[serde(default)]
+    pub hint: Option<HintReport>,
+    /// store size after the following cleanup, with `ParentSelection::Optimal`
+    #[serde(default)]
+    pub projected_store_size: Option<u64>,
+    /// the input becoming a root, and roots removed by the cleanup after the push or for the
+    /// quota
+    #[serde(default)]
+    pub roots: Vec<RootTransition>,
+}
+
+impl PushReport {
+    pub fn ratio_exceeded(&self) -> bool {
+        match (self.ratio, self.max_ratio) {
+            (Some(ratio), Some(max_ratio)) => ratio > max_ratio,
+            _ => false,
+        }
+    }
+}
+
+pub fn push(conn: &mut db::Conn, input_filepath: &str, ty: FileType) -> Result<()> {
+    push_with_options(conn, input_filepath, ty, &PushOptions::default())?;
+    Ok(())
+}
+
+/// `push` with `PushOptions::verify_unchanged`
+pub fn push_with_checksum_verification(
+    conn: &mut db::Conn,
+    input_filepath: &str,
+    ty: FileType,
+) -> Result<PushReport> {
+    let opts = PushOptions {
+        verify_unchanged: true,
+        ..Default::default()
+    };
+    push_with_options(conn, input_filepath, ty, &opts)
+}
+
+/// wait before the first retry of `push_with_retry`, doubled for each following one
+pub const PUSH_RETRY_BACKOFF: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// `push`, retried up to `max_retries` times when a delta fails or the database is locked by
+/// another process. returns the error of the last attempt.
+pub fn push_with_retry(
+    conn: &mut db::Conn,
+    input_filepath: &str,
+    ty: FileType,
+    max_retries: usize,
+) -> Result<PushReport> {
+    let mut attempt = 0;
+    loop {
+        let e = match push_with_options(conn, input_filepath, ty, &PushOptions::default()) {
+            Ok(report) => return Ok(report),
+            Err(e) => e,
+        };
+        let transient = matches!(error_kind(&e), ErrorKind::Delta | ErrorKind::Locked);
+        if !transient || attempt >= max_retries {
+            return Err(e);
+        }
+        let wait = PUSH_RETRY_BACKOFF * 2u32.saturating_pow(attempt as u32);
+        attempt += 1;
+        warn!(
+            "push: retry {}/{} in {}ms: {}",
+            attempt,
+            max_retries,
+            wait.as_millis(),
+            e
+        );
+        std::thread::sleep(wait);
+    }
+}
+
+/// key of `db::original_content_hash`: the same input converts to different contents by type
+fn original_kind(ty: FileType, explode_bundle: bool) -> String {
+    if explode_bundle {
+        format!("{:?}+explode", ty)
+    } else {
+        format!("{:?}", ty)
+    }
+}
+
+/// adds `filename` as an alias of an existing content, unless the name is already known
+fn add_alias(conn: &mut db::Conn, filename: &str, content_hash: &str) -> Result<bool> {
+    if !db::by_filename(conn, filename)?.is_empty() {
+        return Ok(false);
+    }
+    info!(
+        "push: filename={} is an alias of {}",
+        filename, content_hash
+    );
+    Ok(db::alias_add(conn, filename, content_hash)?)
+}
+
+/// hash of the input file, when it is verified to be unchanged after push
+fn input_hash(input_filepath: &str, opts: &PushOptions) -> Result<Option<String>> {
+    if opts.verify_unchanged {
+        Ok(Some(file_hash(input_filepath)?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// runs `f`, hashing the input file on another thread meanwhile unless its hash is known, so the
+/// hash does not add to the time of the conversion
+fn hashing_input<T, F>(input_filepath: &str, known: Option<String>, f: F) -> Result<(T, String)>
+where
+    F: FnOnce() -> Result<T>,
+{
+    if let Some(hash) = known {
+        return Ok((f()?, hash));
+    }
+    std::thread::scope(|s| {
+        let hasher = s.spawn(|| file_hash(input_filepath));
+        let res = f();
+        let hash = hasher.join().expect("failed to join hash thread")?;
+        Ok((res?, hash))
+    })
+}
+
+fn check_unchanged(input_filepath: &str, input_hash: Option<&str>) -> Result<()> {
+    if let Some(input_hash) = input_hash {
+        if file_hash(input_filepath)? != input_hash {
+            return Err(Error::FileMutated(input_filepath.to_owned()));
+        }
+    }
+    Ok(())
+}
+
+fn check_cancelled(opts: &PushOptions) -> Result<()> {
+    match &opts.cancel {
+        Some(cancel) if cancel.load(Ordering::SeqCst) => Err(Error::Cancelled),
+        _ => Ok(()),
+    }
+}
+
+/// removes the full blob stored by append_full, when a push is refused
+fn undo_append_full(conn: &mut db::Conn, input_blob: &Blob) -> Result<()> {
+    db::remove(conn, input_blob)?;
+    db::set_exploded(conn, &input_blob.content_hash, false)?;
+    db::remove_sketch(conn, &input_blob.content_hash)?;
+    std::fs::remove_file(filepath(&input_blob.store_hash))?;
+    Ok(())
+}
+
+pub fn push_with_options(
+    conn: &mut db::Conn,
+    input_filepath: &str,
+    ty: FileType,
+    opts: &PushOptions,
+) -> Result<PushReport> {
+    debug!("push: input_filepath={}", input_filepath);
+
+    if opts.explode_bundle && !matches!(ty, FileType::Zip) {
+        return Err(crate::format_err!(
+            "explode_bundle requires a zip input: {}",
+            input_filepath
+        ));
+    }
+    check_cancelled(opts)?;
+
+    push_logged(conn, input_filepath, ty, opts)
+}
+
+/// what a push records along with the blobs it stores, run by `commit_push`
+type FinishPush<'a> = &'a dyn Fn(&mut db::Conn, &mut PushReport) -> Result<()>;
+
+/// runs `f`, the part of a push which writes the store, under the `push` savepoint, so the
+/// blobs and the oplog entry are kept together. deltas are encoded before, so the write lock is
+/// not held meanwhile. on failure the savepoint is rolled back and `appended`, the root blob
+/// stored before it by `append_full`, is removed. object files `f` adds to its `unlink` list are
+/// deleted only once the savepoint is released.
+fn commit_push<F>(conn: &mut db::Conn, appended: Option<&Blob>, f: F) -> Result<()>
+where
+    F: FnOnce(&mut db::Conn, &mut Vec<String>) -> Result<()>,
+{
+    db::savepoint(conn, "push")?;
+    let mut unlink = Vec::new();
+    let res = f(conn, &mut unlink).and_then(|_| Ok(db::release(conn, "push")?));
+    if let Err(e) = res {
+        db::rollback_to(conn, "push")?;
+        if let Some(blob) = appended {
+            undo_append_full(conn, blob)?;
+        }
+        return Err(e);
+    }
+    // the push is committed, a file left behind is only unreferenced
+    if let Err(e) = unlink_objects(&unlink) {
+        warn!("push: failed to remove root objects: {}", e);
+    }
+    Ok(())
+}
+
+/// `push_with_options` and its oplog entry, which includes roots removed by the push
+fn push_logged(
+    conn: &mut db::Conn,
+    input_filepath: &str,
+    ty: FileType,
+    opts: &PushOptions,
+) -> Result<PushReport> {
+    let (store_size_before, _) = db::total_sizes(conn)?;
+    let finish = |conn: &mut db::Conn, report: &mut PushReport| {
+        finish_push(conn, input_filepath, opts, store_size_before, report)
+    };
+    if storage_mode(conn)? != StorageMode::Chunk {
+        return push_delta(conn, input_filepath, ty, opts, &finish);
+    }
+
+    // nothing is encoded against other versions, the whole push runs under the savepoint
+    let mut report = PushReport::default();
+    commit_push(conn, None, |conn, _unlink| {
+        report = chunk::push_chunked(conn, input_filepath, ty, opts)?;
+        finish(conn, &mut report)
+    })?;
+    Ok(report)
+}
+
+/// records the series member, provenance and oplog entry of a push
+fn finish_push(
+    conn: &mut db::Conn,
+    input_filepath: &str,
+    opts: &PushOptions,
+    store_size_before: u64,
+    report: &mut PushReport,
+) -> Result<()> {
+    if let Some(series) = &opts.series {
+        let seq = db::series_append(conn, series, &report.filename, &report.content_hash)?;
+        info!("push: series={} seq={}", series, seq);
+        report.series_seq = Some(seq);
+    }
+
+    let provenance = db::Provenance {
+        source_path: Some(absolute_path(input_filepath)),
+        source_host: hostname(),
+        source_url: opts.source_url.clone(),
+    };
+    record_provenance(conn, &report.content_hash, provenance)?;
+
+    let ids = db::by_content_hash(conn, &report.content_hash)?
+        .iter()
+        .map(|blob| blob.id)
+        .collect::<Vec<_>>();
+    log_roots(conn, &report.roots)?;
+    let (store_size_after, _) = db::total_sizes(conn)?;
+    let details = serde_json::json!({
+        "filename": report.filename,
+        "content_hash": report.content_hash,
+        "skipped": report.skipped,
+        "ids": ids,
+        "store_size_before": store_size_before,
+        "store_size_after": store_size_after,
+    });
+    log_op(conn, "push", details)
+}
+
+/// the root blob of the latest member of a series, if it is still kept as a root
+fn series_root(conn: &mut db::Conn, series: &str) -> Result<Option<Blob>> {
+    let member = match db::series_latest(conn, series)? {
+        Some(member) => member,
+        None => return Ok(None),
+    };
+    let root = db::by_content_hash(conn, &member.content_hash)?
+        .into_iter()
+        .find(|blob| blob.is_root());
+    if root.is_none() {
+        info!(
+            "push: series={} previous member {} is not a root blob",
+            series, member.filename
+        );
+    }
+    Ok(root)
+}
+
+/// newest member of a series, as the filename to `get`
+pub fn series_latest_filename(conn: &mut db::Conn, series: &str) -> Result<String> {
+    let member = match db::series_latest(conn, series)? {
+        Some(member) => member,
+        None => return Err(crate::format_err!("unknown series: {}", series)),
+    };
+    // the member may have been renamed since
+    match db::by_content_hash(conn, &member.content_hash)?.pop() {
+        Some(blob) => Ok(blob.filename),
+        None => Ok(member.filename),
+    }
+}
+
+/// `push` in delta storage mode
+fn push_delta(
+    conn: &mut db::Conn,
+    input_filepath: &str,
+    ty: FileType,
+    opts: &PushOptions,
+    finish: FinishPush,
+) -> Result<PushReport> {
+    let filename = Path::new(input_filepath)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut report = PushReport {
+        filename: filename.clone(),
+        max_ratio: opts.max_ratio,
+        ..Default::default()
+    };
+
+    let root_blobs = db::roots(conn)?;
+    let input_hash = input_hash(input_filepath, opts)?;
+
+    // the same input was converted before, skips the conversion. hashing reads the whole input,
+    // so it is only looked up if an input of the same size was converted.
+    let kind = original_kind(ty, opts.explode_bundle);
+    let original_size = std::fs::metadata(input_filepath)?.len();
+    let mut original_hash = input_hash.clone();
+    if db::has_original_size(conn, &kind, original_size)? {
+        let hash = match original_hash {
+            Some(hash) => hash,
+            None => file_hash(input_filepath)?,
+        };
+        if let Some(content_hash) = db::original_content_hash(conn, &hash, &kind)? {
+            if !db::by_content_hash(conn, &content_hash)?.is_empty() {
+                info!("push: input already converted, skipping");
+                report.content_hash = content_hash;
+                report.skipped = true;
+                commit_push(conn, None, |conn, _unlink| {
+                    report.aliased = add_alias(conn, &filename, &report.content_hash)?;
+                    finish(conn, &mut report)
+                })?;
+                return Ok(report);
+            }
+        }
+        original_hash = Some(hash);
+    }
+
+    let sw = Stopwatch::start_new();
+    let ((input_blob, inserted), original_hash) =
+        hashing_input(input_filepath, original_hash, || {
+            append_full(conn, input_filepath, ty, opts.explode_bundle)
+        })?;
+    report.content_hash = input_blob.content_hash.clone();
+    if !inserted {
+        check_unchanged(input_filepath, input_hash.as_deref())?;
+        info!("push: content already exists, skipping");
+        report.skipped = true;
+        commit_push(conn, None, |conn, _unlink| {
+            db::set_original_hash(
+                conn,
+                &original_hash,
+                original_size,
+                &kind,
+                &input_blob.content_hash,
+            )?;
+            report.aliased = add_alias(conn, &filename, &report.content_hash)?;
+            finish(conn, &mut report)
+        })?;
+        return Ok(report);
+    }
+    info!("push: append_full={}ms", sw.elapsed_ms(),);
+
+    // the input is read only by append_full, deltas are encoded from the stored blob
+    let checked =
+        check_unchanged(input_filepath, input_hash.as_deref()).and_then(|_| check_cancelled(opts));
+    if let Err(e) = checked {
+        undo_append_full(conn, &input_blob)?;
+        return Err(e);
+    }
+
+    if root_blobs.is_empty() {
+        info!("push: no root blobs: genesis");
+        commit_push(conn, Some(&input_blob), |conn, _unlink| {
+            if let Some(max_store_size) = opts.max_store_size {
+                check_quota(conn, &filename, None, max_store_size, opts.quota_policy)?;
+            }
+            db::set_original_hash(
+                conn,
+                &original_hash,
+                original_size,
+                &kind,
+                &input_blob.content_hash,
+            )?;
+            let genesis = RootTransition::new(EVENT_ROOT_ADDED, &input_blob, "genesis");
+            report.roots.push(genesis);
+            finish(conn, &mut report)
+        })?;
+        return Ok(report);
+    }
+
+    let series_root = match &opts.series {
+        Some(series) => series_root(conn, series)?,
+        None => None,
+    };
+
+    let root_blobs = rank_roots(root_blobs, &input_blob)
+        .into_iter()
+        .filter(|root| {
+            !series_root
+                .as_ref()
+                .is_some_and(|s| s.is_same_content(root))
+        })
+        .collect::<Vec<_>>();
+    let scored = if !opts.no_prefilter || opts.record_trials {
+        match sketch::score_candidates(conn, &input_blob, root_blobs.clone()) {
+            Ok(scored) => scored,
+            Err(e) => {
+                undo_append_full(conn, &input_blob)?;
+                return Err(e);
+            }
+        }
+    } else {
+        Vec::new()
+    };
+    let (root_blobs, skipped) = if opts.no_prefilter {
+        (root_blobs, Vec::new())
+    } else {
+        let (kept, skipped) = sketch::prefilter(
+            scored.clone(),
+            sketch::PREFILTER_THRESHOLD,
+            sketch::PREFILTER_MIN_CANDIDATES,
+        );
+        for (root, similarity) in &skipped {
+            info!(
+                "push: prefilter skipped root={} similarity={:.03}",
+                root.store_hash,
+                similarity.unwrap_or(0.0)
+            );
+        }
+        (kept, skipped)
+    };
+    let estimator = match &opts.estimator {
+        Some(estimator) => estimator.clone(),
+        None => Arc::new(MinhashEstimator),
+    };
+    let root_blobs = sketch::order_candidates(conn, estimator.as_ref(), &input_blob, root_blobs);
+    let (same_flavor, others) = match filename_hint(conn)? {
+        Some(regex) => {
+            let (same_flavor, others, hint) =
+                hint::split_candidates(&regex, &filename, root_blobs, opts.hint_strict)?;
+            info!(
+                "push: filename hint path={:?} flavor={:?} same_flavor={}",
+                hint.path, hint.flavor, hint.same_flavor
+            );
+            report.hint = Some(hint);
+            (same_flavor, others)
+        }
+        None => (Vec::new(), root_blobs),
+    };
+    // with a good enough ratio, the most promising candidate goes first, then the rest of its
+    // flavor, then the others. a good enough delta ends the search, otherwise the best delta so
+    // far starts the race, so the later candidates stop once they grow past it.
+    let same_flavor_len = same_flavor.len();
+    let mut root_blobs = same_flavor.into_iter().chain(others).take(DELTA_CANDIDATES);
+    let groups = match opts.good_enough_ratio {
+        Some(_) => vec![
+            root_blobs.by_ref().take(1).collect::<Vec<_>>(),
+            root_blobs
+                .by_ref()
+                .take(same_flavor_len.saturating_sub(1))
+                .collect(),
+            root_blobs.collect(),
+        ],
+        None => vec![root_blobs.collect()],
+    };
+
+    // a larger delta may be picked, so candidates neither race nor stop the search
+    let optimal = opts.parent_selection == ParentSelection::Optimal;
+    let race = Arc::new(AtomicUsize::new(0));
+    let budget = TmpBudget::new(opts.tmp_budget);
+    // a delta is rarely larger than its content
+    let reserve = input_blob.content_size;
+    let candidates = std::sync::Mutex::new(DeltaCandidates {
+        keep_all: optimal,
+        ..Default::default()
+    });
+    let encode = |root_blob: Blob| {
+        budget.acquire(reserve);
+        let race = if optimal {
+            Arc::new(AtomicUsize::new(0))
+        } else {
+            race.clone()
+        };
+        let res = append_delta(&input_blob, &root_blob, race, opts.cancel.clone());
+        let kept = match &res {
+            Ok(Some((_tmp, blob))) => blob.store_size,
+            _ => 0,
+        };
+        budget.release(reserve, kept);
+        candidates.lock().expect("poisoned").add(&budget, res);
+    };
+
+    let mut remaining = groups.iter().map(Vec::len).sum::<usize>();
+    for group in groups.into_iter().filter(|group| !group.is_empty()) {
+        let good_enough = match (
+            &candidates.lock().expect("poisoned").best,
+            opts.good_enough_ratio,
+        ) {
+            (Some((_tmp, best)), Some(ratio)) => best.compression_ratio() <= ratio,
+            _ => false,
+        };
+        if good_enough && !optimal {
+            info!(
+                "push: delta is good enough, skipping {} candidates",
+                remaining
+            );
+            break;
+        }
+        remaining -= group.len();
+        let hashes = group.iter().map(|root| root.store_hash.clone());
+        report.tried_roots.extend(hashes);
+        group.into_par_iter().for_each(encode);
+    }
+    let mut candidates = candidates.into_inner().expect("poisoned");
+
+    debug!("compression ratio: {}", ratio_summary(&candidates.blobs));
+
+    if optimal {
+        let kept = std::mem::take(&mut candidates.kept);
+        let deltas = kept
+            .iter()
+            .map(|(_tmp, blob)| blob.clone())
+            .collect::<Vec<_>>();
+        let selected = match select_delta(conn, &deltas, opts.parent_selection) {
+            Ok(selected) => selected,
+            Err(e) => {
+                undo_append_full(conn, &input_blob)?;
+                return Err(e);
+            }
+        };
+        for (idx, link) in kept.into_iter().enumerate() {
+            if Some(idx) == selected {
+                candidates.best = Some(link);
+            } else {
+                budget.free(link.1.store_size);
+            }
+        }
+        if let Some((_tmp, best)) = &candidates.best {
+            match project_cleanup(conn, Some(best)) {
+                Ok((projected_size, _)) => report.projected_store_size = Some(projected_size),
+                Err(e) => {
+                    undo_append_full(conn, &input_blob)?;
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    // the series predecessor does not take part in the race, so it is never cut short
+    if let Some(series_root) = &series_root {
+        let series_race = Arc::new(AtomicUsize::new(0));
+        budget.acquire(reserve);
+        let res = append_delta(&input_blob, series_root, series_race, opts.cancel.clone());
+        match res {
+            Ok(Some(series_link)) => {
+                budget.release(reserve, series_link.1.store_size);
+                let preferred = match &candidates.best {
+                    Some((_tmp, best)) => {
+                        series_link.1.store_size as f64
+                            <= best.store_size as f64 * SERIES_PARENT_SLACK
+                    }
+                    None => true,
+                };
+                if preferred {
+                    if let Some((_tmp, old)) = candidates.best.replace(series_link) {
+                        budget.free(old.store_size);
+                    }
+                } else {
+                    info!(
+                        "push: series predecessor is not used, store_size={} best={}",
+                        series_link.1.store_size,
+                        candidates.best.as_ref().map_or(0, |best| best.1.store_size)
+                    );
+                    budget.free(series_link.1.store_size);
+                }
+            }
+            res => {
+                budget.release(reserve, 0);
+                candidates.add(&budget, res);
+            }
+        }
+    }
+    report.peak_tmp_bytes = Some(budget.peak());
+
+    // the last check, the delta is stored below
+    if let Err(e) = check_cancelled(opts) {
+        undo_append_full(conn, &input_blob)?;
+        return Err(e);
+    }
+
+    let (tmp_path, blob) = match candidates.best {
+        Some(best) => best,
+        None => {
+            undo_append_full(conn, &input_blob)?;
+            let summary = format!(
+                "all {} delta candidates failed, cut_short={}",
+                candidates.errors.len() + candidates.cut_short,
+                candidates.cut_short
+            );
+            warn!("push: {}", summary);
+            // the error of the first candidate, so `push_with_retry` can tell it is transient
+            let error = match candidates.errors.into_iter().next() {
+                Some(Error::Delta { error, .. }) => error,
+                Some(e) => io::Error::other(e),
+                None => io::Error::other(summary),
+            };
+            return Err(Error::Delta {
+                filename: input_blob.filename.clone(),
+                error,
+            });
+        }
+    };
+    report.ratio = Some(blob.compression_ratio());
+    match opts.max_ratio {
+        Some(max_ratio) => info!(
+            "push: ratio={:.02}% max_ratio={:.02}% peak_tmp={}",
+            blob.compression_ratio() * 100.0,
+            max_ratio * 100.0,
+            bytesize::ByteSize(budget.peak())
+        ),
+        None => info!(
+            "push: ratio={:.02}% peak_tmp={}",
+            blob.compression_ratio() * 100.0,
+            bytesize::ByteSize(budget.peak())
+        ),
+    }
+
+    if report.ratio_exceeded() && opts.max_ratio_strict {
+        // the delta is dropped with tmp_path
+        undo_append_full(conn, &input_blob)?;
+        return Err(Error::RatioExceeded {
+            filename: input_blob.filename,
+            ratio: blob.compression_ratio(),
+            max_ratio: opts.max_ratio.unwrap_or_default(),
+        });
+    }
+
+    if opts.record_trials {
+        match sketch::trials(&input_blob, &blob, &scored, &skipped, &candidates.blobs) {
+            Ok(trials) => report.trials = Some(trials),
+            Err(e) => {
+                undo_append_full(conn, &input_blob)?;
+                return Err(e);
+            }
+        }
+    }
+
+    commit_push(conn, Some(&input_blob), |conn, unlink| {
+        let pruned = match opts.max_store_size {
+            Some(max_store_size) => check_quota(
+                conn,
+                &filename,
+                Some(&blob),
+                max_store_size,
+                opts.quota_policy,
+            )?,
+            None => Vec::new(),
+        };
+
+        db::set_original_hash(
+            conn,
+            &original_hash,
+            original_size,
+            &kind,
+            &input_blob.content_hash,
+        )?;
+
+        // optimal block
+        if !update_blob(conn, tmp_path, &blob)? {
+            info!(
+                "append_delta: failed to insert, store_hash={}",
+                blob.store_hash
+            );
+        }
+        report.delta = Some(blob);
+        report
+            .roots
+            .push(RootTransition::new(EVENT_ROOT_ADDED, &input_blob, "push"));
+
+        let (_, removed) = remove_cleanup_victims(conn, false, unlink)?;
+        report
+            .roots
+            .extend(removed_transitions(&removed, CLEANUP_REASON));
+        for root in pruned {
+            info!("push: pruning root blob={} for quota", root.store_hash);
+            remove_root_deferred(conn, &root, unlink)?;
+            report
+                .roots
+                .push(RootTransition::new(EVENT_ROOT_REMOVED, &root, "quota"));
+        }
+        finish(conn, &mut report)
+    })?;
+    Ok(report)
+}
+
+pub fn bench_zip(input_filepath: &str, parallel: bool) -> Result<()> {
+    let tmp_dir = tmpdir();
+    let tempfile = NamedTempFile::new_in(&tmp_dir)?;
 
     let ws = Stopwatch::start_new();
     let _meta = store_zip(input_filepath, tempfile.path(), parallel)?;
@@ -476,245 +3128,2209 @@ pub fn bench_zip(input_filepath: &str, parallel: bool) -> Result<()> {
     Ok(())
 }
 
-pub fn debug_stats(conn: &mut db::Conn) -> Result<()> {
-    let blobs = db::all(conn)?;
+/// `Stats::disk_usage` from the root files in the object directory
+fn probe_disk_usage(stats: &Stats) -> stats::DiskUsage {
+    stats.disk_usage(|blob| {
+        std::fs::metadata(filepath(&blob.content_hash))
+            .ok()
+            .map(|meta| meta.len())
+    })
+}
+
+/// prints stats of the store. `probe_disk` stats root files, to tell hydrated roots apart.
+pub fn debug_stats(conn: &mut db::Conn, probe_disk: bool) -> Result<()> {
+    let stats = load_stats(conn)?;
+    println!("info\n{}", stats.size_info());
+    if probe_disk {
+        let usage = probe_disk_usage(&stats);
+        println!(
+            "on disk\n  hydrated_roots={} size={}",
+            usage.hydrated_roots,
+            bytesize::ByteSize(usage.size)
+        );
+    }
+
+    let parents = stats.top_delta_parents(5);
+    if !parents.is_empty() {
+        println!("top delta parents");
+        for (idx, ref_count) in parents {
+            let blob = &stats.blobs[idx];
+            println!("  V{} {} refs={}", blob.id, blob.filename, ref_count);
+        }
+    }
+
+    let dehydrated = dehydrated_roots(conn)?;
+    if !dehydrated.is_empty() {
+        println!("dehydrated roots");
+        for root in dehydrated {
+            let health = if root.missing.is_empty() {
+                "ok".to_owned()
+            } else {
+                format!("missing={:?}", root.missing)
+            };
+            let chain = root
+                .deps
+                .iter()
+                .map(|blob| blob.id.to_string())
+                .collect::<Vec<_>>()
+                .join("->");
+            println!(
+                "  {} {} chain={} {}",
+                root.content_hash,
+                root.filename.as_deref().unwrap_or("-"),
+                chain,
+                health
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// prints `root_transitions`, oldest first. the filename is the latest one of the content, or
+/// `-` if the content is no longer stored.
+pub fn debug_root_transitions(conn: &mut db::Conn) -> Result<()> {
+    for (ts, root) in root_transitions(conn)? {
+        // the store hash of a root is its content hash
+        let filename = match db::by_content_hash(conn, &root.store_hash)?.pop() {
+            Some(blob) => blob.filename,
+            None => "-".to_owned(),
+        };
+        println!(
+            "{} {} {} {} reason={}",
+            ts, root.event, root.store_hash, filename, root.reason
+        );
+    }
+    Ok(())
+}
+
+pub fn debug_graph(conn: &mut db::Conn, filename: &str) -> Result<()> {
+    use std::fmt::Write;
+
+    let blobs = db::all(conn)?;
+    let stats = Stats::from_blobs(blobs);
+
+    let mut s = String::new();
+    writeln!(s, "digraph increstore {{").ok();
+    writeln!(s, "  rankdir=\"LR\"").ok();
+
+    let min_size = (stats.blobs.iter().map(|v| v.store_size).min().unwrap_or(10) as f32).log10();
+    let max_size = (stats.blobs.iter().map(|v| v.store_size).max().unwrap_or(10) as f32).log10();
+
+    let min_width = 0.4;
+    let max_width = 2.0;
+    let abs_min_width = 0.7;
+
+    let size_project = |size: u64| {
+        let size = (size as f32).log10();
+        let ratio = (size - min_size) / (max_size - min_size);
+        (min_width + (max_width - min_width) * ratio).max(abs_min_width)
+    };
+
+    for (idx, blob) in stats.blobs.iter().enumerate() {
+        let name = stats.node_name(idx);
+        let label = format!("{}\\n{}", name, bytesize::ByteSize(blob.store_size));
+
+        let size = size_project(blob.store_size);
+        let style = if blob.is_root() {
+            "shape=doublecircle style=filled fillcolor=red"
+        } else {
+            "shape=circle"
+        };
+        writeln!(
+            s,
+            "  {} [label=\"{}\" width={:.02} fixedsize=true {}];",
+            name, label, size, style
+        )
+        .ok();
+    }
+
+    {
+        let spine = stats.spine();
+
+        for (idx, pair) in spine.windows(2).enumerate() {
+            writeln!(
+                s,
+                "{}->{}[label=\"{}\"];",
+                stats.node_name(pair[0]),
+                stats.node_name(pair[1]),
+                idx
+            )
+            .ok();
+        }
+
+        for (i, idx) in spine.into_iter().enumerate() {
+            let name = stats.node_name(idx);
+            if i == 0 {
+                writeln!(s, "{}", name).ok();
+            } else {
+                writeln!(s, "->{}", name).ok();
+            }
+        }
+        writeln!(s, " [style=invis weight=100]").ok();
+    }
+
+    for (idx, _blob) in stats.blobs.iter().enumerate() {
+        let node = &stats.depths[idx];
+        if let Some(parent_idx) = node.parent_idx {
+            writeln!(
+                s,
+                "  {} -> {};",
+                stats.node_name(parent_idx),
+                stats.node_name(idx),
+            )
+            .ok();
+            //
+        }
+    }
+
+    writeln!(s, "}}").ok();
+
+    write_graph(filename, &s)
+}
+
+/// replaces `filename` atomically, so a reader sees either the old graph or the new one.
+/// writers of the same file are serialized under a lock of its directory.
+fn write_graph(filename: &str, graph: &str) -> Result<()> {
+    use std::io::Write;
+
+    let _lock = lock_parent_dir(filename)?;
+    let mut tmpfile = NamedTempFile::new_in(parent_dir(filename))?;
+    tmpfile.write_all(graph.as_bytes())?;
+    tmpfile.as_file().sync_all()?;
+    set_readable(&tmpfile)?;
+    retry::persist(tmpfile, filename, false)?;
+    Ok(())
+}
+
+pub fn debug_list_files(
+    conn: &mut db::Conn,
+    genesis: bool,
+    roots: bool,
+    non_roots: bool,
+    long: bool,
+    json: bool,
+) -> Result<()> {
+    for entry in db::iter_all(conn).with_provenance() {
+        let (blob, provenance) = entry?;
+        let is_root = blob.is_root();
+
+        // TODO: better genesis check?
+        let should_print =
+            (roots && is_root) || (non_roots && !is_root) || (genesis && blob.is_genesis());
+
+        if !should_print {
+            continue;
+        }
+
+        let path = filepath(&blob.store_hash);
+        if json {
+            println!("{}", db::blob_json(&blob, &provenance));
+        } else if long {
+            println!("{} {}", path, blob.filename);
+        } else {
+            println!("{}", path);
+        }
+    }
+    Ok(())
+}
+
+fn path_to_hash(mut path: PathBuf, root: &Path) -> Option<String> {
+    let mut s = String::new();
+    while let Some(name) = path.file_name() {
+        let file_name = name.to_str()?;
+        s = file_name.to_owned() + &s;
+
+        path.pop();
+        if path == root {
+            break;
+        }
+    }
+    Some(s)
+}
+
+/// files in the object directory, keyed by hash derived from their paths
+fn object_files() -> Result<HashMap<String, std::fs::Metadata>> {
+    let pathstr = objectdir();
+    let objectdir = Path::new(&pathstr);
+
+    let mut objects = HashMap::new();
+    for entry in walkdir::WalkDir::new(&objectdir) {
+        let entry = entry?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let hash = match path_to_hash(entry.path().to_path_buf(), &objectdir) {
+            Some(hash) => hash,
+            None => {
+                error!("failed to get hash from path: {:?}", entry.path());
+                continue;
+            }
+        };
+        objects.insert(hash, entry.metadata()?);
+    }
+    Ok(objects)
+}
+
+pub fn debug_blobs(conn: &mut db::Conn) -> Result<()> {
+    let blobs = db::all(conn)?;
+
+    // check blob store
+    {
+        use std::collections::hash_map::Entry;
+
+        let mut objects = object_files()?;
+
+        for blob in &blobs {
+            match objects.entry(blob.store_hash.clone()) {
+                Entry::Occupied(ent) => {
+                    let (_k, v) = ent.remove_entry();
+                    if v.len() != blob.store_size {
+                        error!(
+                            "invalid file size: expected={}, actual={}",
+                            blob.store_size,
+                            v.len()
+                        );
+                    }
+                }
+                Entry::Vacant(_ent) => {
+                    error!("blob not exists: {}", blob.store_hash);
+                }
+            }
+        }
+
+        for (k, _v) in objects {
+            error!("unexpected blob: {}", k);
+        }
+    }
+
+    // check if all blobs are reachable from a genesis blob
+    {
+        let stats = Stats::from_blobs(blobs);
+        let mut reached = Vec::with_capacity(stats.blobs.len());
+        reached.resize(stats.blobs.len(), false);
+        mark_reached(0, &stats, &mut reached);
+
+        for (idx, reached) in reached.iter().enumerate() {
+            if stats.blobs[idx].is_root() {
+                continue;
+            }
+
+            if !reached {
+                error!("blob not reachable, idx={}", idx);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn mark_reached(idx: usize, stats: &Stats, reached: &mut [bool]) {
+    reached[idx] = true;
+    for child_idx in stats.children(idx, true) {
+        mark_reached(child_idx, stats, reached);
+    }
+}
+
+/// fails unless the file at `path` is the content of `blob`
+fn check_content(path: &Path, blob: &Blob) -> Result<()> {
+    let digest = file_hash(path.to_str().expect("invalid path"))?;
+    if digest != blob.content_hash {
+        return Err(Error::ChecksumMismatch {
+            filename: blob.filename.clone(),
+            expected: blob.content_hash.clone(),
+            actual: digest,
+        });
+    }
+    Ok(())
+}
+
+fn file_hash(filename: &str) -> Result<String> {
+    const BUF_SIZE: usize = 8 * 1024 * 1024;
+
+    use std::io::Read;
+
+    let file = std::fs::File::open(filename)?;
+    let mut reader = rw::HashRW::new(file);
+
+    let mut buf = Vec::with_capacity(BUF_SIZE);
+    buf.resize(BUF_SIZE, 0u8);
+
+    while reader.read(&mut buf)? != 0 {
+        //
+    }
+
+    Ok(reader.meta().digest())
+}
+
+/// default chunk size of `hash_file_parallel`
+pub const PARALLEL_HASH_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+
+/// hashes chunks of a file in parallel, then the list of chunk digests. the digest depends on
+/// `chunk_size` and differs from the content hash of the file, compare it only with digests from
+/// `hash_file_parallel`.
+pub fn hash_file_parallel(path: impl AsRef<Path>, chunk_size: usize) -> Result<String> {
+    use std::io::Write;
+
+    if chunk_size == 0 {
+        return Err(crate::format_err!("chunk_size should be positive"));
+    }
+
+    let file = std::fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    let mmap;
+    let data: &[u8] = if len == 0 {
+        &[]
+    } else {
+        mmap = unsafe { memmap::Mmap::map(&file)? };
+        &mmap
+    };
+
+    let digests = data
+        .par_chunks(chunk_size)
+        .map(|chunk| {
+            let mut w = HashRW::new(io::sink());
+            w.write_all(chunk)?;
+            Ok(w.meta().digest())
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let mut w = HashRW::new(io::sink());
+    write!(w, "{}:{}:", len, chunk_size)?;
+    for digest in digests {
+        w.write_all(digest.as_bytes())?;
+    }
+    Ok(w.meta().digest())
+}
+
+pub fn zip_hashes(filename: &str) -> Result<()> {
+    let hashes = zip::store_zip_entry_hashes(filename)?;
+
+    let mut entries = hashes.into_iter().collect::<Vec<_>>();
+    entries.sort();
+    for (name, hash) in entries {
+        println!("{} {}", hash, name);
+    }
+
+    Ok(())
+}
+
+/// prints the content hash of a file, or its `hash_file_parallel` digest with `chunk_size`
+pub fn debug_hash(filename: &str, chunk_size: Option<usize>) -> Result<()> {
+    let hash = match chunk_size {
+        Some(chunk_size) => hash_file_parallel(filename, chunk_size)?,
+        None => file_hash(filename)?,
+    };
+    println!("{}", hash);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+
+    #[test]
+    fn hydrate_output_template() {
+        with_store(|conn, dir| {
+            let v1 = write_file(dir, "app-1.tar", b"hello, world");
+            let v2 = write_file(dir, "app-2.tar", b"hello, world!!");
+            push(conn, &v1, FileType::Plain).unwrap();
+            push(conn, &v2, FileType::Plain).unwrap();
+
+            let template = format!("{}/out/{{name}}", dir.display());
+            hydrate(conn, Some(&template)).unwrap();
+
+            let restored = std::fs::read(dir.join("out/app-2.tar")).unwrap();
+            assert_eq!(restored, b"hello, world!!");
+            // genesis is not a frontier version
+            assert!(!dir.join("out/app-1.tar").exists());
+        });
+    }
+
+    #[test]
+    fn dehydrate_cleanup_hydrate() {
+        with_store(|conn, dir| {
+            push_two_versions(conn, dir);
+            let root = db::by_filename(conn, "app-2.tar").unwrap().remove(0);
+            assert!(root.is_root());
+
+            dehydrate(conn, false).unwrap();
+            assert!(!Path::new(&filepath(&root.content_hash)).exists());
+            let dehydrated = dehydrated_roots(conn).unwrap();
+            assert_eq!(dehydrated.len(), 1);
+            assert!(dehydrated[0].missing.is_empty());
+            // genesis and the alias delta of app-2.tar
+            assert_eq!(dehydrated[0].deps.len(), 2);
+
+            let protected = db::protected_store_hashes(conn).unwrap();
+            let genesis = db::by_filename(conn, "app-1.tar").unwrap().remove(0);
+            assert!(protected.contains(&genesis.store_hash));
+            cleanup(conn).unwrap();
+            assert_eq!(db::all(conn).unwrap().len(), 3);
+
+            hydrate(conn, None).unwrap();
+            assert!(Path::new(&filepath(&root.content_hash)).exists());
+            assert!(dehydrated_roots(conn).unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn dehydrate_dry_run() {
+        with_store(|conn, dir| {
+            push_two_versions(conn, dir);
+            let root = db::by_filename(conn, "app-2.tar").unwrap().remove(0);
+            let objects = db::all(conn).unwrap();
+
+            let report = dehydrate(conn, true).unwrap();
+            assert_eq!(report.roots.len(), 1);
+            assert_eq!(report.roots[0].filename, "app-2.tar");
+            assert_eq!(report.roots[0].path, filepath(&root.content_hash));
+            assert_eq!(report.total_size, b"hello, world!!".len() as u64);
+            for blob in &objects {
+                assert!(Path::new(&filepath(&blob.store_hash)).exists());
+            }
+            assert!(dehydrated_roots(conn).unwrap().is_empty());
+
+            let report = dehydrate(conn, false).unwrap();
+            assert_eq!(report.total_size, b"hello, world!!".len() as u64);
+            assert!(!Path::new(&filepath(&root.content_hash)).exists());
+        });
+    }
+
+    #[test]
+    fn dehydrate_refuses_broken_chain() {
+        with_store(|conn, dir| {
+            push_two_versions(conn, dir);
+            let genesis = db::by_filename(conn, "app-1.tar").unwrap().remove(0);
+            std::fs::remove_file(filepath(&genesis.content_hash)).unwrap();
+
+            assert!(dehydrate(conn, false).is_err());
+            assert!(dehydrated_roots(conn).unwrap().is_empty());
+        });
+    }
+
+    fn push_two_versions(conn: &mut db::Conn, dir: &Path) {
+        let v1 = write_file(dir, "app-1.tar", b"hello, world");
+        let v2 = write_file(dir, "app-2.tar", b"hello, world!!");
+        push(conn, &v1, FileType::Plain).unwrap();
+        push(conn, &v2, FileType::Plain).unwrap();
+    }
+
+    #[test]
+    fn push_max_ratio() {
+        with_store(|conn, dir| {
+            let v1 = write_file(dir, "app-1.tar", b"hello, world");
+            let v2 = write_file(dir, "app-2.tar", b"hello, world!!");
+            push(conn, &v1, FileType::Plain).unwrap();
+
+            let opts = PushOptions {
+                max_ratio: Some(0.0),
+                max_ratio_strict: false,
+                ..Default::default()
+            };
+            let report = push_with_options(conn, &v2, FileType::Plain, &opts).unwrap();
+            assert!(report.ratio_exceeded());
+            assert!(report.delta.is_some());
+            assert_eq!(db::by_filename(conn, "app-2.tar").unwrap().len(), 2);
+        });
+    }
+
+    #[test]
+    fn push_max_ratio_strict() {
+        with_store(|conn, dir| {
+            let v1 = write_file(dir, "app-1.tar", b"hello, world");
+            let v2 = write_file(dir, "app-2.tar", b"hello, world!!");
+            push(conn, &v1, FileType::Plain).unwrap();
+            let before = object_files().unwrap();
+
+            let opts = PushOptions {
+                max_ratio: Some(0.0),
+                max_ratio_strict: true,
+                ..Default::default()
+            };
+            let err = push_with_options(conn, &v2, FileType::Plain, &opts).unwrap_err();
+            assert_eq!(exit_code(&err), EXIT_RATIO_EXCEEDED);
+            assert_eq!(db::all(conn).unwrap().len(), 1);
+            assert_eq!(object_files().unwrap().len(), before.len());
+
+            // within the threshold
+            let opts = PushOptions {
+                max_ratio: Some(100.0),
+                max_ratio_strict: true,
+                ..Default::default()
+            };
+            let report = push_with_options(conn, &v2, FileType::Plain, &opts).unwrap();
+            assert!(!report.ratio_exceeded());
+            assert_eq!(db::all(conn).unwrap().len(), 3);
+        });
+    }
+
+    /// cancels the push while it orders delta candidates, so encoding is cut short
+    #[derive(Debug)]
+    struct CancellingEstimator(Arc<AtomicBool>);
+
+    impl SimilarityEstimator for CancellingEstimator {
+        fn predicted_ratio(
+            &self,
+            _conn: &mut db::Conn,
+            _input: &Blob,
+            _root: &Blob,
+        ) -> Result<f64> {
+            self.0.store(true, Ordering::SeqCst);
+            Ok(0.0)
+        }
+    }
+
+    #[test]
+    fn parent_selection() {
+        with_store(|conn, _dir| {
+            let blob =
+                |store_hash: &str, content_hash: &str, size: u64, parent: Option<&str>| Blob {
+                    id: 0,
+                    filename: format!("{}.tar", content_hash),
+                    time_created: time::OffsetDateTime::now_utc(),
+                    store_size: size,
+                    content_size: 10_000,
+                    store_hash: store_hash.to_owned(),
+                    content_hash: content_hash.to_owned(),
+                    parent_hash: parent.map(str::to_owned),
+                };
+            // r1 is a large root with a small alias, the first one cleanup removes unless a new
+            // delta keeps it in use
+            db::insert(conn, &blob("g", "g", 1000, None)).unwrap();
+            for (name, full, alias) in [
+                ("r1", 5000, 15),
+                ("r2", 1000, 1000),
+                ("r3", 1000, 1000),
+                ("r4", 1000, 1000),
+                ("r5", 1000, 1000),
+            ] {
+                db::insert(conn, &blob(name, name, full, None)).unwrap();
+                db::insert(conn, &blob(&format!("{}-d", name), name, alias, Some("g"))).unwrap();
+            }
+            db::insert(conn, &blob("x", "x", 100, None)).unwrap();
+            let count = db::count(conn).unwrap();
+
+            // the smallest delta keeps r1 fresh, so cleanup removes the small root of x instead
+            let deltas = [
+                blob("x-r1", "x", 10, Some("r1")),
+                blob("x-r2", "x", 20, Some("r2")),
+            ];
+            let greedy = select_delta(conn, &deltas, ParentSelection::Greedy).unwrap();
+            let optimal = select_delta(conn, &deltas, ParentSelection::Optimal).unwrap();
+            assert_eq!(greedy, Some(0));
+            assert_eq!(optimal, Some(1));
+            let (greedy_size, _) = project_cleanup(conn, Some(&deltas[0])).unwrap();
+            let (optimal_size, _) = project_cleanup(conn, Some(&deltas[1])).unwrap();
+            assert!(optimal_size < greedy_size);
+            assert_eq!(db::count(conn).unwrap(), count);
+            assert_eq!(
+                select_delta(conn, &[], ParentSelection::Optimal).unwrap(),
+                None
+            );
+        });
+
+        // a push reports the store size it projects
+        with_store(|conn, dir| {
+            push_two_versions(conn, dir);
+            let input = write_file(dir, "app-3.tar", b"hello, world!!!");
+            let opts = PushOptions {
+                parent_selection: ParentSelection::Optimal,
+                ..Default::default()
+            };
+            let report = push_with_options(conn, &input, FileType::Plain, &opts).unwrap();
+            assert!(report.delta.is_some());
+            let (store_size, _) = db::total_sizes(conn).unwrap();
+            assert_eq!(report.projected_store_size, Some(store_size));
+        });
+    }
+
+    #[test]
+    fn push_retry() {
+        with_store(|conn, dir| {
+            let input = write_file(dir, "app-1.tar", b"hello, world");
+            let lock = || {
+                let other = db::open().unwrap();
+                other.execute_batch("begin exclusive").unwrap();
+                other
+            };
+
+            let other = lock();
+            let e = push_with_retry(conn, &input, FileType::Plain, 0).unwrap_err();
+            assert_eq!(error_kind(&e), ErrorKind::Locked);
+
+            // the lock is released while the push waits for its retry
+            let unlock = std::thread::spawn(move || {
+                std::thread::sleep(PUSH_RETRY_BACKOFF / 2);
+                other.execute_batch("commit").unwrap();
+            });
+            let report = push_with_retry(conn, &input, FileType::Plain, 5).unwrap();
+            unlock.join().unwrap();
+            assert!(!report.skipped);
+            assert_eq!(db::count(conn).unwrap(), 1);
+
+            // a delta fails while the object of the root is missing, and is retried once it is
+            // back
+            let root = db::roots(conn).unwrap().pop().unwrap();
+            let root_path = filepath(&root.store_hash);
+            let hidden = format!("{}.hidden", root_path);
+            let input = write_file(dir, "app-2.tar", b"hello, world!!");
+            std::fs::rename(&root_path, &hidden).unwrap();
+            let e = push_with_retry(conn, &input, FileType::Plain, 0).unwrap_err();
+            assert!(matches!(e, Error::Delta { .. }), "{:?}", e);
+            assert_eq!(db::count(conn).unwrap(), 1);
+
+            let restore = std::thread::spawn(move || {
+                std::thread::sleep(PUSH_RETRY_BACKOFF / 2);
+                std::fs::rename(&hidden, &root_path).unwrap();
+            });
+            push_with_retry(conn, &input, FileType::Plain, 5).unwrap();
+            restore.join().unwrap();
+            assert!(db::by_filename(conn, "app-2.tar")
+                .unwrap()
+                .iter()
+                .any(|blob| blob.parent_hash.as_ref() == Some(&root.content_hash)));
+
+            // other errors are not retried
+            let missing = dir.join("missing.tar");
+            let e = push_with_retry(conn, missing.to_str().unwrap(), FileType::Plain, 5);
+            assert_eq!(error_kind(&e.unwrap_err()), ErrorKind::Io);
+        });
+    }
+
+    #[test]
+    fn push_cancelled() {
+        with_store(|conn, dir| {
+            push_two_versions(conn, dir);
+            let v3 = write_file(dir, "app-3.tar", b"hello, world!!!");
+            let blobs = db::all(conn).unwrap();
+            let objects = object_files().unwrap();
+            let unchanged = |conn: &mut db::Conn| {
+                assert_eq!(db::all(conn).unwrap(), blobs);
+                assert_eq!(object_files().unwrap().len(), objects.len());
+                assert_eq!(std::fs::read_dir(tmpdir()).unwrap().count(), 0);
+            };
+
+            // before the push
+            let cancel = Arc::new(AtomicBool::new(true));
+            let opts = PushOptions {
+                cancel: Some(cancel.clone()),
+                ..Default::default()
+            };
+            let err = push_with_options(conn, &v3, FileType::Plain, &opts).unwrap_err();
+            assert_eq!(error_kind(&err), ErrorKind::Cancelled);
+            assert_eq!(exit_code(&err), EXIT_CANCELLED);
+            unchanged(conn);
+
+            // after the full blob is stored
+            cancel.store(false, Ordering::SeqCst);
+            let opts = PushOptions {
+                cancel: Some(cancel.clone()),
+                estimator: Some(Arc::new(CancellingEstimator(cancel.clone()))),
+                ..Default::default()
+            };
+            let err = push_with_options(conn, &v3, FileType::Plain, &opts).unwrap_err();
+            assert_eq!(error_kind(&err), ErrorKind::Cancelled);
+            unchanged(conn);
+
+            cancel.store(false, Ordering::SeqCst);
+            let opts = PushOptions {
+                cancel: Some(cancel),
+                ..Default::default()
+            };
+            push_with_options(conn, &v3, FileType::Plain, &opts).unwrap();
+            assert_eq!(db::all(conn).unwrap().len(), blobs.len() + 2);
+        });
+    }
+
+    #[test]
+    fn cancel_on_signal_once() {
+        // the handler is installed by the first call only
+        let first = cancel_on_signal().unwrap();
+        let second = cancel_on_signal().unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+    }
+
+    #[test]
+    fn push_max_store_size() {
+        with_store(|conn, dir| {
+            let v1 = write_file(dir, "app-1.tar", b"hello, world");
+            let v2 = write_file(dir, "app-2.tar", b"hello, world!!");
+
+            let opts = PushOptions {
+                max_store_size: Some(4),
+                ..Default::default()
+            };
+            let err = push_with_options(conn, &v1, FileType::Plain, &opts).unwrap_err();
+            assert_eq!(exit_code(&err), EXIT_QUOTA_EXCEEDED);
+            assert!(db::all(conn).unwrap().is_empty());
+
+            push(conn, &v1, FileType::Plain).unwrap();
+            let (store_size, _) = db::total_sizes(conn).unwrap();
+            let before = object_files().unwrap();
+
+            let opts = PushOptions {
+                max_store_size: Some(store_size),
+                ..Default::default()
+            };
+            let err = push_with_options(conn, &v2, FileType::Plain, &opts).unwrap_err();
+            assert_eq!(exit_code(&err), EXIT_QUOTA_EXCEEDED);
+            assert_eq!(db::all(conn).unwrap().len(), 1);
+            assert_eq!(object_files().unwrap().len(), before.len());
+            assert_eq!(db::total_sizes(conn).unwrap().0, store_size);
+
+            let opts = PushOptions {
+                max_store_size: Some(store_size * 100),
+                ..Default::default()
+            };
+            push_with_options(conn, &v2, FileType::Plain, &opts).unwrap();
+            assert_eq!(db::all(conn).unwrap().len(), 3);
+        });
+    }
+
+    #[test]
+    fn push_explode_bundle() {
+        with_store(|conn, dir| {
+            let v1 = write_file(dir, "app-1.apks", &bundle_bytes(false));
+            let v2 = write_file(dir, "app-2.apks", &bundle_bytes(true));
+            let opts = PushOptions {
+                explode_bundle: true,
+                ..Default::default()
+            };
+            push_with_options(conn, &v1, FileType::Zip, &opts).unwrap();
+            let report = push_with_options(conn, &v2, FileType::Zip, &opts).unwrap();
+            let delta = report.delta.unwrap();
+            assert!(db::is_exploded(conn, &delta.content_hash).unwrap());
+
+            let out = dir.join("out.tar");
+            get(conn, "app-2.apks", out.to_str().unwrap(), false).unwrap();
+
+            let config = StoreConfig {
+                explode_nested: true,
+                ..Default::default()
+            };
+            let expected = dir.join("expected.tar");
+            store_zip_reproducible(&v2, &expected, &config).unwrap();
+            assert_eq!(
+                std::fs::read(&out).unwrap(),
+                std::fs::read(&expected).unwrap()
+            );
+
+            let plain = write_file(dir, "app-3.tar", b"hello");
+            assert!(push_with_options(conn, &plain, FileType::Plain, &opts).is_err());
+        });
+    }
+
+    #[test]
+    fn push_series() {
+        with_store(|conn, dir| {
+            let opts = PushOptions {
+                series: Some("app".to_owned()),
+                ..Default::default()
+            };
+            let v1 = write_file(dir, "app-1.tar", b"hello, world");
+            let report = push_with_options(conn, &v1, FileType::Plain, &opts).unwrap();
+            assert_eq!(report.series_seq, Some(1));
+
+            // closer in size to app-2.tar, so ranked first without a series
+            let other = write_file(dir, "other.tar", b"goodbye, world!!!!!!");
+            push(conn, &other, FileType::Plain).unwrap();
+
+            let v2 = write_file(dir, "app-2.tar", b"hello, world!!!!!!!!");
+            let report = push_with_options(conn, &v2, FileType::Plain, &opts).unwrap();
+            assert_eq!(report.series_seq, Some(2));
+            let v1_hash = db::by_filename(conn, "app-1.tar").unwrap()[0]
+                .content_hash
+                .clone();
+            assert_eq!(report.delta.unwrap().parent_hash, Some(v1_hash.clone()));
+
+            // the same push outside the series picks the best ranked root
+            let v2b = write_file(dir, "app-2b.tar", b"hello, world!!!!!!!?");
+            let report = push(conn, &v2b, FileType::Plain);
+            assert!(report.is_ok());
+            let delta = db::by_filename(conn, "app-2b.tar")
+                .unwrap()
+                .into_iter()
+                .find(|blob| !blob.is_root())
+                .unwrap();
+            assert_ne!(delta.parent_hash, Some(v1_hash));
+
+            // pushing the latest member again does not add a member
+            let report = push_with_options(conn, &v2, FileType::Plain, &opts).unwrap();
+            assert!(report.skipped);
+            assert_eq!(report.series_seq, Some(2));
+
+            let v3 = write_file(dir, "app-3.tar", b"hello, world!!!!!!!!!!");
+            push_with_options(conn, &v3, FileType::Plain, &opts).unwrap();
+
+            let members = db::series_members(conn, "app").unwrap();
+            let filenames = members
+                .iter()
+                .map(|member| (member.seq, member.filename.as_str()))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                filenames,
+                vec![(1, "app-1.tar"), (2, "app-2.tar"), (3, "app-3.tar")]
+            );
+
+            let latest = series_latest_filename(conn, "app").unwrap();
+            assert_eq!(latest, "app-3.tar");
+            let out = dir.join("out.tar");
+            get(conn, &latest, out.to_str().unwrap(), false).unwrap();
+            assert_eq!(std::fs::read(&out).unwrap(), b"hello, world!!!!!!!!!!");
+
+            assert!(series_latest_filename(conn, "unknown").is_err());
+        });
+    }
+
+    /// files of a tar stream, sorted by name
+    fn tar_files(data: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let mut ar = tar::Archive::new(data);
+        let mut files = ar
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let name = entry.path().unwrap().to_str().unwrap().to_owned();
+                let mut body = Vec::new();
+                io::Read::read_to_end(&mut entry, &mut body).unwrap();
+                (name, body)
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn roundtrip_random_zips() {
+        with_store(|conn, dir| {
+            let mut expected = Vec::new();
+            for seed in 0..16 {
+                let mut rng = Rng::new(seed);
+                let (data, mut files) = random_zip(&mut rng);
+                files.sort();
+                let filename = format!("app-{}.zip", seed);
+                let path = write_file(dir, &filename, &data);
+                push(conn, &path, FileType::Zip).unwrap();
+                expected.push((filename, data, files));
+            }
+
+            for (filename, data, files) in expected {
+                let out = dir.join(format!("{}.tar", filename));
+                get(conn, &filename, out.to_str().unwrap(), false).unwrap();
+                let out = std::fs::read(&out).unwrap();
+                assert_eq!(tar_files(&out), files, "{}", filename);
+                // the parallel conversion on push matches the sequential one
+                let tar = zip::zip_to_tar_bytes(&data, &StoreConfig::default()).unwrap();
+                assert_eq!(out, tar, "{}", filename);
+            }
+        });
+    }
+
+    #[test]
+    fn roundtrip_random_gz() {
+        with_store(|conn, dir| {
+            let mut expected = Vec::new();
+            for seed in 0..16 {
+                let mut rng = Rng::new(seed);
+                let body = rng.body();
+                let filename = format!("log-{}.gz", seed);
+                let path = write_file(dir, &filename, &random_gz(&mut rng, &body));
+                push(conn, &path, FileType::Gz).unwrap();
+                expected.push((filename, body));
+            }
+
+            for (filename, body) in expected {
+                let out = dir.join(&filename);
+                get(conn, &filename, out.to_str().unwrap(), false).unwrap();
+                assert_eq!(std::fs::read(&out).unwrap(), body, "{}", filename);
+            }
+        });
+    }
+
+    #[test]
+    fn hash_parallel() {
+        let dir = tempfile::tempdir().unwrap();
+        let body = "hello, world\n".repeat(100);
+        let a = write_file(dir.path(), "a", body.as_bytes());
+        let b = write_file(dir.path(), "b", body.as_bytes());
+        let c = write_file(dir.path(), "c", body.replace("world", "WORLD").as_bytes());
+        let empty = write_file(dir.path(), "empty", b"");
+
+        let a_hash = hash_file_parallel(&a, 64).unwrap();
+        assert_eq!(a_hash, hash_file_parallel(&b, 64).unwrap());
+        assert_ne!(a_hash, hash_file_parallel(&c, 64).unwrap());
+        assert_ne!(a_hash, hash_file_parallel(&a, 128).unwrap());
+        assert!(hash_file_parallel(&empty, 64).is_ok());
+        assert!(hash_file_parallel(&a, 0).is_err());
+    }
+
+    #[test]
+    fn top_delta_parents() {
+        with_store(|conn, dir| {
+            // app-3 and app-5 are deltas against app-2, app-2 and app-4 against app-1
+            let mut blobs = HashMap::new();
+            for i in 1..=5 {
+                let body = format!("{}v{}", "hello, world\n".repeat(1024), i);
+                let path = write_file(dir, &format!("app-{}.tar", i), body.as_bytes());
+                let (blob, _) = append_full(conn, &path, FileType::Plain, false).unwrap();
+                blobs.insert(i, blob);
+            }
+            for (i, parent) in &[(2, 1), (3, 2), (4, 1), (5, 2)] {
+                let race = Arc::new(AtomicUsize::new(0));
+                let (tmp, delta) = append_delta(&blobs[i], &blobs[parent], race, None)
+                    .unwrap()
+                    .unwrap();
+                update_blob(conn, tmp, &delta).unwrap();
+            }
+
+            let stats = Stats::from_blobs(db::all(conn).unwrap());
+            let parents = stats
+                .top_delta_parents(5)
+                .into_iter()
+                .map(|(idx, ref_count)| (stats.blobs[idx].filename.as_str(), ref_count))
+                .collect::<Vec<_>>();
+            assert_eq!(parents, vec![("app-1.tar", 2), ("app-2.tar", 2)]);
+
+            // the delta blob of app-2, the latest blob of its content, is reported
+            let (idx, _) = stats.top_delta_parents(2)[1];
+            assert!(!stats.blobs[idx].is_root());
+            assert_eq!(stats.top_delta_parents(1).len(), 1);
+        });
+    }
+
+    #[test]
+    fn disk_usage_after_dehydrate() {
+        with_store(|conn, dir| {
+            push_two_versions(conn, dir);
+            let stats = Stats::from_blobs(db::all(conn).unwrap());
+            let hydrated = probe_disk_usage(&stats);
+            assert_eq!(hydrated.hydrated_roots, 2);
+
+            dehydrate(conn, false).unwrap();
+            let stats = Stats::from_blobs(db::all(conn).unwrap());
+            let usage = probe_disk_usage(&stats);
+            assert_eq!(usage.hydrated_roots, 1);
+            assert_eq!(usage.size, stats.min_dehydrated_size());
+            assert!(usage.size < hydrated.size);
+        });
+    }
+
+    #[test]
+    fn push_verify_unchanged() {
+        with_store(|conn, dir| {
+            let v1 = write_file(dir, "app-1.tar", b"hello, world");
+            let v2 = write_file(dir, "app-2.tar", b"hello, world!!");
+            push_with_checksum_verification(conn, &v1, FileType::Plain).unwrap();
+            let report = push_with_checksum_verification(conn, &v2, FileType::Plain).unwrap();
+            assert!(report.delta.is_some());
+
+            let opts = PushOptions {
+                verify_unchanged: true,
+                ..Default::default()
+            };
+            let hash = input_hash(&v2, &opts).unwrap();
+            assert!(check_unchanged(&v2, hash.as_deref()).is_ok());
+            std::fs::write(&v2, b"hello, world??").unwrap();
+            let err = check_unchanged(&v2, hash.as_deref()).unwrap_err();
+            assert_eq!(error_kind(&err), ErrorKind::FileMutated);
+            assert_eq!(exit_code(&err), EXIT_FILE_MUTATED);
+        });
+    }
+
+    #[test]
+    fn push_alias_skips_conversion() {
+        with_store(|conn, dir| {
+            push_two_versions(conn, dir);
+            let copy = write_file(dir, "app-2-copy.tar", b"hello, world!!");
+            // inputs are only hashed for a lookup if one of the same size was converted
+            let kind = original_kind(FileType::Plain, false);
+            assert!(db::has_original_size(conn, &kind, 14).unwrap());
+            assert!(!db::has_original_size(conn, &kind, 15).unwrap());
+
+            CONVERSIONS.with(|count| count.set(0));
+            let opts = PushOptions::default();
+            let report = push_with_options(conn, &copy, FileType::Plain, &opts).unwrap();
+            assert_eq!(CONVERSIONS.with(|count| count.get()), 0);
+            assert!(report.skipped);
+            assert!(report.aliased);
+
+            // the alias resolves to the existing content, no blob is added
+            assert_eq!(db::count(conn).unwrap(), 3);
+            let out = dir.join("out");
+            get(conn, "app-2-copy.tar", out.to_str().unwrap(), false).unwrap();
+            assert_eq!(std::fs::read(&out).unwrap(), b"hello, world!!");
+
+            // pushed again under a known name, nothing changes
+            let report = push_with_options(conn, &copy, FileType::Plain, &opts).unwrap();
+            assert!(report.skipped);
+            assert!(!report.aliased);
+
+            // the same bytes as another type are converted
+            push_with_options(conn, &copy, FileType::Gz, &opts).unwrap_err();
+            assert_eq!(CONVERSIONS.with(|count| count.get()), 1);
+        });
+    }
+
+    #[test]
+    fn stats_index() {
+        with_store(|conn, dir| {
+            push_two_versions(conn, dir);
+            assert!(db::cached_depths(conn).unwrap().is_none());
+
+            rebuild_stats_index(conn).unwrap();
+            let depths = db::cached_depths(conn).unwrap().unwrap();
+            assert_eq!(depths.len(), db::count(conn).unwrap());
+            assert_eq!(load_stats(conn).unwrap().max_depth(), 2);
+
+            // a push invalidates the cache
+            let v3 = write_file(dir, "app-3.tar", b"hello, world!!!");
+            push(conn, &v3, FileType::Plain).unwrap();
+            assert!(db::cached_depths(conn).unwrap().is_none());
+
+            // migration is idempotent
+            db::prepare(conn).unwrap();
+        });
+    }
+
+    #[test]
+    fn list_roots() {
+        with_store(|conn, dir| {
+            for i in 0..8 {
+                let body = "hello, world".repeat(i + 1);
+                let input = write_file(dir, &format!("app-{}.tar", i), body.as_bytes());
+                push(conn, &input, FileType::Plain).unwrap();
+            }
+
+            let roots = list_root_blobs(conn).unwrap();
+            let stats = load_stats(conn).unwrap();
+            let candidates = stats.root_candidates();
+            assert_eq!(roots.len(), db::roots(conn).unwrap().len());
+            for (root, info) in &roots {
+                assert!(info.hydrated);
+                assert!(!info.pinned);
+                assert!(info.age_secs >= 0);
+
+                let idx = stats.blobs.iter().position(|b| b.id == root.id).unwrap();
+                let score = candidates
+                    .iter()
+                    .find(|candidate| candidate.idx == idx)
+                    .map_or(u64::MAX, |candidate| candidate.score);
+                assert_eq!(info.score, score, "{:?}", root);
+            }
+            // every delta is below the genesis
+            let (genesis, info) = &roots[0];
+            assert!(genesis.is_genesis());
+            assert_eq!(info.child_count, db::delta_count(conn).unwrap());
+        });
+    }
+
+    #[test]
+    fn storage_projection() {
+        with_store(|conn, dir| {
+            assert_eq!(average_delta_ratio(conn).unwrap(), None);
+            for i in 0..3 {
+                let body = format!("{}{}", "hello, world\n".repeat(1024), i);
+                let input = write_file(dir, &format!("app-{}.tar", i), body.as_bytes());
+                push(conn, &input, FileType::Plain).unwrap();
+            }
+            let ratio = average_delta_ratio(conn).unwrap().unwrap();
+            assert!(ratio > 0.0);
+            assert!(estimate_storage_after_n_pushes(conn, 1, f32::NAN).is_err());
+
+            let (store_size, _) = db::total_sizes(conn).unwrap();
+            let roots = db::roots(conn).unwrap().len();
+            let max_depth = load_stats(conn).unwrap().max_depth();
+
+            let none = estimate_storage_after_n_pushes(conn, 0, ratio).unwrap();
+            assert_eq!(none.estimated_store_size, store_size);
+            assert_eq!(none.estimated_root_count, roots);
+            assert_eq!(none.estimated_max_depth, max_depth);
+            assert!(!none.compact_advised);
+
+            // the same pushes, each followed by cleanup as the projection assumes
+            let n = 8;
+            let p = estimate_storage_after_n_pushes(conn, n, ratio).unwrap();
+            for i in 3..3 + n {
+                let body = format!("{}{}", "hello, world\n".repeat(1024), i);
+                let input = write_file(dir, &format!("app-{}.tar", i), body.as_bytes());
+                push(conn, &input, FileType::Plain).unwrap();
+                cleanup(conn).unwrap();
+            }
+            assert_eq!(p.estimated_root_count, db::roots(conn).unwrap().len());
+            let (actual_size, _) = db::total_sizes(conn).unwrap();
+            let error = (p.estimated_store_size as f64 - actual_size as f64).abs();
+            assert!(error < actual_size as f64 * 0.01, "{:?} {}", p, actual_size);
+            assert!(p.estimated_max_depth >= load_stats(conn).unwrap().max_depth());
+            assert!(!p.compact_advised);
+
+            let p = estimate_storage_after_n_pushes(conn, 40, ratio).unwrap();
+            assert!(p.compact_advised);
+        });
+    }
+
+    #[test]
+    fn push_provenance() {
+        with_store(|conn, dir| {
+            let v1 = write_file(dir, "app-1.tar", b"hello, world");
+            push(conn, &v1, FileType::Plain).unwrap();
+            let v2 = write_file(dir, "app-2.tar", b"hello, world!!");
+            let opts = PushOptions {
+                source_url: Some("https://example.com/app-2.tar".to_owned()),
+                ..Default::default()
+            };
+            push_with_options(conn, &v2, FileType::Plain, &opts).unwrap();
+
+            let blob = db::by_filename(conn, "app-1.tar").unwrap().pop().unwrap();
+            let provenance = db::provenance(conn, &blob.store_hash).unwrap();
+            let path = std::fs::canonicalize(&v1).unwrap();
+            assert_eq!(provenance.source_path, path.to_str().map(str::to_owned));
+            assert!(provenance.source_host.is_some());
+            assert_eq!(provenance.source_url, None);
+
+            // the root and the delta of app-2
+            for blob in db::by_filename(conn, "app-2.tar").unwrap() {
+                let provenance = db::provenance(conn, &blob.store_hash).unwrap();
+                assert_eq!(
+                    provenance.source_url.as_deref(),
+                    Some("https://example.com/app-2.tar")
+                );
+            }
+
+            // the first source of a content is kept
+            let copy = write_file(dir, "copy.tar", b"hello, world");
+            push(conn, &copy, FileType::Plain).unwrap();
+            let provenance = db::provenance(conn, &blob.store_hash).unwrap();
+            assert_eq!(provenance.source_path, path.to_str().map(str::to_owned));
+        });
+    }
+
+    #[test]
+    fn oplog_entries() {
+        with_store(|conn, dir| {
+            let input = write_file(dir, "app-1.tar", b"hello, world");
+            push(conn, &input, FileType::Plain).unwrap();
+            rename(conn, "app-1.tar", "app-1-renamed.tar", false).unwrap();
+            cleanup(conn).unwrap();
+
+            let entries = db::oplog(conn).unwrap();
+            let ops = entries.iter().map(|e| e.op.as_str()).collect::<Vec<_>>();
+            assert_eq!(ops, ["push", "rename", "cleanup"]);
+
+            let details = entries
+                .iter()
+                .map(|e| serde_json::from_str::<serde_json::Value>(&e.details_json).unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(details[0]["filename"], "app-1.tar");
+            assert_eq!(details[0]["ids"], serde_json::json!([1]));
+            assert_eq!(details[0]["store_size_before"], 0);
+            assert_eq!(details[1]["to"], "app-1-renamed.tar");
+            assert_eq!(details[2]["removed_ids"], serde_json::json!([]));
+
+            // a rename of an unknown file is not an operation
+            rename(conn, "unknown.tar", "other.tar", false).unwrap();
+            assert_eq!(db::oplog(conn).unwrap().len(), 3);
+        });
+    }
+
+    #[test]
+    fn push_commit_rollback() {
+        with_store(|conn, dir| {
+            push_two_versions(conn, dir);
+            let root = db::by_filename(conn, "app-2.tar")
+                .unwrap()
+                .into_iter()
+                .find(|blob| blob.is_root())
+                .unwrap();
+            let path = filepath(&root.content_hash);
+
+            // a failure after the removal brings back the row, and the file was never deleted
+            let res = commit_push(conn, None, |conn, unlink| {
+                remove_root_deferred(conn, &root, unlink)?;
+                Err(crate::format_err!("failed after the removal"))
+            });
+            assert!(res.is_err());
+            assert!(db::by_id(conn, root.id).unwrap().is_some());
+            assert!(Path::new(&path).exists());
+            validate(conn).unwrap();
+
+            commit_push(conn, None, |conn, unlink| {
+                remove_root_deferred(conn, &root, unlink)
+            })
+            .unwrap();
+            assert!(db::by_id(conn, root.id).unwrap().is_none());
+            assert!(!Path::new(&path).exists());
+        });
+    }
+
+    #[test]
+    fn oplog_push_removals() {
+        with_store(|conn, dir| {
+            let mut rng = Rng::new(7);
+            let body = (0..64 * 1024)
+                .map(|_| rng.next_u64() as u8)
+                .collect::<Vec<_>>();
+            let edit = |offset: usize| {
+                let mut edited = body.clone();
+                edited[offset] ^= 0xff;
+                edited
+            };
+            push(conn, &write_file(dir, "app-1.tar", &body), FileType::Plain).unwrap();
+            let input = write_file(dir, "app-2.tar", &edit(1000));
+            push(conn, &input, FileType::Plain).unwrap();
+
+            // another full copy does not fit, a root is pruned by the push
+            let (store_size, _) = db::total_sizes(conn).unwrap();
+            let opts = PushOptions {
+                max_store_size: Some(store_size + body.len() as u64 / 2),
+                quota_policy: QuotaPolicy::Prune,
+                ..Default::default()
+            };
+            let input = write_file(dir, "app-3.tar", &edit(2000));
+            let report = push_with_options(conn, &input, FileType::Plain, &opts).unwrap();
+            let removed = report
+                .roots
+                .iter()
+                .filter(|root| root.event == EVENT_ROOT_REMOVED)
+                .collect::<Vec<_>>();
+            assert!(!removed.is_empty());
+
+            let entries = db::oplog(conn).unwrap();
+            let ops = entries.iter().map(|e| e.op.as_str()).collect::<Vec<_>>();
+            assert_eq!(ops, ["push", "push", "push"]);
+            let details: serde_json::Value =
+                serde_json::from_str(&entries[2].details_json).unwrap();
+            assert_eq!(details["filename"], "app-3.tar");
+            let ids = db::by_content_hash(conn, &report.content_hash)
+                .unwrap()
+                .iter()
+                .map(|blob| blob.id)
+                .collect::<Vec<_>>();
+            assert!(!ids.is_empty());
+            assert_eq!(details["ids"], serde_json::json!(ids));
+            let transitions = root_transitions(conn).unwrap();
+            let transitions = transitions.iter().map(|(_ts, root)| root);
+            assert!(transitions
+                .rev()
+                .take(report.roots.len())
+                .eq(report.roots.iter().rev()));
+            for root in &removed {
+                let blobs = db::by_content_hash(conn, &root.store_hash).unwrap();
+                assert!(blobs.iter().all(|blob| !blob.is_root()));
+            }
+            let (store_size, _) = db::total_sizes(conn).unwrap();
+            assert_eq!(details["store_size_after"], store_size);
+
+            // a refused push leaves no entry
+            let opts = PushOptions {
+                max_store_size: Some(4),
+                ..Default::default()
+            };
+            let input = write_file(dir, "app-4.tar", b"hello, world");
+            assert!(push_with_options(conn, &input, FileType::Plain, &opts).is_err());
+            assert_eq!(db::oplog(conn).unwrap().len(), 3);
+        });
+    }
+
+    #[test]
+    fn debug_graph_atomic() {
+        with_store(|conn, dir| {
+            for i in 0..3 {
+                let body = format!("hello, world{}", "!".repeat(i));
+                let input = write_file(dir, &format!("app-{}.tar", i), body.as_bytes());
+                push(conn, &input, FileType::Plain).unwrap();
+            }
+            let out = dir.join("graph").join("store.dot");
+            std::fs::create_dir(out.parent().unwrap()).unwrap();
+            let out_str = out.to_str().unwrap();
+            let files = || {
+                let mut names = std::fs::read_dir(out.parent().unwrap())
+                    .unwrap()
+                    .map(|e| e.unwrap().file_name().into_string().unwrap())
+                    .collect::<Vec<_>>();
+                names.sort();
+                names
+            };
+
+            let failing = |conn: &mut db::Conn| {
+                retry::with_test_fs(Box::new(FailingPersist), || debug_graph(conn, out_str))
+            };
+            assert!(failing(conn).is_err());
+            assert!(!out.exists());
+            assert!(files().is_empty());
+
+            debug_graph(conn, out_str).unwrap();
+            let graph = std::fs::read_to_string(&out).unwrap();
+            assert!(graph.starts_with("digraph increstore {"));
+            assert!(graph.ends_with("}\n"));
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let mode = std::fs::metadata(&out).unwrap().permissions().mode();
+                assert_eq!(mode & 0o777, 0o644);
+            }
+
+            // a failed write keeps the previous graph
+            assert!(failing(conn).is_err());
+            assert_eq!(std::fs::read_to_string(&out).unwrap(), graph);
+            assert_eq!(files(), ["store.dot"]);
+        });
+    }
+
+    #[test]
+    fn rename_rollback() {
+        with_store(|conn, dir| {
+            for i in 0..3 {
+                let body = format!("hello, world{}", "!".repeat(i));
+                let input = write_file(dir, &format!("app-{}.tar", i), body.as_bytes());
+                push(conn, &input, FileType::Plain).unwrap();
+            }
+
+            // the objects of the renamed file are missing as they are checked
+            let paths = db::by_filename(conn, "app-1.tar")
+                .unwrap()
+                .iter()
+                .map(|blob| PathBuf::from(filepath(&blob.store_hash)))
+                .collect::<Vec<_>>();
+            let fs = MissingFs(move |path: &Path| paths.iter().any(|p| p == path));
+            let res = retry::with_test_fs(Box::new(fs), || {
+                rename(conn, "app-1.tar", "renamed.tar", true)
+            });
+            assert_eq!(error_kind(&res.unwrap_err()), ErrorKind::MissingObject);
+            assert!(!db::by_filename(conn, "app-1.tar").unwrap().is_empty());
+            assert!(db::by_filename(conn, "renamed.tar").unwrap().is_empty());
+
+            rename(conn, "app-1.tar", "renamed.tar", true).unwrap();
+            assert!(db::by_filename(conn, "app-1.tar").unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn root_events() {
+        with_store(|conn, dir| {
+            for i in 0..8 {
+                let body = "hello, world".repeat(i + 1);
+                let input = write_file(dir, &format!("app-{}.tar", i), body.as_bytes());
+                push(conn, &input, FileType::Plain).unwrap();
+            }
 
-    let stats = Stats::from_blobs(blobs);
-    println!("info\n{}", stats.size_info());
+            // transitions are kept in the events table, not in the push entries
+            let transitions = root_transitions(conn).unwrap();
+            assert_eq!(transitions.len(), db::events(conn).unwrap().len());
+            for entry in db::oplog(conn).unwrap() {
+                assert_eq!(entry.op, "push");
+                assert!(!entry.details_json.contains("\"roots\""));
+            }
+            let added = transitions
+                .iter()
+                .map(|(_ts, root)| root)
+                .filter(|root| root.event == EVENT_ROOT_ADDED)
+                .collect::<Vec<_>>();
+            let removed = transitions
+                .iter()
+                .map(|(_ts, root)| root)
+                .filter(|root| root.event == EVENT_ROOT_REMOVED)
+                .collect::<Vec<_>>();
+            assert_eq!(added.len(), 8);
+            assert_eq!(added[0].reason, "genesis");
+            assert!(added[1..].iter().all(|root| root.reason == "push"));
+
+            // every root is either still a root or removed by cleanup
+            let roots = db::roots(conn).unwrap();
+            assert!(!removed.is_empty());
+            assert_eq!(added.len() - removed.len(), roots.len());
+            for event in &removed {
+                assert_eq!(event.reason, "score_below_threshold");
+                assert!(roots.iter().all(|root| root.store_hash != event.store_hash));
+            }
+        });
+    }
 
-    Ok(())
-}
+    #[test]
+    fn hash_algo() {
+        with_store(|conn, _dir| {
+            // an untagged store is tagged
+            check_hash_algo(conn).unwrap();
+            let algo = db::get_setting(conn, HASH_ALGO_KEY).unwrap();
+            assert_eq!(algo.as_deref(), Some(rw::HASH_ALGO));
+            check_hash_algo(conn).unwrap();
+
+            db::set_setting(conn, HASH_ALGO_KEY, "sha1").unwrap();
+            let err = check_hash_algo(conn).unwrap_err();
+            assert_eq!(error_kind(&err), ErrorKind::UnsupportedHashAlgo);
+            assert!(err.to_string().contains("store=sha1"), "{}", err);
+        });
+    }
 
-pub fn debug_graph(conn: &mut db::Conn, filename: &str) -> Result<()> {
-    use std::fmt::Write;
+    #[test]
+    fn hash_algo_before_migration() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("meta.db").to_str().unwrap().to_owned();
+        let mut conn = db::open_path(&path).unwrap();
+        conn.execute_batch("create table settings (key text primary key, value text not null)")
+            .unwrap();
+        db::set_setting(&mut conn, HASH_ALGO_KEY, "sha1").unwrap();
+        drop(conn);
+
+        // refused as it is, no table is added
+        let err = open_store_path(path.clone()).unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::UnsupportedHashAlgo);
+        let mut conn = db::open_path(&path).unwrap();
+        assert!(!db::has_table(&conn, "blobs").unwrap());
+
+        db::set_setting(&mut conn, HASH_ALGO_KEY, rw::HASH_ALGO).unwrap();
+        drop(conn);
+        open_store_path(path.clone()).unwrap();
+        assert!(db::has_table(&db::open_path(&path).unwrap(), "blobs").unwrap());
+    }
 
-    let blobs = db::all(conn)?;
-    let stats = Stats::from_blobs(blobs);
+    #[test]
+    fn insert_missing_parent() {
+        with_store(|conn, dir| {
+            push_two_versions(conn, dir);
+            let mut blob = db::by_filename(conn, "app-2.tar").unwrap().pop().unwrap();
+            blob.filename = "app-3.tar".to_owned();
+            blob.store_hash = "new-delta".to_owned();
+            blob.content_hash = "new".to_owned();
+            blob.parent_hash = Some("missing".to_owned());
+
+            let err = insert_blob(conn, &blob).unwrap_err();
+            assert_eq!(error_kind(&err), ErrorKind::MissingParent);
+            assert!(db::by_filename(conn, "app-3.tar").unwrap().is_empty());
+
+            // an existing parent is accepted
+            let v1 = db::by_filename(conn, "app-1.tar").unwrap().pop().unwrap();
+            blob.parent_hash = Some(v1.content_hash);
+            assert!(insert_blob(conn, &blob).unwrap());
+        });
+    }
 
-    let mut s = String::new();
-    writeln!(s, "digraph increstore {{").ok();
-    writeln!(s, "  rankdir=\"LR\"").ok();
+    #[test]
+    fn tmp_budget_serializes() {
+        let budget = Arc::new(TmpBudget::new(Some(15)));
+        budget.acquire(10);
+
+        let started = Arc::new(AtomicUsize::new(0));
+        let handle = {
+            let budget = budget.clone();
+            let started = started.clone();
+            std::thread::spawn(move || {
+                budget.acquire(10);
+                started.store(1, std::sync::atomic::Ordering::SeqCst);
+                budget.release(10, 0);
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert_eq!(started.load(std::sync::atomic::Ordering::SeqCst), 0);
+
+        budget.release(10, 4);
+        handle.join().unwrap();
+        assert_eq!(started.load(std::sync::atomic::Ordering::SeqCst), 1);
+        // one candidate always runs, on top of the kept delta
+        assert_eq!(budget.peak(), 14);
+    }
 
-    let min_size = (stats.blobs.iter().map(|v| v.store_size).min().unwrap_or(10) as f32).log10();
-    let max_size = (stats.blobs.iter().map(|v| v.store_size).max().unwrap_or(10) as f32).log10();
+    #[test]
+    fn push_tmp_budget() {
+        with_store(|conn, dir| {
+            let mut rng = Rng::new(1);
+            let base = (0..64 * 1024)
+                .map(|_| rng.next_u64() as u8)
+                .collect::<Vec<_>>();
+            for i in 0..4 {
+                let mut body = base.clone();
+                body.extend_from_slice(format!("v{}", i).as_bytes());
+                let path = write_file(dir, &format!("app-{}.tar", i), &body);
+                push(conn, &path, FileType::Plain).unwrap();
+            }
 
-    let min_width = 0.4;
-    let max_width = 2.0;
-    let abs_min_width = 0.7;
+            let mut body = base.clone();
+            body.extend_from_slice(b"v4");
+            let path = write_file(dir, "app-4.tar", &body);
+            let content_size = body.len() as u64;
+            let opts = PushOptions {
+                tmp_budget: Some(content_size),
+                ..Default::default()
+            };
+            let report = push_with_options(conn, &path, FileType::Plain, &opts).unwrap();
+            assert!(report.delta.is_some());
+            // candidates ran one at a time, next to the smallest delta so far
+            let peak = report.peak_tmp_bytes.unwrap();
+            assert!(peak >= content_size, "peak={}", peak);
+            assert!(peak <= 2 * content_size + 1, "peak={}", peak);
+
+            let out = dir.join("out.tar");
+            get(conn, "app-4.tar", out.to_str().unwrap(), false).unwrap();
+            assert_eq!(std::fs::read(&out).unwrap(), body);
+        });
+    }
 
-    let size_project = |size: u64| {
-        let size = (size as f32).log10();
-        let ratio = (size - min_size) / (max_size - min_size);
-        (min_width + (max_width - min_width) * ratio).max(abs_min_width)
-    };
+    #[test]
+    fn object_dir_override() {
+        with_store(|conn, dir| {
+            let object_dir = dir.join("hdd/objects");
+            std::env::set_var(OBJECT_DIR_ENV, &object_dir);
+            // env vars outlive the store
+            struct Unset;
+            impl Drop for Unset {
+                fn drop(&mut self) {
+                    std::env::remove_var(OBJECT_DIR_ENV);
+                }
+            }
+            let _unset = Unset;
 
-    for (idx, blob) in stats.blobs.iter().enumerate() {
-        let name = stats.node_name(idx);
-        let label = format!("{}\\n{}", name, bytesize::ByteSize(blob.store_size));
+            // enough versions for cleanup to remove roots
+            for i in 0..(max_root_blobs() + 2) {
+                let body = format!("hello, world{}", "!".repeat(i));
+                let path = write_file(dir, &format!("app-{}.tar", i), body.as_bytes());
+                push(conn, &path, FileType::Plain).unwrap();
+            }
+            assert!(!dir.join("objects").exists());
+            assert!(object_dir.exists());
+
+            let mut objects = object_files().unwrap().into_keys().collect::<Vec<_>>();
+            objects.sort();
+            let mut hashes = db::all(conn)
+                .unwrap()
+                .into_iter()
+                .map(|blob| blob.store_hash)
+                .collect::<Vec<_>>();
+            hashes.sort();
+            hashes.dedup();
+            assert_eq!(objects, hashes);
+
+            let out = dir.join("out.tar");
+            get(conn, "app-3.tar", out.to_str().unwrap(), false).unwrap();
+            assert_eq!(std::fs::read(&out).unwrap(), b"hello, world!!!");
+
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            let mut ar = Vec::new();
+            rt.block_on(archive0_async(conn, &mut ar)).unwrap();
+            let mut ar = tar::Archive::new(ar.as_slice());
+            for entry in ar.entries().unwrap() {
+                let path = entry.unwrap().path().unwrap().to_str().unwrap().to_owned();
+                assert!(
+                    path == "meta.db" || path.starts_with("objects/"),
+                    "{}",
+                    path
+                );
+            }
+        });
+    }
 
-        let size = size_project(blob.store_size);
-        let style = if blob.is_root() {
-            "shape=doublecircle style=filled fillcolor=red"
-        } else {
-            "shape=circle"
-        };
-        writeln!(
-            s,
-            "  {} [label=\"{}\" width={:.02} fixedsize=true {}];",
-            name, label, size, style
-        )
-        .ok();
+    #[test]
+    fn get_keep_temp() {
+        with_store(|conn, dir| {
+            push_two_versions(conn, dir);
+            let delta = db::by_filename(conn, "app-2.tar")
+                .unwrap()
+                .into_iter()
+                .find(|blob| !blob.is_root())
+                .unwrap();
+            // decodes, but to a different content
+            let delta_path = filepath(&delta.store_hash);
+            let mut corrupted = std::fs::read(&delta_path).unwrap();
+            *corrupted.last_mut().unwrap() ^= 0xff;
+            std::fs::write(&delta_path, &corrupted).unwrap();
+            let out = dir.join("out.tar");
+            let out = out.to_str().unwrap();
+
+            // normal runs leave nothing behind
+            let err = get(conn, "app-2.tar", out, false).unwrap_err();
+            assert_eq!(exit_code(&err), EXIT_CHECKSUM_MISMATCH);
+            let debug_dir = dir.join("debug");
+            assert!(!debug_dir.exists());
+
+            set_keep_temp(true);
+            let res = get(conn, "app-2.tar", out, false);
+            set_keep_temp(false);
+            assert_eq!(exit_code(&res.unwrap_err()), EXIT_CHECKSUM_MISMATCH);
+
+            let kept = std::fs::read_dir(&debug_dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect::<Vec<_>>();
+            assert_eq!(kept.len(), 1);
+            assert_eq!(std::fs::read(kept[0].join("delta")).unwrap(), corrupted);
+            assert!(kept[0].join("src").exists() && kept[0].join("dst").exists());
+
+            // validate decodes leaves to a file only with keep_temp
+            set_keep_temp(true);
+            let res = validate(conn);
+            set_keep_temp(false);
+            assert_eq!(exit_code(&res.unwrap_err()), EXIT_CHECKSUM_MISMATCH);
+            assert_eq!(std::fs::read_dir(&debug_dir).unwrap().count(), 2);
+        });
     }
 
-    {
-        let spine = stats.spine();
+    #[test]
+    fn gc_tmpfiles() {
+        with_store(|_conn, _dir| {
+            let tmp_dir = tmpdir();
+            let kept = NamedTempFile::new_in(&tmp_dir).unwrap();
+            std::fs::create_dir_all(format!("{}/nested", tmp_dir)).unwrap();
+            std::fs::write(format!("{}/nested/stale", tmp_dir), b"stale").unwrap();
+
+            let day = std::time::Duration::from_secs(24 * 3600);
+            assert_eq!(gc_unreferenced_tmpfiles(&tmp_dir, day).unwrap(), 0);
+            assert!(kept.path().exists());
+
+            let zero = std::time::Duration::from_secs(0);
+            assert_eq!(gc_unreferenced_tmpfiles(&tmp_dir, zero).unwrap(), 2);
+            assert!(!kept.path().exists());
+            assert!(Path::new(&format!("{}/nested", tmp_dir)).is_dir());
+        });
+    }
 
-        for (idx, pair) in spine.windows(2).enumerate() {
-            writeln!(
-                s,
-                "{}->{}[label=\"{}\"];",
-                stats.node_name(pair[0]),
-                stats.node_name(pair[1]),
-                idx
-            )
-            .ok();
-        }
+    #[cfg(feature = "admin-ops")]
+    #[test]
+    fn update_time_created() {
+        with_store(|conn, dir| {
+            push_two_versions(conn, dir);
+            let blob = db::all(conn).unwrap().remove(0);
+            let new_time = time::OffsetDateTime::from_unix_timestamp(1_577_934_245).unwrap();
+
+            db::update_time_created(conn, &blob.store_hash, new_time).unwrap();
+            for updated in db::all(conn).unwrap() {
+                if updated.id == blob.id {
+                    assert_eq!(updated.time_created, new_time);
+                } else {
+                    assert_ne!(updated.time_created, new_time);
+                }
+            }
+            assert!(db::update_time_created(conn, "unknown", new_time).is_err());
+        });
+    }
 
-        for (i, idx) in spine.into_iter().enumerate() {
-            let name = stats.node_name(idx);
-            if i == 0 {
-                writeln!(s, "{}", name).ok();
-            } else {
-                writeln!(s, "->{}", name).ok();
+    #[test]
+    fn iter_all_blobs() {
+        with_store(|conn, dir| {
+            push_two_versions(conn, dir);
+            let mut all = db::all(conn).unwrap();
+            all.sort_by_key(|blob| blob.id);
+
+            // a page at a time, never the whole store
+            let mut iter = db::iter_all_paged(conn, 2);
+            let mut blobs = Vec::new();
+            while let Some(blob) = iter.next() {
+                blobs.push(blob.unwrap());
+                assert!(iter.buffered() < 2);
             }
-        }
-        writeln!(s, " [style=invis weight=100]").ok();
+            assert_eq!(blobs, all);
+
+            let blobs = db::iter_all(conn).collect::<rusqlite::Result<Vec<_>>>();
+            assert_eq!(blobs.unwrap(), all);
+            assert_eq!(db::iter_all_paged(conn, 1).count(), all.len());
+
+            let mut blobs = Vec::new();
+            db::for_each(conn, |blob| -> Result<()> {
+                blobs.push(blob);
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(blobs, all);
+            let mut seen = 0;
+            let res = db::for_each(conn, |_| {
+                seen += 1;
+                Err(format_err!("stop"))
+            });
+            assert!(res.is_err());
+            assert_eq!(seen, 1);
+
+            let entries = db::iter_all_paged(conn, 2)
+                .with_provenance()
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .unwrap();
+            assert_eq!(entries.len(), all.len());
+            for ((read, provenance), blob) in entries.into_iter().zip(&all) {
+                assert_eq!(&read, blob);
+                assert_eq!(provenance, db::provenance(conn, &blob.store_hash).unwrap());
+            }
+        });
     }
 
-    for (idx, _blob) in stats.blobs.iter().enumerate() {
-        let node = &stats.depths[idx];
-        if let Some(parent_idx) = node.parent_idx {
-            writeln!(
-                s,
-                "  {} -> {};",
-                stats.node_name(parent_idx),
-                stats.node_name(idx),
-            )
-            .ok();
-            //
-        }
+    #[test]
+    fn list_blobs_paginated() {
+        with_store(|conn, dir| {
+            push_two_versions(conn, dir);
+            let mut all = db::all(conn).unwrap();
+            all.sort_by_key(|blob| blob.id);
+            assert_eq!(all.len(), 3);
+
+            let (page, has_next) = db::list_blobs_paginated(conn, 0, 2).unwrap();
+            assert!(has_next);
+            assert_eq!(page, all[..2]);
+
+            let (page, has_next) = db::list_blobs_paginated(conn, 1, 2).unwrap();
+            assert!(!has_next);
+            assert_eq!(page, all[2..]);
+
+            let (page, has_next) = db::list_blobs_paginated(conn, 2, 2).unwrap();
+            assert!(!has_next);
+            assert!(page.is_empty());
+        });
     }
 
-    writeln!(s, "}}").ok();
+    #[test]
+    fn bench_cleanup_rollback() {
+        with_store(|conn, dir| {
+            push_two_versions(conn, dir);
 
-    std::fs::write(filename, s)?;
+            let before = db::all(conn).unwrap();
+            let res = bench_cleanup(conn).unwrap();
+            assert!(res.current_strategy.removed.is_empty());
+            assert_eq!(res.age_only_strategy.root_count, 2);
 
-    Ok(())
-}
+            let after = db::all(conn).unwrap();
+            assert_eq!(before.len(), after.len());
+        });
+    }
 
-pub fn debug_list_files(
-    conn: &mut db::Conn,
-    genesis: bool,
-    roots: bool,
-    non_roots: bool,
-    long: bool,
-) -> Result<()> {
-    let blobs = db::all(conn)?;
-    for blob in blobs.into_iter() {
-        let is_root = blob.is_root();
+    #[test]
+    fn optimal_root() {
+        with_store(|conn, _dir| {
+            // roots are ranked by how close their content size is
+            for (id, hash, content_size) in [
+                (1, "aa", 100),
+                (2, "bb", 1000),
+                (3, "cc", 500),
+                (4, "dd", 950),
+            ] {
+                let root = Blob {
+                    content_size,
+                    ..blob(id, hash, None, content_size)
+                };
+                db::insert(conn, &root).unwrap();
+            }
 
-        // TODO: better genesis check?
-        let should_print =
-            (roots && is_root) || (non_roots && !is_root) || (genesis && blob.is_genesis());
+            let root = find_optimal_root_for(conn, "dd").unwrap().unwrap();
+            assert_eq!(root.content_hash, "bb");
+            let root = find_optimal_root_for(conn, "aa").unwrap().unwrap();
+            assert_eq!(root.content_hash, "cc");
+            assert!(find_optimal_root_for(conn, "ee").unwrap().is_none());
+        });
+    }
 
-        if !should_print {
-            continue;
-        }
+    #[test]
+    fn estimate_push() {
+        with_store(|conn, dir| {
+            push_two_versions(conn, dir);
+            let v3 = write_file(dir, "app-3.tar", b"hello, world!!!");
+            let before = db::all(conn).unwrap();
+            let roots = db::roots(conn).unwrap();
+
+            let sizes = estimate_push_delta_sizes(conn, &v3, FileType::Plain, false).unwrap();
+            assert_eq!(sizes.len(), roots.len());
+            for root in &roots {
+                assert!(sizes.iter().any(|(hash, _size)| *hash == root.store_hash));
+            }
+            assert!(sizes.windows(2).all(|w| w[0].1 <= w[1].1));
+            assert_eq!(db::all(conn).unwrap(), before);
+
+            // a gz input is estimated from its unpacked content, as it is stored
+            let mut rng = Rng::new(3);
+            let gz = random_gz(&mut rng, b"hello, world!!!");
+            let v3_gz = write_file(dir, "app-3.tar.gz", &gz);
+            let gz_sizes = estimate_push_delta_sizes(conn, &v3_gz, FileType::Gz, false).unwrap();
+            assert_eq!(gz_sizes, sizes);
+            assert_eq!(db::all(conn).unwrap(), before);
+        });
+    }
 
-        let path = filepath(&blob.store_hash);
-        if long {
-            println!("{} {}", path, blob.filename);
-        } else {
-            println!("{}", path);
-        }
+    #[test]
+    fn get_push_async() {
+        with_store(|conn, dir| {
+            let v1 = write_file(dir, "app-1.tar", b"hello, world");
+            let v2 = write_file(dir, "app-2.tar", b"hello, world!!");
+
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            rt.block_on(push_async(v1, FileType::Plain)).unwrap();
+            rt.block_on(push_async(v2, FileType::Plain)).unwrap();
+
+            let out = dir.join("app.tar");
+            get(conn, "app-2.tar", out.to_str().unwrap(), false).unwrap();
+
+            let mut body = Vec::new();
+            let meta = rt
+                .block_on(get_async("app-2.tar".to_owned(), &mut body))
+                .unwrap();
+            assert_eq!(body, std::fs::read(&out).unwrap());
+            assert_eq!(meta.len(), body.len() as u64);
+        });
     }
 
-    Ok(())
-}
+    #[test]
+    fn get_overwrite() {
+        with_store(|conn, dir| {
+            push_two_versions(conn, dir);
 
-fn path_to_hash(mut path: PathBuf, root: &Path) -> Option<String> {
-    let mut s = String::new();
-    while let Some(name) = path.file_name() {
-        let file_name = name.to_str()?;
-        s = file_name.to_owned() + &s;
+            let out = dir.join("app.tar");
+            let out_filename = out.to_str().unwrap();
+            std::fs::write(&out, b"old").unwrap();
 
-        path.pop();
-        if path == root {
-            break;
-        }
+            // refuses to overwrite by default
+            assert!(get(conn, "app-2.tar", out_filename, false).is_err());
+            assert_eq!(std::fs::read(&out).unwrap(), b"old");
+
+            let opts = GetOptions {
+                overwrite: true,
+                ..Default::default()
+            };
+            get_with_options(conn, "app-2.tar", out_filename, &opts).unwrap();
+            assert_eq!(std::fs::read(&out).unwrap(), b"hello, world!!");
+        });
     }
-    Some(s)
-}
 
-pub fn debug_blobs(conn: &mut db::Conn) -> Result<()> {
-    let blobs = db::all(conn)?;
+    #[test]
+    fn get_no_clobber() {
+        with_store(|conn, dir| {
+            push_two_versions(conn, dir);
 
-    // check blob store
-    {
-        use std::collections::hash_map::Entry;
-        use std::collections::HashMap;
+            let out = dir.join("app.tar");
+            let out_filename = out.to_str().unwrap();
 
-        let pathstr = format!("{}/objects", prefix());
-        let objectdir = Path::new(&pathstr);
+            get(conn, "app-2.tar", out_filename, false).unwrap();
+            assert_eq!(std::fs::read(&out).unwrap(), b"hello, world!!");
 
-        let mut objects = HashMap::new();
-        for entry in walkdir::WalkDir::new(&objectdir) {
-            let entry = entry?;
-            if entry.file_type().is_dir() {
-                continue;
-            }
-            let hash = match path_to_hash(entry.path().to_path_buf(), &objectdir) {
-                Some(hash) => hash,
-                None => {
-                    error!("failed to get hash from path: {:?}", entry.path());
-                    continue;
-                }
+            std::fs::write(&out, b"old").unwrap();
+            assert!(get(conn, "app-2.tar", out_filename, false).is_err());
+            assert_eq!(std::fs::read(&out).unwrap(), b"old");
+        });
+    }
+
+    #[test]
+    fn get_in_place() {
+        with_store(|conn, dir| {
+            push_two_versions(conn, dir);
+
+            let out = dir.join("app.tar");
+            let out_filename = out.to_str().unwrap();
+            let partial = dir.join("app.tar.partial");
+            let opts = GetOptions {
+                in_place: true,
+                ..Default::default()
             };
-            objects.insert(hash, entry.metadata()?);
-        }
+            for (filename, body) in &[
+                ("app-1.tar", "hello, world"),
+                ("app-2.tar", "hello, world!!"),
+            ] {
+                std::fs::write(&out, b"old").unwrap();
+                get_with_options(conn, filename, out_filename, &opts).unwrap();
+                assert_eq!(std::fs::read(&out).unwrap(), body.as_bytes());
+                assert!(!partial.exists());
+            }
 
-        for blob in &blobs {
-            match objects.entry(blob.store_hash.clone()) {
-                Entry::Occupied(ent) => {
-                    let (_k, v) = ent.remove_entry();
-                    if v.len() != blob.store_size {
-                        error!(
-                            "invalid file size: expected={}, actual={}",
-                            blob.store_size,
-                            v.len()
-                        );
-                    }
-                }
-                Entry::Vacant(_ent) => {
-                    error!("blob not exists: {}", blob.store_hash);
+            // the destination is full: fails before decoding, the output is kept
+            std::fs::write(&out, b"old").unwrap();
+            FAKE_FREE_SPACE.with(|free| free.set(Some(4)));
+            let res = get_with_options(conn, "app-2.tar", out_filename, &opts);
+            FAKE_FREE_SPACE.with(|free| free.set(None));
+            match res.unwrap_err() {
+                Error::Io(e) => assert_eq!(e.raw_os_error(), Some(libc::ENOSPC)),
+                e => panic!("unexpected error: {}", e),
+            }
+            assert_eq!(std::fs::read(&out).unwrap(), b"old");
+            assert!(!partial.exists());
+
+            // a broken delta fails after writing the partial output
+            for blob in db::all(conn).unwrap() {
+                if !blob.is_root() {
+                    let path = filepath(&blob.store_hash);
+                    let mut corrupted = std::fs::read(&path).unwrap();
+                    *corrupted.last_mut().unwrap() ^= 0xff;
+                    std::fs::write(&path, &corrupted).unwrap();
                 }
             }
-        }
-
-        for (k, _v) in objects {
-            error!("unexpected blob: {}", k);
-        }
+            let filename = db::all(conn)
+                .unwrap()
+                .into_iter()
+                .find(|blob| !blob.is_root())
+                .unwrap()
+                .filename;
+            assert!(get_with_options(conn, &filename, out_filename, &opts).is_err());
+            assert_eq!(std::fs::read(&out).unwrap(), b"old");
+            assert!(!partial.exists());
+        });
     }
 
-    // check if all blobs are reachable from a genesis blob
-    {
-        let stats = Stats::from_blobs(blobs);
-        let mut reached = Vec::with_capacity(stats.blobs.len());
-        reached.resize(stats.blobs.len(), false);
-        mark_reached(0, &stats, &mut reached);
+    #[test]
+    fn get_backup() {
+        with_store(|conn, dir| {
+            push_two_versions(conn, dir);
 
-        for (idx, reached) in reached.iter().enumerate() {
-            if stats.blobs[idx].is_root() {
-                continue;
-            }
+            let out = dir.join("app.tar");
+            let out_filename = out.to_str().unwrap();
+            std::fs::write(&out, b"old").unwrap();
 
-            if !reached {
-                error!("blob not reachable, idx={}", idx);
+            let opts = GetOptions {
+                backup: true,
+                ..Default::default()
+            };
+            get_with_options(conn, "app-2.tar", out_filename, &opts).unwrap();
+            assert_eq!(std::fs::read(&out).unwrap(), b"hello, world!!");
+            assert_eq!(std::fs::read(dir.join("app.tar.bak")).unwrap(), b"old");
+        });
+    }
+
+    #[test]
+    fn get_progress() {
+        with_store(|conn, dir| {
+            push_two_versions(conn, dir);
+
+            for filename in &["app-1.tar", "app-2.tar"] {
+                let chain = db::chain(conn, filename).unwrap();
+                let total = chain.iter().map(|blob| blob.content_size).sum::<u64>();
+
+                let calls = std::cell::RefCell::new(Vec::new());
+                let out = dir.join(format!("out-{}", filename));
+                get_with_progress(conn, filename, out.to_str().unwrap(), |decoded, total| {
+                    calls.borrow_mut().push((decoded, total))
+                })
+                .unwrap();
+
+                let calls = calls.into_inner();
+                assert_eq!(calls.len(), chain.len().max(2) - 1, "{:?}", calls);
+                assert!(calls.windows(2).all(|w| w[0].0 <= w[1].0));
+                assert_eq!(calls.last(), Some(&(total, total)));
             }
-        }
+        });
     }
 
-    Ok(())
-}
+    #[test]
+    fn get_format() {
+        with_store(|conn, dir| {
+            push_two_versions(conn, dir);
+            let blob = db::by_filename(conn, "app-2.tar").unwrap().pop().unwrap();
+            assert!(!blob.is_root());
+
+            let content = dir.join("content");
+            let delta = dir.join("delta");
+            get(conn, "app-2.tar", content.to_str().unwrap(), false).unwrap();
+            let opts = GetOptions {
+                format: GetFormat::Delta,
+                ..Default::default()
+            };
+            get_with_options(conn, "app-2.tar", delta.to_str().unwrap(), &opts).unwrap();
 
-fn mark_reached(idx: usize, stats: &Stats, reached: &mut [bool]) {
-    reached[idx] = true;
-    for child_idx in stats.children(idx, true) {
-        mark_reached(child_idx, stats, reached);
+            let content = std::fs::read(&content).unwrap();
+            let delta = std::fs::read(&delta).unwrap();
+            assert_eq!(content, b"hello, world!!");
+            assert_eq!(delta, std::fs::read(filepath(&blob.store_hash)).unwrap());
+            assert_ne!(delta, content);
+        });
     }
-}
 
-fn file_hash(filename: &str) -> Result<String> {
-    const BUF_SIZE: usize = 8 * 1024 * 1024;
+    #[test]
+    fn get_intermediate_cache() {
+        with_store(|conn, dir| {
+            // app-5 -> app-4 -> app-3 -> app-2 -> app-1
+            let mut bodies = vec!["hello, world\n".repeat(1024).into_bytes()];
+            let mut blobs = Vec::new();
+            for i in 1..=5 {
+                let mut body = bodies.last().unwrap().clone();
+                body.extend_from_slice(format!("v{}", i).as_bytes());
+                let path = write_file(dir, &format!("app-{}.tar", i), &body);
+                let (blob, _) = append_full(conn, &path, FileType::Plain, false).unwrap();
+                bodies.push(body);
+                blobs.push(blob);
+            }
+            for pair in blobs.windows(2) {
+                let race = Arc::new(AtomicUsize::new(0));
+                let (tmp, delta) = append_delta(&pair[1], &pair[0], race, None)
+                    .unwrap()
+                    .unwrap();
+                update_blob(conn, tmp, &delta).unwrap();
+            }
+            for blob in &blobs[1..] {
+                remove_root(conn, blob).unwrap();
+            }
 
-    use std::io::Read;
+            let opts = GetOptions {
+                overwrite: true,
+                intermediate_cache: Some(1 << 20),
+                ..Default::default()
+            };
+            let hops = |conn: &mut db::Conn, filename: &str| {
+                let calls = std::cell::Cell::new(0);
+                let out = dir.join("out");
+                let out = out.to_str().unwrap();
+                get_with_options_progress(conn, filename, out, &opts, |_, _| {
+                    calls.set(calls.get() + 1)
+                })
+                .unwrap();
+                let i = filename[4..5].parse::<usize>().unwrap();
+                assert_eq!(std::fs::read(out).unwrap(), bodies[i]);
+                calls.get()
+            };
 
-    let file = std::fs::File::open(filename)?;
-    let mut reader = rw::HashRW::new(file);
+            // interrupted after app-3: app-2 is cached
+            assert_eq!(hops(conn, "app-3.tar"), 2);
+            assert_eq!(hops(conn, "app-5.tar"), 3);
+            // app-2 to app-4 are cached
+            assert_eq!(hops(conn, "app-5.tar"), 1);
+
+            // a broken entry is not trusted
+            let cache = cache::IntermediateCache::new(&StoreDirs::from_env(), 1 << 20).unwrap();
+            let app4 = cache.lookup(&blobs[3].content_hash).unwrap().unwrap();
+            std::fs::write(app4, b"broken").unwrap();
+            assert_eq!(hops(conn, "app-5.tar"), 2);
+        });
+    }
 
-    let mut buf = Vec::with_capacity(BUF_SIZE);
-    buf.resize(BUF_SIZE, 0u8);
+    #[test]
+    fn get_verify_chain() {
+        with_store(|conn, dir| {
+            // app-3 -> app-2 -> app-1
+            let mut bodies = vec!["hello, world\n".repeat(1024).into_bytes()];
+            let mut blobs = Vec::new();
+            for i in 1..=3 {
+                let mut body = bodies.last().unwrap().clone();
+                body.extend_from_slice(format!("v{}", i).as_bytes());
+                let path = write_file(dir, &format!("app-{}.tar", i), &body);
+                let (blob, _) = append_full(conn, &path, FileType::Plain, false).unwrap();
+                bodies.push(body);
+                blobs.push(blob);
+            }
+            for pair in blobs.windows(2) {
+                let race = Arc::new(AtomicUsize::new(0));
+                let (tmp, delta) = append_delta(&pair[1], &pair[0], race, None)
+                    .unwrap()
+                    .unwrap();
+                update_blob(conn, tmp, &delta).unwrap();
+            }
+            for blob in &blobs[1..] {
+                remove_root(conn, blob).unwrap();
+            }
 
-    while reader.read(&mut buf)? != 0 {
-        //
+            let out = dir.join("out");
+            let out = out.to_str().unwrap();
+            let get = |conn: &mut db::Conn, filename: &str, verify_chain: bool| {
+                let opts = GetOptions {
+                    overwrite: true,
+                    intermediate_cache: Some(1 << 20),
+                    verify_chain,
+                    ..Default::default()
+                };
+                get_with_options(conn, filename, out, &opts)
+            };
+            let cache = cache::IntermediateCache::new(&StoreDirs::from_env(), 1 << 20).unwrap();
+            let corrupt_app2 = |conn: &mut db::Conn| {
+                get(conn, "app-3.tar", false).unwrap();
+                let app2 = cache.lookup(&blobs[1].content_hash).unwrap().unwrap();
+                std::fs::write(app2, b"broken").unwrap();
+            };
+
+            // verified hops, from the cache too
+            get(conn, "app-3.tar", true).unwrap();
+            assert_eq!(std::fs::read(out).unwrap(), bodies[3]);
+            get(conn, "app-3.tar", true).unwrap();
+            assert_eq!(std::fs::read(out).unwrap(), bodies[3]);
+
+            // a broken entry is decoded again without, and detected with verify_chain
+            corrupt_app2(conn);
+            get(conn, "app-3.tar", false).unwrap();
+            assert_eq!(std::fs::read(out).unwrap(), bodies[3]);
+            corrupt_app2(conn);
+            std::fs::remove_file(out).unwrap();
+            let err = get(conn, "app-3.tar", true).unwrap_err();
+            assert_eq!(error_kind(&err), ErrorKind::ChecksumMismatch);
+            assert!(!Path::new(out).exists());
+            assert!(cache.lookup(&blobs[1].content_hash).unwrap().is_none());
+
+            // the root object is checked even when nothing is decoded
+            std::fs::write(filepath(&blobs[0].content_hash), b"broken").unwrap();
+            let err = get(conn, "app-1.tar", true).unwrap_err();
+            assert_eq!(error_kind(&err), ErrorKind::ChecksumMismatch);
+        });
     }
 
-    Ok(reader.meta().digest())
-}
+    #[test]
+    fn decode_path_objects() {
+        with_store(|conn, dir| {
+            push_two_versions(conn, dir);
+            let v3 = write_file(dir, "app-3.tar", b"hello, world!!!");
+            push(conn, &v3, FileType::Plain).unwrap();
+
+            let path = debug_decode_path(conn, "app-3.tar").unwrap();
+            assert!(path[0].0.is_root());
+            for pair in path.windows(2) {
+                assert_eq!(pair[1].0.parent_hash, Some(pair[0].0.content_hash.clone()));
+            }
+            let (leaf, _) = path.last().unwrap();
+            assert_eq!(leaf.content_hash, file_hash(&v3).unwrap());
+
+            let objects = path
+                .iter()
+                .map(|(_, object)| object.to_str().unwrap().to_owned())
+                .collect::<Vec<_>>();
+            let hashes = decode_objects(conn, "app-3.tar").unwrap();
+            assert_eq!(
+                objects,
+                hashes.iter().map(|hash| filepath(hash)).collect::<Vec<_>>()
+            );
+            assert!(path.iter().all(|(_, object)| object.exists()));
 
-pub fn debug_hash(filename: &str) -> Result<()> {
-    let hash = file_hash(filename)?;
-    println!("{}", hash);
+            assert!(debug_decode_path(conn, "unknown.tar").is_err());
+        });
+    }
 
-    Ok(())
+    #[test]
+    fn get_parents_only_objects() {
+        with_store(|conn, dir| {
+            push_two_versions(conn, dir);
+            let v3 = write_file(dir, "app-3.tar", b"hello, world!!!");
+            push(conn, &v3, FileType::Plain).unwrap();
+
+            let fetch_dir = dir.join("fetch");
+            let fetch_dir = fetch_dir.to_str().unwrap();
+            get_parents_only(conn, "app-3.tar", Some(fetch_dir)).unwrap();
+
+            // a store with only the listed objects can still decode the version
+            let hashes = decode_objects(conn, "app-3.tar").unwrap();
+            for hash in object_files().unwrap().keys() {
+                if !hashes.contains(hash) {
+                    std::fs::remove_file(filepath(hash)).unwrap();
+                }
+            }
+            for hash in &hashes {
+                let fetched = Path::new(fetch_dir).join("objects").join(&hash[..2]);
+                assert!(fetched.join(&hash[2..]).exists());
+            }
+
+            let out = dir.join("out.tar");
+            get(conn, "app-3.tar", out.to_str().unwrap(), false).unwrap();
+            assert_eq!(std::fs::read(&out).unwrap(), b"hello, world!!!");
+            std::fs::remove_file(&out).unwrap();
+
+            // every listed object is read
+            for hash in &hashes {
+                let path = filepath(hash);
+                let moved = format!("{}.moved", path);
+                std::fs::rename(&path, &moved).unwrap();
+                assert!(get(conn, "app-3.tar", out.to_str().unwrap(), false).is_err());
+                assert!(!out.exists());
+                std::fs::rename(&moved, &path).unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn get_parents() {
+        with_store(|conn, dir| {
+            push_two_versions(conn, dir);
+
+            let out = dir.join("a/b/app.tar");
+            let out_filename = out.to_str().unwrap();
+            assert!(get(conn, "app-2.tar", out_filename, false).is_err());
+
+            let opts = GetOptions {
+                parents: true,
+                ..Default::default()
+            };
+            get_with_options(conn, "app-2.tar", out_filename, &opts).unwrap();
+            assert_eq!(std::fs::read(&out).unwrap(), b"hello, world!!");
+        });
+    }
 }
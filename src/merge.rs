@@ -0,0 +1,157 @@
+use super::*;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MergeConflict {
+    /// same filename refers to different contents
+    Filename {
+        filename: String,
+        content_hash: String,
+        other_content_hash: String,
+    },
+    /// same object with different metadata
+    StoreHash { store_hash: String },
+    /// archives are started from different genesis blobs
+    Genesis {
+        content_hash: String,
+        other_content_hash: String,
+    },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MergeReport {
+    pub conflicts: Vec<MergeConflict>,
+    /// blobs of other archive which do not exist in this archive
+    pub new_blobs: usize,
+}
+
+/// detects conflicts between this archive and other archive's database. nothing is written.
+pub fn merge_conflicts(conn: &mut db::Conn, other: &mut db::Conn) -> Result<MergeReport> {
+    let blobs = db::all(conn)?;
+    let other_blobs = db::all(other)?;
+
+    let mut report = MergeReport::default();
+
+    let genesis = blobs.iter().find(|blob| blob.is_genesis());
+    let other_genesis = other_blobs.iter().find(|blob| blob.is_genesis());
+    if let (Some(genesis), Some(other_genesis)) = (genesis, other_genesis) {
+        if !genesis.is_same_content(other_genesis) {
+            report.conflicts.push(MergeConflict::Genesis {
+                content_hash: genesis.content_hash.clone(),
+                other_content_hash: other_genesis.content_hash.clone(),
+            });
+        }
+    }
+
+    let mut by_filename = HashMap::new();
+    let mut by_store_hash = HashMap::new();
+    for blob in &blobs {
+        by_filename.insert(blob.filename.as_str(), blob);
+        by_store_hash.insert(blob.store_hash.as_str(), blob);
+    }
+
+    for other_blob in &other_blobs {
+        if let Some(blob) = by_filename.get(other_blob.filename.as_str()) {
+            if !blob.is_same_content(other_blob) {
+                report.conflicts.push(MergeConflict::Filename {
+                    filename: other_blob.filename.clone(),
+                    content_hash: blob.content_hash.clone(),
+                    other_content_hash: other_blob.content_hash.clone(),
+                });
+            }
+        }
+
+        match by_store_hash.get(other_blob.store_hash.as_str()) {
+            Some(blob) => {
+                if !blob.is_same_content(other_blob)
+                    || blob.parent_hash != other_blob.parent_hash
+                    || blob.store_size != other_blob.store_size
+                {
+                    report.conflicts.push(MergeConflict::StoreHash {
+                        store_hash: other_blob.store_hash.clone(),
+                    });
+                }
+            }
+            None => {
+                report.new_blobs += 1;
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// prints conflicts of merging other archive at `other_prefix` (its WORKDIR). nothing is written.
+pub fn print_merge_conflicts(conn: &mut db::Conn, other_prefix: &str) -> Result<()> {
+    let mut other = db::open_readonly(format!("{}/meta.db", other_prefix))?;
+    let report = merge_conflicts(conn, &mut other)?;
+
+    for conflict in &report.conflicts {
+        match conflict {
+            MergeConflict::Filename {
+                filename,
+                content_hash,
+                other_content_hash,
+            } => println!(
+                "conflict=filename filename={} content_hash={} other_content_hash={}",
+                filename, content_hash, other_content_hash
+            ),
+            MergeConflict::StoreHash { store_hash } => {
+                println!("conflict=store_hash store_hash={}", store_hash)
+            }
+            MergeConflict::Genesis {
+                content_hash,
+                other_content_hash,
+            } => println!(
+                "conflict=genesis content_hash={} other_content_hash={}",
+                content_hash, other_content_hash
+            ),
+        }
+    }
+    println!(
+        "conflicts={} new_blobs={}",
+        report.conflicts.len(),
+        report.new_blobs
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+
+    #[test]
+    fn merge_conflicts_filename() {
+        with_store(|conn, dir| {
+            db::insert(conn, &blob(1, "aa00", None, 10)).unwrap();
+            db::insert(conn, &blob(2, "bb00", None, 10)).unwrap();
+
+            let other_dir = dir.join("other");
+            std::fs::create_dir_all(&other_dir).unwrap();
+            {
+                let mut other = db::open_path(other_dir.join("meta.db")).unwrap();
+                db::prepare(&mut other).unwrap();
+                db::insert(&mut other, &blob(1, "aa00", None, 10)).unwrap();
+                db::insert(&mut other, &blob(2, "cc00", None, 10)).unwrap();
+            }
+
+            let mut other = db::open_readonly(other_dir.join("meta.db")).unwrap();
+            let report = merge_conflicts(conn, &mut other).unwrap();
+            assert_eq!(
+                report.conflicts,
+                vec![MergeConflict::Filename {
+                    filename: "app-2.tar".to_owned(),
+                    content_hash: "bb00".to_owned(),
+                    other_content_hash: "cc00".to_owned(),
+                }]
+            );
+            assert_eq!(report.new_blobs, 1);
+
+            print_merge_conflicts(conn, other_dir.to_str().unwrap()).unwrap();
+            assert_eq!(db::all(conn).unwrap().len(), 2);
+            assert_eq!(db::all(&mut other).unwrap().len(), 2);
+        });
+    }
+}
@@ -0,0 +1,338 @@
+//! prometheus metrics of one-shot commands, written to a node_exporter textfile collector file.
+//! counters and histograms are read back from the file and accumulated across runs, gauges are
+//! replaced by the latest run.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::time::Duration;
+
+use super::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+    Histogram(&'static [f64]),
+}
+
+/// a metric family. names and labels are stable, dashboards depend on them.
+#[derive(Debug)]
+pub struct Family {
+    pub name: &'static str,
+    pub kind: Kind,
+    pub help: &'static str,
+}
+
+/// all metrics, in the order they are written
+pub const FAMILIES: &[Family] = &[
+    Family {
+        name: "increstore_pushes_total",
+        kind: Kind::Counter,
+        help: "successful pushes, including deduplicated ones",
+    },
+    Family {
+        name: "increstore_dedupes_total",
+        kind: Kind::Counter,
+        help: "pushes skipped as the content already exists",
+    },
+    Family {
+        name: "increstore_gets_total",
+        kind: Kind::Counter,
+        help: "successful gets",
+    },
+    Family {
+        name: "increstore_cleanups_total",
+        kind: Kind::Counter,
+        help: "successful cleanups",
+    },
+    Family {
+        name: "increstore_failures_total",
+        kind: Kind::Counter,
+        help: "failed commands, by op label: push, get or cleanup",
+    },
+    Family {
+        name: "increstore_push_duration_seconds",
+        kind: Kind::Histogram(&[0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 600.0]),
+        help: "duration of successful pushes",
+    },
+    Family {
+        name: "increstore_push_delta_ratio",
+        kind: Kind::Histogram(&[0.001, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0]),
+        help: "store_size/content_size of the delta stored on push",
+    },
+    Family {
+        name: "increstore_store_bytes",
+        kind: Kind::Gauge,
+        help: "sum of store_size of all blobs",
+    },
+    Family {
+        name: "increstore_content_bytes",
+        kind: Kind::Gauge,
+        help: "sum of content_size of all blobs",
+    },
+    Family {
+        name: "increstore_blobs",
+        kind: Kind::Gauge,
+        help: "number of blobs",
+    },
+    Family {
+        name: "increstore_roots",
+        kind: Kind::Gauge,
+        help: "number of root blobs",
+    },
+];
+
+/// the family of a series, e.g. `increstore_push_delta_ratio` of
+/// `increstore_push_delta_ratio_bucket{le="0.1"}`
+fn family_of(series: &str) -> Option<&'static Family> {
+    let name = series.split('{').next().unwrap_or(series);
+    FAMILIES.iter().find(|family| match family.kind {
+        Kind::Histogram(_) => ["", "_bucket", "_sum", "_count"]
+            .iter()
+            .any(|suffix| name.strip_suffix(suffix) == Some(family.name)),
+        _ => name == family.name,
+    })
+}
+
+/// values of series, keyed by the series name with its labels
+#[derive(Debug, Default)]
+pub struct Registry {
+    series: BTreeMap<String, f64>,
+}
+
+impl Registry {
+    /// reads series of known families from the text format. other lines are dropped.
+    pub fn parse(text: &str) -> Self {
+        let mut series = BTreeMap::new();
+        for line in text.lines() {
+            if line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = match line.rsplit_once(' ') {
+                Some(pair) => pair,
+                None => continue,
+            };
+            if let (Some(_), Ok(value)) = (family_of(name), value.parse::<f64>()) {
+                series.insert(name.to_owned(), value);
+            }
+        }
+        Registry { series }
+    }
+
+    pub fn get(&self, series: &str) -> Option<f64> {
+        self.series.get(series).copied()
+    }
+
+    pub fn inc(&mut self, series: &str, v: f64) {
+        *self.series.entry(series.to_owned()).or_default() += v;
+    }
+
+    pub fn set(&mut self, series: &str, v: f64) {
+        self.series.insert(series.to_owned(), v);
+    }
+
+    pub fn observe(&mut self, name: &str, v: f64) {
+        let buckets = match family_of(name).map(|family| family.kind) {
+            Some(Kind::Histogram(buckets)) => buckets,
+            _ => panic!("not a histogram: {}", name),
+        };
+        for le in buckets.iter().filter(|le| v <= **le) {
+            self.inc(&format!("{}_bucket{{le=\"{}\"}}", name, le), 1.0);
+        }
+        self.inc(&format!("{}_bucket{{le=\"+Inf\"}}", name), 1.0);
+        self.inc(&format!("{}_sum", name), v);
+        self.inc(&format!("{}_count", name), 1.0);
+    }
+
+    pub fn record_push(&mut self, report: &PushReport, elapsed: Duration) {
+        self.inc("increstore_pushes_total", 1.0);
+        if report.skipped {
+            self.inc("increstore_dedupes_total", 1.0);
+        }
+        self.observe("increstore_push_duration_seconds", elapsed.as_secs_f64());
+        if let Some(ratio) = report.ratio {
+            self.observe("increstore_push_delta_ratio", ratio as f64);
+        }
+    }
+
+    pub fn record_get(&mut self) {
+        self.inc("increstore_gets_total", 1.0);
+    }
+
+    pub fn record_cleanup(&mut self) {
+        self.inc("increstore_cleanups_total", 1.0);
+    }
+
+    pub fn record_failure(&mut self, op: &str) {
+        self.inc(&format!("increstore_failures_total{{op=\"{}\"}}", op), 1.0);
+    }
+
+    /// sets the gauges of the store totals
+    pub fn record_store(&mut self, conn: &mut db::Conn) -> Result<()> {
+        let (store_size, content_size) = db::total_sizes(conn)?;
+        self.set("increstore_store_bytes", store_size as f64);
+        self.set("increstore_content_bytes", content_size as f64);
+        self.set("increstore_blobs", db::count(conn)? as f64);
+        self.set("increstore_roots", db::roots(conn)?.len() as f64);
+        Ok(())
+    }
+
+    /// the text exposition format. histogram buckets are written in increasing order.
+    pub fn render(&self) -> String {
+        let mut s = String::new();
+        for family in FAMILIES {
+            let kind = match family.kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+                Kind::Histogram(_) => "histogram",
+            };
+            let mut lines = Vec::new();
+            match family.kind {
+                Kind::Histogram(buckets) => {
+                    if self.get(&format!("{}_count", family.name)).is_none() {
+                        continue;
+                    }
+                    let les = buckets.iter().map(|le| le.to_string());
+                    for le in les.chain(std::iter::once("+Inf".to_owned())) {
+                        let series = format!("{}_bucket{{le=\"{}\"}}", family.name, le);
+                        lines.push((series.clone(), self.get(&series).unwrap_or(0.0)));
+                    }
+                    for suffix in &["_sum", "_count"] {
+                        let series = format!("{}{}", family.name, suffix);
+                        lines.push((series.clone(), self.get(&series).unwrap_or(0.0)));
+                    }
+                }
+                _ => {
+                    for (series, v) in &self.series {
+                        if family_of(series).map(|f| f.name) == Some(family.name) {
+                            lines.push((series.clone(), *v));
+                        }
+                    }
+                }
+            }
+            if lines.is_empty() {
+                continue;
+            }
+
+            s += &format!("# HELP {} {}\n", family.name, family.help);
+            s += &format!("# TYPE {} {}\n", family.name, kind);
+            for (series, v) in lines {
+                s += &format!("{} {}\n", series, v);
+            }
+        }
+        s
+    }
+}
+
+/// updates a textfile collector file: reads its series, applies `f`, and atomically replaces it.
+/// concurrent updates are serialized, so no run is lost.
+pub fn update_textfile<F>(path: &str, f: F) -> Result<()>
+where
+    F: FnOnce(&mut Registry) -> Result<()>,
+{
+    let _lock = lock_parent_dir(path)?;
+    let mut registry = match std::fs::read_to_string(path) {
+        Ok(text) => Registry::parse(&text),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Registry::default(),
+        Err(e) => return Err(e.into()),
+    };
+    f(&mut registry)?;
+
+    // node_exporter must never read a partial file, and runs as another user
+    let mut tmpfile = NamedTempFile::new_in(parent_dir(path))?;
+    tmpfile.write_all(registry.render().as_bytes())?;
+    set_readable(&tmpfile)?;
+    tmpfile.persist(path)?;
+    Ok(())
+}
+
+/// records the result of command `op` into the textfile at `path`, along with the store gauges.
+/// `record` is called on success, failures are counted by op. a failure to update the file is
+/// logged, so it never hides the result of the command.
+pub fn record_command<T, F>(conn: &mut db::Conn, path: &str, op: &str, res: &Result<T>, record: F)
+where
+    F: FnOnce(&mut Registry, &T),
+{
+    let updated = update_textfile(path, |reg| {
+        match res {
+            Ok(v) => record(reg, v),
+            Err(_) => reg.record_failure(op),
+        }
+        reg.record_store(conn)
+    });
+    if let Err(e) = updated {
+        error!("failed to update metrics {}: {}", path, e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+
+    #[test]
+    fn textfile() {
+        with_store(|conn, dir| {
+            let path = dir.join("increstore.prom");
+            let path = path.to_str().unwrap();
+
+            for (i, body) in ["hello, world", "hello, world!!", "hello, world"]
+                .iter()
+                .enumerate()
+            {
+                let input = write_file(dir, &format!("app-{}.tar", i), body.as_bytes());
+                let opts = PushOptions::default();
+                let report = push_with_options(conn, &input, FileType::Plain, &opts).unwrap();
+                update_textfile(path, |reg| {
+                    reg.record_push(&report, Duration::from_millis(200));
+                    reg.record_store(conn)
+                })
+                .unwrap();
+            }
+
+            let out = dir.join("out");
+            let res = get(conn, "unknown.tar", out.to_str().unwrap(), false);
+            record_command(conn, path, "get", &res, |reg, _| reg.record_get());
+
+            let text = std::fs::read_to_string(path).unwrap();
+            for line in &[
+                "# TYPE increstore_pushes_total counter\n",
+                "increstore_pushes_total 3\n",
+                "increstore_dedupes_total 1\n",
+                "increstore_failures_total{op=\"get\"} 1\n",
+                "# TYPE increstore_push_duration_seconds histogram\n",
+                "increstore_push_duration_seconds_bucket{le=\"0.1\"} 0\n",
+                "increstore_push_duration_seconds_bucket{le=\"0.5\"} 3\n",
+                "increstore_push_duration_seconds_bucket{le=\"+Inf\"} 3\n",
+                "increstore_push_duration_seconds_count 3\n",
+                "increstore_push_delta_ratio_count 1\n",
+                "increstore_blobs 3\n",
+            ] {
+                assert!(text.contains(line), "{} not in\n{}", line, text);
+            }
+            let roots = db::roots(conn).unwrap().len();
+            assert!(text.contains(&format!("increstore_roots {}\n", roots)));
+            assert!(!text.contains("increstore_gets_total"));
+
+            // round trip
+            assert_eq!(Registry::parse(&text).render(), text);
+
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let mode = std::fs::metadata(path).unwrap().permissions().mode();
+                assert_eq!(mode & 0o777, 0o644);
+            }
+            // no lock file is left behind
+            let files = std::fs::read_dir(dir).unwrap().count();
+            record_command(conn, path, "get", &res, |reg, _| reg.record_get());
+            assert_eq!(std::fs::read_dir(dir).unwrap().count(), files);
+
+            // a missing directory fails the update, not the command
+            let missing = dir.join("missing/increstore.prom");
+            record_command(conn, missing.to_str().unwrap(), "get", &res, |reg, _| {
+                reg.record_get()
+            });
+        });
+    }
+}
@@ -0,0 +1,161 @@
+//! patches between two arbitrary versions, for clients updating from one known version to
+//! another. unlike deltas of the store, a patch does not depend on decode chains: both versions
+//! are decoded and the patch is encoded directly from one to the other.
+
+use std::io::Write;
+
+use super::*;
+
+/// format of patches written by `make_patch`, as decoded by `xdelta3 -d -s <from>`
+pub const PATCH_FORMAT: &str = "vcdiff";
+
+/// what a client needs to verify a patch, written next to it as `<out>.json`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PatchManifest {
+    pub format: String,
+    pub hash_algo: String,
+    pub from_hash: String,
+    pub from_size: u64,
+    /// content hash of the version the patch decodes to
+    pub to_hash: String,
+    pub to_size: u64,
+    pub patch_hash: String,
+    pub patch_size: u64,
+    /// sha256 of the patch in hex, for clients checking it without the hash of the store
+    #[serde(default)]
+    pub patch_sha256: String,
+}
+
+pub fn manifest_path(out_filename: &str) -> String {
+    format!("{}.json", out_filename)
+}
+
+/// sha256 of a file in hex
+fn sha256_file(path: &Path) -> Result<String> {
+    use sha2::Digest;
+
+    let mut hasher = sha2::Sha256::new();
+    io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// writes a patch from version `from` to version `to` into `out_filename`, and its manifest into
+/// `manifest_path(out_filename)`. versions are named like `describe`, by a filename or a hash.
+/// the patch is removed if its manifest can not be written.
+pub fn make_patch(
+    conn: &mut db::Conn,
+    from: &str,
+    to: &str,
+    out_filename: &str,
+) -> Result<PatchManifest> {
+    let from_blob = resolve_blob(conn, from)?;
+    let to_blob = resolve_blob(conn, to)?;
+    let from_content = compact::decode_content(conn, &from_blob)?;
+    let to_content = compact::decode_content(conn, &to_blob)?;
+
+    let (tmpfile, patch) =
+        compact::encode_delta(&to_blob.filename, from_content.path(), to_content.path())?;
+    let manifest = PatchManifest {
+        format: PATCH_FORMAT.to_owned(),
+        hash_algo: HASH_ALGO.to_owned(),
+        from_hash: from_blob.content_hash,
+        from_size: from_blob.content_size,
+        to_hash: to_blob.content_hash,
+        to_size: to_blob.content_size,
+        patch_hash: patch.store_hash,
+        patch_size: patch.store_size,
+        patch_sha256: sha256_file(tmpfile.path())?,
+    };
+
+    let mut manifest_file = NamedTempFile::new_in(tmpdir())?;
+    serde_json::to_writer_pretty(&mut manifest_file, &manifest)?;
+    writeln!(manifest_file)?;
+
+    let opts = GetOptions::default();
+    persist_output(tmpfile, out_filename, &opts)?;
+    if let Err(e) = persist_output(manifest_file, &manifest_path(out_filename), &opts) {
+        // a patch without its manifest can not be verified by clients
+        std::fs::remove_file(out_filename)?;
+        return Err(e);
+    }
+
+    info!(
+        "make-patch: {} -> {}, patch_size={}",
+        from_blob.filename, to_blob.filename, manifest.patch_size
+    );
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+    use sha2::Digest;
+
+    /// applies a patch as a client would, without the store
+    fn apply_patch(from: &Path, patch: &Path, out: &Path) -> WriteMetadata {
+        use tokio::fs::File;
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            delta::delta(
+                delta::ProcessMode::Decode,
+                File::open(from).await?,
+                File::open(patch).await?,
+                File::create(out).await?,
+            )
+            .await
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn patch_between_versions() {
+        with_store(|conn, dir| {
+            let bodies = (0..4)
+                .map(|i| format!("{}{}", "hello, world\n".repeat(1024), i).into_bytes())
+                .collect::<Vec<_>>();
+            for (i, body) in bodies.iter().enumerate() {
+                let input = write_file(dir, &format!("app-{}.tar", i), body);
+                push(conn, &input, FileType::Plain).unwrap();
+            }
+
+            // versions far apart in the graph, one named by its content hash
+            let to_hash = db::by_filename(conn, "app-3.tar").unwrap()[0]
+                .content_hash
+                .clone();
+            let out = dir.join("app-1-3.patch");
+            let out_str = out.to_str().unwrap();
+            let manifest = make_patch(conn, "app-1.tar", &to_hash, out_str).unwrap();
+
+            let written = std::fs::read_to_string(manifest_path(out_str)).unwrap();
+            assert_eq!(
+                serde_json::from_str::<PatchManifest>(&written).unwrap(),
+                manifest
+            );
+            assert_eq!(manifest.format, PATCH_FORMAT);
+            assert_eq!(manifest.to_hash, to_hash);
+            assert_eq!(manifest.to_size, bodies[3].len() as u64);
+            assert_eq!(manifest.patch_size, std::fs::metadata(&out).unwrap().len());
+            assert_eq!(file_hash(out_str).unwrap(), manifest.patch_hash);
+            let sha256 = sha2::Sha256::digest(std::fs::read(&out).unwrap());
+            assert_eq!(manifest.patch_sha256, format!("{:x}", sha256));
+
+            let from = write_file(dir, "client-app-1.tar", &bodies[1]);
+            let applied = dir.join("client-app-3.tar");
+            let meta = apply_patch(Path::new(&from), &out, &applied);
+            assert_eq!(meta.digest(), manifest.to_hash);
+            assert_eq!(std::fs::read(&applied).unwrap(), bodies[3]);
+
+            // an existing patch is not replaced
+            assert!(make_patch(conn, "app-1.tar", "app-3.tar", out_str).is_err());
+
+            // nor an existing manifest, and the patch written before it is removed
+            let out = dir.join("app-0-3.patch");
+            let out_str = out.to_str().unwrap();
+            std::fs::write(manifest_path(out_str), b"{}").unwrap();
+            assert!(make_patch(conn, "app-0.tar", "app-3.tar", out_str).is_err());
+            assert!(!out.exists());
+        });
+    }
+}
@@ -0,0 +1,370 @@
+//! decoding several versions at once. decode chains of the versions are merged into a tree by
+//! content, so an ancestor shared by several versions is decoded a single time. used by
+//! `get_many` and `hydrate`.
+
+use super::*;
+
+/// a version of `get_many` and its result
+#[derive(Debug, Clone, Serialize)]
+pub struct GetItem {
+    pub filename: String,
+    pub out_filename: String,
+    /// None if the version is written to out_filename
+    pub error: Option<String>,
+}
+
+#[cfg(test)]
+thread_local! {
+    /// delta steps decoded by plans, to check shared ancestors are decoded once in tests
+    static DECODES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+struct Node {
+    /// the root blob of a tree, or the delta blob decoding this content from its parent
+    blob: Blob,
+    children: Vec<usize>,
+    /// indices of items which are this content
+    items: Vec<usize>,
+}
+
+/// content of a node: the object of a root, or a decoded temporary file
+enum Content {
+    Object(PathBuf),
+    Decoded(NamedTempFile),
+}
+
+impl Content {
+    fn path(&self) -> &Path {
+        match self {
+            Content::Object(path) => path,
+            Content::Decoded(tmpfile) => tmpfile.path(),
+        }
+    }
+}
+
+struct Plan {
+    nodes: Vec<Node>,
+    roots: Vec<usize>,
+}
+
+impl Plan {
+    /// merges decode chains of items. items which can not be planned fail here.
+    fn new(conn: &mut db::Conn, items: &mut [GetItem]) -> Result<Self> {
+        let mut plan = Plan {
+            nodes: Vec::new(),
+            roots: Vec::new(),
+        };
+        let mut by_parent = HashMap::<(Option<usize>, String), usize>::new();
+
+        for (item_idx, item) in items.iter_mut().enumerate() {
+            let (root, decode_path) = match decode_path(conn, &item.filename) {
+                Ok(path) => path,
+                Err(e) => {
+                    item.error = Some(e.to_string());
+                    continue;
+                }
+            };
+
+            let mut parent = None;
+            for blob in std::iter::once(root).chain(decode_path) {
+                let key = (parent, blob.content_hash.clone());
+                let idx = match by_parent.get(&key) {
+                    Some(idx) => *idx,
+                    None => {
+                        let idx = plan.nodes.len();
+                        plan.nodes.push(Node {
+                            blob,
+                            children: Vec::new(),
+                            items: Vec::new(),
+                        });
+                        match parent {
+                            Some(parent) => plan.nodes[parent].children.push(idx),
+                            None => plan.roots.push(idx),
+                        }
+                        by_parent.insert(key, idx);
+                        idx
+                    }
+                };
+                parent = Some(idx);
+            }
+            if let Some(idx) = parent {
+                plan.nodes[idx].items.push(item_idx);
+            }
+        }
+        Ok(plan)
+    }
+
+    /// objects read by the plan, each once, root objects first
+    fn objects(&self) -> Vec<&Blob> {
+        let mut stack = self.roots.iter().rev().copied().collect::<Vec<_>>();
+        let mut objects = Vec::new();
+        while let Some(idx) = stack.pop() {
+            objects.push(&self.nodes[idx].blob);
+            stack.extend(self.nodes[idx].children.iter().rev());
+        }
+        objects
+    }
+
+    fn run(&self, items: &mut [GetItem], opts: &GetOptions) -> Result<()> {
+        let rt = tokio::runtime::Runtime::new()?;
+        for &root_idx in &self.roots {
+            let path = PathBuf::from(filepath(&self.nodes[root_idx].blob.content_hash));
+            if !path.exists() {
+                let e = Error::MissingObject(path.to_string_lossy().into_owned());
+                self.fail(root_idx, &e, items);
+                continue;
+            }
+            self.run_tree(&rt, root_idx, Content::Object(path), items, opts);
+        }
+        Ok(())
+    }
+
+    /// writes items of a node and decodes its children. the last child continues the loop, so
+    /// a chain keeps two decoded contents at a time like `get`, and recursion happens only at
+    /// branches.
+    fn run_tree(
+        &self,
+        rt: &tokio::runtime::Runtime,
+        mut idx: usize,
+        mut src: Content,
+        items: &mut [GetItem],
+        opts: &GetOptions,
+    ) {
+        loop {
+            let node = &self.nodes[idx];
+            for &item_idx in &node.items {
+                let item = &mut items[item_idx];
+                if let Err(e) = write_output(src.path(), &node.blob, &item.out_filename, opts) {
+                    item.error = Some(e.to_string());
+                }
+            }
+
+            let (last, rest) = match node.children.split_last() {
+                Some(children) => children,
+                None => return,
+            };
+            // a shared content is read by each child, and checked again before each
+            let decode = |child: usize| {
+                if opts.verify_chain {
+                    check_content(src.path(), &node.blob)?;
+                }
+                self.decode(rt, child, src.path())
+            };
+            for &child in rest {
+                match decode(child) {
+                    Ok(tmpfile) => self.run_tree(rt, child, Content::Decoded(tmpfile), items, opts),
+                    Err(e) => self.fail(child, &e, items),
+                }
+            }
+            match decode(*last) {
+                Ok(tmpfile) => {
+                    idx = *last;
+                    src = Content::Decoded(tmpfile);
+                }
+                Err(e) => {
+                    self.fail(*last, &e, items);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// decodes the content of node `idx` from the content of its parent, verifying its hash
+    fn decode(
+        &self,
+        rt: &tokio::runtime::Runtime,
+        idx: usize,
+        src_path: &Path,
+    ) -> Result<NamedTempFile> {
+        use tokio::fs::File;
+        use tokio::io::{BufReader, BufWriter};
+
+        #[cfg(test)]
+        DECODES.with(|count| count.set(count.get() + 1));
+
+        let blob = &self.nodes[idx].blob;
+        let delta_filepath = filepath(&blob.store_hash);
+        if !Path::new(&delta_filepath).exists() {
+            return Err(Error::MissingObject(delta_filepath));
+        }
+        debug!("plan: decode filename={}", blob.filename);
+
+        let tmpfile = NamedTempFile::new_in(tmpdir())?;
+        let dst_meta = rt.block_on(async {
+            let src_file = File::open(src_path).await?;
+            let input_file = File::open(&delta_filepath).await?;
+            let dst_file = File::create(tmpfile.path()).await?;
+
+            delta::delta(
+                delta::ProcessMode::Decode,
+                BufReader::with_capacity(BUF_SIZE, src_file),
+                BufReader::with_capacity(BUF_SIZE, input_file),
+                BufWriter::with_capacity(BUF_SIZE, dst_file),
+            )
+            .await
+            .map_err(|error| crate::Error::Delta {
+                filename: blob.filename.clone(),
+                error,
+            })
+        })?;
+
+        if dst_meta.digest() != blob.content_hash {
+            keep_decode_files(
+                &StoreDirs::from_env(),
+                blob,
+                src_path,
+                Path::new(&delta_filepath),
+                tmpfile.path(),
+            )?;
+            return Err(Error::ChecksumMismatch {
+                filename: blob.filename.clone(),
+                expected: blob.content_hash.clone(),
+                actual: dst_meta.digest(),
+            });
+        }
+        Ok(tmpfile)
+    }
+
+    /// fails items of node `idx` and of its descendants
+    fn fail(&self, idx: usize, e: &Error, items: &mut [GetItem]) {
+        let mut stack = vec![idx];
+        while let Some(idx) = stack.pop() {
+            for &item_idx in &self.nodes[idx].items {
+                items[item_idx].error = Some(e.to_string());
+            }
+            stack.extend(&self.nodes[idx].children);
+        }
+    }
+}
+
+/// copies a decoded content to out_filename, verifying what is written
+fn write_output(src_path: &Path, blob: &Blob, out_filename: &str, opts: &GetOptions) -> Result<()> {
+    let tmpfile = NamedTempFile::new_in(tmpdir())?;
+    let mut w = HashRW::new(tmpfile.as_file());
+    io::copy(&mut std::fs::File::open(src_path)?, &mut w)?;
+    if w.meta().digest() != blob.content_hash {
+        return Err(Error::ChecksumMismatch {
+            filename: blob.filename.clone(),
+            expected: blob.content_hash.clone(),
+            actual: w.meta().digest(),
+        });
+    }
+    persist_output(tmpfile, out_filename, opts)
+}
+
+/// gets several versions, given as (filename, out_filename), decoding their shared ancestors
+/// once. a failed version does not stop the others, see `GetItem::error`. options which do not
+/// apply to a shared decode, `in_place`, `intermediate_cache` and `GetFormat::Delta`, get the
+/// versions one by one instead, as do versions stored as chunks.
+pub fn get_many(
+    conn: &mut db::Conn,
+    targets: &[(String, String)],
+    opts: &GetOptions,
+) -> Result<Vec<GetItem>> {
+    let mut items = targets
+        .iter()
+        .map(|(filename, out_filename)| GetItem {
+            filename: filename.clone(),
+            out_filename: out_filename.clone(),
+            error: None,
+        })
+        .collect::<Vec<_>>();
+
+    let one_by_one = opts.in_place || opts.intermediate_cache.is_some();
+    let one_by_one = one_by_one || opts.format != GetFormat::Content;
+    let mut planned = Vec::new();
+    for (idx, item) in items.iter_mut().enumerate() {
+        let chunked = db::by_filename(conn, &item.filename)?.is_empty()
+            && db::chunked_by_filename(conn, &item.filename)?.is_some();
+        if one_by_one || chunked {
+            let res = get_with_options(conn, &item.filename, &item.out_filename, opts);
+            item.error = res.err().map(|e| e.to_string());
+        } else {
+            planned.push(idx);
+        }
+    }
+
+    let mut planned_items = planned
+        .iter()
+        .map(|idx| items[*idx].clone())
+        .collect::<Vec<_>>();
+    let plan = Plan::new(conn, &mut planned_items)?;
+    if opts.dry_run {
+        for blob in plan.objects() {
+            println!("{} {}", filepath(&blob.store_hash), blob.filename);
+        }
+    } else {
+        plan.run(&mut planned_items, opts)?;
+    }
+    for (idx, item) in planned.into_iter().zip(planned_items) {
+        items[idx] = item;
+    }
+
+    Ok(items)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+
+    #[test]
+    fn shared_ancestors() {
+        with_store(|conn, dir| {
+            // app-4 -> app-3 -> app-2 -> app-1, app-5 -> app-2
+            let base = "hello, world\n".repeat(1024).into_bytes();
+            let mut bodies = HashMap::new();
+            let mut blobs = HashMap::new();
+            for (i, parent) in &[(1, 0), (2, 1), (3, 2), (4, 3), (5, 2)] {
+                let mut body = bodies.get(parent).cloned().unwrap_or_else(|| base.clone());
+                body.extend_from_slice(format!("v{}", i).as_bytes());
+                let path = write_file(dir, &format!("app-{}.tar", i), &body);
+                let (blob, _) = append_full(conn, &path, FileType::Plain, false).unwrap();
+                bodies.insert(*i, body);
+                blobs.insert(*i, blob);
+            }
+            for (i, parent) in &[(2, 1), (3, 2), (4, 3), (5, 2)] {
+                let race = Arc::new(AtomicUsize::new(0));
+                let (tmp, delta) = append_delta(&blobs[i], &blobs[parent], race, None)
+                    .unwrap()
+                    .unwrap();
+                update_blob(conn, tmp, &delta).unwrap();
+            }
+            for i in 2..=5 {
+                remove_root(conn, &blobs[&i]).unwrap();
+            }
+
+            let out = |i: usize| dir.join(format!("out-{}", i)).to_str().unwrap().to_owned();
+            let targets = [3, 4, 5, 4]
+                .iter()
+                .map(|i| (format!("app-{}.tar", i), out(*i)))
+                .chain(std::iter::once(("unknown.tar".to_owned(), out(0))))
+                .collect::<Vec<_>>();
+            let opts = GetOptions {
+                overwrite: true,
+                ..Default::default()
+            };
+
+            DECODES.with(|count| count.set(0));
+            let items = get_many(conn, &targets, &opts).unwrap();
+            // app-2, app-3, app-4 and app-5, instead of 2 + 3 + 2 + 3
+            assert_eq!(DECODES.with(|count| count.get()), 4);
+
+            for item in &items[..4] {
+                assert!(item.error.is_none(), "{:?}", item);
+            }
+            assert!(items[4].error.is_some());
+            for i in 3..=5 {
+                assert_eq!(std::fs::read(out(i)).unwrap(), bodies[&i]);
+            }
+
+            // a broken delta fails the versions decoded through it only
+            let delta = db::by_filename(conn, "app-3.tar").unwrap().pop().unwrap();
+            std::fs::write(filepath(&delta.store_hash), b"broken").unwrap();
+            let items = get_many(conn, &targets[..3], &opts).unwrap();
+            assert!(items[0].error.is_some());
+            assert!(items[1].error.is_some());
+            assert!(items[2].error.is_none());
+        });
+    }
+}
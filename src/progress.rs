@@ -0,0 +1,142 @@
+//! progress bars on stderr. whether they are drawn is decided for the whole process, so a CI log
+//! never gets their control characters.
+
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use pbr::{ProgressBar, Units};
+
+use super::*;
+
+/// overrides the default `ProgressMode` when the cli does not set one
+pub const PROGRESS_ENV: &str = "INCRESTORE_PROGRESS";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProgressMode {
+    Always,
+    Never,
+    /// only on a terminal, and not on CI, a dumb terminal or with NO_COLOR set
+    #[default]
+    Auto,
+}
+
+impl std::str::FromStr for ProgressMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "always" => Ok(ProgressMode::Always),
+            "never" => Ok(ProgressMode::Never),
+            "auto" => Ok(ProgressMode::Auto),
+            _ => Err(crate::format_err!("unknown progress mode: {}", s)),
+        }
+    }
+}
+
+/// 0 if not set, otherwise 1 + the mode
+static PROGRESS_MODE: AtomicU8 = AtomicU8::new(0);
+
+pub fn set_progress_mode(mode: ProgressMode) {
+    PROGRESS_MODE.store(1 + mode as u8, Ordering::SeqCst);
+}
+
+/// set with `set_progress_mode` or the INCRESTORE_PROGRESS env var, `Auto` by default
+pub fn progress_mode() -> ProgressMode {
+    match PROGRESS_MODE.load(Ordering::SeqCst) {
+        1 => ProgressMode::Always,
+        2 => ProgressMode::Never,
+        3 => ProgressMode::Auto,
+        _ => match env::var(PROGRESS_ENV) {
+            Ok(mode) if !mode.is_empty() => mode.parse().unwrap_or_else(|e| {
+                warn!("{}: {}", PROGRESS_ENV, e);
+                ProgressMode::Auto
+            }),
+            _ => ProgressMode::Auto,
+        },
+    }
+}
+
+/// whether progress bars are drawn on stderr in this process
+pub fn progress_enabled() -> bool {
+    let tty = io::stderr().is_terminal();
+    let set = |name| env::var_os(name).is_some_and(|v| !v.is_empty());
+    let plain = set("CI") || set("NO_COLOR") || env::var("TERM").is_ok_and(|term| term == "dumb");
+    draws(progress_mode(), tty, plain)
+}
+
+fn draws(mode: ProgressMode, tty: bool, plain: bool) -> bool {
+    match mode {
+        ProgressMode::Always => true,
+        ProgressMode::Never => false,
+        ProgressMode::Auto => tty && !plain,
+    }
+}
+
+/// a progress bar on stderr, which draws nothing unless `progress_enabled`
+pub struct Progress(Option<ProgressBar<io::Stderr>>);
+
+impl Progress {
+    pub fn new(total: u64) -> Self {
+        Progress(progress_enabled().then(|| ProgressBar::on(io::stderr(), total)))
+    }
+
+    /// a bar counting bytes
+    pub fn bytes(total: u64) -> Self {
+        let mut progress = Self::new(total);
+        if let Some(pb) = &mut progress.0 {
+            pb.set_units(Units::Bytes);
+        }
+        progress
+    }
+
+    pub fn inc(&mut self) {
+        if let Some(pb) = &mut self.0 {
+            pb.inc();
+        }
+    }
+
+    pub fn add(&mut self, n: u64) {
+        if let Some(pb) = &mut self.0 {
+            pb.add(n);
+        }
+    }
+
+    pub fn set(&mut self, n: u64) {
+        if let Some(pb) = &mut self.0 {
+            pb.set(n);
+        }
+    }
+
+    pub fn message(&mut self, message: &str) {
+        if let Some(pb) = &mut self.0 {
+            pb.message(message);
+        }
+    }
+
+    pub fn finish(&mut self) {
+        if let Some(pb) = &mut self.0 {
+            pb.finish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn progress_modes() {
+        // never draws even on a terminal, auto never on a pipe or CI
+        assert!(!draws(ProgressMode::Never, true, false));
+        assert!(!draws(ProgressMode::Auto, false, false));
+        assert!(!draws(ProgressMode::Auto, true, true));
+        assert!(draws(ProgressMode::Auto, true, false));
+        assert!(draws(ProgressMode::Always, false, true));
+
+        assert_eq!(
+            "never".parse::<ProgressMode>().unwrap(),
+            ProgressMode::Never
+        );
+        assert!("sometimes".parse::<ProgressMode>().is_err());
+    }
+}
@@ -0,0 +1,376 @@
+//! retries of filesystem calls which fail transiently on network mounts, e.g. ESTALE on NFS. only
+//! the calls finishing a push or a get, and opens of `archive`, are retried. errors which would
+//! not go away, e.g. ENOSPC or EPERM, are returned at once.
+
+use std::cell::Cell;
+use std::fs::File;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
+use tempfile::{NamedTempFile, PersistError};
+
+use super::*;
+
+const ATTEMPTS_KEY: &str = "fs_retry_attempts";
+const BACKOFF_MS_KEY: &str = "fs_retry_backoff_ms";
+
+const DEFAULT_ATTEMPTS: u32 = 5;
+const DEFAULT_BACKOFF_MS: u64 = 100;
+
+static ATTEMPTS: AtomicU32 = AtomicU32::new(DEFAULT_ATTEMPTS);
+static BACKOFF_MS: AtomicU64 = AtomicU64::new(DEFAULT_BACKOFF_MS);
+
+/// how retried calls are retried. the wait before the n-th retry is `backoff * n`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// calls made at most, including the first one
+    pub attempts: u32,
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: DEFAULT_ATTEMPTS,
+            backoff: Duration::from_millis(DEFAULT_BACKOFF_MS),
+        }
+    }
+}
+
+thread_local! {
+    /// the policy of a `Store` on this thread, see `with_policy`
+    static STORE_POLICY: Cell<Option<RetryPolicy>> = const { Cell::new(None) };
+}
+
+/// runs `f` with `policy` on this thread, instead of the one of `load_retry_policy`. a `Store`
+/// uses its own policy this way, without changing the one of the process.
+pub fn with_policy<T>(policy: RetryPolicy, f: impl FnOnce() -> T) -> T {
+    let prev = STORE_POLICY.with(|store_policy| store_policy.replace(Some(policy)));
+    let res = f();
+    STORE_POLICY.with(|store_policy| store_policy.set(prev));
+    res
+}
+
+fn policy() -> RetryPolicy {
+    if let Some(policy) = STORE_POLICY.with(Cell::get) {
+        return policy;
+    }
+    RetryPolicy {
+        attempts: ATTEMPTS.load(Ordering::SeqCst),
+        backoff: Duration::from_millis(BACKOFF_MS.load(Ordering::SeqCst)),
+    }
+}
+
+/// the policy stored in the settings of the store, the default one if it is not set
+pub fn retry_policy(conn: &mut db::Conn) -> Result<RetryPolicy> {
+    let mut policy = RetryPolicy::default();
+    if let Some(attempts) = db::get_setting(conn, ATTEMPTS_KEY)? {
+        policy.attempts = attempts.parse()?;
+    }
+    if let Some(backoff_ms) = db::get_setting(conn, BACKOFF_MS_KEY)? {
+        policy.backoff = Duration::from_millis(backoff_ms.parse()?);
+    }
+    Ok(policy)
+}
+
+pub fn set_retry_policy(conn: &mut db::Conn, policy: RetryPolicy) -> Result<()> {
+    if policy.attempts == 0 {
+        return Err(crate::format_err!("attempts should be positive"));
+    }
+    db::set_setting(conn, ATTEMPTS_KEY, &policy.attempts.to_string())?;
+    let backoff_ms = policy.backoff.as_millis().to_string();
+    db::set_setting(conn, BACKOFF_MS_KEY, &backoff_ms)?;
+    Ok(())
+}
+
+/// uses the policy of the store for following calls of this process, see `open_store`
+pub fn load_retry_policy(conn: &mut db::Conn) -> Result<()> {
+    let policy = retry_policy(conn)?;
+    ATTEMPTS.store(policy.attempts, Ordering::SeqCst);
+    BACKOFF_MS.store(policy.backoff.as_millis() as u64, Ordering::SeqCst);
+    Ok(())
+}
+
+/// ESTALE, EINTR and EAGAIN
+fn is_transient(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    {
+        if let Some(code) = e.raw_os_error() {
+            return code == libc::ESTALE || code == libc::EINTR || code == libc::EAGAIN;
+        }
+    }
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+    )
+}
+
+/// the retried calls, so tests can inject failures. the provided methods make the real calls.
+pub trait Fs {
+    fn persist(
+        &self,
+        tmpfile: NamedTempFile,
+        dst: &Path,
+        noclobber: bool,
+    ) -> std::result::Result<File, PersistError> {
+        if noclobber {
+            tmpfile.persist_noclobber(dst)
+        } else {
+            tmpfile.persist(dst)
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<File> {
+        File::open(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<std::fs::Metadata> {
+        std::fs::metadata(path)
+    }
+}
+
+pub struct RealFs;
+
+impl Fs for RealFs {}
+
+#[cfg(test)]
+thread_local! {
+    /// replaces `RealFs` on this thread in tests
+    static TEST_FS: std::cell::RefCell<Option<Box<dyn Fs>>> = const { std::cell::RefCell::new(None) };
+}
+
+/// runs `f` with `fs` replacing `RealFs` on this thread
+#[cfg(test)]
+pub fn with_test_fs<T>(fs: Box<dyn Fs>, f: impl FnOnce() -> T) -> T {
+    TEST_FS.with(|test_fs| *test_fs.borrow_mut() = Some(fs));
+    let res = f();
+    TEST_FS.with(|test_fs| *test_fs.borrow_mut() = None);
+    res
+}
+
+fn with_fs<T>(f: impl FnOnce(&dyn Fs) -> T) -> T {
+    #[cfg(test)]
+    {
+        TEST_FS.with(|fs| match &*fs.borrow() {
+            Some(fs) => f(fs.as_ref()),
+            None => f(&RealFs),
+        })
+    }
+    #[cfg(not(test))]
+    {
+        f(&RealFs)
+    }
+}
+
+fn backoff(policy: RetryPolicy, attempt: u32, what: &str, path: &Path, e: &io::Error) {
+    warn!(
+        "retry: {} {:?} failed, attempt {}/{}: {}",
+        what, path, attempt, policy.attempts, e
+    );
+    std::thread::sleep(policy.backoff * attempt);
+}
+
+fn retry_with<T, F>(policy: RetryPolicy, what: &str, path: &Path, mut f: F) -> io::Result<T>
+where
+    F: FnMut(&dyn Fs) -> io::Result<T>,
+{
+    let mut attempt = 1;
+    loop {
+        match with_fs(&mut f) {
+            Err(e) if attempt < policy.attempts && is_transient(&e) => {
+                backoff(policy, attempt, what, path, &e);
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+fn persist_with(
+    policy: RetryPolicy,
+    mut tmpfile: NamedTempFile,
+    dst: &Path,
+    noclobber: bool,
+) -> std::result::Result<File, PersistError> {
+    let mut attempt = 1;
+    loop {
+        match with_fs(|fs| fs.persist(tmpfile, dst, noclobber)) {
+            Err(e) if attempt < policy.attempts && is_transient(&e.error) => {
+                backoff(policy, attempt, "persist", dst, &e.error);
+                attempt += 1;
+                tmpfile = e.file;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// `NamedTempFile::persist`, or `persist_noclobber` with `noclobber`
+pub fn persist<P: AsRef<Path>>(
+    tmpfile: NamedTempFile,
+    dst: P,
+    noclobber: bool,
+) -> std::result::Result<File, PersistError> {
+    persist_with(policy(), tmpfile, dst.as_ref(), noclobber)
+}
+
+pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<()> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    retry_with(policy(), "rename", from, |fs| fs.rename(from, to))
+}
+
+pub fn open<P: AsRef<Path>>(path: P) -> io::Result<File> {
+    let path = path.as_ref();
+    retry_with(policy(), "open", path, |fs| fs.open(path))
+}
+
+pub fn metadata<P: AsRef<Path>>(path: P) -> io::Result<std::fs::Metadata> {
+    let path = path.as_ref();
+    retry_with(policy(), "stat", path, |fs| fs.metadata(path))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+    use std::sync::{atomic::AtomicUsize, Arc};
+
+    /// fails the first `failures` calls with `errno`
+    struct FlakyFs {
+        failures: usize,
+        errno: i32,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl FlakyFs {
+        fn fail(&self) -> Option<io::Error> {
+            let call = self.calls.fetch_add(1, Ordering::SeqCst);
+            if call < self.failures {
+                Some(io::Error::from_raw_os_error(self.errno))
+            } else {
+                None
+            }
+        }
+    }
+
+    impl Fs for FlakyFs {
+        fn persist(
+            &self,
+            tmpfile: NamedTempFile,
+            dst: &Path,
+            noclobber: bool,
+        ) -> std::result::Result<File, PersistError> {
+            match self.fail() {
+                Some(error) => Err(PersistError {
+                    error,
+                    file: tmpfile,
+                }),
+                None => RealFs.persist(tmpfile, dst, noclobber),
+            }
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            match self.fail() {
+                Some(e) => Err(e),
+                None => RealFs.rename(from, to),
+            }
+        }
+
+        fn open(&self, path: &Path) -> io::Result<File> {
+            match self.fail() {
+                Some(e) => Err(e),
+                None => RealFs.open(path),
+            }
+        }
+    }
+
+    fn with_flaky_fs<T>(failures: usize, errno: i32, f: impl FnOnce() -> T) -> (T, usize) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let fs = FlakyFs {
+            failures,
+            errno,
+            calls: calls.clone(),
+        };
+        let res = with_test_fs(Box::new(fs), f);
+        (res, calls.load(Ordering::SeqCst))
+    }
+
+    #[test]
+    fn transient_errors() {
+        with_store(|conn, dir| {
+            let policy = RetryPolicy {
+                attempts: 3,
+                backoff: Duration::from_millis(1),
+            };
+            set_retry_policy(conn, policy).unwrap();
+            assert_eq!(retry_policy(conn).unwrap(), policy);
+
+            let src = write_file(dir, "app-1.tar", b"hello, world");
+            let tmpfile = || {
+                let mut tmpfile = NamedTempFile::new_in(tmpdir()).unwrap();
+                io::copy(&mut File::open(&src).unwrap(), &mut tmpfile).unwrap();
+                tmpfile
+            };
+            let dst = dir.join("object");
+
+            // eventual success
+            let (res, calls) = with_flaky_fs(2, libc::ESTALE, || {
+                persist_with(policy, tmpfile(), &dst, false)
+            });
+            res.unwrap();
+            assert_eq!(calls, 3);
+            assert_eq!(std::fs::read(&dst).unwrap(), b"hello, world");
+
+            // bounded retries
+            let (res, calls) = with_flaky_fs(10, libc::EINTR, || {
+                retry_with(policy, "open", &dst, |fs| fs.open(&dst))
+            });
+            assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::EINTR));
+            assert_eq!(calls, 3);
+
+            // not retried
+            let (res, calls) = with_flaky_fs(10, libc::ENOSPC, || {
+                persist_with(policy, tmpfile(), &dst, false)
+            });
+            assert_eq!(res.unwrap_err().error.raw_os_error(), Some(libc::ENOSPC));
+            assert_eq!(calls, 1);
+
+            // store_object goes through the retried persist
+            let (res, calls) = with_flaky_fs(1, libc::EAGAIN, || {
+                store_object(tmpfile(), dir.join("objects/ab/cdef"))
+            });
+            res.unwrap();
+            assert_eq!(calls, 2);
+        });
+    }
+
+    #[test]
+    fn store_policy() {
+        with_store(|conn, dir| {
+            let policy = RetryPolicy {
+                attempts: 2,
+                backoff: Duration::from_millis(1),
+            };
+            set_retry_policy(conn, policy).unwrap();
+            let global = super::policy();
+
+            // opening a store leaves the policy of the process alone
+            let mut store = Store::open_readonly(dir).unwrap();
+            assert_eq!(super::policy(), global);
+            assert_eq!(with_policy(policy, super::policy), policy);
+            assert_eq!(super::policy(), global);
+
+            // reads of the store retry as its policy says
+            let src = write_file(dir, "app-1.tar", b"hello, world");
+            push(conn, &src, FileType::Plain).unwrap();
+            let out = dir.join("out.tar");
+            let (res, calls) = with_flaky_fs(10, libc::ESTALE, || store.read("app-1.tar", &out));
+            // the output is persisted twice, the second time from a copy next to it
+            assert!(res.is_err());
+            assert_eq!(calls, 2 * policy.attempts as usize);
+        });
+    }
+}
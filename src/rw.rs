@@ -11,7 +11,6 @@ pub struct WriteMetadata {
     size: u64,
     time_created: time::OffsetDateTime,
 
-    // hash: sha1::Sha1,
     hash0: SseHash,
 }
 
@@ -25,6 +24,10 @@ fn digest_hex(bytes: [u64; 4]) -> String {
     s
 }
 
+/// id of the content hash scheme below, recorded in a store so a build hashing differently
+/// refuses to open it. change it along with the hash or its key.
+pub const HASH_ALGO: &str = "highway-256-1234";
+
 impl WriteMetadata {
     pub fn new() -> Self {
         // TODO
@@ -32,7 +35,6 @@ impl WriteMetadata {
         Self {
             size: 0,
             time_created: time::OffsetDateTime::now_utc(),
-            // hash: sha1::Sha1::new(),
             hash0: SseHash::new(key).unwrap(),
         }
     }
@@ -59,6 +61,10 @@ impl WriteMetadata {
     pub fn len(&self) -> u64 {
         self.size
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
 }
 
 pub struct HashRW<W> {
@@ -85,7 +91,6 @@ impl<W: io::Read> io::Read for HashRW<W> {
         match self.w.read(buf) {
             Ok(n) => {
                 self.meta.size += n as u64;
-                // self.meta.hash.update(&buf[..n]);
                 self.meta.hash0.append(&buf[..n]);
                 Ok(n)
             }
@@ -100,7 +105,6 @@ impl<W: io::Write> io::Write for HashRW<W> {
         match self.w.write(buf) {
             Ok(n) => {
                 self.meta.size += n as u64;
-                // self.meta.hash.update(&buf[..n]);
                 self.meta.hash0.append(&buf[..n]);
                 Ok(n)
             }
@@ -133,7 +137,6 @@ where
         match ready!(w.poll_write(ctx, buf)) {
             Ok(n) => {
                 s.meta.size += n as u64;
-                // s.meta.hash.update(&buf[..n]);
                 s.meta.hash0.append(&buf[..n]);
                 Poll::Ready(Ok(n))
             }
@@ -167,9 +170,13 @@ where
 
         let mut s = self.as_mut();
         let w = Pin::new(&mut s.w);
+        // buf may be partially filled by the caller, hash newly read bytes only
+        let filled = buf.filled().len();
         match ready!(w.poll_read(ctx, buf)) {
             Ok(()) => {
-                s.meta.hash0.append(buf.filled());
+                let read = &buf.filled()[filled..];
+                s.meta.size += read.len() as u64;
+                s.meta.hash0.append(read);
                 Poll::Ready(Ok(()))
             }
             Err(e) => Poll::Ready(Err(e)),
@@ -178,19 +185,37 @@ where
 }
 
 use std::sync::{
-    atomic::{AtomicUsize, Ordering},
+    atomic::{AtomicBool, AtomicUsize, Ordering},
     Arc,
 };
 
 pub struct RaceWrite<W> {
     race: Arc<AtomicUsize>,
+    cancel: Option<Arc<AtomicBool>>,
     size: usize,
     w: W,
 }
 
 impl<W> RaceWrite<W> {
     pub fn new(w: W, race: Arc<AtomicUsize>) -> Self {
-        Self { race, size: 0, w }
+        Self {
+            race,
+            cancel: None,
+            size: 0,
+            w,
+        }
+    }
+
+    /// fails writes once `cancel` is set, like a lost race
+    pub fn cancel_on(mut self, cancel: Option<Arc<AtomicBool>>) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    fn cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::SeqCst))
     }
 
     fn update_race(&mut self) {
@@ -223,6 +248,9 @@ where
     W: std::io::Write,
 {
     fn write(&mut self, buf: &[u8]) -> std::result::Result<usize, std::io::Error> {
+        if self.cancelled() {
+            return Err(io::Error::other("cancelled"));
+        }
         let race_size = self.race.load(Ordering::SeqCst);
         if race_size > 0 && race_size < self.size + buf.len() {
             return Err(io::Error::new(io::ErrorKind::Other, "race"));
@@ -252,6 +280,9 @@ where
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
         let mut s = self.as_mut();
+        if s.cancelled() {
+            return Poll::Ready(Err(io::Error::other("cancelled")));
+        }
         let w = Pin::new(&mut s.w);
         match ready!(w.poll_write(ctx, buf)) {
             Ok(n) => {
@@ -443,6 +474,39 @@ mod test {
         );
     }
 
+    #[test]
+    fn hash_rw_async() {
+        // std::io and tokio::io traits share method names
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let body = (0..100_000u32).map(|v| v as u8).collect::<Vec<_>>();
+
+        let mut sync_rw = HashRW::new(Vec::new());
+        Write::write_all(&mut sync_rw, &body).expect("failed to write");
+        let sync_meta = sync_rw.meta();
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let (write_meta, read_meta) = rt.block_on(async {
+            let mut w = HashRW::new(Vec::new());
+            AsyncWriteExt::write_all(&mut w, &body)
+                .await
+                .expect("failed to write");
+
+            let mut r = HashRW::new(&body[..]);
+            let mut dst = Vec::new();
+            AsyncReadExt::read_to_end(&mut r, &mut dst)
+                .await
+                .expect("failed to read");
+
+            (w.meta(), r.meta())
+        });
+
+        assert_eq!(sync_meta.digest(), write_meta.digest());
+        assert_eq!(sync_meta.digest(), read_meta.digest());
+        assert_eq!(sync_meta.len(), write_meta.len());
+        assert_eq!(sync_meta.len(), read_meta.len());
+    }
+
     #[test]
     fn race() {
         use std::io::Write;
@@ -0,0 +1,456 @@
+//! minhash sketches of contents, to estimate how similar an input is to a root blob without
+//! encoding a delta. a sketch is the bottom `SKETCH_SIZE` hashes of the content-defined chunks of
+//! a content, so an insertion changes only the chunks around it.
+
+use super::*;
+
+/// hashes kept in a sketch
+pub const SKETCH_SIZE: usize = 128;
+/// delta candidates estimated less similar than this are skipped on push
+pub const PREFILTER_THRESHOLD: f64 = 0.05;
+/// the prefilter keeps this many candidates, the most similar ones, even below the threshold
+pub const PREFILTER_MIN_CANDIDATES: usize = 2;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Sketch(Vec<u64>);
+
+/// FNV-1a, fixed so sketches cached in the store stay comparable across builds
+fn chunk_hash(data: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+impl Sketch {
+    pub fn from_bytes(data: &[u8]) -> Self {
+        let mut hashes = Vec::new();
+        let mut offset = 0;
+        for len in chunk::chunk_lens(data) {
+            hashes.push(chunk_hash(&data[offset..offset + len]));
+            offset += len;
+        }
+        hashes.sort_unstable();
+        hashes.dedup();
+        hashes.truncate(SKETCH_SIZE);
+        Sketch(hashes)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = std::fs::File::open(path)?;
+        if file.metadata()?.len() == 0 {
+            return Ok(Sketch::default());
+        }
+        let mmap = unsafe { memmap::Mmap::map(&file)? };
+        Ok(Self::from_bytes(&mmap))
+    }
+
+    /// the sketch as stored in `root_sketches`, little-endian hashes
+    pub fn to_le_bytes(&self) -> Vec<u8> {
+        self.0.iter().flat_map(|hash| hash.to_le_bytes()).collect()
+    }
+
+    pub fn from_le_bytes(bytes: &[u8]) -> Self {
+        let hashes = bytes
+            .chunks_exact(8)
+            .map(|b| u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+            .collect();
+        Sketch(hashes)
+    }
+
+    /// estimated jaccard similarity of the chunks of two contents: the fraction of the smallest
+    /// hashes of both sketches which are in both
+    pub fn similarity(&self, other: &Sketch) -> f64 {
+        if self.0.is_empty() && other.0.is_empty() {
+            return 1.0;
+        }
+        let (mut i, mut j) = (0, 0);
+        let (mut union, mut shared) = (0, 0);
+        while union < SKETCH_SIZE && (i < self.0.len() || j < other.0.len()) {
+            match (self.0.get(i), other.0.get(j)) {
+                (Some(a), Some(b)) if a == b => {
+                    shared += 1;
+                    i += 1;
+                    j += 1;
+                }
+                (Some(a), Some(b)) if a < b => i += 1,
+                (Some(_), None) => i += 1,
+                _ => j += 1,
+            }
+            union += 1;
+        }
+        shared as f64 / union as f64
+    }
+}
+
+/// the sketch of a root blob, from `root_sketches` or computed from its object and cached
+pub fn root_sketch(conn: &mut db::Conn, root: &Blob) -> Result<Sketch> {
+    if let Some(bytes) = db::sketch(conn, &root.content_hash)? {
+        return Ok(Sketch::from_le_bytes(&bytes));
+    }
+    let sketch = Sketch::from_file(filepath(&root.content_hash))?;
+    db::set_sketch(conn, &root.content_hash, &sketch.to_le_bytes())?;
+    Ok(sketch)
+}
+
+/// predicts store_size/content_size of a delta of `input` against `root` without encoding it,
+/// lower is better. push tries the root with the lowest prediction first.
+pub trait SimilarityEstimator: std::fmt::Debug + Send + Sync {
+    fn predicted_ratio(&self, conn: &mut db::Conn, input: &Blob, root: &Blob) -> Result<f64>;
+}
+
+/// predicts from content sizes only, see `size_similarity`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizeRatioEstimator;
+
+impl SimilarityEstimator for SizeRatioEstimator {
+    fn predicted_ratio(&self, _conn: &mut db::Conn, input: &Blob, root: &Blob) -> Result<f64> {
+        Ok(1.0 - size_similarity(input, root))
+    }
+}
+
+/// predicts from the chunks not shared with the root, using cached sketches. the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MinhashEstimator;
+
+impl SimilarityEstimator for MinhashEstimator {
+    fn predicted_ratio(&self, conn: &mut db::Conn, input: &Blob, root: &Blob) -> Result<f64> {
+        let input = root_sketch(conn, input)?;
+        let root = root_sketch(conn, root)?;
+        Ok(1.0 - input.similarity(&root))
+    }
+}
+
+/// sorts candidates by predicted ratio, the most promising first. candidates predicted alike, or
+/// failing the estimate, keep their order.
+pub fn order_candidates(
+    conn: &mut db::Conn,
+    estimator: &dyn SimilarityEstimator,
+    input: &Blob,
+    candidates: Vec<Blob>,
+) -> Vec<Blob> {
+    let mut predicted = Vec::with_capacity(candidates.len());
+    for root in candidates {
+        let ratio = match estimator.predicted_ratio(conn, input, &root) {
+            Ok(ratio) => ratio,
+            Err(e) => {
+                warn!("push: no estimate for root={}: {}", root.store_hash, e);
+                1.0
+            }
+        };
+        debug!(
+            "push: root={} predicted_ratio={:.03}",
+            root.store_hash, ratio
+        );
+        predicted.push((root, ratio));
+    }
+    predicted.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+    predicted.into_iter().map(|(root, _)| root).collect()
+}
+
+/// a delta candidate as seen by the prefilter, see `PushOptions::record_trials`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Trial {
+    pub root_hash: String,
+    /// None if the sketch of the root could not be read, such a candidate is never skipped
+    pub similarity: Option<f64>,
+    /// store_size predicted from the similarity: the chunks not shared with the root
+    pub estimated_size: Option<u64>,
+    /// store_size of the encoded delta. skipped candidates are encoded only to record this.
+    pub actual_size: Option<u64>,
+    pub skipped: bool,
+}
+
+/// splits `candidates`, best first, into the ones to encode and the skipped ones. candidates
+/// below `threshold` are skipped, unless fewer than `min_candidates` would be left.
+pub fn prefilter(
+    candidates: Vec<(Blob, Option<f64>)>,
+    threshold: f64,
+    min_candidates: usize,
+) -> (Vec<Blob>, Vec<(Blob, Option<f64>)>) {
+    let passing = candidates
+        .iter()
+        .filter(|(_, similarity)| similarity.is_none_or(|s| s >= threshold))
+        .count();
+    // the most similar ones of the failing candidates make up `min_candidates`
+    let mut failing = candidates
+        .iter()
+        .filter_map(|(root, similarity)| match similarity {
+            Some(s) if *s < threshold => Some((root.id, *s)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    failing.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    let rescued = failing
+        .into_iter()
+        .take(min_candidates.saturating_sub(passing))
+        .map(|(id, _)| id)
+        .collect::<Vec<_>>();
+
+    let mut kept = Vec::new();
+    let mut skipped = Vec::new();
+    for (root, similarity) in candidates {
+        let passes = similarity.is_none_or(|s| s >= threshold);
+        if passes || rescued.contains(&root.id) {
+            kept.push(root);
+        } else {
+            skipped.push((root, similarity));
+        }
+    }
+    (kept, skipped)
+}
+
+/// scores candidates against the input, keeping their order. the sketch of the input is cached
+/// too, as the input becomes a root.
+pub fn score_candidates(
+    conn: &mut db::Conn,
+    input_blob: &Blob,
+    candidates: Vec<Blob>,
+) -> Result<Vec<(Blob, Option<f64>)>> {
+    let input = root_sketch(conn, input_blob)?;
+    let mut scored = Vec::with_capacity(candidates.len());
+    for root in candidates {
+        let similarity = match root_sketch(conn, &root) {
+            Ok(sketch) => Some(input.similarity(&sketch)),
+            Err(e) => {
+                warn!("prefilter: no sketch of root={}: {}", root.store_hash, e);
+                None
+            }
+        };
+        scored.push((root, similarity));
+    }
+    Ok(scored)
+}
+
+/// trials of the `scored` candidates of a push which stored `chosen`. `encoded` are the deltas
+/// encoded on push. candidates skipped by the prefilter are encoded here to measure them, the
+/// ones cut short by the race are not.
+pub fn trials(
+    input_blob: &Blob,
+    chosen: &Blob,
+    scored: &[(Blob, Option<f64>)],
+    skipped: &[(Blob, Option<f64>)],
+    encoded: &[Blob],
+) -> Result<Vec<Trial>> {
+    let input_path = filepath(&input_blob.content_hash);
+    let mut trials = Vec::with_capacity(scored.len());
+    for (root, similarity) in scored {
+        let is_skipped = skipped.iter().any(|(s, _)| s.is_same_content(root));
+        let actual_size = if is_skipped {
+            let size = estimate_delta_size(root, &input_path)?;
+            if size < chosen.store_size {
+                warn!(
+                    "prefilter: skipped root={} similarity={:?} would have won, store_size={} chosen={}",
+                    root.store_hash, similarity, size, chosen.store_size
+                );
+            }
+            Some(size)
+        } else {
+            encoded
+                .iter()
+                .find(|blob| blob.parent_hash.as_deref() == Some(root.content_hash.as_str()))
+                .map(|blob| blob.store_size)
+        };
+        trials.push(Trial {
+            root_hash: root.store_hash.clone(),
+            similarity: *similarity,
+            estimated_size: similarity.map(|s| estimated_size(input_blob.content_size, s)),
+            actual_size,
+            skipped: is_skipped,
+        });
+    }
+    Ok(trials)
+}
+
+/// predicted store_size of a delta of `content_size` bytes
+pub fn estimated_size(content_size: u64, similarity: f64) -> u64 {
+    (content_size as f64 * (1.0 - similarity)) as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+
+    #[test]
+    fn sketch_similarity() {
+        let mut rng = Rng::new(7);
+        let base = (0..4 << 20)
+            .map(|_| rng.next_u64() as u8)
+            .collect::<Vec<_>>();
+        let other = (0..4 << 20)
+            .map(|_| rng.next_u64() as u8)
+            .collect::<Vec<_>>();
+
+        let sketch = Sketch::from_bytes(&base);
+        assert_eq!(sketch.0.len(), SKETCH_SIZE);
+        assert_eq!(sketch.similarity(&sketch), 1.0);
+        assert_eq!(Sketch::from_le_bytes(&sketch.to_le_bytes()), sketch);
+
+        // an insertion changes only the chunks around it
+        let mut edited = base.clone();
+        edited.splice(1000..1000, b"inserted".iter().copied());
+        assert!(sketch.similarity(&Sketch::from_bytes(&edited)) > 0.9);
+
+        // half of the chunks are shared
+        let mut half = base[..base.len() / 2].to_vec();
+        half.extend_from_slice(&other[..other.len() / 2]);
+        let s = sketch.similarity(&Sketch::from_bytes(&half));
+        assert!(s > 0.15 && s < 0.6, "{}", s);
+
+        assert_eq!(sketch.similarity(&Sketch::from_bytes(&other)), 0.0);
+        assert_eq!(Sketch::default().similarity(&Sketch::default()), 1.0);
+        assert_eq!(sketch.similarity(&Sketch::default()), 0.0);
+    }
+
+    #[test]
+    fn prefilter_skips() {
+        let ids = |blobs: &[Blob]| blobs.iter().map(|b| b.id).collect::<Vec<_>>();
+        let scored = vec![
+            (blob(1, "aa", None, 0), Some(0.01)),
+            (blob(2, "bb", None, 0), Some(0.5)),
+            (blob(3, "cc", None, 0), Some(0.03)),
+            (blob(4, "dd", None, 0), None),
+        ];
+
+        let (kept, skipped) = prefilter(scored.clone(), PREFILTER_THRESHOLD, 2);
+        assert_eq!(ids(&kept), vec![2, 4]);
+        assert_eq!(skipped.len(), 2);
+
+        // the most similar failing candidate is kept to leave two
+        let (kept, _) = prefilter(scored[..3].to_vec(), PREFILTER_THRESHOLD, 2);
+        assert_eq!(ids(&kept), vec![2, 3]);
+        let (kept, skipped) = prefilter(scored[..1].to_vec(), PREFILTER_THRESHOLD, 2);
+        assert_eq!(ids(&kept), vec![1]);
+        assert!(skipped.is_empty());
+
+        assert_eq!(estimated_size(1000, 0.25), 750);
+    }
+
+    #[test]
+    fn push_trials() {
+        with_store(|conn, dir| {
+            let mut rng = Rng::new(11);
+            let mut random =
+                |len: usize| (0..len).map(|_| rng.next_u64() as u8).collect::<Vec<_>>();
+            let base = random(256 * 1024);
+            let mut edited = base.clone();
+            edited.splice(1000..1000, b"inserted".iter().copied());
+            let bodies = [base, random(256 * 1024), random(256 * 1024), edited];
+
+            let mut reports = Vec::new();
+            for (i, body) in bodies.iter().enumerate() {
+                let input = write_file(dir, &format!("app-{}.tar", i), body);
+                let opts = PushOptions {
+                    record_trials: true,
+                    ..Default::default()
+                };
+                reports.push(push_with_options(conn, &input, FileType::Plain, &opts).unwrap());
+            }
+
+            // app-3 is compared to app-0, app-1 and app-2, and one of the unrelated roots is
+            // skipped but still measured
+            let trials = reports[3].trials.as_ref().unwrap();
+            assert_eq!(trials.len(), 3);
+            let skipped = trials.iter().filter(|t| t.skipped).collect::<Vec<_>>();
+            assert_eq!(skipped.len(), 1);
+            assert_eq!(skipped[0].similarity, Some(0.0));
+            assert!(skipped[0].actual_size.is_some());
+            let similar = trials
+                .iter()
+                .max_by(|a, b| a.similarity.partial_cmp(&b.similarity).unwrap())
+                .unwrap();
+            assert!(similar.similarity.unwrap() > 0.5);
+            assert!(similar.estimated_size.unwrap() < bodies[3].len() as u64 / 2);
+
+            // sketches are kept for the current roots only
+            for root in db::roots(conn).unwrap() {
+                assert!(db::sketch(conn, &root.content_hash).unwrap().is_some());
+            }
+            let root = db::roots(conn).unwrap().remove(1);
+            remove_root(conn, &root).unwrap();
+            let cached = conn
+                .query_row("select count(*) from root_sketches", [], |row| {
+                    row.get::<_, usize>(0)
+                })
+                .unwrap();
+            assert_eq!(cached, db::roots(conn).unwrap().len());
+
+            // nothing is skipped without the prefilter
+            let input = write_file(dir, "app-4.tar", &random(256 * 1024));
+            let opts = PushOptions {
+                record_trials: true,
+                no_prefilter: true,
+                ..Default::default()
+            };
+            let report = push_with_options(conn, &input, FileType::Plain, &opts).unwrap();
+            assert!(report.trials.unwrap().iter().all(|t| !t.skipped));
+        });
+    }
+
+    #[test]
+    fn push_tries_similar_first() {
+        with_store(|conn, dir| {
+            let mut rng = Rng::new(13);
+            let mut random =
+                |len: usize| (0..len).map(|_| rng.next_u64() as u8).collect::<Vec<_>>();
+            let bodies = [random(256 * 1024), random(256 * 1024), random(256 * 1024)];
+            for (i, body) in bodies.iter().enumerate() {
+                let input = write_file(dir, &format!("app-{}.tar", i), body);
+                push(conn, &input, FileType::Plain).unwrap();
+            }
+            let root_hash = |conn: &mut db::Conn, filename: &str| {
+                let roots = db::roots(conn).unwrap();
+                let root = roots.into_iter().find(|r| r.filename == filename).unwrap();
+                root.store_hash
+            };
+            let push_edited = |conn: &mut db::Conn, i: usize, edit: &str, opts: &PushOptions| {
+                let mut edited = bodies[i].clone();
+                edited.splice(1000..1000, edit.bytes());
+                let input = write_file(dir, &format!("app-{}-{}.tar", i, edit), &edited);
+                let report = push_with_options(conn, &input, FileType::Plain, opts).unwrap();
+                report.tried_roots
+            };
+
+            // the edit of app-1 is tried against app-1 first, and its delta ends the search.
+            // deltas are not compressed in tests, so any delta is good enough here.
+            let opts = PushOptions {
+                no_prefilter: true,
+                good_enough_ratio: Some(2.0),
+                ..Default::default()
+            };
+            let tried = push_edited(conn, 1, "inserted", &opts);
+            assert_eq!(tried, vec![root_hash(conn, "app-1.tar")]);
+
+            // otherwise the others are tried after it
+            let opts = PushOptions {
+                no_prefilter: true,
+                good_enough_ratio: Some(0.0),
+                ..Default::default()
+            };
+            let tried = push_edited(conn, 0, "inserted", &opts);
+            assert_eq!(tried.len(), DELTA_CANDIDATES);
+            assert_eq!(tried[0], root_hash(conn, "app-0.tar"));
+
+            // the estimator decides the order
+            let opts = PushOptions {
+                no_prefilter: true,
+                good_enough_ratio: Some(2.0),
+                estimator: Some(Arc::new(FilenameEstimator("app-2.tar"))),
+                ..Default::default()
+            };
+            let tried = push_edited(conn, 0, "appended", &opts);
+            assert_eq!(tried, vec![root_hash(conn, "app-2.tar")]);
+
+            // without a good enough ratio, there is no probe and every candidate is encoded
+            let opts = PushOptions {
+                no_prefilter: true,
+                ..Default::default()
+            };
+            let tried = push_edited(conn, 1, "appended", &opts);
+            assert_eq!(tried.len(), DELTA_CANDIDATES);
+        });
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::HashSet;
+
+use super::*;
+
+/// changes of the blobs table and the object directory since a snapshot
+#[derive(Debug, Default)]
+pub struct SnapshotDiff {
+    pub added: Vec<Blob>,
+    pub removed: Vec<Blob>,
+    /// (snapshot, current) pairs of blobs with the same id
+    pub changed: Vec<(Blob, Blob)>,
+    pub objects_added: Vec<String>,
+    pub objects_removed: Vec<String>,
+}
+
+/// object files which do not match the restored tables. rollback does not touch object files,
+/// missing ones should be restored from a backup.
+#[derive(Debug, Default)]
+pub struct RollbackReport {
+    pub missing: Vec<String>,
+    pub extra: Vec<String>,
+    /// extra objects added after the snapshot and removed by `snapshot_restore`
+    pub removed: Vec<String>,
+}
+
+fn check_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(crate::format_err!(
+            "invalid snapshot name, use [A-Za-z0-9_]: {}",
+            name
+        ));
+    }
+    Ok(())
+}
+
+fn check_exists(conn: &mut db::Conn, name: &str) -> Result<()> {
+    check_name(name)?;
+    if !db::snapshots(conn)?.iter().any(|s| s.name == name) {
+        return Err(crate::format_err!("unknown snapshot: {}", name));
+    }
+    Ok(())
+}
+
+fn sorted_object_hashes() -> Result<Vec<String>> {
+    let mut hashes = object_files()?.into_keys().collect::<Vec<_>>();
+    hashes.sort();
+    Ok(hashes)
+}
+
+pub fn snapshot_create(conn: &mut db::Conn, name: &str) -> Result<()> {
+    check_name(name)?;
+    if !db::snapshot_create(conn, name, &sorted_object_hashes()?)? {
+        return Err(crate::format_err!("snapshot already exists: {}", name));
+    }
+    Ok(())
+}
+
+pub fn snapshot_delete(conn: &mut db::Conn, name: &str) -> Result<()> {
+    check_exists(conn, name)?;
+    db::snapshot_delete(conn, name)?;
+    Ok(())
+}
+
+pub fn snapshot_diff(conn: &mut db::Conn, name: &str) -> Result<SnapshotDiff> {
+    check_exists(conn, name)?;
+
+    let mut diff = SnapshotDiff::default();
+    let mut current = db::all(conn)?
+        .into_iter()
+        .map(|blob| (blob.id, blob))
+        .collect::<HashMap<_, _>>();
+    for blob in db::snapshot_blobs(conn, name)? {
+        match current.remove(&blob.id) {
+            Some(current_blob) if current_blob == blob => (),
+            Some(current_blob) => diff.changed.push((blob, current_blob)),
+            None => diff.removed.push(blob),
+        }
+    }
+    diff.added = current.into_values().collect();
+    diff.added.sort_by_key(|blob| blob.id);
+
+    let objects = db::snapshot_objects(conn, name)?
+        .into_iter()
+        .collect::<HashSet<_>>();
+    let current_objects = sorted_object_hashes()?;
+    diff.objects_removed = objects
+        .iter()
+        .filter(|hash| current_objects.binary_search(hash).is_err())
+        .cloned()
+        .collect();
+    diff.objects_removed.sort();
+    diff.objects_added = current_objects
+        .into_iter()
+        .filter(|hash| !objects.contains(hash))
+        .collect();
+
+    Ok(diff)
+}
+
+pub fn snapshot_rollback(conn: &mut db::Conn, name: &str) -> Result<RollbackReport> {
+    check_exists(conn, name)?;
+    db::snapshot_restore(conn, name)?;
+
+    let mut referenced = db::iter_all(conn)
+        .map(|blob| blob.map(|blob| blob.store_hash))
+        .collect::<rusqlite::Result<HashSet<_>>>()?;
+    // objects of quarantined blobs are kept for inspection
+    referenced.extend(
+        db::quarantined(conn)?
+            .into_iter()
+            .map(|blob| blob.store_hash),
+    );
+    let objects = sorted_object_hashes()?;
+
+    let mut report = RollbackReport {
+        missing: referenced
+            .iter()
+            .filter(|hash| objects.binary_search(hash).is_err())
+            .cloned()
+            .collect(),
+        extra: objects
+            .into_iter()
+            .filter(|hash| !referenced.contains(hash))
+            .collect(),
+        ..Default::default()
+    };
+    report.missing.sort();
+    Ok(report)
+}
+
+/// `snapshot_rollback`, then removes extra objects which did not exist at the snapshot, so the
+/// store is back to the snapshot. extra objects which existed then, e.g. not adopted yet, are kept.
+pub fn snapshot_restore(conn: &mut db::Conn, name: &str) -> Result<RollbackReport> {
+    let mut report = snapshot_rollback(conn, name)?;
+    let objects = db::snapshot_objects(conn, name)?
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+    let mut extra = Vec::new();
+    for hash in std::mem::take(&mut report.extra) {
+        if objects.contains(&hash) {
+            extra.push(hash);
+            continue;
+        }
+        match std::fs::remove_file(filepath(&hash)) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+        report.removed.push(hash);
+    }
+    report.extra = extra;
+    Ok(report)
+}
+
+pub fn snapshot_list(conn: &mut db::Conn) -> Result<()> {
+    for snapshot in db::snapshots(conn)? {
+        println!("{} {}", snapshot.name, snapshot.time_created);
+    }
+    Ok(())
+}
+
+pub fn print_snapshot_diff(conn: &mut db::Conn, name: &str) -> Result<()> {
+    let diff = snapshot_diff(conn, name)?;
+    for blob in &diff.added {
+        println!("+ V{} {} {}", blob.id, blob.filename, blob.store_hash);
+    }
+    for blob in &diff.removed {
+        println!("- V{} {} {}", blob.id, blob.filename, blob.store_hash);
+    }
+    for (old, new) in &diff.changed {
+        println!("~ V{} {} -> {}", old.id, old.to_json(), new.to_json());
+    }
+    for hash in &diff.objects_added {
+        println!("+ object {}", filepath(hash));
+    }
+    for hash in &diff.objects_removed {
+        println!("- object {}", filepath(hash));
+    }
+    Ok(())
+}
+
+/// `snapshot_rollback`, or `snapshot_restore` with `remove_extra`
+pub fn print_snapshot_rollback(conn: &mut db::Conn, name: &str, remove_extra: bool) -> Result<()> {
+    let report = if remove_extra {
+        snapshot_restore(conn, name)?
+    } else {
+        snapshot_rollback(conn, name)?
+    };
+    for hash in &report.missing {
+        println!("missing {}", filepath(hash));
+    }
+    for hash in &report.extra {
+        println!("extra {}", filepath(hash));
+    }
+    for hash in &report.removed {
+        println!("removed {}", filepath(hash));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+
+    #[test]
+    fn snapshot_rollback_restores_tables() {
+        with_store(|conn, dir| {
+            let v1 = write_file(dir, "app-1.tar", b"hello, world");
+            let v2 = write_file(dir, "app-2.tar", b"hello, world!!");
+            push(conn, &v1, FileType::Plain).unwrap();
+            push(conn, &v2, FileType::Plain).unwrap();
+
+            let before = db::all(conn).unwrap();
+            let content_hash = before[0].content_hash.clone();
+            db::note_add(
+                conn,
+                &content_hash,
+                "first",
+                time::OffsetDateTime::now_utc(),
+            )
+            .unwrap();
+            snapshot_create(conn, "before").unwrap();
+            assert!(snapshot_create(conn, "before").is_err());
+            assert!(snapshot_create(conn, "x; drop table blobs").is_err());
+
+            // mutate: remove the full blob of app-2.tar, rename app-1.tar
+            let root = before.iter().find(|b| b.id == 2).unwrap().clone();
+            db::remove(conn, &root).unwrap();
+            std::fs::remove_file(filepath(&root.store_hash)).unwrap();
+            db::rename(conn, "app-1.tar", "renamed.tar").unwrap();
+            db::note_add(
+                conn,
+                &content_hash,
+                "second",
+                time::OffsetDateTime::now_utc(),
+            )
+            .unwrap();
+
+            let diff = snapshot_diff(conn, "before").unwrap();
+            assert!(diff.added.is_empty());
+            assert_eq!(diff.removed, vec![root.clone()]);
+            assert_eq!(diff.changed.len(), 1);
+            assert_eq!(diff.changed[0].1.filename, "renamed.tar");
+            assert_eq!(diff.objects_removed, vec![root.store_hash.clone()]);
+
+            let report = snapshot_rollback(conn, "before").unwrap();
+            assert_eq!(db::all(conn).unwrap(), before);
+            assert_eq!(report.missing, vec![root.store_hash]);
+            assert!(report.extra.is_empty());
+            let notes = db::notes(conn, &content_hash).unwrap();
+            assert_eq!(notes.len(), 1);
+            assert_eq!(notes[0].note, "first");
+
+            // quarantined blobs are restored too, and their objects are not extra
+            let quarantined = before.iter().find(|b| b.id == 1).unwrap().clone();
+            db::quarantine(conn, &quarantined, "test").unwrap();
+            snapshot_create(conn, "quarantined").unwrap();
+            let report = snapshot_rollback(conn, "quarantined").unwrap();
+            assert!(report.extra.is_empty());
+            assert_eq!(db::quarantined(conn).unwrap(), vec![quarantined]);
+            snapshot_delete(conn, "quarantined").unwrap();
+
+            assert_eq!(db::snapshots(conn).unwrap().len(), 1);
+            snapshot_delete(conn, "before").unwrap();
+            assert!(db::snapshots(conn).unwrap().is_empty());
+            assert!(snapshot_diff(conn, "before").is_err());
+        });
+    }
+
+    #[test]
+    fn snapshot_restore_removes_new_objects() {
+        with_store(|conn, dir| {
+            let v1 = write_file(dir, "app-1.tar", b"hello, world");
+            push(conn, &v1, FileType::Plain).unwrap();
+            // an object not adopted yet is kept
+            let unadopted = filepath("abcdef");
+            std::fs::create_dir_all(Path::new(&unadopted).parent().unwrap()).unwrap();
+            std::fs::write(&unadopted, b"unadopted").unwrap();
+            snapshot_create(conn, "before").unwrap();
+            let before = db::all(conn).unwrap();
+
+            let v2 = write_file(dir, "app-2.tar", b"hello, world!!");
+            push(conn, &v2, FileType::Plain).unwrap();
+            let added = snapshot_diff(conn, "before").unwrap().objects_added;
+            assert!(!added.is_empty());
+
+            let report = snapshot_restore(conn, "before").unwrap();
+            assert_eq!(db::all(conn).unwrap(), before);
+            assert!(report.missing.is_empty());
+            assert_eq!(report.extra, vec!["abcdef".to_owned()]);
+            assert_eq!(report.removed, added);
+            for hash in &added {
+                assert!(!Path::new(&filepath(hash)).exists());
+            }
+            assert!(Path::new(&unadopted).exists());
+
+            let out = dir.join("out");
+            get(conn, "app-1.tar", out.to_str().unwrap(), false).unwrap();
+            assert_eq!(std::fs::read(&out).unwrap(), b"hello, world");
+        });
+    }
+}
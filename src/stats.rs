@@ -1,8 +1,10 @@
 use crate::db::Blob;
 use bytesize::ByteSize;
 use log::*;
+use std::collections::HashMap;
 
 pub struct RootBlob<'a> {
+    pub idx: usize,
     pub blob: &'a Blob,
     pub alias: &'a Blob,
     pub score: u64,
@@ -18,6 +20,13 @@ pub struct GraphNode {
     pub alias_indices: Vec<usize>,
 }
 
+/// size of a store on disk, see `Stats::disk_usage`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DiskUsage {
+    pub hydrated_roots: usize,
+    pub size: u64,
+}
+
 #[derive(Default)]
 pub struct Stats {
     root_count: usize,
@@ -35,26 +44,75 @@ pub struct Stats {
 
 impl Stats {
     pub fn from_blobs(blobs: Vec<Blob>) -> Self {
+        Self::from_blobs_with_index(blobs).0
+    }
+
+    /// `from_blobs`, with the index of each content hash. a content may have several blobs, e.g.
+    /// a root and its alias delta, the index of the latest one is returned like `get` decodes.
+    pub fn from_blobs_with_index(blobs: Vec<Blob>) -> (Self, HashMap<String, usize>) {
+        Self::build(blobs, None)
+    }
+
+    /// `from_blobs`, with depths by blob id cached by `rebuild_stats_index`. depths are not
+    /// computed, blobs are only linked to their aliases and parents.
+    pub fn from_blobs_with_depths(blobs: Vec<Blob>, cached: &HashMap<u32, usize>) -> Self {
+        Self::build(blobs, Some(cached)).0
+    }
+
+    fn build(
+        blobs: Vec<Blob>,
+        cached: Option<&HashMap<u32, usize>>,
+    ) -> (Self, HashMap<String, usize>) {
         let mut stats = Stats::default();
         let len = blobs.len();
 
-        for blob in &blobs {
+        let mut by_content = HashMap::<String, Vec<usize>>::new();
+        for (idx, blob) in blobs.iter().enumerate() {
             stats.add_blob(blob);
+            by_content
+                .entry(blob.content_hash.clone())
+                .or_default()
+                .push(idx);
         }
 
         stats.depths = Vec::with_capacity(blobs.len());
         stats.depths.resize_with(blobs.len(), Default::default);
         stats.blobs = blobs;
-
-        for i in 0..len {
-            calculate_depth(i, &stats.blobs, &mut stats.depths);
+        match cached {
+            // depths are known, so deltas are only linked to the shallowest of their parents
+            Some(cached) => {
+                for (node, blob) in stats.depths.iter_mut().zip(&stats.blobs) {
+                    node.depth = cached.get(&blob.id).copied().unwrap_or(0);
+                }
+                for i in 0..len {
+                    let parents = link_blob(i, &stats.blobs, &by_content, &mut stats.depths);
+                    if stats.blobs[i].parent_hash.is_some() {
+                        let (parent_idx, _) = shallowest(&parents, &stats.blobs, &stats.depths);
+                        stats.depths[i].parent_idx = Some(parent_idx);
+                    }
+                }
+            }
+            None => {
+                for i in 0..len {
+                    calculate_depth(i, &stats.blobs, &by_content, &mut stats.depths);
+                }
+            }
+        }
+        // a blob visited by the recursion is visited again, drop links added twice
+        for node in &mut stats.depths {
+            dedup_in_order(&mut node.alias_indices);
+            dedup_in_order(&mut node.children_indices);
         }
 
         for i in 0..len {
             stats.add_child_count(i);
         }
 
-        stats
+        let index = by_content
+            .into_iter()
+            .filter_map(|(hash, indices)| indices.last().map(|idx| (hash, *idx)))
+            .collect();
+        (stats, index)
     }
 
     fn add_child_count(&mut self, idx: usize) {
@@ -72,6 +130,10 @@ impl Stats {
         self.depths[idx].child_count
     }
 
+    pub fn max_depth(&self) -> usize {
+        self.depths.iter().map(|d| d.depth).max().unwrap_or(0)
+    }
+
     fn add_blob(&mut self, blob: &Blob) {
         match &blob.parent_hash {
             None => {
@@ -86,7 +148,7 @@ impl Stats {
         }
     }
 
-    fn root_age(&self, root_idx: usize) -> usize {
+    pub fn root_age(&self, root_idx: usize) -> usize {
         let max_idx = self.blobs.len();
         let last_idx = self
             .children(root_idx, true)
@@ -106,11 +168,7 @@ impl Stats {
             None => u64::max_value(),
             Some(alias_idx) => {
                 let alias = &self.blobs[alias_idx];
-
-                let max_unused_age = 100;
-                let age = (self.root_age(root_idx) as u64).min(max_unused_age);
-
-                return alias.store_size * (max_unused_age - age) / max_unused_age;
+                return score_by_age(alias.store_size, self.root_age(root_idx));
                 /*
                 let len = self.children(root_idx).len();
                 let multiplier = (len as f32).sqrt().ceil() as u64 + 1;
@@ -132,16 +190,17 @@ impl Stats {
     }
 
     /// TODO: for graphviz
+    /// picks the minimum id among root aliases, so names do not depend on the order of blobs.
     pub fn node_name(&self, idx: usize) -> String {
-        let aliases = self.aliases(idx);
-        for blob_idx in aliases {
-            let blob = &self.blobs[blob_idx];
-            if blob.is_root() {
-                return format!("V{}", blob.id);
-            }
-        }
-        let blob = &self.blobs[idx];
-        format!("V{}", blob.id)
+        let root_id = self
+            .aliases(idx)
+            .into_iter()
+            .map(|blob_idx| &self.blobs[blob_idx])
+            .filter(|blob| blob.is_root())
+            .map(|blob| blob.id)
+            .min();
+        let id = root_id.unwrap_or(self.blobs[idx].id);
+        format!("V{}", id)
     }
 
     pub fn children_all(&self, idx: usize) -> Vec<usize> {
@@ -177,6 +236,118 @@ impl Stats {
         children
     }
 
+    /// indices of blobs which no blob is a delta against, neither the blob nor its aliases. these
+    /// are the latest versions, and likely delta parents of the next push.
+    pub fn leaf_blobs(&self) -> Vec<usize> {
+        (0..self.blobs.len())
+            .filter(|idx| {
+                self.depths[*idx].children_indices.is_empty()
+                    && self
+                        .aliases(*idx)
+                        .into_iter()
+                        .all(|alias_idx| self.depths[alias_idx].children_indices.is_empty())
+            })
+            .collect()
+    }
+
+    /// the `n` contents most referenced as delta parents, as (idx, ref_count) by ref_count
+    /// descending. idx is the latest blob of the content, like `get` decodes. every version
+    /// below such a blob decodes through it. only reported by `debug_stats`, `cleanup` does not
+    /// consult it and may remove the root of such a content, which then decodes through its alias.
+    pub fn top_delta_parents(&self, n: usize) -> Vec<(usize, usize)> {
+        let mut ref_counts = HashMap::<&str, usize>::new();
+        for blob in &self.blobs {
+            if let Some(parent_hash) = &blob.parent_hash {
+                *ref_counts.entry(parent_hash).or_default() += 1;
+            }
+        }
+
+        let mut latest = HashMap::<&str, usize>::new();
+        for (idx, blob) in self.blobs.iter().enumerate() {
+            if ref_counts.contains_key(blob.content_hash.as_str()) {
+                latest.insert(&blob.content_hash, idx);
+            }
+        }
+
+        let mut parents = latest
+            .into_iter()
+            .map(|(hash, idx)| (idx, ref_counts[hash]))
+            .collect::<Vec<_>>();
+        parents.sort_by_key(|(idx, ref_count)| (usize::MAX - ref_count, *idx));
+        parents.truncate(n);
+        parents
+    }
+
+    /// roots which can not be dehydrated, as no delta decodes to them. usually only the genesis.
+    pub fn base_roots(&self) -> Vec<usize> {
+        (0..self.blobs.len())
+            .filter(|idx| self.blobs[*idx].is_root() && self.aliases(*idx).is_empty())
+            .collect()
+    }
+
+    /// store size with all roots dehydrated, except `base_roots`
+    pub fn min_dehydrated_size(&self) -> u64 {
+        let base_size = self
+            .base_roots()
+            .into_iter()
+            .map(|idx| self.blobs[idx].store_size)
+            .sum::<u64>();
+        base_size + self.non_root_store_size
+    }
+
+    /// store size given the size of each root file on disk, None for a dehydrated root
+    pub fn disk_usage<F>(&self, root_file_size: F) -> DiskUsage
+    where
+        F: Fn(&Blob) -> Option<u64>,
+    {
+        let mut usage = DiskUsage {
+            hydrated_roots: 0,
+            size: self.non_root_store_size,
+        };
+        for blob in self.blobs.iter().filter(|blob| blob.is_root()) {
+            if let Some(size) = root_file_size(blob) {
+                usage.hydrated_roots += 1;
+                usage.size += size;
+            }
+        }
+        usage
+    }
+
+    /// delta chains as blob indices from a root to a leaf. children with more descendants are
+    /// followed first, so the first chain of a root is its longest-lived line; a blob with
+    /// multiple children appears in a chain per child.
+    pub fn chains(&self) -> Vec<Vec<usize>> {
+        let mut chains = Vec::new();
+        for (idx, blob) in self.blobs.iter().enumerate() {
+            if blob.is_root() {
+                self.chains0(&mut vec![idx], &mut chains);
+            }
+        }
+        chains
+    }
+
+    fn chains0(&self, path: &mut Vec<usize>, chains: &mut Vec<Vec<usize>>) {
+        let idx = *path.last().expect("empty path");
+        let mut children = self.depths[idx]
+            .children_indices
+            .iter()
+            .copied()
+            .filter(|child_idx| !path.contains(child_idx))
+            .collect::<Vec<_>>();
+        children.sort_by_key(|child_idx| (usize::MAX - self.child_count(*child_idx), *child_idx));
+        children.dedup();
+
+        if children.is_empty() {
+            chains.push(path.clone());
+            return;
+        }
+        for child_idx in children {
+            path.push(child_idx);
+            self.chains0(path, chains);
+            path.pop();
+        }
+    }
+
     //TODO: name
     pub fn root_candidates(&self) -> Vec<RootBlob> {
         let mut root_candidates = Vec::new();
@@ -190,6 +361,7 @@ impl Stats {
                 let alias = &self.blobs[alias_idx];
                 let score = self.root_score(root_idx);
                 root_candidates.push(RootBlob {
+                    idx: root_idx,
                     blob: root_blob,
                     alias,
                     score,
@@ -200,6 +372,61 @@ impl Stats {
         root_candidates
     }
 
+    /// mean_children / max_children across all root blobs. near 1.0 means children are evenly
+    /// distributed, near 0.0 means a single root dominates.
+    pub fn balance_factor(&self) -> f64 {
+        let counts = self
+            .blobs
+            .iter()
+            .enumerate()
+            .filter(|(_idx, blob)| blob.is_root())
+            .map(|(idx, _blob)| self.children(idx, true).len())
+            .collect::<Vec<_>>();
+
+        let max_children = counts.iter().copied().max().unwrap_or(0);
+        if max_children == 0 {
+            return 1.0;
+        }
+
+        let mean_children = counts.iter().sum::<usize>() as f64 / counts.len() as f64;
+        mean_children / max_children as f64
+    }
+
+    /// heuristic value for `max_root_blobs`. it picks enough roots to bring the worst-case decode
+    /// depth down to `TARGET_DECODE_DEPTH`, as long as the extra space for full root blobs
+    /// stays under `ROOT_BUDGET_FACTOR` times the size of the delta blobs.
+    pub fn suggest_max_roots(&self) -> usize {
+        const TARGET_DECODE_DEPTH: usize = 8;
+        const ROOT_BUDGET_FACTOR: f64 = 1.0;
+
+        let max_depth = self.max_depth();
+        let roots_for_depth = max_depth.div_ceil(TARGET_DECODE_DEPTH);
+
+        // extra space of keeping a root as a full blob instead of its delta alias
+        let mut costs = self
+            .root_candidates()
+            .into_iter()
+            .map(|root| {
+                let saved_ratio = 1.0 - root.alias.compression_ratio() as f64;
+                (root.blob.content_size as f64 * saved_ratio.max(0.0)) as u64
+            })
+            .collect::<Vec<_>>();
+        costs.sort();
+
+        let budget = (self.non_root_store_size as f64 * ROOT_BUDGET_FACTOR) as u64;
+        let mut used = 0;
+        let mut affordable = 0;
+        for cost in costs {
+            if used + cost > budget {
+                break;
+            }
+            used += cost;
+            affordable += 1;
+        }
+
+        roots_for_depth.min(affordable).max(1)
+    }
+
     pub fn spine(&self) -> Vec<usize> {
         // TODO: genesis
         let mut spine_idx = 0;
@@ -248,10 +475,10 @@ impl Stats {
             writeln!(s, "## stats").ok();
             writeln!(
                 s,
-                "  total count={}, size={}, dehydrated={}",
+                "  total count={}, size={}, min_dehydrated={}",
                 self.root_count + self.non_root_count,
                 ByteSize(self.root_total_size + self.non_root_store_size),
-                ByteSize(self.blobs[0].store_size + self.non_root_store_size),
+                ByteSize(self.min_dehydrated_size()),
             )
             .ok();
 
@@ -278,6 +505,24 @@ impl Stats {
                 100.0 / compression_ratio
             )
             .ok();
+
+            writeln!(s, "  balance_factor={:.2}", self.balance_factor()).ok();
+            let chains = self.chains();
+            writeln!(
+                s,
+                "  chains count={}, max_len={}",
+                chains.len(),
+                chains.iter().map(|chain| chain.len()).max().unwrap_or(0)
+            )
+            .ok();
+            writeln!(s, "  leaves count={}", self.leaf_blobs().len()).ok();
+            writeln!(
+                s,
+                "  suggested max_root_blobs={} (current={})",
+                self.suggest_max_roots(),
+                crate::max_root_blobs()
+            )
+            .ok();
         }
 
         // root blobs
@@ -386,50 +631,264 @@ impl Histogram {
     }
 }
 
-fn calculate_depth(idx: usize, blobs: &[Blob], depths: &mut [GraphNode]) {
-    let blob = &blobs[idx];
+/// `Stats::root_score` of a root whose alias has `store_size`. `age` is the number of blobs pushed
+/// since the root or its last child, the score drops to 0 after 100.
+pub fn score_by_age(store_size: u64, age: usize) -> u64 {
+    let max_unused_age = 100;
+    let age = (age as u64).min(max_unused_age);
+    store_size * (max_unused_age - age) / max_unused_age
+}
+
+fn dedup_in_order(indices: &mut Vec<usize>) {
+    let mut seen = std::collections::HashSet::new();
+    indices.retain(|idx| seen.insert(*idx));
+}
+
+/// `by_content` holds the indices of blobs of each content hash, so only aliases and parents of
+/// the blob are visited
+fn calculate_depth(
+    idx: usize,
+    blobs: &[Blob],
+    by_content: &HashMap<String, Vec<usize>>,
+    depths: &mut [GraphNode],
+) {
+    if blobs[idx].parent_hash.is_none() {
+        depths[idx].depth = 1;
+        return;
+    }
 
-    match blob.parent_hash {
-        None => {
-            depths[idx].depth = 1;
+    let parents = link_blob(idx, blobs, by_content, depths);
+    for &parent_idx in &parents {
+        if depths[parent_idx].depth == 0 {
+            calculate_depth(parent_idx, blobs, by_content, depths)
         }
+    }
 
-        Some(ref parent_hash) => {
-            let mut min_depth = blobs.len();
-            let mut min_idx = 0;
+    let (min_idx, min_depth) = shallowest(&parents, blobs, depths);
+    trace!("{}={}", idx, min_depth + 1);
+    depths[idx].depth = min_depth + 1;
+    depths[idx].parent_idx = Some(min_idx);
+}
 
-            for (other_idx, other) in blobs.iter().enumerate() {
-                // aliases
-                if other.content_hash == blob.content_hash {
-                    depths[idx].alias_indices.push(other_idx);
-                    depths[other_idx].alias_indices.push(idx);
-                }
-                if other_idx == idx {
-                    continue;
-                }
+/// links a delta with its aliases and direct parents, and returns the parents
+fn link_blob(
+    idx: usize,
+    blobs: &[Blob],
+    by_content: &HashMap<String, Vec<usize>>,
+    depths: &mut [GraphNode],
+) -> Vec<usize> {
+    let blob = &blobs[idx];
+    let parent_hash = match &blob.parent_hash {
+        Some(parent_hash) => parent_hash,
+        None => return Vec::new(),
+    };
+
+    // in the order of blobs, as a scan over all blobs would visit them
+    let mut others = [&blob.content_hash, parent_hash]
+        .iter()
+        .filter_map(|hash| by_content.get(*hash))
+        .flatten()
+        .copied()
+        .collect::<Vec<_>>();
+    others.sort_unstable();
+    others.dedup();
+
+    let mut parents = Vec::new();
+    for other_idx in others {
+        let other = &blobs[other_idx];
+        // aliases
+        if other.is_same_content(blob) {
+            depths[idx].alias_indices.push(other_idx);
+            depths[other_idx].alias_indices.push(idx);
+        }
+        if other_idx == idx || !blob.is_direct_child_of(other) {
+            continue;
+        }
 
-                let parent_idx = other_idx;
-                let parent = other;
+        depths[other_idx].children_indices.push(idx);
+        parents.push(other_idx);
+    }
+    parents
+}
 
-                if &parent.content_hash != parent_hash {
-                    continue;
-                }
+/// the first of the parents with the smallest depth, and its depth. a delta without parents
+/// is deeper than any chain.
+fn shallowest(parents: &[usize], blobs: &[Blob], depths: &[GraphNode]) -> (usize, usize) {
+    parents
+        .iter()
+        .map(|&parent_idx| (parent_idx, depths[parent_idx].depth))
+        .min_by_key(|(_, depth)| *depth)
+        .unwrap_or((0, blobs.len()))
+}
 
-                if depths[parent_idx].depth == 0 {
-                    calculate_depth(parent_idx, blobs, depths)
-                }
-                let depth = depths[parent_idx].depth;
-                if depth < min_depth {
-                    min_depth = depth;
-                    min_idx = parent_idx;
-                }
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+
+    #[test]
+    fn leaf_blobs() {
+        // genesis `a` -> `b` -> {`c`, `d`}, `b` also kept as a root
+        let stats = Stats::from_blobs(vec![
+            blob(1, "a", None, 10),
+            blob(2, "b", Some("a"), 10),
+            blob(3, "b", None, 10),
+            blob(4, "c", Some("b"), 10),
+            blob(5, "d", Some("b"), 10),
+        ]);
+        assert_eq!(stats.leaf_blobs(), vec![3, 4]);
+    }
+
+    #[test]
+    fn content_index() {
+        let (stats, index) = Stats::from_blobs_with_index(vec![
+            blob(1, "a", None, 10),
+            blob(2, "b", Some("a"), 10),
+            blob(3, "b", None, 10),
+            blob(4, "c", Some("b"), 10),
+        ]);
+        assert_eq!(index.len(), 3);
+        assert_eq!(index["a"], 0);
+        // the latest blob of `b`
+        assert_eq!(index["b"], 2);
+        assert_eq!(index["c"], 3);
+
+        let depths = stats.depths.iter().map(|d| d.depth).collect::<Vec<_>>();
+        assert_eq!(depths, vec![1, 2, 1, 2]);
+        assert_eq!(stats.depths[3].parent_idx, Some(2));
+        assert_eq!(stats.depths[0].children_indices, vec![1]);
+        assert_eq!(stats.aliases(2), vec![1]);
+    }
+
+    #[test]
+    fn dehydrated_size() {
+        // genesis `a` is not the first row, root `b` is an alias of 1
+        let stats = Stats::from_blobs(vec![
+            blob(2, "b", Some("a"), 10),
+            blob(3, "b", None, 100),
+            blob(1, "a", None, 1000),
+            blob(4, "c", Some("b"), 20),
+        ]);
+        assert_eq!(stats.base_roots(), vec![2]);
+        assert_eq!(stats.min_dehydrated_size(), 1000 + 10 + 20);
+
+        let all = stats.disk_usage(|blob| Some(blob.store_size));
+        assert_eq!(all.hydrated_roots, 2);
+        assert_eq!(all.size, 1000 + 100 + 10 + 20);
+
+        // `b` dehydrated
+        let usage = stats.disk_usage(|blob| Some(blob.store_size).filter(|_| blob.id == 1));
+        assert_eq!(usage.hydrated_roots, 1);
+        assert_eq!(usage.size, stats.min_dehydrated_size());
+    }
+
+    #[test]
+    fn chains() {
+        // genesis `a` -> `b` -> {`c`, `d` -> `e`}
+        let stats = Stats::from_blobs(vec![
+            blob(1, "a", None, 10),
+            blob(2, "b", Some("a"), 10),
+            blob(3, "c", Some("b"), 10),
+            blob(4, "d", Some("b"), 10),
+            blob(5, "e", Some("d"), 10),
+        ]);
+        assert_eq!(stats.chains(), vec![vec![0, 1, 3, 4], vec![0, 1, 2]]);
+    }
+
+    #[test]
+    fn blob_relations() {
+        let (genesis, delta, root) = (
+            blob(1, "a", None, 10),
+            blob(2, "b", Some("a"), 10),
+            blob(3, "b", None, 10),
+        );
+        assert!(delta.is_same_content(&root));
+        assert!(!delta.is_same_content(&genesis));
+        assert!(delta.is_direct_child_of(&genesis));
+        assert!(!delta.is_direct_child_of(&root));
+        assert!(!genesis.is_direct_child_of(&genesis));
+    }
+
+    #[test]
+    fn balance_factor() {
+        // genesis `a` with two children, root `b` (alias of 2) with one child
+        let stats = Stats::from_blobs(vec![
+            blob(1, "a", None, 10),
+            blob(2, "b", Some("a"), 10),
+            blob(3, "c", Some("a"), 10),
+            blob(4, "b", None, 10),
+            blob(5, "d", Some("b"), 10),
+        ]);
+        assert!((stats.balance_factor() - 0.75).abs() < 1e-9);
+
+        let stats = Stats::from_blobs(vec![blob(1, "a", None, 10)]);
+        assert_eq!(stats.balance_factor(), 1.0);
+    }
+
+    #[test]
+    fn node_name_stable() {
+        let blobs = vec![
+            blob(1, "a", None, 10),
+            blob(2, "b", None, 10),
+            blob(3, "b", Some("a"), 10),
+            blob(4, "b", None, 10),
+            blob(5, "c", Some("b"), 10),
+        ];
+
+        let names = |blobs: Vec<Blob>| {
+            let stats = Stats::from_blobs(blobs);
+            let mut names = (0..stats.blobs.len())
+                .map(|idx| (stats.blobs[idx].id, stats.node_name(idx)))
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        let forward = names(blobs.clone());
+        let reversed = names(blobs.into_iter().rev().collect());
+        assert_eq!(forward, reversed);
+        assert_eq!(forward[2], (3, "V2".to_owned()));
+    }
 
-                depths[parent_idx].children_indices.push(idx);
+    #[test]
+    fn cached_depths() {
+        // `c` is promoted to a root after its children are pushed
+        let blobs = vec![
+            blob(1, "a", None, 10),
+            blob(2, "b", Some("a"), 10),
+            blob(3, "c", Some("b"), 10),
+            blob(4, "d", Some("c"), 10),
+            blob(5, "c", None, 10),
+            blob(6, "e", Some("c"), 10),
+            blob(7, "f", Some("e"), 10),
+        ];
+
+        // reversed, parents are visited after their children
+        for blobs in vec![blobs.clone(), blobs.into_iter().rev().collect()] {
+            let computed = Stats::from_blobs(blobs.clone());
+            let depths = computed
+                .blobs
+                .iter()
+                .zip(&computed.depths)
+                .map(|(blob, node)| (blob.id, node.depth))
+                .collect::<HashMap<_, _>>();
+            let cached = Stats::from_blobs_with_depths(blobs, &depths);
+
+            for idx in 0..computed.blobs.len() {
+                let (a, b) = (&computed.depths[idx], &cached.depths[idx]);
+                assert_eq!(a.depth, b.depth);
+                assert_eq!(a.parent_idx, b.parent_idx);
+                assert_eq!(a.child_count, b.child_count);
+                assert_eq!(a.alias_indices, b.alias_indices);
+                assert_eq!(a.children_indices, b.children_indices);
             }
+            assert_eq!(computed.max_depth(), 3);
 
-            trace!("{}={}", idx, min_depth + 1);
-            depths[idx].depth = min_depth + 1;
-            depths[idx].parent_idx = Some(min_idx);
+            // cached depths are taken as they are, not computed again
+            let mut stale = depths.clone();
+            stale.insert(4, 9);
+            let cached = Stats::from_blobs_with_depths(computed.blobs.clone(), &stale);
+            assert_eq!(cached.max_depth(), 9);
         }
     }
 }
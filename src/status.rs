@@ -0,0 +1,125 @@
+use std::time::Duration;
+
+use super::*;
+
+/// a cheap summary of the store for monitoring, as written by `status --write`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoreStatus {
+    /// the last result of every check passed, and the latest one is not older than `max_age`
+    pub healthy: bool,
+    #[serde(with = "time::serde::rfc3339")]
+    pub time_generated: time::OffsetDateTime,
+    pub schema_version: u32,
+    pub storage_mode: String,
+    /// last result of each check, oldest first
+    pub checks: Vec<db::CheckResult>,
+
+    pub blob_count: usize,
+    pub chunked_count: usize,
+    pub store_size: u64,
+    pub content_size: u64,
+    /// roots whose full blob was removed by dehydrate
+    pub dehydrated_count: usize,
+}
+
+pub fn status(conn: &mut db::Conn, max_age: Option<Duration>) -> Result<StoreStatus> {
+    let now = time::OffsetDateTime::now_utc();
+    let checks = db::checks(conn)?;
+
+    let fresh = match (checks.last(), max_age) {
+        (None, _) => false,
+        (Some(_), None) => true,
+        (Some(last), Some(max_age)) => {
+            (now - last.time_checked).whole_seconds() <= max_age.as_secs() as i64
+        }
+    };
+    let healthy = fresh && checks.iter().all(|check| check.ok);
+
+    let (store_size, content_size) = db::total_sizes(conn)?;
+    Ok(StoreStatus {
+        healthy,
+        time_generated: now,
+        schema_version: db::SCHEMA_VERSION,
+        storage_mode: storage_mode(conn)?.to_string(),
+        checks,
+        blob_count: db::count(conn)?,
+        chunked_count: db::chunked_count(conn)?,
+        store_size,
+        content_size,
+        dehydrated_count: db::dehydrated_roots(conn)?.len(),
+    })
+}
+
+/// prints the status as json, or writes it to `write_path`
+pub fn print_status(
+    conn: &mut db::Conn,
+    write_path: Option<&str>,
+    max_age: Option<Duration>,
+) -> Result<()> {
+    let status = status(conn, max_age)?;
+    match write_path {
+        Some(path) => write_report(path, &status)?,
+        None => println!("{}", serde_json::to_string_pretty(&status)?),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+
+    #[test]
+    fn status_after_validate() {
+        with_store(|conn, dir| {
+            let v1 = write_file(dir, "app-1.tar", b"hello, world");
+            let v2 = write_file(dir, "app-2.tar", b"hello, world!!");
+            push(conn, &v1, FileType::Plain).unwrap();
+            push(conn, &v2, FileType::Plain).unwrap();
+
+            // never checked
+            assert!(!status(conn, None).unwrap().healthy);
+
+            validate(conn).unwrap();
+            let s = status(conn, Some(Duration::from_secs(3600))).unwrap();
+            assert!(s.healthy);
+            assert_eq!(s.blob_count, 3);
+            assert_eq!(s.checks.len(), 1);
+            assert_eq!(s.checks[0].kind, CHECK_VALIDATE);
+
+            let delta = db::all(conn)
+                .unwrap()
+                .into_iter()
+                .find(|blob| !blob.is_root())
+                .unwrap();
+            let delta_path = filepath(&delta.store_hash);
+            let original = std::fs::read(&delta_path).unwrap();
+            let mut corrupted = original.clone();
+            *corrupted.last_mut().unwrap() ^= 0xff;
+            std::fs::write(&delta_path, &corrupted).unwrap();
+
+            assert!(validate(conn).is_err());
+            let s = status(conn, None).unwrap();
+            assert!(!s.healthy);
+            assert!(!s.checks[0].ok);
+            assert!(s.checks[0].error.is_some());
+
+            // a passing sampled check does not hide the failed full one
+            std::fs::write(&delta_path, &original).unwrap();
+            let opts = ValidateOptions {
+                sample: Some(0.0),
+                seed: Some(1),
+            };
+            validate_with_options(conn, &opts).unwrap();
+            let s = status(conn, None).unwrap();
+            assert_eq!(s.checks.len(), 2);
+            assert!(!s.healthy);
+
+            let path = dir.join("status.json");
+            print_status(conn, path.to_str(), None).unwrap();
+            let written: StoreStatus =
+                serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+            assert!(!written.healthy);
+        });
+    }
+}
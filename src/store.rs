@@ -0,0 +1,94 @@
+//! a read-only store at an explicit path, for tools linking the crate which only verify, read or
+//! archive versions, e.g. a backup verifier. free functions find the store through WORKDIR and
+//! INCRESTORE_OBJECT_DIR; `Store` passes its own directories instead, and leaves the environment
+//! alone.
+
+use super::*;
+
+/// a store opened read-only at `path`, its WORKDIR. the database is never written, decoded
+/// contents go through `{path}/tmp` like `get`.
+///
+/// ```no_run
+/// # fn main() -> increstore::Result<()> {
+/// let mut store = increstore::Store::open_readonly("/var/lib/increstore")?;
+/// for blob in store.versions()? {
+///     let report = store.verify(&blob.filename)?;
+///     if !report.ok {
+///         eprintln!("{}: {:?}", blob.filename, report.error);
+///     }
+/// }
+/// store.read("app-2.tar", "/tmp/app-2.tar")?;
+/// store.archive_to(std::fs::File::create("/backup/increstore.tar")?)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Store {
+    path: PathBuf,
+    dirs: StoreDirs,
+    conn: db::Conn,
+    /// retry policy of the store, used only by its own calls, see `retry::with_policy`
+    retry: RetryPolicy,
+}
+
+impl Store {
+    /// opens `{path}/meta.db` read-only, refusing a store of another hash scheme
+    pub fn open_readonly<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let dirs = StoreDirs::at(path.to_str().expect("invalid store path"));
+        let mut conn = db::open_readonly(dirs.dbpath())?;
+
+        check_stored_hash_algo(&mut conn)?;
+        // a store created before settings has no policy
+        let retry = if db::has_table(&conn, "settings")? {
+            retry::retry_policy(&mut conn)?
+        } else {
+            RetryPolicy::default()
+        };
+        Ok(Store {
+            path,
+            dirs,
+            conn,
+            retry,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// the blob `read` gets for each filename, oldest first
+    pub fn versions(&mut self) -> Result<Vec<Blob>> {
+        let mut latest = HashMap::new();
+        for blob in db::all(&mut self.conn)? {
+            latest.insert(blob.filename.clone(), blob);
+        }
+        let mut versions = latest.into_values().collect::<Vec<_>>();
+        versions.sort_by_key(|blob| blob.id);
+        Ok(versions)
+    }
+
+    /// decodes the chain of version `name`, a filename or a content hash, see `validate_chain`.
+    /// a failed decode is reported in `ValidateReport::error`.
+    pub fn verify(&mut self, name: &str) -> Result<ValidateReport> {
+        let (conn, dirs) = (&mut self.conn, &self.dirs);
+        retry::with_policy(self.retry, || validate::validate_chain_in(conn, dirs, name))
+    }
+
+    /// writes the content of version `name` to `out`, which should not exist
+    pub fn read<P: AsRef<Path>>(&mut self, name: &str, out: P) -> Result<()> {
+        let out = out.as_ref().to_str().expect("invalid out path");
+        let opts = GetOptions::default();
+        let (conn, dirs) = (&mut self.conn, &self.dirs);
+        retry::with_policy(self.retry, || {
+            get_in(conn, dirs, name, out, &opts, |_decoded, _total| {})
+        })
+    }
+
+    /// writes the store as a tar archive, like `archive`
+    pub fn archive_to<W: io::Write>(&mut self, w: W) -> Result<ArchiveSummary> {
+        let (conn, dirs) = (&mut self.conn, &self.dirs);
+        retry::with_policy(self.retry, || {
+            archive::archive_in(conn, dirs, w, &ArchiveOptions::default())
+        })
+    }
+}
@@ -0,0 +1,298 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::db;
+
+/// the system allocator, counting live bytes of a thread inside `peak_alloc`
+struct CountingAlloc;
+
+thread_local! {
+    /// (live, peak) bytes allocated by this thread, while counted
+    static ALLOC_COUNTER: Cell<Option<(isize, isize)>> = const { Cell::new(None) };
+}
+
+fn count_alloc(delta: isize) {
+    ALLOC_COUNTER.with(|counter| {
+        if let Some((live, peak)) = counter.get() {
+            let live = live + delta;
+            counter.set(Some((live, peak.max(live))));
+        }
+    });
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_alloc(layout.size() as isize);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        count_alloc(-(layout.size() as isize));
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count_alloc(new_size as isize - layout.size() as isize);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+/// runs `f`, returning the peak of bytes allocated and not yet freed by this thread meanwhile.
+/// sqlite allocates with its own malloc, so it is not counted.
+pub fn peak_alloc<F, R>(f: F) -> (R, usize)
+where
+    F: FnOnce() -> R,
+{
+    ALLOC_COUNTER.with(|counter| counter.set(Some((0, 0))));
+    let res = f();
+    let (_live, peak) = ALLOC_COUNTER.with(|counter| counter.replace(None)).unwrap();
+    (res, peak as usize)
+}
+
+// WORKDIR is process-global, so tests touching a store run one at a time
+static STORE_LOCK: Mutex<()> = Mutex::new(());
+
+/// runs `f` against a fresh store in a temporary WORKDIR
+pub fn with_store<F>(f: F)
+where
+    F: FnOnce(&mut db::Conn, &Path),
+{
+    let _guard = STORE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    env_logger::builder().is_test(true).try_init().ok();
+
+    let dir = tempfile::tempdir().expect("failed to create tempdir");
+    std::env::set_var("WORKDIR", dir.path());
+
+    let mut conn = db::open().expect("failed to open db");
+    db::prepare(&mut conn).expect("failed to prepare");
+
+    f(&mut conn, dir.path());
+}
+
+/// a `retry::Fs` on which files matching the predicate are missing, to break a store in the
+/// middle of a command
+pub struct MissingFs<F>(pub F);
+
+impl<F: Fn(&Path) -> bool> crate::retry::Fs for MissingFs<F> {
+    fn metadata(&self, path: &Path) -> std::io::Result<std::fs::Metadata> {
+        if (self.0)(path) {
+            return Err(std::io::ErrorKind::NotFound.into());
+        }
+        std::fs::metadata(path)
+    }
+}
+
+/// a `retry::Fs` on which temporary files fail to persist, as if the process stopped before the
+/// rename
+pub struct FailingPersist;
+
+impl crate::retry::Fs for FailingPersist {
+    fn persist(
+        &self,
+        file: tempfile::NamedTempFile,
+        _dst: &Path,
+        _noclobber: bool,
+    ) -> Result<std::fs::File, tempfile::PersistError> {
+        let error = std::io::Error::from_raw_os_error(libc::EIO);
+        Err(tempfile::PersistError { error, file })
+    }
+}
+
+/// a `SimilarityEstimator` preferring the root of a given filename
+#[derive(Debug)]
+pub struct FilenameEstimator(pub &'static str);
+
+impl crate::SimilarityEstimator for FilenameEstimator {
+    fn predicted_ratio(
+        &self,
+        _conn: &mut db::Conn,
+        _input: &db::Blob,
+        root: &db::Blob,
+    ) -> crate::Result<f64> {
+        Ok(if root.filename == self.0 { 0.0 } else { 1.0 })
+    }
+}
+
+/// writes an input file outside of the object directory, returns its path
+pub fn write_file(dir: &Path, name: &str, body: &[u8]) -> String {
+    let input_dir = dir.join("input");
+    std::fs::create_dir_all(&input_dir).expect("failed to create input dir");
+
+    let path = input_dir.join(name);
+    std::fs::write(&path, body).expect("failed to write input");
+    path.to_str().unwrap().to_owned()
+}
+
+/// a blob row without an object, named `app-{id}.tar`. `parent_hash` makes it a delta, and the
+/// id in its store hash keeps roots and deltas of the same content apart.
+pub fn blob(id: u32, content_hash: &str, parent_hash: Option<&str>, store_size: u64) -> db::Blob {
+    db::Blob {
+        id,
+        filename: format!("app-{}.tar", id),
+        time_created: time::OffsetDateTime::UNIX_EPOCH,
+        store_size,
+        content_size: 1000,
+        store_hash: format!("{}{}", content_hash, id),
+        content_hash: content_hash.to_owned(),
+        parent_hash: parent_hash.map(|s| s.to_owned()),
+    }
+}
+
+/// xorshift64, for generated test inputs which are reproducible from a seed
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    /// either random bytes or repetitive text, so compressors see both
+    pub fn body(&mut self) -> Vec<u8> {
+        let len = self.below(8 * 1024) as usize;
+        if self.below(2) == 0 {
+            (0..len).map(|_| self.next_u64() as u8).collect()
+        } else {
+            let line = format!("line {:x}\n", self.next_u64());
+            line.repeat(len / line.len() + 1).into_bytes()[..len].to_vec()
+        }
+    }
+}
+
+const NAME_PARTS: &[&str] = &[
+    "a",
+    "src",
+    "res",
+    "日本語",
+    "é",
+    "with space",
+    "😀",
+    "lib.so",
+];
+
+/// a zip archive of a random file tree, with unicode names, nested directories and varied
+/// compression methods, timestamps and modes. returns the archive and its files by name.
+pub fn random_zip(rng: &mut Rng) -> (Vec<u8>, Vec<(String, Vec<u8>)>) {
+    use zip::CompressionMethod::*;
+    const METHODS: &[zip::CompressionMethod] = &[Stored, Deflated, Bzip2, Zstd];
+
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let mut files = Vec::new();
+    for i in 0..rng.below(8) {
+        let depth = 1 + rng.below(3);
+        let mut name = (0..depth)
+            .map(|_| NAME_PARTS[rng.below(NAME_PARTS.len() as u64) as usize])
+            .collect::<Vec<_>>()
+            .join("/");
+        // names are unique within an archive
+        name += &format!("-{}", i);
+
+        let mtime = zip::DateTime::from_date_and_time(
+            1980 + rng.below(128) as u16,
+            1 + rng.below(12) as u8,
+            1 + rng.below(28) as u8,
+            rng.below(24) as u8,
+            rng.below(60) as u8,
+            2 * rng.below(30) as u8,
+        )
+        .unwrap();
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(METHODS[rng.below(METHODS.len() as u64) as usize])
+            .last_modified_time(mtime)
+            .unix_permissions(0o600 + rng.below(0o200) as u32);
+
+        let body = rng.body();
+        zip.start_file(name.as_str(), options).unwrap();
+        zip.write_all(&body).unwrap();
+        files.push((name, body));
+    }
+    (zip.finish().unwrap().into_inner(), files)
+}
+
+/// a gzip stream of `data`, split into several members sometimes
+pub fn random_gz(rng: &mut Rng, data: &[u8]) -> Vec<u8> {
+    let level = flate2::Compression::new(rng.below(10) as u32);
+    let split = match rng.below(2) {
+        0 => data.len(),
+        _ => rng.below(data.len() as u64 + 1) as usize,
+    };
+
+    let mut out = Vec::new();
+    for part in &[&data[..split], &data[split..]] {
+        if part.is_empty() && !out.is_empty() {
+            continue;
+        }
+        let mut w = flate2::write::GzEncoder::new(Vec::new(), level);
+        w.write_all(part).unwrap();
+        out.extend_from_slice(&w.finish().unwrap());
+    }
+    out
+}
+
+fn zip_bytes(entries: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let options = zip::write::SimpleFileOptions::default();
+    for (name, body) in entries {
+        zip.start_file(*name, options).unwrap();
+        zip.write_all(body).unwrap();
+    }
+    zip.finish().unwrap().into_inner()
+}
+
+/// an .apks with a compressed base apk. `changed` alters a line near the start of classes.dex,
+/// which changes most of the compressed apk.
+pub fn bundle_bytes(changed: bool) -> Vec<u8> {
+    let mut dex = (0..4000)
+        .map(|i| {
+            format!(
+                "line {:05} {:016x}\n",
+                i,
+                (i as u64).wrapping_mul(0x9e37_79b9)
+            )
+        })
+        .collect::<String>()
+        .into_bytes();
+    if changed {
+        dex[..10].copy_from_slice(b"LINE 00000");
+    }
+    let apk = zip_bytes(&[
+        ("AndroidManifest.xml", b"<manifest/>"),
+        ("classes.dex", &dex),
+    ]);
+    zip_bytes(&[("splits/base-master.apk", &apk), ("toc.pb", b"toc")])
+}
+
+/// regression inputs of a fuzz target, checked in under `fuzz/corpus`
+pub fn fuzz_corpus(target: &str) -> Vec<(PathBuf, Vec<u8>)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("fuzz/corpus")
+        .join(target);
+    let mut paths = std::fs::read_dir(&dir)
+        .expect("failed to read corpus")
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| {
+            let data = std::fs::read(&path).expect("failed to read corpus entry");
+            (path, data)
+        })
+        .collect()
+}
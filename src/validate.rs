@@ -1,25 +1,368 @@
 use super::*;
 
+/// outcome of validate, as written by `validate --report-path`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ValidateReport {
+    pub ok: bool,
+    pub blob_count: usize,
+    pub exit_code: i32,
+    /// the first error found
+    pub error: Option<String>,
+    /// ids of the validated delta blobs, with `validate --sample` or `validate_chain`
+    #[serde(default)]
+    pub checked: Option<Vec<u32>>,
+}
+
+impl ValidateReport {
+    pub fn new(conn: &mut db::Conn, res: &Result<()>) -> Result<Self> {
+        let blob_count = db::count(conn)?;
+        Ok(Self::from_result(blob_count, res))
+    }
+
+    fn from_result<T>(blob_count: usize, res: &Result<T>) -> Self {
+        match res {
+            Ok(_) => ValidateReport {
+                ok: true,
+                blob_count,
+                exit_code: 0,
+                error: None,
+                checked: None,
+            },
+            Err(e) => ValidateReport {
+                ok: false,
+                blob_count,
+                exit_code: exit_code(e),
+                error: Some(e.to_string()),
+                checked: None,
+            },
+        }
+    }
+}
+
+pub const CHECK_VALIDATE: &str = "validate";
+pub const CHECK_VALIDATE_SAMPLE: &str = "validate-sample";
+
+#[derive(Debug, Clone, Default)]
+pub struct ValidateOptions {
+    /// validate only this fraction of leaf versions. blobs needed to decode the sampled leaves
+    /// and delta aliases of root blobs are always validated.
+    pub sample: Option<f64>,
+    /// seed of the sampling, a random seed is used if not set
+    pub seed: Option<u64>,
+}
+
 pub fn validate(conn: &mut db::Conn) -> Result<()> {
-    let blobs = db::all(conn)?;
+    validate_with_options(conn, &ValidateOptions::default())?;
+    Ok(())
+}
+
+/// validates blobs, returning the delta blobs which were decoded. the outcome is recorded for
+/// `status`.
+pub fn validate_with_options(conn: &mut db::Conn, opts: &ValidateOptions) -> Result<Vec<Blob>> {
+    let kind = if opts.sample.is_some() {
+        CHECK_VALIDATE_SAMPLE
+    } else {
+        CHECK_VALIDATE
+    };
+    let res = validate0(conn, opts);
+    let error = res.as_ref().err().map(|e| e.to_string());
+    db::record_check(conn, kind, error.as_deref())?;
+    res
+}
+
+fn validate0(conn: &mut db::Conn, opts: &ValidateOptions) -> Result<Vec<Blob>> {
+    let mut blobs = db::all(conn)?;
+    if let Some(fraction) = opts.sample {
+        let seed = opts.seed.unwrap_or_else(random_seed);
+        info!("validate: sample={} seed={}", fraction, seed);
+        blobs = sample_blobs(blobs, fraction, seed);
+    }
+    // root blobs are validated through their delta aliases
+    let checked = blobs.iter().filter(|b| !b.is_root()).cloned().collect();
     let stats = Stats::from_blobs(blobs);
 
     validate_blob_root(0, stats)?;
 
+    Ok(checked)
+}
+
+/// validates the decode chain of a content, from its root blob. `name` is a content hash or a
+/// filename. unlike `validate`, the outcome is not recorded for `status`, and a failure is
+/// reported in `ValidateReport::error`.
+pub fn validate_chain(conn: &mut db::Conn, name: &str) -> Result<ValidateReport> {
+    validate_chain_in(conn, &StoreDirs::from_env(), name)
+}
+
+/// `validate_chain` with the error of a failed chain, which the report holds only as a message,
+/// e.g. to exit with its `exit_code`
+pub fn validate_chain_with_error(
+    conn: &mut db::Conn,
+    name: &str,
+) -> Result<(ValidateReport, Result<()>)> {
+    chain_report(conn, &StoreDirs::from_env(), name)
+}
+
+/// `validate_chain` of the store in `dirs`
+pub(crate) fn validate_chain_in(
+    conn: &mut db::Conn,
+    dirs: &StoreDirs,
+    name: &str,
+) -> Result<ValidateReport> {
+    let (report, _res) = chain_report(conn, dirs, name)?;
+    Ok(report)
+}
+
+fn chain_report(
+    conn: &mut db::Conn,
+    dirs: &StoreDirs,
+    name: &str,
+) -> Result<(ValidateReport, Result<()>)> {
+    let chain = db::chain(conn, name)?;
+    let root = match chain.first() {
+        Some(root) => root.clone(),
+        None => return Err(Error::UnknownFilename(name.to_owned())),
+    };
+    let checked = chain
+        .iter()
+        .filter(|blob| !blob.is_root())
+        .map(|blob| blob.id)
+        .collect();
+
+    let blob_count = chain.len();
+    let res = if !Path::new(&dirs.filepath(&root.store_hash)).exists() {
+        Err(Error::MissingObject(dirs.filepath(&root.store_hash)))
+    } else if chain.len() == 1 {
+        // nothing to decode, the root object itself is the content
+        validate_root(dirs, &root)
+    } else {
+        validate_blob_root_in(dirs, 0, Stats::from_blobs(chain))
+    };
+
+    let mut report = ValidateReport::from_result(blob_count, &res);
+    report.checked = Some(checked);
+    Ok((report, res))
+}
+
+/// validates a stored `.gz` file. the object holds the decompressed content rather than the
+/// gzip stream, which is not kept, so the decoded content is checked against the hash of the
+/// blob.
+pub fn validate_gz_stored(conn: &mut db::Conn, filename: &str) -> Result<()> {
+    if FileType::from_path(filename) != Some(FileType::Gz) {
+        return Err(crate::format_err!("not a gz file: {}", filename));
+    }
+    let blob = match db::by_filename(conn, filename)?.pop() {
+        Some(blob) => blob,
+        None => return Err(Error::UnknownFilename(filename.to_owned())),
+    };
+
+    compact::decode_content(conn, &blob)?;
     Ok(())
 }
 
+/// `verify_scope` depth of commands run with `--verify`: the changed versions and the deltas
+/// decoded directly from them
+pub const VERIFY_DECODE_DEPTH: usize = 1;
+
+/// `verify_scope` depth of a command, decoding only with `verify`
+pub fn verify_depth(verify: bool) -> usize {
+    if verify {
+        VERIFY_DECODE_DEPTH
+    } else {
+        0
+    }
+}
+
+/// checks blobs of `blob_ids` after a command changed them: each object exists with its
+/// store_size, and the parent content of each delta is stored. with `depth` above 0, the deltas
+/// decoded from them are included up to `depth` levels below, and every included version is
+/// decoded. returns the ids of the checked blobs.
+pub fn verify_scope(conn: &mut db::Conn, blob_ids: &[u32], depth: usize) -> Result<Vec<u32>> {
+    let mut blobs = Vec::new();
+    for &id in blob_ids {
+        match db::by_id(conn, id)? {
+            Some(blob) => blobs.push(blob),
+            None => return Err(crate::format_err!("verify: blob {} does not exist", id)),
+        }
+    }
+    let mut level = blobs.clone();
+    for _ in 0..depth {
+        let mut children = Vec::new();
+        for blob in &level {
+            children.extend(db::children(conn, &blob.content_hash)?);
+        }
+        children.retain(|child| !blobs.iter().any(|blob| blob.id == child.id));
+        blobs.extend(children.iter().cloned());
+        level = children;
+    }
+
+    let dehydrated = db::dehydrated_roots(conn)?;
+    for blob in &blobs {
+        let path = filepath(&blob.store_hash);
+        match retry::metadata(&path) {
+            Ok(meta) if meta.len() == blob.store_size => (),
+            Ok(meta) => {
+                return Err(crate::format_err!(
+                    "verify: object size mismatch: {}, expected={}, actual={}",
+                    path,
+                    blob.store_size,
+                    meta.len()
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                if !(blob.is_root() && dehydrated.contains(&blob.content_hash)) {
+                    return Err(Error::MissingObject(path));
+                }
+            }
+            Err(e) => return Err(e.into()),
+        }
+        if let Some(parent_hash) = &blob.parent_hash {
+            if db::by_content_hash(conn, parent_hash)?.is_empty() {
+                return Err(Error::MissingParent {
+                    filename: blob.filename.clone(),
+                    parent_hash: parent_hash.clone(),
+                });
+            }
+        }
+    }
+    if depth > 0 {
+        for blob in &blobs {
+            if let Some(error) = validate_chain(conn, &blob.content_hash)?.error {
+                return Err(crate::format_err!(
+                    "verify: {} does not decode: {}",
+                    blob.filename,
+                    error
+                ));
+            }
+        }
+    }
+    Ok(blobs.iter().map(|blob| blob.id).collect())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ParentHashReport {
+    pub total_deltas: usize,
+    /// parent hashes referenced by deltas, but not stored as a content
+    pub broken_refs: Vec<String>,
+}
+
+/// checks that the parent of every delta exists, without decoding anything
+pub fn verify_parent_hashes(conn: &mut db::Conn) -> Result<ParentHashReport> {
+    Ok(ParentHashReport {
+        total_deltas: db::delta_count(conn)?,
+        broken_refs: db::broken_parent_hashes(conn)?,
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FsckReport {
+    /// deltas whose parent content does not exist
+    pub dangling: Vec<Blob>,
+    /// blobs moved into `blobs_quarantine` by `repair`
+    pub quarantined: usize,
+}
+
+/// checks for deltas with a dangling parent. with `repair`, they are moved into
+/// `blobs_quarantine`, along with deltas which become dangling by doing so.
+pub fn fsck(conn: &mut db::Conn, repair: bool) -> Result<FsckReport> {
+    let dangling = db::dangling_blobs(conn)?;
+    let mut report = FsckReport {
+        dangling: dangling.clone(),
+        quarantined: 0,
+    };
+    if !repair {
+        return Ok(report);
+    }
+
+    let mut dangling = dangling;
+    while !dangling.is_empty() {
+        for blob in &dangling {
+            warn!(
+                "fsck: quarantining filename={}, parent_hash={:?}",
+                blob.filename, blob.parent_hash
+            );
+            db::quarantine(conn, blob, "dangling parent")?;
+            report.quarantined += 1;
+        }
+        dangling = db::dangling_blobs(conn)?;
+    }
+    Ok(report)
+}
+
+fn validate_root(dirs: &StoreDirs, root: &Blob) -> Result<()> {
+    let digest = file_hash(&dirs.filepath(&root.store_hash))?;
+    if digest != root.content_hash {
+        return Err(Error::ChecksumMismatch {
+            filename: root.filename.clone(),
+            expected: root.content_hash.clone(),
+            actual: digest,
+        });
+    }
+    Ok(())
+}
+
+fn random_seed() -> u64 {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_nanos() as u64 ^ u64::from(std::process::id())
+}
+
+/// splitmix64 of the seed and the blob's store_hash, so selection of a blob does not depend on
+/// the other blobs in the store
+fn sample_score(seed: u64, blob: &Blob) -> u64 {
+    let mut z = blob
+        .store_hash
+        .bytes()
+        .fold(seed, |h, b| (h ^ b as u64).wrapping_mul(0x100_0000_01b3));
+    z = z.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// drops leaf blobs which are not sampled. leaves have no dependents, so the rest of the graph
+/// is unchanged.
+fn sample_blobs(blobs: Vec<Blob>, fraction: f64, seed: u64) -> Vec<Blob> {
+    let stats = Stats::from_blobs(blobs);
+    let threshold = (fraction.clamp(0.0, 1.0) * u64::MAX as f64) as u64;
+
+    let keep = (0..stats.blobs.len())
+        .map(|idx| {
+            let blob = &stats.blobs[idx];
+            let is_leaf = idx != 0 && !blob.is_root() && stats.child_count(idx) == 1;
+            let is_root_alias = stats
+                .aliases(idx)
+                .into_iter()
+                .any(|alias_idx| stats.blobs[alias_idx].is_root());
+            !is_leaf || is_root_alias || sample_score(seed, blob) < threshold
+        })
+        .collect::<Vec<_>>();
+
+    stats
+        .blobs
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(blob, keep)| if keep { Some(blob) } else { None })
+        .collect()
+}
+
 pub fn validate_blob_root(idx: usize, stats: Stats) -> Result<()> {
+    validate_blob_root_in(&StoreDirs::from_env(), idx, stats)
+}
+
+fn validate_blob_root_in(dirs: &StoreDirs, idx: usize, stats: Stats) -> Result<()> {
+    let dirs = Arc::new(dirs.clone());
     let stats = Arc::new(stats);
-    let src_filepath = filepath(&stats.blobs[idx].store_hash);
+    let src_filepath = dirs.filepath(&stats.blobs[idx].store_hash);
 
     let rt = tokio::runtime::Runtime::new()?;
-    rt.block_on(validate_blob_children(0, src_filepath, stats))?;
+    rt.block_on(validate_blob_children(dirs, 0, src_filepath, stats))?;
 
     Ok(())
 }
 
 async fn validate_blob_children<P>(
+    dirs: Arc<StoreDirs>,
     parent_idx: usize,
     src_filepath: P,
     stats: Arc<Stats>,
@@ -34,7 +377,8 @@ where
     let src_path_buf = src_filepath.as_ref().to_path_buf();
     let mut handles = Vec::new();
     for child_idx in children {
-        let f = validate_blob_children0(child_idx, src_path_buf.clone(), stats.clone());
+        let f =
+            validate_blob_children0(dirs.clone(), child_idx, src_path_buf.clone(), stats.clone());
         if stats.child_count(child_idx) == 1 {
             handles.push(tokio::task::spawn(f));
         } else {
@@ -49,13 +393,14 @@ where
 
     if let Some(child_idx) = last {
         // drop src_filepath (probably NamedTempFile itself) while handling last child
-        validate_blob_children0(child_idx, src_filepath, stats).await?;
+        validate_blob_children0(dirs, child_idx, src_filepath, stats).await?;
     }
     Ok(())
 }
 
 use futures::future::*;
 fn validate_blob_children0<'a, P>(
+    dirs: Arc<StoreDirs>,
     child_idx: usize,
     src_filepath: P,
     stats: Arc<Stats>,
@@ -65,18 +410,22 @@ where
 {
     if stats.child_count(child_idx) == 1 {
         // leaf node
-        validate_blob_delta_null(child_idx, src_filepath, stats).boxed()
+        let f =
+            async move { validate_blob_delta_null(&dirs, child_idx, src_filepath, stats).await };
+        f.boxed()
     } else {
         // non-leaf node
         let f = async move {
-            let tmpfile = validate_blob_delta(child_idx, src_filepath, stats.clone()).await?;
-            validate_blob_children(child_idx, tmpfile, stats).await
+            let tmpfile =
+                validate_blob_delta(&dirs, child_idx, src_filepath, stats.clone()).await?;
+            validate_blob_children(dirs, child_idx, tmpfile, stats).await
         };
         f.boxed()
     }
 }
 
 async fn validate_blob_delta<P>(
+    dirs: &StoreDirs,
     idx: usize,
     src_filepath: P,
     stats: Arc<Stats>,
@@ -84,22 +433,28 @@ async fn validate_blob_delta<P>(
 where
     P: AsRef<Path>,
 {
-    let dst_file = NamedTempFile::new_in(&tmpdir())?;
-    let dst_file = validate_blob_delta0(idx, src_filepath, &stats, Some(dst_file))
+    let dst_file = NamedTempFile::new_in(dirs.tmpdir())?;
+    let dst_file = validate_blob_delta0(dirs, idx, src_filepath, &stats, Some(dst_file))
         .await?
         .unwrap();
     Ok(dst_file)
 }
 
-async fn validate_blob_delta_null<P>(idx: usize, src_filepath: P, stats: Arc<Stats>) -> Result<()>
+async fn validate_blob_delta_null<P>(
+    dirs: &StoreDirs,
+    idx: usize,
+    src_filepath: P,
+    stats: Arc<Stats>,
+) -> Result<()>
 where
     P: AsRef<Path>,
 {
-    validate_blob_delta0(idx, src_filepath, &stats, None).await?;
+    validate_blob_delta0(dirs, idx, src_filepath, &stats, None).await?;
     Ok(())
 }
 
 async fn validate_blob_delta0<P>(
+    dirs: &StoreDirs,
     idx: usize,
     src_filepath: P,
     stats: &Stats,
@@ -109,27 +464,38 @@ where
     P: AsRef<Path>,
 {
     let blob = &stats.blobs[idx];
-    let delta_filepath = filepath(&blob.store_hash);
+    let delta_filepath = dirs.filepath(&blob.store_hash);
+
+    // leaves are decoded to a file only when it is kept on mismatch
+    let leaf = dst_file.is_none();
+    let dst_file = match dst_file {
+        None if keep_temp() => Some(NamedTempFile::new_in(dirs.tmpdir())?),
+        dst_file => dst_file,
+    };
 
     let sw = Stopwatch::start_new();
     let mode = delta::ProcessMode::Decode;
 
-    let (_input_meta, dst_meta) = {
+    let dst_meta = {
         // mmap based
         let input_file = rw::MmapBuf::from_path(&delta_filepath)?;
-        let src_file = rw::MmapBuf::from_path(src_filepath)?;
+        let src_file = rw::MmapBuf::from_path(src_filepath.as_ref())?;
 
-        match dst_file {
+        let res = match dst_file {
             Some(ref file) => {
                 let dst_file =
                     rw::MmapBufMut::from_path_len(file.path(), blob.content_size as usize)?;
-                delta::delta(mode, src_file, input_file, dst_file).await?
+                delta::delta(mode, src_file, input_file, dst_file).await
             }
-            None => delta::delta(mode, src_file, input_file, tokio::io::sink()).await?,
-        }
+            None => delta::delta(mode, src_file, input_file, tokio::io::sink()).await,
+        };
+        res.map_err(|error| Error::Delta {
+            filename: blob.filename.clone(),
+            error,
+        })?
     };
 
-    let throughput = 1000 * dst_meta.len() / sw.elapsed_ms() as u64;
+    let throughput = 1000 * dst_meta.len() / (sw.elapsed_ms() as u64).max(1);
     debug!(
         "validate took={}ms {}/s filename={}",
         sw.elapsed_ms(),
@@ -137,8 +503,211 @@ where
         blob.filename
     );
 
-    assert_eq!(blob.content_hash, dst_meta.digest());
-    assert_eq!(blob.content_size, dst_meta.len());
+    if blob.content_hash != dst_meta.digest() {
+        if let Some(file) = &dst_file {
+            keep_decode_files(
+                dirs,
+                blob,
+                src_filepath.as_ref(),
+                Path::new(&delta_filepath),
+                file.path(),
+            )?;
+        }
+        return Err(Error::ChecksumMismatch {
+            filename: blob.filename.clone(),
+            expected: blob.content_hash.clone(),
+            actual: dst_meta.digest(),
+        });
+    }
+    if blob.content_size != dst_meta.len() {
+        return Err(crate::format_err!(
+            "content size mismatch: filename={}, expected={}, actual={}",
+            blob.filename,
+            blob.content_size,
+            dst_meta.len()
+        ));
+    }
 
+    if leaf {
+        return Ok(None);
+    }
     Ok(dst_file)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+
+    fn sampled_ids(blobs: &[Blob], fraction: f64, seed: u64) -> Vec<u32> {
+        sample_blobs(blobs.to_vec(), fraction, seed)
+            .into_iter()
+            .map(|blob| blob.id)
+            .collect()
+    }
+
+    #[test]
+    fn sample() {
+        // genesis `a`, root `b` with its alias, 1000 leaves
+        let mut blobs = vec![
+            blob(1, "a", None, 10),
+            blob(2, "b", Some("a"), 10),
+            blob(3, "b", None, 10),
+        ];
+        for id in 4..1004 {
+            blobs.push(blob(id, &format!("l{}", id), Some("b"), 10));
+        }
+
+        let ids = sampled_ids(&blobs, 0.1, 1);
+        assert_eq!(ids[..3], [1, 2, 3]);
+        let leaves = ids.len() - 3;
+        assert!(leaves > 50 && leaves < 150, "leaves={}", leaves);
+
+        assert_eq!(ids, sampled_ids(&blobs, 0.1, 1));
+        assert_ne!(ids, sampled_ids(&blobs, 0.1, 2));
+
+        assert_eq!(sampled_ids(&blobs, 0.0, 1), vec![1, 2, 3]);
+        assert_eq!(sampled_ids(&blobs, 1.0, 1).len(), blobs.len());
+    }
+
+    #[test]
+    fn gz_stored() {
+        with_store(|conn, dir| {
+            let mut rng = Rng::new(3);
+            let body = rng.body();
+            let path = write_file(dir, "log.gz", &random_gz(&mut rng, &body));
+            push(conn, &path, FileType::Gz).unwrap();
+            let path = write_file(dir, "app.tar", &body);
+            push(conn, &path, FileType::Plain).unwrap();
+
+            validate_gz_stored(conn, "log.gz").unwrap();
+            assert!(validate_gz_stored(conn, "app.tar").is_err());
+            assert!(validate_gz_stored(conn, "unknown.gz").is_err());
+
+            let blob = db::by_filename(conn, "log.gz").unwrap().pop().unwrap();
+            let path = filepath(&blob.store_hash);
+            let mut corrupted = std::fs::read(&path).unwrap();
+            *corrupted.last_mut().unwrap() ^= 0xff;
+            std::fs::write(&path, &corrupted).unwrap();
+            let err = validate_gz_stored(conn, "log.gz").unwrap_err();
+            assert!(matches!(err, Error::ChecksumMismatch { .. }), "{:?}", err);
+
+            // a truncated object decodes to other content as well
+            corrupted.pop();
+            std::fs::write(&path, &corrupted).unwrap();
+            let err = validate_gz_stored(conn, "log.gz").unwrap_err();
+            assert!(matches!(err, Error::ChecksumMismatch { .. }), "{:?}", err);
+        });
+    }
+
+    #[test]
+    fn chain() {
+        with_store(|conn, dir| {
+            for i in 0..3 {
+                let body = format!("hello, world{}", "!".repeat(i));
+                let path = write_file(dir, &format!("app-{}.tar", i), body.as_bytes());
+                push(conn, &path, FileType::Plain).unwrap();
+            }
+            let blobs = db::all(conn).unwrap();
+            for blob in &blobs {
+                let report = validate_chain(conn, &blob.content_hash).unwrap();
+                assert!(report.ok, "{:?}", report);
+            }
+
+            let delta = blobs.iter().find(|blob| !blob.is_root()).unwrap();
+            let path = filepath(&delta.store_hash);
+            let mut corrupted = std::fs::read(&path).unwrap();
+            *corrupted.last_mut().unwrap() ^= 0xff;
+            std::fs::write(&path, &corrupted).unwrap();
+
+            for blob in &blobs {
+                let chain = db::chain(conn, &blob.content_hash).unwrap();
+                let report = validate_chain(conn, &blob.content_hash).unwrap();
+                let broken = chain.iter().any(|b| b.store_hash == delta.store_hash);
+                assert_eq!(report.ok, !broken, "{:?}", report);
+                if broken {
+                    assert_ne!(report.exit_code, 0);
+                    assert!(report.checked.unwrap().contains(&delta.id));
+                }
+
+                let (_report, res) = validate_chain_with_error(conn, &blob.content_hash).unwrap();
+                assert_eq!(res.is_err(), broken);
+                if let Err(e) = res {
+                    assert_eq!(exit_code(&e), report.exit_code);
+                }
+            }
+            assert!(validate_chain(conn, "unknown").is_err());
+        });
+    }
+
+    #[test]
+    fn content_size_mismatch() {
+        with_store(|conn, dir| {
+            let v1 = write_file(dir, "app-1.tar", b"hello, world");
+            let v2 = write_file(dir, "app-2.tar", b"hello, world!!");
+            push(conn, &v1, FileType::Plain).unwrap();
+            push(conn, &v2, FileType::Plain).unwrap();
+
+            // the delta decodes to its content hash, but the recorded size is wrong
+            let mut blobs = db::all(conn).unwrap();
+            let delta = blobs.iter_mut().find(|blob| !blob.is_root()).unwrap();
+            delta.content_size += 1;
+            let err = validate_blob_root(0, Stats::from_blobs(blobs)).unwrap_err();
+            assert!(
+                err.to_string().contains("content size mismatch"),
+                "{:?}",
+                err
+            );
+        });
+    }
+
+    #[test]
+    fn parent_hashes() {
+        with_store(|conn, _dir| {
+            for blob in &[
+                blob(1, "a", None, 10),
+                blob(2, "b", Some("a"), 10),
+                blob(3, "c", Some("b"), 10),
+                blob(4, "d", Some("x"), 10),
+                blob(5, "e", Some("x"), 10),
+            ] {
+                db::insert(conn, blob).unwrap();
+            }
+            let report = verify_parent_hashes(conn).unwrap();
+            assert_eq!(report.total_deltas, 4);
+            assert_eq!(report.broken_refs, vec!["x".to_owned()]);
+        });
+    }
+
+    #[test]
+    fn fsck_quarantine() {
+        with_store(|conn, _dir| {
+            for blob in &[
+                blob(1, "a", None, 10),
+                blob(2, "b", Some("a"), 10),
+                blob(3, "c", Some("x"), 10),
+                blob(4, "d", Some("c"), 10),
+            ] {
+                db::insert(conn, blob).unwrap();
+            }
+
+            let report = fsck(conn, false).unwrap();
+            let ids = report.dangling.iter().map(|b| b.id).collect::<Vec<_>>();
+            assert_eq!(ids, vec![3]);
+            assert_eq!(report.quarantined, 0);
+            assert_eq!(db::count(conn).unwrap(), 4);
+
+            // `d` is dangling once `c` is quarantined
+            let report = fsck(conn, true).unwrap();
+            assert_eq!(report.quarantined, 2);
+            assert_eq!(db::count(conn).unwrap(), 2);
+            let ids = db::quarantined(conn)
+                .unwrap()
+                .iter()
+                .map(|b| b.id)
+                .collect::<Vec<_>>();
+            assert_eq!(ids, vec![3, 4]);
+            assert!(fsck(conn, false).unwrap().dangling.is_empty());
+        });
+    }
+}
@@ -0,0 +1,415 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use super::*;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone)]
+pub struct WatchOptions {
+    /// only push files whose name matches this glob, `*` and `?` are supported
+    pub pattern: Option<String>,
+    /// remove files once they are pushed
+    pub delete_after: bool,
+    /// move files into `done/` under the watched directory once they are pushed
+    pub move_done: bool,
+    /// a file is pushed after its size has not changed for this long
+    pub stable_duration: Duration,
+    /// push every file as this type, instead of detecting it from the extension
+    pub ty: Option<FileType>,
+    /// push files as soon as they are closed after writing, without waiting for `stable_duration`
+    pub push_on_close: bool,
+    /// return the first failed push instead of retrying it
+    pub fail_fast: bool,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        WatchOptions {
+            pattern: None,
+            delete_after: false,
+            move_done: false,
+            stable_duration: Duration::from_secs(5),
+            ty: None,
+            push_on_close: false,
+            fail_fast: false,
+        }
+    }
+}
+
+struct Pending {
+    size: u64,
+    stable_since: Instant,
+    /// closed after writing, so it is pushed without waiting for its size to be stable
+    complete: bool,
+    attempts: u32,
+    retry_at: Instant,
+}
+
+impl Pending {
+    fn new(now: Instant) -> Self {
+        Pending {
+            size: 0,
+            stable_since: now,
+            complete: false,
+            attempts: 0,
+            retry_at: now,
+        }
+    }
+}
+
+fn backoff(attempts: u32) -> Duration {
+    let secs = 1u64 << attempts.min(16);
+    Duration::from_secs(secs).min(MAX_BACKOFF)
+}
+
+/// matches a file name against a glob pattern with `*` and `?`
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let p = pattern.chars().collect::<Vec<_>>();
+    let n = name.chars().collect::<Vec<_>>();
+
+    let (mut pi, mut ni) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while ni < n.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == n[ni]) {
+            pi += 1;
+            ni += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ni));
+            pi += 1;
+        } else if let Some((star_pi, star_ni)) = star {
+            // let the last `*` consume one more char
+            pi = star_pi + 1;
+            ni = star_ni + 1;
+            star = Some((star_pi, star_ni + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+fn is_candidate(path: &Path, opts: &WatchOptions) -> bool {
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name,
+        None => return false,
+    };
+    if name.starts_with('.') {
+        return false;
+    }
+    if let Some(pattern) = &opts.pattern {
+        if !glob_match(pattern, name) {
+            return false;
+        }
+    }
+    if opts.ty.is_none() && FileType::from_path(path).is_none() {
+        debug!("watch: unknown file type, ignoring {:?}", path);
+        return false;
+    }
+    true
+}
+
+fn finish(dir: &Path, path: &Path, opts: &WatchOptions) -> Result<()> {
+    if opts.delete_after {
+        std::fs::remove_file(path)?;
+    } else if opts.move_done {
+        let done_dir = dir.join("done");
+        std::fs::create_dir_all(&done_dir)?;
+        let file_name = path.file_name().expect("no file name");
+        std::fs::rename(path, done_dir.join(file_name))?;
+    }
+    Ok(())
+}
+
+fn push_pending(conn: &mut db::Conn, dir: &Path, path: &Path, opts: &WatchOptions) -> Result<()> {
+    let filepath = path
+        .to_str()
+        .ok_or_else(|| crate::format_err!("non-utf8 path: {:?}", path))?;
+    let ty = match opts.ty {
+        Some(ty) => ty,
+        None => FileType::from_path(path).expect("unknown file type"),
+    };
+
+    let _lock = lock_store()?;
+    push(conn, filepath, ty)?;
+    finish(dir, path, opts)
+}
+
+/// pushes files appearing in `dir` until SIGINT. a file is pushed once its size is stable, and
+/// failed pushes are retried with exponential backoff, see `WatchOptions::fail_fast`.
+pub fn watch(conn: &mut db::Conn, dir: &str, opts: &WatchOptions) -> Result<()> {
+    let shutdown = cancel_on_signal()?;
+    watch_until(conn, Path::new(dir), opts, &shutdown)
+}
+
+/// pushes files as `ty` as soon as they are closed after writing to `dir`, with a connection from
+/// `conn_factory`. unlike `watch`, the first error is returned instead of retried. returns when
+/// the watcher stops or on SIGINT.
+pub fn watch_and_push<F>(conn_factory: F, dir: &Path, ty: FileType) -> Result<()>
+where
+    F: Fn() -> Result<db::Conn>,
+{
+    let opts = WatchOptions {
+        ty: Some(ty),
+        push_on_close: true,
+        fail_fast: true,
+        ..Default::default()
+    };
+    let mut conn = conn_factory()?;
+    let shutdown = cancel_on_signal()?;
+    watch_until(&mut conn, dir, &opts, &shutdown)
+}
+
+/// `watch` until `shutdown` is set
+fn watch_until(
+    conn: &mut db::Conn,
+    dir: &Path,
+    opts: &WatchOptions,
+    shutdown: &AtomicBool,
+) -> Result<()> {
+    let (tx, rx) = channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    info!("watch: watching {:?}", dir);
+
+    let mut pending = HashMap::<PathBuf, Pending>::new();
+
+    // files created before the watcher started
+    let now = Instant::now();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && is_candidate(&path, opts) {
+            pending.insert(path, Pending::new(now));
+        }
+    }
+
+    while !shutdown.load(Ordering::SeqCst) {
+        match rx.recv_timeout(POLL_INTERVAL) {
+            Ok(Ok(event)) => {
+                let complete = opts.push_on_close && is_complete(&event.kind);
+                if complete || matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    let now = Instant::now();
+                    for path in event.paths {
+                        if is_candidate(&path, opts) {
+                            let p = pending.entry(path).or_insert_with(|| Pending::new(now));
+                            p.complete |= complete;
+                        }
+                    }
+                }
+            }
+            Ok(Err(e)) => warn!("watch: {}", e),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(crate::format_err!("watcher stopped"));
+            }
+        }
+
+        let mut paths = pending.keys().cloned().collect::<Vec<_>>();
+        paths.sort();
+        for path in paths {
+            if shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+
+            let now = Instant::now();
+            let size = match std::fs::metadata(&path) {
+                Ok(meta) if meta.is_file() => meta.len(),
+                _ => {
+                    pending.remove(&path);
+                    continue;
+                }
+            };
+
+            let p = pending.get_mut(&path).expect("no pending file");
+            if !p.complete {
+                if size != p.size {
+                    p.size = size;
+                    p.stable_since = now;
+                    continue;
+                }
+                if now.duration_since(p.stable_since) < opts.stable_duration {
+                    continue;
+                }
+            }
+            if now < p.retry_at {
+                continue;
+            }
+
+            match push_pending(conn, dir, &path, opts) {
+                Ok(()) => {
+                    info!("watch: pushed {:?}", path);
+                    pending.remove(&path);
+                }
+                Err(e) if opts.fail_fast => return Err(e),
+                Err(e) => {
+                    p.attempts += 1;
+                    p.retry_at = Instant::now() + backoff(p.attempts);
+                    warn!(
+                        "watch: failed to push {:?}, attempts={}: {}",
+                        path, p.attempts, e
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// whether `event` marks a file as complete. inotify reports when a writer closes the file, other
+/// backends only report creation. files moved into the directory are complete when they appear.
+fn is_complete(kind: &EventKind) -> bool {
+    use notify::event::{AccessKind, AccessMode, CreateKind, ModifyKind, RenameMode};
+
+    match kind {
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => true,
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => true,
+        EventKind::Create(CreateKind::File) | EventKind::Create(CreateKind::Any) => {
+            !cfg!(target_os = "linux")
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+    use std::io::Write;
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("*.apk", "app-1.apk"));
+        assert!(glob_match("app-?.apk", "app-1.apk"));
+        assert!(glob_match("app-*-*.apk", "app-us-1.apk"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("*.apk", "app-1.apk.part"));
+        assert!(!glob_match("app-?.apk", "app-10.apk"));
+    }
+
+    #[test]
+    fn push_dropped_files() {
+        with_store(|conn, dir| {
+            let drop_dir = dir.join("drop");
+            std::fs::create_dir(&drop_dir).unwrap();
+            let opts = WatchOptions {
+                move_done: true,
+                stable_duration: Duration::from_millis(300),
+                ty: Some(FileType::Plain),
+                ..Default::default()
+            };
+            let shutdown = AtomicBool::new(false);
+            let done = drop_dir.join("done");
+
+            std::thread::scope(|s| {
+                let watcher = s.spawn(|| watch_until(conn, &drop_dir, &opts, &shutdown));
+
+                // written in two parts, slower than the poll interval
+                let mut file = std::fs::File::create(drop_dir.join("app-1.bin")).unwrap();
+                file.write_all(b"hello, ").unwrap();
+                file.sync_all().unwrap();
+                std::thread::sleep(Duration::from_millis(150));
+                file.write_all(b"world").unwrap();
+                drop(file);
+                std::fs::write(drop_dir.join("app-2.bin"), b"hello, world!!").unwrap();
+
+                let deadline = Instant::now() + Duration::from_secs(30);
+                let pushed = || done.join("app-1.bin").exists() && done.join("app-2.bin").exists();
+                while !pushed() && Instant::now() < deadline {
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                shutdown.store(true, Ordering::SeqCst);
+                watcher.join().unwrap().unwrap();
+                assert!(pushed(), "files are not pushed");
+            });
+
+            // the first part alone is never pushed
+            for blob in db::by_filename(conn, "app-1.bin").unwrap() {
+                assert_eq!(blob.content_size, 12);
+            }
+            assert!(!db::by_filename(conn, "app-2.bin").unwrap().is_empty());
+            let out = dir.join("out");
+            get(conn, "app-1.bin", out.to_str().unwrap(), false).unwrap();
+            assert_eq!(std::fs::read(&out).unwrap(), b"hello, world");
+        });
+    }
+
+    #[test]
+    fn push_written_files() {
+        with_store(|conn, dir| {
+            let drop_dir = dir.join("drop");
+            std::fs::create_dir(&drop_dir).unwrap();
+            // longer than the test, so files are only pushed once they are closed
+            let opts = WatchOptions {
+                pattern: Some("app-*".to_owned()),
+                move_done: true,
+                stable_duration: Duration::from_secs(3600),
+                push_on_close: true,
+                fail_fast: true,
+                ..Default::default()
+            };
+            let shutdown = AtomicBool::new(false);
+            let done = drop_dir.join("done");
+
+            let res = std::thread::scope(|s| {
+                let watcher = s.spawn(|| watch_until(conn, &drop_dir, &opts, &shutdown));
+
+                let mut file = std::fs::File::create(drop_dir.join("app-1.tar")).unwrap();
+                file.write_all(b"hello, ").unwrap();
+                file.sync_all().unwrap();
+                std::thread::sleep(Duration::from_millis(100));
+                file.write_all(b"world").unwrap();
+                drop(file);
+
+                let deadline = Instant::now() + Duration::from_secs(30);
+                while !done.join("app-1.tar").exists() && Instant::now() < deadline {
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+                // not a zip archive, so the push fails and stops the watcher
+                std::fs::write(drop_dir.join("app-2.zip"), b"hello, world!!").unwrap();
+                while !watcher.is_finished() && Instant::now() < deadline {
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+                shutdown.store(true, Ordering::SeqCst);
+                watcher.join().unwrap()
+            });
+            assert!(res.is_err(), "watcher does not stop on the first error");
+
+            // pushed once it is closed, not at creation
+            let blobs = db::by_filename(conn, "app-1.tar").unwrap();
+            assert!(!blobs.is_empty());
+            for blob in blobs {
+                assert_eq!(blob.content_size, 12);
+            }
+            assert!(db::by_filename(conn, "app-2.zip").unwrap().is_empty());
+            assert!(drop_dir.join("app-2.zip").exists());
+        });
+    }
+
+    #[test]
+    fn fail_fast_waits_for_stable_size() {
+        with_store(|conn, dir| {
+            let drop_dir = dir.join("drop");
+            std::fs::create_dir(&drop_dir).unwrap();
+            // not a zip archive, so the push fails
+            std::fs::write(drop_dir.join("app-1.zip"), b"hello, world").unwrap();
+            let opts = WatchOptions {
+                stable_duration: Duration::from_secs(1),
+                fail_fast: true,
+                ..Default::default()
+            };
+            let shutdown = AtomicBool::new(false);
+
+            // stopping on error does not push the file before its size is stable
+            let start = Instant::now();
+            let res = watch_until(conn, &drop_dir, &opts, &shutdown);
+            assert!(res.is_err());
+            assert!(start.elapsed() >= opts.stable_duration);
+            assert!(drop_dir.join("app-1.zip").exists());
+        });
+    }
+}
@@ -1,40 +1,132 @@
+use std::collections::HashMap;
 use std::io;
 use std::path::Path;
 use std::sync::Arc;
 
 use futures::prelude::*;
 use log::*;
-use pbr::ProgressBar;
 
+use crate::progress::Progress;
 use crate::rw::*;
 
-struct TarEntry {
-    header: tar::Header,
-    data: Vec<u8>,
+/// normalization applied when converting a zip archive to a tar stream. the defaults keep
+/// entries as they are in the zip archive.
+#[derive(Debug, Clone, Default)]
+pub struct StoreConfig {
+    /// sort entries by name instead of keeping the central directory order
+    pub normalize_zip_order: bool,
+    /// set mtime of all entries to unix epoch
+    pub normalize_mtime: bool,
+    /// ignore unix modes of entries, use 0o644 for files and 0o755 for directories
+    pub normalize_permissions: bool,
+    /// skip entries whose name starts with one of these prefixes
+    pub exclude_entries: Vec<String>,
+    /// write entries of members which are zip archives themselves, e.g. `base-master.apk` of an
+    /// .apks, as `{member}/{entry}` instead of the member. entries are sorted by name, one level
+    /// deep. used for app bundles, where member offsets shuffle between builds.
+    pub explode_nested: bool,
 }
 
-fn zip_to_tarentry<R>(zipar: &mut zip::ZipArchive<R>, idx: usize) -> io::Result<TarEntry>
+impl StoreConfig {
+    fn excluded(&self, name: &str) -> bool {
+        self.exclude_entries
+            .iter()
+            .any(|p| name.starts_with(p.as_str()))
+    }
+}
+
+/// indices of zip entries to store, in the order they are written to tar
+fn entry_order<R>(zipar: &mut zip::ZipArchive<R>, config: &StoreConfig) -> io::Result<Vec<usize>>
 where
     R: io::Read + io::Seek,
 {
-    let mut file = zipar.by_index(idx)?;
-    let filename = file.name().to_owned();
+    let mut entries = Vec::with_capacity(zipar.len());
+    for i in 0..zipar.len() {
+        let name = zipar.by_index_raw(i)?.name().to_owned();
+        if !config.excluded(&name) {
+            entries.push((name, i));
+        }
+    }
+    if config.normalize_zip_order || config.explode_nested {
+        entries.sort();
+    }
+    Ok(entries.into_iter().map(|(_name, i)| i).collect())
+}
+
+struct TarEntry {
+    header: tar::Header,
+    data: Vec<u8>,
+    /// size of the entry in the zip archive
+    compressed_size: u64,
+}
 
+/// (name, offset of its data in the tar stream, compressed size in the zip archive) of each tar
+/// entry written from a zip archive, in tar order
+pub type SourceMap = Vec<(String, u64, u64)>;
+
+/// counts bytes written, for offsets of tar entries
+struct CountWrite<W> {
+    w: W,
+    count: u64,
+}
+
+impl<W: io::Write> io::Write for CountWrite<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.w.write(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+/// entry buffers are preallocated up to this size. sizes in a zip archive are not trusted, a
+/// corrupted one may claim gigabytes.
+const MAX_PREALLOC: u64 = 16 * 1024 * 1024;
+
+/// reads a zip entry, checking its size against the one in the archive
+fn read_entry(file: &mut zip::read::ZipFile, name: &str) -> io::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(file.size().min(MAX_PREALLOC) as usize);
+    io::copy(file, &mut data)?;
+    if data.len() as u64 != file.size() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "size mismatch of zip entry {:?}: expected={} actual={}",
+                name,
+                file.size(),
+                data.len()
+            ),
+        ));
+    }
+    Ok(data)
+}
+
+fn entry_header(
+    file: &zip::read::ZipFile,
+    path: &str,
+    config: &StoreConfig,
+) -> io::Result<tar::Header> {
     let mut header = tar::Header::new_ustar();
-    header.set_path(&filename)?;
+    header.set_path(path)?;
     header.set_size(file.size());
 
-    if let Some(mode) = file.unix_mode() {
-        header.set_mode(mode);
-    } else {
-        if file.is_dir() {
-            header.set_mode(0o755);
-        } else {
-            header.set_mode(0o644);
+    match file.unix_mode() {
+        Some(mode) if !config.normalize_permissions => header.set_mode(mode),
+        _ => {
+            if file.is_dir() {
+                header.set_mode(0o755);
+            } else {
+                header.set_mode(0o644);
+            }
         }
     }
 
-    if let Some(t) = file.last_modified() {
+    if config.normalize_mtime {
+        header.set_mtime(0);
+    } else if let Some(t) = file.last_modified() {
         use std::convert::TryFrom;
 
         if let Ok(unixtime) = time::OffsetDateTime::try_from(t) {
@@ -43,54 +135,116 @@ where
     }
 
     header.set_cksum();
+    Ok(header)
+}
 
-    let mut data = Vec::with_capacity(file.size() as usize);
-    io::copy(&mut file, &mut data)?;
+/// entries of a nested zip archive, or None if `data` is not a zip archive
+fn explode_entry(
+    name: &str,
+    data: &[u8],
+    config: &StoreConfig,
+) -> io::Result<Option<Vec<TarEntry>>> {
+    if !data.starts_with(b"PK\x03\x04") {
+        return Ok(None);
+    }
+    let mut zipar = match zip::ZipArchive::new(io::Cursor::new(data)) {
+        Ok(zipar) => zipar,
+        Err(_e) => return Ok(None),
+    };
 
-    Ok(TarEntry { header, data })
+    let mut order = (0..zipar.len())
+        .map(|i| Ok((zipar.by_index_raw(i)?.name().to_owned(), i)))
+        .collect::<io::Result<Vec<_>>>()?;
+    order.sort();
+
+    let mut entries = Vec::with_capacity(order.len());
+    for (nested_name, i) in order {
+        let mut file = zipar.by_index(i)?;
+        let header = entry_header(&file, &format!("{}/{}", name, nested_name), config)?;
+        let data = read_entry(&mut file, &nested_name)?;
+        entries.push(TarEntry {
+            header,
+            data,
+            compressed_size: file.compressed_size(),
+        });
+    }
+    Ok(Some(entries))
+}
+
+fn zip_to_tarentry<R>(
+    zipar: &mut zip::ZipArchive<R>,
+    idx: usize,
+    config: &StoreConfig,
+) -> io::Result<Vec<TarEntry>>
+where
+    R: io::Read + io::Seek,
+{
+    let mut file = zipar.by_index(idx)?;
+    let filename = file.name().to_owned();
+    let header = entry_header(&file, &filename, config)?;
+    let data = read_entry(&mut file, &filename)?;
+    let compressed_size = file.compressed_size();
+
+    if config.explode_nested {
+        if let Some(entries) = explode_entry(&filename, &data, config)? {
+            return Ok(entries);
+        }
+    }
+    Ok(vec![TarEntry {
+        header,
+        data,
+        compressed_size,
+    }])
 }
 
 #[allow(unused)]
-fn zip_to_tar_par<P: AsRef<Path>, W: io::Write>(src_path: P, dst: W) -> io::Result<()> {
+fn zip_to_tar_par<P: AsRef<Path>, W: io::Write>(
+    src_path: P,
+    dst: W,
+    config: &StoreConfig,
+) -> io::Result<()> {
     const PAR_JOBS: usize = 8;
 
     let mut files = Vec::new();
-    let mut file_len = 0;
+    let mut order = Vec::new();
     for _ in 0..PAR_JOBS {
         let file = std::fs::File::open(&src_path)?;
-        let zipar = zip::ZipArchive::new(std::io::BufReader::new(file))?;
-        file_len = zipar.len();
+        let mut zipar = zip::ZipArchive::new(std::io::BufReader::new(file))?;
+        if files.is_empty() {
+            order = entry_order(&mut zipar, config)?;
+        }
         let file = Arc::new(std::sync::RwLock::new(zipar));
         files.push(file);
     }
 
+    let file_len = order.len();
     let mut f_list = Vec::new();
-    for i in 0..file_len {
-        let file_idx = i % PAR_JOBS;
+    for (n, i) in order.into_iter().enumerate() {
+        let file_idx = n % PAR_JOBS;
         let file_lock = files[file_idx].clone();
-        f_list.push((i, file_lock));
+        f_list.push((i, file_lock, config.clone()));
     }
 
-    let mut pb = ProgressBar::new(file_len as u64);
+    let mut pb = Progress::new(file_len as u64);
     let mut ar = tar::Builder::new(dst);
     let res = stream::iter(f_list)
-        .map(|(i, file_lock)| {
+        .map(|(i, file_lock, config)| {
             tokio::task::spawn_blocking(move || {
                 let file = &mut file_lock.write().expect("failed to acquire lock");
-                let res = zip_to_tarentry(file, i);
+                let res = zip_to_tarentry(file, i, &config);
                 res
             })
             .map(|res| res.expect("failed to spawn"))
         })
         .buffered(PAR_JOBS * 16)
-        .try_fold((pb, ar), |(mut pb, mut ar), entry| {
-            match ar.append(&entry.header, entry.data.as_slice()) {
-                Ok(_) => {
-                    pb.inc();
-                    future::ready(Ok((pb, ar)))
+        .try_fold((pb, ar), |(mut pb, mut ar), entries| {
+            for entry in entries {
+                if let Err(e) = ar.append(&entry.header, entry.data.as_slice()) {
+                    return future::ready(Err(e));
                 }
-                Err(e) => future::ready(Err(e)),
             }
+            pb.inc();
+            future::ready(Ok((pb, ar)))
         });
 
     let rt = tokio::runtime::Runtime::new()?;
@@ -100,21 +254,58 @@ fn zip_to_tar_par<P: AsRef<Path>, W: io::Write>(src_path: P, dst: W) -> io::Resu
     Ok(())
 }
 
+/// converts entries one by one. returns the source map, see `store_zip_with_source_map`.
 #[allow(unused)]
-fn zip_to_tar<R: io::Read + io::Seek, W: io::Write>(src: R, dst: W) -> io::Result<()> {
+fn zip_to_tar<R: io::Read + io::Seek, W: io::Write>(
+    src: R,
+    dst: W,
+    config: &StoreConfig,
+) -> io::Result<SourceMap> {
     let mut zip = zip::ZipArchive::new(src)?;
-    let mut ar = tar::Builder::new(dst);
+    let mut ar = tar::Builder::new(CountWrite { w: dst, count: 0 });
 
-    let mut pb = ProgressBar::new(zip.len() as u64);
+    let order = entry_order(&mut zip, config)?;
+    let mut pb = Progress::new(order.len() as u64);
 
-    for i in 0..zip.len() {
-        let entry = zip_to_tarentry(&mut zip, i)?;
-        ar.append(&entry.header, entry.data.as_slice())?;
+    let mut source_map = Vec::with_capacity(order.len());
+    for i in order {
+        for entry in zip_to_tarentry(&mut zip, i, config)? {
+            ar.append(&entry.header, entry.data.as_slice())?;
+            // data is padded to a whole block, after the header blocks
+            let padded = (entry.data.len() as u64).div_ceil(512) * 512;
+            let offset = ar.get_ref().count - padded;
+            let name = entry.header.path()?.to_string_lossy().into_owned();
+            source_map.push((name, offset, entry.compressed_size));
+        }
         pb.inc();
     }
     pb.finish();
 
-    Ok(())
+    Ok(source_map)
+}
+
+/// `zip_to_tar` of an in-memory archive, for fuzzing
+pub fn zip_to_tar_bytes(data: &[u8], config: &StoreConfig) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    zip_to_tar(io::Cursor::new(data), &mut out, config)?;
+    Ok(out)
+}
+
+/// `store_zip_reproducible` of an archive read through `src`, e.g. `http::RangeReader`. entries
+/// are converted one by one, and entries skipped by `exclude_entries` are never read.
+pub fn store_zip_reader<R, P>(
+    src: R,
+    dst_path: P,
+    config: &StoreConfig,
+) -> io::Result<WriteMetadata>
+where
+    R: io::Read + io::Seek,
+    P: AsRef<Path>,
+{
+    let dst_file = std::fs::File::create(dst_path.as_ref())?;
+    let mut dst_file = HashRW::new(dst_file);
+    zip_to_tar(src, io::BufWriter::new(&mut dst_file), config)?;
+    Ok(dst_file.meta())
 }
 
 pub fn store_zip<P1, P2>(
@@ -122,6 +313,32 @@ pub fn store_zip<P1, P2>(
     dst_path: P2,
     parallel: bool,
 ) -> std::io::Result<WriteMetadata>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    store_zip0(input_path, dst_path, parallel, &StoreConfig::default())
+}
+
+/// converts a zip archive to a tar stream, applying all normalization steps in `config`
+pub fn store_zip_reproducible<P1, P2>(
+    input_path: P1,
+    dst_path: P2,
+    config: &StoreConfig,
+) -> std::io::Result<WriteMetadata>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    store_zip0(input_path, dst_path, true, config)
+}
+
+fn store_zip0<P1, P2>(
+    input_path: P1,
+    dst_path: P2,
+    parallel: bool,
+    config: &StoreConfig,
+) -> std::io::Result<WriteMetadata>
 where
     P1: AsRef<Path>,
     P2: AsRef<Path>,
@@ -139,11 +356,227 @@ where
         zip_to_tar_par(
             input_path,
             io::BufWriter::with_capacity(1024 * 1024 * 8, &mut dst_file),
+            config,
         )?;
     } else {
         let mut input_file = std::fs::File::open(input_path.as_ref())?;
-        zip_to_tar(&mut input_file, io::BufWriter::new(&mut dst_file))?;
+        zip_to_tar(&mut input_file, io::BufWriter::new(&mut dst_file), config)?;
     }
 
     Ok(dst_file.meta())
 }
+
+/// `store_zip` which also returns where each entry was written
+pub fn store_zip_with_source_map<P1, P2>(
+    input_path: P1,
+    dst_path: P2,
+) -> io::Result<(WriteMetadata, SourceMap)>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let dst_file = std::fs::File::create(dst_path.as_ref())?;
+    let mut dst_file = HashRW::new(dst_file);
+    let input_file = std::fs::File::open(input_path.as_ref())?;
+    let source_map = zip_to_tar(
+        io::BufReader::new(input_file),
+        io::BufWriter::new(&mut dst_file),
+        &StoreConfig::default(),
+    )?;
+    Ok((dst_file.meta(), source_map))
+}
+
+/// hashes each zip entry independently, keyed by entry name
+pub fn store_zip_entry_hashes<P>(input_path: P) -> io::Result<HashMap<String, String>>
+where
+    P: AsRef<Path>,
+{
+    let file = std::fs::File::open(input_path.as_ref())?;
+    let mut zip = zip::ZipArchive::new(io::BufReader::new(file))?;
+
+    let mut hashes = HashMap::with_capacity(zip.len());
+    for i in 0..zip.len() {
+        let mut file = zip.by_index(i)?;
+        let mut out = HashRW::new(io::sink());
+        io::copy(&mut file, &mut out)?;
+        hashes.insert(file.name().to_owned(), out.meta().digest());
+    }
+
+    Ok(hashes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+    use std::io::Write;
+
+    fn write_zip(path: &Path, entries: &[(&str, &str)], mtime: zip::DateTime) {
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(path).unwrap());
+        let options = zip::write::SimpleFileOptions::default()
+            .last_modified_time(mtime)
+            .unix_permissions(0o600);
+        for (name, body) in entries {
+            zip.start_file(*name, options).unwrap();
+            zip.write_all(body.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn reproducible() {
+        let dir = tempfile::tempdir().unwrap();
+        let (zip1, zip2) = (dir.path().join("1.zip"), dir.path().join("2.zip"));
+        let t1 = zip::DateTime::from_date_and_time(2020, 1, 1, 0, 0, 0).unwrap();
+        let t2 = zip::DateTime::from_date_and_time(2021, 6, 1, 12, 0, 0).unwrap();
+        write_zip(&zip1, &[("a.dex", "hello"), ("b.arsc", "world")], t1);
+        write_zip(
+            &zip2,
+            &[
+                ("META-INF/CERT.SF", "sig"),
+                ("b.arsc", "world"),
+                ("a.dex", "hello"),
+            ],
+            t2,
+        );
+
+        let store = |src: &Path, config: &StoreConfig| {
+            let dst = tempfile::NamedTempFile::new_in(dir.path()).unwrap();
+            store_zip_reproducible(src, dst.path(), config)
+                .unwrap()
+                .digest()
+        };
+
+        let config = StoreConfig::default();
+        assert_ne!(store(&zip1, &config), store(&zip2, &config));
+
+        let config = StoreConfig {
+            normalize_zip_order: true,
+            normalize_mtime: true,
+            normalize_permissions: true,
+            exclude_entries: vec!["META-INF/".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(store(&zip1, &config), store(&zip2, &config));
+    }
+
+    /// 512-byte blocks of `b` which also appear in `a`, a rough measure of what a delta can reuse
+    fn shared_blocks(a: &[u8], b: &[u8]) -> usize {
+        let blocks = a.chunks(512).collect::<std::collections::HashSet<_>>();
+        b.chunks(512).filter(|block| blocks.contains(block)).count()
+    }
+
+    #[test]
+    fn explode_nested() {
+        let dir = tempfile::tempdir().unwrap();
+        let (v1, v2) = (dir.path().join("1.apks"), dir.path().join("2.apks"));
+        std::fs::write(&v1, bundle_bytes(false)).unwrap();
+        std::fs::write(&v2, bundle_bytes(true)).unwrap();
+
+        let store = |src: &Path, explode_nested: bool| {
+            let dst = dir.path().join("out.tar");
+            let config = StoreConfig {
+                explode_nested,
+                ..Default::default()
+            };
+            store_zip_reproducible(src, &dst, &config).unwrap();
+            std::fs::read(&dst).unwrap()
+        };
+
+        let exploded = store(&v1, true);
+        let names = tar::Archive::new(exploded.as_slice())
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_str().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "splits/base-master.apk/AndroidManifest.xml",
+                "splits/base-master.apk/classes.dex",
+                "toc.pb",
+            ]
+        );
+
+        let plain_shared = shared_blocks(&store(&v1, false), &store(&v2, false));
+        let exploded_shared = shared_blocks(&exploded, &store(&v2, true));
+        assert!(
+            exploded_shared > 2 * plain_shared + 100,
+            "plain_shared={} exploded_shared={}",
+            plain_shared,
+            exploded_shared
+        );
+    }
+
+    #[test]
+    fn source_map() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("app.zip"), dir.path().join("app.tar"));
+        let t = zip::DateTime::from_date_and_time(2020, 1, 1, 0, 0, 0).unwrap();
+        let body = "hello, world\n".repeat(100);
+        let entries = [
+            ("a.dex", body.as_str()),
+            ("b/c.arsc", "world"),
+            ("d.txt", ""),
+        ];
+        write_zip(&src, &entries, t);
+
+        let (meta, source_map) = store_zip_with_source_map(&src, &dst).unwrap();
+        let tar = std::fs::read(&dst).unwrap();
+        assert_eq!(
+            meta.digest(),
+            store_zip(&src, dir.path().join("par.tar"), true)
+                .unwrap()
+                .digest()
+        );
+
+        let mut zip = zip::ZipArchive::new(std::fs::File::open(&src).unwrap()).unwrap();
+        assert_eq!(source_map.len(), entries.len());
+        for ((name, offset, compressed_size), (expected, body)) in source_map.iter().zip(&entries) {
+            assert_eq!(name, expected);
+            let offset = *offset as usize;
+            assert_eq!(&tar[offset..offset + body.len()], body.as_bytes());
+            assert_eq!(
+                *compressed_size,
+                zip.by_name(name).unwrap().compressed_size()
+            );
+        }
+    }
+
+    #[test]
+    fn entry_hashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.zip");
+
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        for (name, body) in &[("a.dex", "hello"), ("b.arsc", "world"), ("c.dex", "hello")] {
+            zip.start_file(*name, options).unwrap();
+            zip.write_all(body.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let hashes = store_zip_entry_hashes(&path).unwrap();
+        assert_eq!(hashes.len(), 3);
+        assert_eq!(hashes["a.dex"], hashes["c.dex"]);
+        assert_ne!(hashes["a.dex"], hashes["b.arsc"]);
+
+        let mut expected = HashRW::new(io::sink());
+        expected.write_all(b"world").unwrap();
+        assert_eq!(hashes["b.arsc"], expected.meta().digest());
+    }
+
+    #[test]
+    fn fuzz_regressions() {
+        let explode = StoreConfig {
+            explode_nested: true,
+            ..Default::default()
+        };
+        // all of the checked in inputs are broken, and should be rejected without panicking
+        for (path, data) in fuzz_corpus("zip_to_tar") {
+            for config in &[StoreConfig::default(), explode.clone()] {
+                assert!(zip_to_tar_bytes(&data, config).is_err(), "{:?}", path);
+            }
+        }
+    }
+}
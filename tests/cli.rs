@@ -0,0 +1,512 @@
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn cli(workdir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_cli"))
+        .env("WORKDIR", workdir)
+        .args(args)
+        .output()
+        .expect("failed to run cli")
+}
+
+/// pushes app-1.tar (genesis) and app-2.tar (blob 2, with delta blob 3)
+fn push_two_versions(workdir: &Path) {
+    let input_dir = workdir.join("input");
+    std::fs::create_dir_all(&input_dir).unwrap();
+    for (name, body) in &[
+        ("app-1.tar", "hello, world"),
+        ("app-2.tar", "hello, world!!"),
+    ] {
+        let path = input_dir.join(name);
+        std::fs::write(&path, body).unwrap();
+        let out = cli(workdir, &["push", path.to_str().unwrap()]);
+        assert!(out.status.success(), "{:?}", out);
+    }
+}
+
+fn db_execute(workdir: &Path, sql: &str) {
+    let conn = rusqlite::Connection::open(workdir.join("meta.db")).unwrap();
+    conn.execute_batch(sql).unwrap();
+}
+
+#[test]
+fn exit_code_unknown_filename() {
+    let dir = tempfile::tempdir().unwrap();
+    push_two_versions(dir.path());
+
+    let out_path = dir.path().join("out.tar");
+    let out = cli(
+        dir.path(),
+        &["get", "unknown.tar", out_path.to_str().unwrap()],
+    );
+    assert_eq!(out.status.code(), Some(increstore::EXIT_UNKNOWN_FILENAME));
+}
+
+#[test]
+fn exit_code_missing_object() {
+    let dir = tempfile::tempdir().unwrap();
+    push_two_versions(dir.path());
+
+    let conn = rusqlite::Connection::open(dir.path().join("meta.db")).unwrap();
+    let store_hash: String = conn
+        .query_row("select store_hash from blobs where id = 3", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    let object = dir
+        .path()
+        .join("objects")
+        .join(&store_hash[..2])
+        .join(&store_hash[2..]);
+    std::fs::remove_file(object).unwrap();
+
+    let out_path = dir.path().join("out.tar");
+    let out = cli(
+        dir.path(),
+        &["get", "app-2.tar", out_path.to_str().unwrap()],
+    );
+    assert_eq!(out.status.code(), Some(increstore::EXIT_MISSING_OBJECT));
+}
+
+#[test]
+fn exit_code_checksum_mismatch() {
+    let dir = tempfile::tempdir().unwrap();
+    push_two_versions(dir.path());
+
+    db_execute(
+        dir.path(),
+        "update blobs set content_hash = 'ffff' where id = 3",
+    );
+
+    let out_path = dir.path().join("out.tar");
+    let out = cli(
+        dir.path(),
+        &["get", "app-2.tar", out_path.to_str().unwrap()],
+    );
+    assert_eq!(out.status.code(), Some(increstore::EXIT_CHECKSUM_MISMATCH));
+}
+
+#[test]
+fn exit_code_locked() {
+    let dir = tempfile::tempdir().unwrap();
+    push_two_versions(dir.path());
+
+    let conn = rusqlite::Connection::open(dir.path().join("meta.db")).unwrap();
+    conn.execute_batch("begin exclusive").unwrap();
+
+    let out = cli(dir.path(), &["debug-stats"]);
+    assert_eq!(out.status.code(), Some(increstore::EXIT_LOCKED));
+}
+
+#[test]
+fn exit_code_unsupported_hash_algo() {
+    let dir = tempfile::tempdir().unwrap();
+    push_two_versions(dir.path());
+
+    db_execute(
+        dir.path(),
+        "update settings set value = 'sha1' where key = 'hash_algo'",
+    );
+    let input = dir.path().join("input").join("app-3.tar");
+    std::fs::write(&input, "hello, world!!!").unwrap();
+    let out = cli(dir.path(), &["push", input.to_str().unwrap()]);
+    assert_eq!(
+        out.status.code(),
+        Some(increstore::EXIT_UNSUPPORTED_HASH_ALGO)
+    );
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("unsupported hash algorithm"), "{}", stderr);
+
+    let conn = rusqlite::Connection::open(dir.path().join("meta.db")).unwrap();
+    let count: i64 = conn
+        .query_row("select count(*) from blobs", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 3);
+}
+
+#[test]
+fn push_report_path() {
+    let dir = tempfile::tempdir().unwrap();
+    push_two_versions(dir.path());
+
+    let input = dir.path().join("input").join("app-3.tar");
+    std::fs::write(&input, "hello, world!!!").unwrap();
+    let report_path = dir.path().join("push.json");
+    let out = cli(
+        dir.path(),
+        &[
+            "push",
+            input.to_str().unwrap(),
+            "--report-path",
+            report_path.to_str().unwrap(),
+        ],
+    );
+    assert!(out.status.success(), "{:?}", out);
+
+    let report: increstore::PushReport =
+        serde_json::from_slice(&std::fs::read(&report_path).unwrap()).unwrap();
+    assert_eq!(report.filename, "app-3.tar");
+    assert!(!report.skipped);
+    let delta = report.delta.unwrap();
+    assert_eq!(delta.filename, "app-3.tar");
+    assert_eq!(report.ratio, Some(delta.compression_ratio()));
+
+    let report_path = dir.path().join("validate.json");
+    let out = cli(
+        dir.path(),
+        &["validate", "--report-path", report_path.to_str().unwrap()],
+    );
+    assert!(out.status.success(), "{:?}", out);
+    let report: increstore::ValidateReport =
+        serde_json::from_slice(&std::fs::read(&report_path).unwrap()).unwrap();
+    assert!(report.ok);
+    assert_eq!(report.blob_count, 5);
+}
+
+#[test]
+fn watch_pushes_new_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let incoming = dir.path().join("incoming");
+    std::fs::create_dir_all(&incoming).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_cli"))
+        .env("WORKDIR", dir.path())
+        .args(["watch", "--dir", incoming.to_str().unwrap()])
+        .args(["--pattern", "app-*.tar"])
+        .args(["--stable-secs", "1", "--delete-after"])
+        .spawn()
+        .expect("failed to run cli");
+
+    std::fs::write(incoming.join("app-1.tar"), "hello, world").unwrap();
+    std::fs::write(incoming.join("app-2.tar"), "hello, world!!").unwrap();
+    std::fs::write(incoming.join("other.tar"), "ignored").unwrap();
+
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(30);
+    let mut pushed = 0;
+    while std::time::Instant::now() < deadline {
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        if !dir.path().join("meta.db").exists() {
+            continue;
+        }
+        let conn = rusqlite::Connection::open(dir.path().join("meta.db")).unwrap();
+        pushed = conn
+            .query_row(
+                "select count(distinct filename) from blobs where filename like 'app-%'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap_or(0);
+        if pushed == 2 && !incoming.join("app-2.tar").exists() {
+            break;
+        }
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    assert_eq!(pushed, 2);
+    assert!(!incoming.join("app-1.tar").exists());
+    assert!(!incoming.join("app-2.tar").exists());
+    assert!(incoming.join("other.tar").exists());
+}
+
+#[test]
+fn list_files_json() {
+    let dir = tempfile::tempdir().unwrap();
+    push_two_versions(dir.path());
+
+    let out = cli(dir.path(), &["debug-ls-files", "--roots", "--json"]);
+    assert!(out.status.success(), "{:?}", out);
+
+    let blobs = String::from_utf8(out.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<increstore::db::Blob>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(blobs.len(), 2);
+    assert_eq!(blobs[0].filename, "app-1.tar");
+    assert!(blobs.iter().all(|blob| blob.is_root()));
+}
+
+#[test]
+fn get_pairs_partial_failure() {
+    let dir = tempfile::tempdir().unwrap();
+    push_two_versions(dir.path());
+
+    let out = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+    let pairs = [
+        format!("app-1.tar={}", out("out-1")),
+        format!("app-2.tar={}", out("out-2")),
+        format!("unknown.tar={}", out("out-3")),
+    ];
+    let mut args = vec!["get"];
+    args.extend(pairs.iter().map(|pair| pair.as_str()));
+    let res = cli(dir.path(), &args);
+    assert!(!res.status.success());
+    let stdout = String::from_utf8(res.stdout).unwrap();
+    assert!(stdout.contains(&format!("ok app-2.tar {}", out("out-2"))));
+    assert!(stdout.contains("failed unknown.tar"));
+    assert_eq!(std::fs::read(out("out-1")).unwrap(), b"hello, world");
+    assert_eq!(std::fs::read(out("out-2")).unwrap(), b"hello, world!!");
+
+    // the same versions from a batch file
+    let batch = dir.path().join("batch.tsv");
+    let lines = format!("app-2.tar\t{}\n", out("out-4"));
+    std::fs::write(&batch, lines).unwrap();
+    let res = cli(dir.path(), &["get", "--batch", batch.to_str().unwrap()]);
+    assert!(res.status.success(), "{:?}", res);
+    assert_eq!(std::fs::read(out("out-4")).unwrap(), b"hello, world!!");
+}
+
+#[test]
+fn get_no_clobber() {
+    let dir = tempfile::tempdir().unwrap();
+    push_two_versions(dir.path());
+    let out_path = dir.path().join("out.tar");
+    std::fs::write(&out_path, "existing").unwrap();
+    let out_path = out_path.to_str().unwrap();
+
+    // the default, stated explicitly
+    let out = cli(dir.path(), &["get", "--no-clobber", "app-1.tar", out_path]);
+    assert!(!out.status.success());
+    assert_eq!(std::fs::read(out_path).unwrap(), b"existing");
+
+    let args = ["get", "--overwrite", "--no-clobber", "app-1.tar", out_path];
+    let out = cli(dir.path(), &args);
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("exclusive"), "{}", stderr);
+    assert_eq!(std::fs::read(out_path).unwrap(), b"existing");
+
+    let out = cli(dir.path(), &["get", "--overwrite", "app-1.tar", out_path]);
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(std::fs::read(out_path).unwrap(), b"hello, world");
+}
+
+#[test]
+fn command_aliases() {
+    let dir = tempfile::tempdir().unwrap();
+    push_two_versions(dir.path());
+
+    let out = cli(dir.path(), &["ls", "--roots", "--non-roots", "-l"]);
+    assert!(out.status.success(), "{:?}", out);
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.contains("app-1.tar"), "{}", stdout);
+    assert!(stdout.contains("app-2.tar"), "{}", stdout);
+
+    let out = cli(dir.path(), &["stat"]);
+    assert!(out.status.success(), "{:?}", out);
+
+    // values of top-level options are not taken for the command
+    let out = cli(dir.path(), &["--progress", "never", "stat"]);
+    assert!(out.status.success(), "{:?}", out);
+    let out = cli(dir.path(), &["--object-dir", "stat", "stat"]);
+    assert!(out.status.success(), "{:?}", out);
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.contains("\"blob_count\""), "{}", stdout);
+    let out = cli(
+        dir.path(),
+        &["--progress=never", "--object-dir=stat", "stat"],
+    );
+    assert!(out.status.success(), "{:?}", out);
+}
+
+#[test]
+fn top_level_value_options() {
+    let dir = tempfile::tempdir().unwrap();
+    let out = cli(dir.path(), &["--help"]);
+    assert!(out.status.success(), "{:?}", out);
+    let stdout = String::from_utf8(out.stdout).unwrap();
+
+    // options shown as `[--name <value>]` on the usage line, which VALUE_OPTIONS of the cli lists
+    let usage = stdout.lines().next().unwrap();
+    let options = usage
+        .split('[')
+        .filter_map(|option| {
+            let mut words = option.trim_end_matches([']', ' ']).split_whitespace();
+            let name = words.next().filter(|name| name.starts_with("--"))?;
+            words.next()?.starts_with('<').then_some(name)
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(options, ["--object-dir", "--progress"], "{}", usage);
+}
+
+#[test]
+fn unknown_command_suggestion() {
+    let dir = tempfile::tempdir().unwrap();
+    let out = cli(dir.path(), &["--keep-temp", "puhs", "app-1.tar"]);
+    // EXIT_USAGE, not an error of a command such as EXIT_UNKNOWN_FILENAME
+    assert_eq!(out.status.code(), Some(64));
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("unknown command: puhs"), "{}", stderr);
+    assert!(stderr.contains("Did you mean 'push'?"), "{}", stderr);
+    // nothing is created for a mistyped command
+    assert!(!dir.path().join("meta.db").exists());
+}
+
+#[test]
+fn no_provenance() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_dir = dir.path().join("input");
+    std::fs::create_dir_all(&input_dir).unwrap();
+    let input = input_dir.join("app-1.tar");
+    std::fs::write(&input, "hello, world").unwrap();
+
+    let out = cli(
+        dir.path(),
+        &["--no-provenance", "push", input.to_str().unwrap()],
+    );
+    assert!(out.status.success(), "{:?}", out);
+    let out = cli(dir.path(), &["describe", "app-1.tar"]);
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.contains("provenance     unknown"), "{}", stdout);
+
+    let out = cli(dir.path(), &["debug-ls-files", "--roots", "--json"]);
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.contains("\"source_path\":null"), "{}", stdout);
+}
+
+#[test]
+fn push_interrupted() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_dir = dir.path().join("input");
+    std::fs::create_dir_all(&input_dir).unwrap();
+    // large enough for the push to be interrupted midway
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut random = |len: usize| {
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect::<Vec<_>>()
+    };
+    let v1 = input_dir.join("app-1.tar");
+    let v2 = input_dir.join("app-2.tar");
+    std::fs::write(&v1, random(32 << 20)).unwrap();
+    std::fs::write(&v2, random(32 << 20)).unwrap();
+    let out = cli(dir.path(), &["push", v1.to_str().unwrap()]);
+    assert!(out.status.success(), "{:?}", out);
+
+    let child = Command::new(env!("CARGO_BIN_EXE_cli"))
+        .env("WORKDIR", dir.path())
+        .args(["push", v2.to_str().unwrap()])
+        .spawn()
+        .expect("failed to run cli");
+    // the push has started once it writes temporary files
+    let tmp = dir.path().join("tmp");
+    while std::fs::read_dir(&tmp).unwrap().count() == 0 {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+    let out = child.wait_with_output().unwrap();
+    assert_eq!(out.status.code(), Some(increstore::EXIT_CANCELLED));
+
+    // nothing is left of the second version
+    let conn = rusqlite::Connection::open(dir.path().join("meta.db")).unwrap();
+    let blobs: usize = conn
+        .query_row("select count(*) from blobs", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(blobs, 1);
+    assert_eq!(std::fs::read_dir(&tmp).unwrap().count(), 0);
+    let objects = walkdir::WalkDir::new(dir.path().join("objects"))
+        .into_iter()
+        .filter(|entry| entry.as_ref().unwrap().file_type().is_file())
+        .count();
+    assert_eq!(objects, 1);
+}
+
+#[test]
+fn gc_messy_store() {
+    let dir = tempfile::tempdir().unwrap();
+    push_two_versions(dir.path());
+    let old = std::time::SystemTime::now() - std::time::Duration::from_secs(2 * 24 * 3600);
+    let write = |path: std::path::PathBuf, len: usize, stale: bool| {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, vec![0u8; len]).unwrap();
+        if stale {
+            let file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(old).unwrap();
+        }
+        path
+    };
+
+    // left by killed pushes, and by a running one
+    let stale_tmp = write(dir.path().join("tmp/.tmpstale"), 100, true);
+    let fresh_tmp = write(dir.path().join("tmp/.tmpfresh"), 100, false);
+    let stale_object = write(dir.path().join("objects/ab/cdef"), 1000, true);
+    let fresh_object = write(dir.path().join("objects/ab/cdeg"), 1000, false);
+    // the least recently used entry is evicted
+    let cache_dir = dir.path().join("cache/intermediate");
+    let evicted = write(cache_dir.join("a"), 1024, true);
+    let kept = write(cache_dir.join("b"), 1024, false);
+
+    let run = |args: &[&str]| {
+        let mut cmd = vec!["gc", "--cache-budget", "1500", "--json"];
+        cmd.extend_from_slice(args);
+        let out = cli(dir.path(), &cmd);
+        assert!(out.status.success(), "{:?}", out);
+        serde_json::from_slice::<serde_json::Value>(&out.stdout).unwrap()
+    };
+    let phase = |report: &serde_json::Value, name: &str| {
+        let phases = report["phases"].as_array().unwrap();
+        phases.iter().find(|p| p["name"] == name).unwrap().clone()
+    };
+
+    let report = run(&["--dry-run", "--aggressive"]);
+    assert_eq!(phase(&report, "tmp")["reclaimed"]["bytes"], 100);
+    assert_eq!(phase(&report, "cache")["reclaimed"]["bytes"], 1024);
+    assert_eq!(phase(&report, "orphans")["reclaimed"]["bytes"], 1000);
+    assert_eq!(report["bytes_reclaimed"], 100 + 1024 + 1000);
+    for path in [&stale_tmp, &stale_object, &evicted] {
+        assert!(path.exists());
+    }
+
+    // orphaned objects are pruned only with --aggressive
+    let report = run(&["--skip-tmp"]);
+    assert_eq!(phase(&report, "tmp")["skipped"], true);
+    assert_eq!(phase(&report, "orphans")["skipped"], true);
+    assert!(stale_tmp.exists() && stale_object.exists());
+    assert!(!evicted.exists() && kept.exists());
+
+    let report = run(&["--aggressive"]);
+    assert_eq!(report["bytes_reclaimed"], 100 + 1000);
+    assert!(!stale_tmp.exists() && fresh_tmp.exists());
+    assert!(!stale_object.exists() && fresh_object.exists());
+    assert_eq!(report["blob_count"], 3);
+
+    // the store is intact
+    let out_path = dir.path().join("out.tar");
+    let out = cli(
+        dir.path(),
+        &["get", "app-2.tar", out_path.to_str().unwrap()],
+    );
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(std::fs::read(&out_path).unwrap(), b"hello, world!!");
+}
+
+#[test]
+fn progress_modes() {
+    let dir = tempfile::tempdir().unwrap();
+    push_two_versions(dir.path());
+
+    // stderr is a pipe here: auto draws nothing even off CI, and never draws nothing anyway
+    for (i, mode) in ["never", "auto"].iter().enumerate() {
+        let out_path = dir.path().join(format!("out-{}.tar", i));
+        let out = Command::new(env!("CARGO_BIN_EXE_cli"))
+            .env("WORKDIR", dir.path())
+            .env_remove("CI")
+            .env_remove("NO_COLOR")
+            .args(["--progress", mode, "get", "app-2.tar"])
+            .arg(&out_path)
+            .output()
+            .expect("failed to run cli");
+        assert!(out.status.success(), "{:?}", out);
+        assert!(!out.stderr.contains(&b'\r'), "{:?}", out);
+        assert_eq!(std::fs::read(&out_path).unwrap(), b"hello, world!!");
+    }
+
+    let out = cli(dir.path(), &["--progress", "sometimes", "list-files"]);
+    assert!(!out.status.success());
+}
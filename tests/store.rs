@@ -0,0 +1,132 @@
+use std::path::Path;
+use std::process::Command;
+
+use increstore::Store;
+
+/// pushes app-1.tar (genesis) and app-2.tar (blob 2, with delta blob 3) with the cli, so the
+/// store is written the same way as in production
+fn push_two_versions(workdir: &Path) {
+    let input_dir = workdir.join("input");
+    std::fs::create_dir_all(&input_dir).unwrap();
+    for (name, body) in &[
+        ("app-1.tar", "hello, world"),
+        ("app-2.tar", "hello, world!!"),
+    ] {
+        let path = input_dir.join(name);
+        std::fs::write(&path, body).unwrap();
+        let out = Command::new(env!("CARGO_BIN_EXE_cli"))
+            .env("WORKDIR", workdir)
+            .args(["push", path.to_str().unwrap()])
+            .output()
+            .expect("failed to run cli");
+        assert!(out.status.success(), "{:?}", out);
+    }
+}
+
+#[test]
+fn readonly_store() {
+    let dir = tempfile::tempdir().unwrap();
+    push_two_versions(dir.path());
+    let meta_db = std::fs::read(dir.path().join("meta.db")).unwrap();
+
+    let mut store = Store::open_readonly(dir.path()).unwrap();
+    let versions = store.versions().unwrap();
+    let filenames = versions
+        .iter()
+        .map(|blob| blob.filename.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(filenames, vec!["app-1.tar", "app-2.tar"]);
+
+    for blob in &versions {
+        let report = store.verify(&blob.filename).unwrap();
+        assert!(report.ok, "{:?}", report);
+    }
+
+    let out = dir.path().join("out.tar");
+    store.read("app-2.tar", &out).unwrap();
+    assert_eq!(std::fs::read(&out).unwrap(), b"hello, world!!");
+    // an existing output is not replaced
+    assert!(store.read("app-1.tar", &out).is_err());
+
+    let mut archive = Vec::new();
+    let summary = store.archive_to(&mut archive).unwrap();
+    let mut names = tar::Archive::new(archive.as_slice())
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap().path().unwrap().to_str().unwrap().to_owned())
+        .collect::<Vec<_>>();
+    assert_eq!(names.len(), summary.files);
+    assert_eq!(names.remove(0), "meta.db");
+    assert!(names.iter().all(|name| name.starts_with("objects/")));
+
+    // nothing is written, and the environment of the caller is left as is
+    assert_eq!(std::fs::read(dir.path().join("meta.db")).unwrap(), meta_db);
+    assert!(std::env::var_os("WORKDIR").is_none());
+    assert!(std::env::var_os(increstore::OBJECT_DIR_ENV).is_none());
+}
+
+#[test]
+fn verify_broken_version() {
+    let dir = tempfile::tempdir().unwrap();
+    push_two_versions(dir.path());
+
+    let mut store = Store::open_readonly(dir.path()).unwrap();
+    let delta = store
+        .versions()
+        .unwrap()
+        .into_iter()
+        .find(|blob| blob.filename == "app-2.tar")
+        .unwrap();
+    assert!(!delta.is_root());
+    let object = dir
+        .path()
+        .join("objects")
+        .join(&delta.store_hash[..2])
+        .join(&delta.store_hash[2..]);
+    std::fs::write(object, b"broken").unwrap();
+
+    let report = store.verify("app-2.tar").unwrap();
+    assert!(!report.ok);
+    assert!(report.error.is_some());
+    assert!(store.verify("app-1.tar").unwrap().ok);
+    assert!(store.verify("unknown.tar").is_err());
+}
+
+#[test]
+fn readonly_unmigrated_store() {
+    let dir = tempfile::tempdir().unwrap();
+    push_two_versions(dir.path());
+
+    // back to the schema of a store created before migrations: only the original blobs columns
+    let conn = rusqlite::Connection::open(dir.path().join("meta.db")).unwrap();
+    let tables = conn
+        .prepare("select name from sqlite_master where type = 'table' and name != 'blobs'")
+        .unwrap()
+        .query_map([], |row| row.get::<_, String>(0))
+        .unwrap()
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .unwrap();
+    for table in tables {
+        conn.execute(&format!("drop table {}", table), []).unwrap();
+    }
+    conn.execute_batch(
+        r#"
+create table blobs_old as select
+    id, filename, time_created,
+    store_size, content_size, store_hash, content_hash, parent_hash
+from blobs;
+drop table blobs;
+alter table blobs_old rename to blobs;
+"#,
+    )
+    .unwrap();
+    drop(conn);
+
+    let mut store = Store::open_readonly(dir.path()).unwrap();
+    assert_eq!(store.versions().unwrap().len(), 2);
+    assert!(store.verify("app-2.tar").unwrap().ok);
+    let out = dir.path().join("out.tar");
+    store.read("app-2.tar", &out).unwrap();
+    assert_eq!(std::fs::read(&out).unwrap(), b"hello, world!!");
+    store.archive_to(std::io::sink()).unwrap();
+}